@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 
 mod bindings {
@@ -14,30 +15,40 @@ use bindings::exports::osagent::agent::planner::{
     self, AgentError, CompletePlan, ContinuePlan, PlannedAction, StepResponse,
 };
 use bindings::osagent::common::types::{CapabilityError, CapabilityErrorCode};
-use bindings::osagent::llm::llm::{self, Message, Role};
+use bindings::osagent::llm::llm::{self, Message, Role, ToolSchema};
 
 const SYSTEM_PROMPT: &str = r#"
 You are an expert automation planner operating inside a secure agent runtime.
-Respond ONLY with JSON matching this schema:
-{
-  "status": "continue" | "complete",
-  "thought": "human-readable reasoning",
-  "actions": [
-     { "capability": "fs.list_dir" | "proc.spawn" | "fs.read_file",
-       "input": { ... json arguments ... }
-     }
-  ],
-  "result": { ... final result json when status == "complete" },
-  "reason": "short explanation when status == \"complete\""
-}
-When status is \"continue\" you MUST include at least one action describing the next capability call.
-Capabilities available:
-- fs.list_dir { "path": "<relative path>" }
-- fs.read_file { "path": "<relative path>", "max_bytes": 4096 }
-- proc.spawn { "command": "<program>", "args": ["..."] }
-Always keep paths relative to the provided workspace.
+Plan the next step by calling one or more tools; each call is executed and its
+result comes back as the next observation. When the task is done, call
+task_complete instead. Always keep paths relative to the provided workspace.
 "#;
 
+/// Tool name the model calls to finish the task.
+const COMPLETE_TOOL: &str = "task_complete";
+
+/// Capabilities offered as tools. Tool names may not contain dots, so each
+/// capability is offered under its name with `_` for `.`.
+const CAPABILITY_TOOLS: &[(&str, &str, &str)] = &[
+    (
+        "fs.list_dir",
+        "List the entries of a workspace directory.",
+        r#"{"type":"object","properties":{"path":{"type":"string","description":"Relative path; the workspace root when omitted"}}}"#,
+    ),
+    (
+        "fs.read_file",
+        "Read the start of a workspace file.",
+        r#"{"type":"object","properties":{"path":{"type":"string"},"max_bytes":{"type":"integer","description":"Defaults to 4096"}},"required":["path"]}"#,
+    ),
+    (
+        "proc.spawn",
+        "Run an allowed program in the workspace and capture its output.",
+        r#"{"type":"object","properties":{"command":{"type":"string"},"args":{"type":"array","items":{"type":"string"}}},"required":["command"]}"#,
+    ),
+];
+
+const COMPLETE_SCHEMA: &str = r#"{"type":"object","properties":{"reason":{"type":"string","description":"Short explanation of the outcome"},"result":{"description":"Final result as JSON"}},"required":["reason"]}"#;
+
 struct Agent;
 
 impl planner::Guest for Agent {
@@ -60,44 +71,66 @@ fn plan_with_llm(
         stop: Vec::new(),
         presence_penalty: None,
         frequency_penalty: None,
+        json: false,
+        json_schema: None,
+        profile: None,
     };
-    let completion = llm::complete(&messages, &options).map_err(cap_err("llm.complete"))?;
-    let envelope: PlanEnvelope = serde_json::from_str(&completion.content).map_err(|err| {
+    let response =
+        llm::call_tools(&messages, &tools(), &options).map_err(cap_err("llm.call_tools"))?;
+    let calls: Vec<ToolCall> = serde_json::from_str(&response.tool_calls_json).map_err(|err| {
         AgentErr::fatal(format!(
-            "failed to parse LLM response: {err}; content: {}",
-            completion.content
+            "failed to parse LLM tool calls: {err}; content: {}",
+            response.tool_calls_json
         ))
     })?;
 
-    match envelope.status {
-        PlanStatus::Continue => {
-            let actions = envelope
-                .actions
-                .ok_or_else(|| AgentErr::fatal("LLM continuation missing actions"))?;
-            if actions.is_empty() {
-                return Err(AgentErr::fatal("LLM returned no actions"));
-            }
-            let planned = actions
-                .into_iter()
-                .map(to_planned_action)
-                .collect::<Result<_, _>>()?;
-            let thought = envelope
-                .thought
-                .unwrap_or_else(|| "No reasoning provided.".to_string());
-            Ok(StepResponse::Continue(ContinuePlan {
-                thought,
-                actions: planned,
-            }))
-        }
-        PlanStatus::Complete => {
-            let reason = envelope
-                .reason
-                .or(envelope.thought)
-                .unwrap_or_else(|| "task complete".to_string());
-            let outcome = envelope.result.unwrap_or(Value::Null).to_string();
-            Ok(StepResponse::Complete(CompletePlan { reason, outcome }))
-        }
+    if let Some(call) = calls
+        .iter()
+        .find(|call| call.function.name == COMPLETE_TOOL)
+    {
+        let args: CompleteArgs = parse_arguments(call)?;
+        let outcome = args.result.unwrap_or(Value::Null).to_string();
+        return Ok(StepResponse::Complete(CompletePlan {
+            reason: args.reason,
+            outcome,
+        }));
+    }
+    if calls.is_empty() {
+        return Err(AgentErr::fatal("LLM returned no tool calls"));
     }
+    let actions = calls
+        .iter()
+        .map(to_planned_action)
+        .collect::<Result<Vec<_>, _>>()?;
+    let thought = format!(
+        "Calling {}",
+        actions
+            .iter()
+            .map(|action| action.capability.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(StepResponse::Continue(ContinuePlan { thought, actions }))
+}
+
+fn tools() -> Vec<ToolSchema> {
+    CAPABILITY_TOOLS
+        .iter()
+        .map(|(capability, description, schema)| ToolSchema {
+            name: tool_name(capability),
+            description: description.to_string(),
+            schema_json: schema.to_string(),
+        })
+        .chain([ToolSchema {
+            name: COMPLETE_TOOL.to_string(),
+            description: "Finish the task and report its outcome.".to_string(),
+            schema_json: COMPLETE_SCHEMA.to_string(),
+        }])
+        .collect()
+}
+
+fn tool_name(capability: &str) -> String {
+    capability.replace('.', "_")
 }
 
 fn build_messages(task: &str, observation: &planner::Observation) -> Vec<Message> {
@@ -118,14 +151,36 @@ fn build_messages(task: &str, observation: &planner::Observation) -> Vec<Message
     ]
 }
 
-fn to_planned_action(action: LlmAction) -> Result<PlannedAction, AgentErr> {
+fn to_planned_action(call: &ToolCall) -> Result<PlannedAction, AgentErr> {
+    let capability = CAPABILITY_TOOLS
+        .iter()
+        .map(|(capability, _, _)| *capability)
+        .find(|capability| tool_name(capability) == call.function.name)
+        .ok_or_else(|| {
+            AgentErr::fatal(format!("LLM called unknown tool `{}`", call.function.name))
+        })?;
+    let input: Value = parse_arguments(call)?;
     Ok(PlannedAction {
-        capability: action.capability,
-        input: action.input.to_string(),
+        capability: capability.to_string(),
+        input: input.to_string(),
         audit_tag: None,
     })
 }
 
+fn parse_arguments<T: DeserializeOwned>(call: &ToolCall) -> Result<T, AgentErr> {
+    // Some providers send empty arguments for a call without parameters.
+    let arguments = match call.function.arguments.trim() {
+        "" => "{}",
+        arguments => arguments,
+    };
+    serde_json::from_str(arguments).map_err(|err| {
+        AgentErr::fatal(format!(
+            "invalid arguments for tool `{}`: {err}; arguments: {}",
+            call.function.name, call.function.arguments
+        ))
+    })
+}
+
 fn cap_err(op: &'static str) -> impl Fn(CapabilityError) -> AgentErr {
     move |err| {
         let retryable = matches!(err.code, CapabilityErrorCode::Unavailable);
@@ -162,23 +217,18 @@ impl AgentErr {
 }
 
 #[derive(Deserialize)]
-struct PlanEnvelope {
-    status: PlanStatus,
-    thought: Option<String>,
-    actions: Option<Vec<LlmAction>>,
-    result: Option<Value>,
-    reason: Option<String>,
+struct ToolCall {
+    function: FunctionCall,
 }
 
 #[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum PlanStatus {
-    Continue,
-    Complete,
+struct FunctionCall {
+    name: String,
+    arguments: String,
 }
 
 #[derive(Deserialize)]
-struct LlmAction {
-    capability: String,
-    input: Value,
+struct CompleteArgs {
+    reason: String,
+    result: Option<Value>,
 }
@@ -0,0 +1,9 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=../proto");
+        tonic_build::compile_protos("../proto/warden/v1/control.proto")
+            .expect("failed to compile proto/warden/v1/control.proto");
+    }
+}
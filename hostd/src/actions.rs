@@ -1,20 +1,43 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::{Read, Take};
-use std::path::{Component, Path};
-use std::process::Command;
-use std::time::Duration;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, anyhow, bail};
 use base64::{Engine as _, engine::general_purpose::STANDARD as Base64};
 use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use thirtyfour::prelude::*;
 use tokio::runtime::Handle;
+use tracing::{info, warn};
 
+use crate::archive::{self, ExtractLimits};
+use crate::audit;
 use crate::bindings::exports::osagent::agent::planner::PlannedAction;
-use crate::config::{BrowserSettings, HostConfig};
+use crate::browser::{
+    self, BrowserElement, BrowserKind, BrowserSession, FrameTarget, OptionChoice, PdfOptions,
+    ScreenshotKind, ScreenshotOptions, Selector, SelectorKind, WaitCondition,
+};
+use crate::budget::{BudgetKind, Budgets};
+use crate::capabilities;
+use crate::cgroup::Cgroup;
+use crate::changes::{ChangeKind, ChangeSet};
+use crate::config::{BrowserSettings, CAPABILITIES, HostConfig};
+use crate::deadline;
+use crate::encoding;
+use crate::executor::{self, CommandSpec, Prepared};
+use crate::grant::{self, GrantDecision, Grants};
+use crate::notify;
+use crate::policy::Access;
+use crate::process::{self, CapturedOutput, Priority, Watchdog};
+use crate::ratelimit::RateLimits;
+use crate::shell::ShellSession;
+use crate::snapshot::{RestoreSummary, Snapshot};
+use crate::taint::{TaintMode, Untrusted};
+use crate::workspace;
 
 #[derive(Debug, Serialize)]
 pub struct ActionReport {
@@ -22,6 +45,12 @@ pub struct ActionReport {
     pub success: bool,
     pub output: Value,
     pub error: Option<String>,
+    /// Set when `output` or `error` was cut to the action's output limit.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// What the whole output took serialized, when it was cut.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<usize>,
 }
 
 impl ActionReport {
@@ -31,6 +60,8 @@ impl ActionReport {
             success: true,
             output,
             error: None,
+            truncated: false,
+            output_bytes: None,
         }
     }
 
@@ -40,52 +71,262 @@ impl ActionReport {
             success: false,
             output: Value::Null,
             error: Some(err.to_string()),
+            truncated: false,
+            output_bytes: None,
         }
     }
+
+    /// Cuts the output to about `limit` bytes serialized, keeping its shape
+    /// so the planner can still read what is left: long strings are
+    /// shortened, long arrays and objects lose their tail, and base64 data
+    /// that does not fit is dropped. A long error is shortened too.
+    pub fn truncate(&mut self, limit: usize) {
+        let bytes = serde_json::to_vec(&self.output).map_or(0, |json| json.len());
+        if bytes > limit {
+            let mut budget = limit;
+            self.output = fit_json(&self.output, None, &mut budget);
+            self.output_bytes = Some(bytes);
+            self.truncated = true;
+        }
+        if let Some(error) = self.error.as_mut().filter(|error| error.len() > limit) {
+            error.truncate(char_boundary(error, limit));
+            self.truncated = true;
+        }
+    }
+
+    /// The report for an action's result, recorded in the audit log.
+    pub fn recorded(capability: String, result: Result<Value>) -> Self {
+        let report = match result {
+            Ok(value) => Self::succeeded(capability, value),
+            Err(err) => Self::failed(capability, err),
+        };
+        audit::record(
+            "host",
+            "action",
+            None,
+            json!({
+                "capability": report.capability,
+                "success": report.success,
+                "error": report.error,
+            }),
+        );
+        report
+    }
 }
 
 pub struct ActionExecutor {
     config: HostConfig,
     tokio: Handle,
-    browser_sessions: HashMap<String, BrowserSessionEntry>,
+    browser_sessions: HashMap<String, BrowserSession>,
     browser_elements: HashMap<String, BrowserElementEntry>,
-}
-
-struct BrowserSessionEntry {
-    driver: WebDriver,
-    #[allow(dead_code)]
-    profile: Option<String>,
+    shell_sessions: HashMap<String, ShellSession>,
+    changes: ChangeSet,
+    snapshot: Option<Snapshot>,
+    budgets: Arc<Budgets>,
+    grants: Arc<Grants>,
+    rate_limits: Arc<RateLimits>,
+    untrusted: Arc<Untrusted>,
 }
 
 struct BrowserElementEntry {
-    element: WebElement,
-    #[allow(dead_code)]
+    element: BrowserElement,
     session: String,
 }
 
 impl ActionExecutor {
-    pub fn new(config: HostConfig, tokio: Handle) -> Self {
+    pub fn new(
+        config: HostConfig,
+        tokio: Handle,
+        budgets: Arc<Budgets>,
+        grants: Arc<Grants>,
+        rate_limits: Arc<RateLimits>,
+        untrusted: Arc<Untrusted>,
+    ) -> Self {
         Self {
             config,
             tokio,
             browser_sessions: HashMap::new(),
             browser_elements: HashMap::new(),
+            shell_sessions: HashMap::new(),
+            changes: ChangeSet::default(),
+            snapshot: None,
+            budgets,
+            grants,
+            rate_limits,
+            untrusted,
         }
     }
 
-    pub fn execute(&mut self, actions: &[PlannedAction]) -> Vec<ActionReport> {
-        actions
-            .iter()
-            .map(|action| self.execute_action(action))
-            .collect()
+    /// Installs the pre-task workspace snapshot used by `fs.revert` and rollback.
+    pub fn set_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Restores the workspace snapshot, if one was taken.
+    pub fn rollback(&mut self) -> Result<Option<RestoreSummary>> {
+        match self.snapshot.as_ref() {
+            Some(snapshot) => snapshot.restore(&mut self.changes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies a reloaded `[policy]` to the actions that follow.
+    pub fn reload_policy(&mut self, fresh: &HostConfig) -> Result<()> {
+        self.config.reload_policy(fresh)
+    }
+
+    /// Drains the workspace paths changed by actions since the previous call.
+    pub fn take_changes(&mut self) -> ChangeSet {
+        self.changes.take()
+    }
+
+    pub fn execute_action(&mut self, action: &PlannedAction) -> ActionReport {
+        self.close_idle_browser_sessions();
+        let result = self.execute_budgeted(action);
+        ActionReport::recorded(action.capability.clone(), result)
+    }
+
+    /// Checks that the policy lets the action run now: its interface is
+    /// enabled, its rate limit has room and a reviewer approved it when
+    /// `approval_required` lists it.
+    pub fn admit(&self, action: &PlannedAction) -> Result<()> {
+        let capability = action.capability.as_str();
+        let interface = capability.split('.').next().unwrap_or_default();
+        if CAPABILITIES.contains(&interface) && !self.config.is_capability_enabled(interface) {
+            bail!("the {interface} capability is not in enabled_capabilities");
+        }
+        let function = capability.rsplit('.').next().unwrap_or_default();
+        self.rate_limits.admit(interface, function)?;
+        if self.config.policy.needs_approval(capability) {
+            self.await_approval(action)?;
+        }
+        Ok(())
+    }
+
+    /// Holds an `approval_required` action until a reviewer approves it,
+    /// unless an earlier answer already covers this step or task.
+    fn await_approval(&self, action: &PlannedAction) -> Result<()> {
+        let capability = action.capability.as_str();
+        if self.grants.allows(capability, None) {
+            return Ok(());
+        }
+        let pending = grant::Pending {
+            kind: "action",
+            capability,
+            scope: None,
+            detail: &action.input,
+        };
+        let answer = grant::approve(&self.config, &pending)
+            .map_err(|reason| anyhow!("`{capability}` needs approval: {reason}"))?;
+        self.grants.insert(capability, None, answer.decision);
+        if answer.decision == GrantDecision::Deny {
+            let reason = answer.reason.as_deref().unwrap_or("denied by the reviewer");
+            bail!("`{capability}` was not approved: {reason}");
+        }
+        Ok(())
+    }
+
+    /// Holds an operation `[policy.confirm]` flagged until a reviewer
+    /// confirms it, once per exact command or path.
+    fn confirm(&self, capability: &str, scope: &str, reason: &str) -> Result<()> {
+        grant::confirm(&self.config, &self.grants, capability, scope, reason)
+            .map_err(|why| anyhow!("`{scope}` needs confirmation ({reason}): {why}"))
+    }
+
+    /// Resolves a path the action writes to, confirming it first when it
+    /// falls outside `policy.confirm.writes_outside`.
+    fn resolve_write_target(&self, relative: &str) -> Result<Utf8PathBuf> {
+        let target = resolve_workspace_child(&self.config, relative, Access::Write)?;
+        if let Some(reason) = self
+            .config
+            .policy
+            .write_confirmation(&self.config.workspace_root, &target)
+        {
+            self.confirm("fs.write", relative, &reason)?;
+        }
+        self.check_untrusted("fs.write", relative, [relative])?;
+        Ok(target)
+    }
+
+    /// Holds an operation that quotes text the browser returned, per
+    /// `policy.taint`.
+    fn check_untrusted<'a>(
+        &self,
+        capability: &str,
+        scope: &str,
+        candidates: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let mode = self.config.policy.taint;
+        if mode == TaintMode::Off {
+            return Ok(());
+        }
+        let Some(quoted) = self.untrusted.find(candidates) else {
+            return Ok(());
+        };
+        let reason = format!("quotes web content `{quoted}` (policy.taint)");
+        if mode == TaintMode::Deny {
+            bail!("`{scope}` {reason}");
+        }
+        self.confirm(capability, scope, &reason)
+    }
+
+    /// Spends the action's share of `[policy.budgets]` around running it.
+    fn execute_budgeted(&mut self, action: &PlannedAction) -> Result<Value> {
+        self.admit(action)?;
+        let capability = action.capability.as_str();
+        if let Some(kind) = action_budget(capability) {
+            self.budgets.claim(kind, 1)?;
+        }
+        if !matches!(capability, "proc.spawn" | "shell.session.exec") {
+            return self.execute_retried(action);
+        }
+        self.budgets.check(BudgetKind::ProcessMs)?;
+        let started = Instant::now();
+        let result = self.execute_retried(action);
+        self.budgets
+            .record(BudgetKind::ProcessMs, started.elapsed().as_millis() as u64);
+        result
+    }
+
+    /// Runs the action again, up to `planner.max_retries` times with
+    /// backoff, while it fails because the browser was unavailable.
+    fn execute_retried(&mut self, action: &PlannedAction) -> Result<Value> {
+        let mut attempt = 0;
+        loop {
+            match self.execute_timed(action) {
+                Err(err)
+                    if attempt < self.config.planner.max_retries
+                        && capabilities::is_unavailable(&err) =>
+                {
+                    let delay = self.config.planner.retry_delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        capability = action.capability,
+                        attempt,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %format!("{err:#}"),
+                        "action failed as unavailable; retrying"
+                    );
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
     }
 
-    fn execute_action(&mut self, action: &PlannedAction) -> ActionReport {
-        let capability = action.capability.clone();
-        let result = self.execute_action_inner(action);
+    /// Runs the action under `planner.action_timeout_ms`. Browser requests,
+    /// processes and shell commands still waiting at the deadline fail, and
+    /// so does the action.
+    fn execute_timed(&mut self, action: &PlannedAction) -> Result<Value> {
+        let limit = self.config.planner.action_timeout;
+        let started = Instant::now();
+        let result = deadline::within(limit, || self.execute_action_inner(action));
         match result {
-            Ok(value) => ActionReport::succeeded(capability, value),
-            Err(err) => ActionReport::failed(capability, err),
+            Err(err) if started.elapsed() >= limit => Err(anyhow!(
+                "`{}` timed out after {limit:?} (planner.action_timeout_ms): {err:#}",
+                action.capability
+            )),
+            result => result,
         }
     }
 
@@ -102,10 +343,31 @@ impl ActionExecutor {
                 let params: FsReadFileInput = serde_json::from_value(input)?;
                 self.fs_read_file(params)
             }
+            "fs.revert" => self.fs_revert(),
+            "archive.extract" => {
+                let params: ArchiveExtractInput = serde_json::from_value(input)?;
+                self.archive_extract(params)
+            }
+            "archive.create" => {
+                let params: ArchiveCreateInput = serde_json::from_value(input)?;
+                self.archive_create(params)
+            }
             "proc.spawn" => {
                 let params: ProcSpawnInput = serde_json::from_value(input)?;
                 self.proc_spawn(params)
             }
+            "shell.open_session" => {
+                let params: ShellOpenSessionInput = serde_json::from_value(input)?;
+                self.shell_open_session(params)
+            }
+            "shell.session.exec" => {
+                let params: ShellExecInput = serde_json::from_value(input)?;
+                self.shell_session_exec(params)
+            }
+            "shell.session.close" => {
+                let params: ShellSessionInput = serde_json::from_value(input)?;
+                self.shell_session_close(params)
+            }
             "browser.open_session" => {
                 let params: BrowserOpenSessionInput = serde_json::from_value(input)?;
                 self.browser_open_session(params)
@@ -114,6 +376,10 @@ impl ActionExecutor {
                 let params: BrowserGotoInput = serde_json::from_value(input)?;
                 self.browser_session_goto(params)
             }
+            "browser.session.wait_for" => {
+                let params: BrowserWaitForInput = serde_json::from_value(input)?;
+                self.browser_session_wait_for(params)
+            }
             "browser.session.describe_page" => {
                 let params: BrowserDescribeInput = serde_json::from_value(input)?;
                 self.browser_session_describe(params)
@@ -122,6 +388,42 @@ impl ActionExecutor {
                 let params: BrowserFindInput = serde_json::from_value(input)?;
                 self.browser_session_find(params)
             }
+            "browser.session.close" => {
+                let params: BrowserSessionInput = serde_json::from_value(input)?;
+                self.browser_session_close(params)
+            }
+            "browser.element.release" => {
+                let params: BrowserElementActionInput = serde_json::from_value(input)?;
+                self.browser_element_release(params)
+            }
+            "browser.session.scroll" => {
+                let params: BrowserScrollInput = serde_json::from_value(input)?;
+                self.browser_session_scroll(params)
+            }
+            "browser.session.eval" => {
+                let params: BrowserEvalInput = serde_json::from_value(input)?;
+                self.browser_session_eval(params)
+            }
+            "browser.session.fill_form" => {
+                let params: BrowserFillFormInput = serde_json::from_value(input)?;
+                self.browser_session_fill_form(params)
+            }
+            "browser.session.query_all" => {
+                let params: BrowserQueryAllInput = serde_json::from_value(input)?;
+                self.browser_session_query_all(params)
+            }
+            "browser.element.select_option" => {
+                let params: BrowserElementChoiceInput = serde_json::from_value(input)?;
+                self.browser_element_select_option(params)
+            }
+            "browser.element.set_checked" => {
+                let params: BrowserElementSetCheckedInput = serde_json::from_value(input)?;
+                self.browser_element_set_checked(params)
+            }
+            "browser.element.choose_radio" => {
+                let params: BrowserElementChoiceInput = serde_json::from_value(input)?;
+                self.browser_element_choose_radio(params)
+            }
             "browser.element.click" => {
                 let params: BrowserElementActionInput = serde_json::from_value(input)?;
                 self.browser_element_click(params)
@@ -134,10 +436,50 @@ impl ActionExecutor {
                 let params: BrowserElementActionInput = serde_json::from_value(input)?;
                 self.browser_element_inner_text(params)
             }
+            "browser.element.screenshot" => {
+                let params: BrowserElementActionInput = serde_json::from_value(input)?;
+                self.browser_element_screenshot(params)
+            }
             "browser.session.screenshot" => {
                 let params: BrowserScreenshotInput = serde_json::from_value(input)?;
                 self.browser_session_screenshot(params)
             }
+            "browser.session.print_pdf" => {
+                let params: BrowserPrintPdfInput = serde_json::from_value(input)?;
+                self.browser_session_print_pdf(params)
+            }
+            "browser.session.wait_for_download" => {
+                let params: BrowserWaitForDownloadInput = serde_json::from_value(input)?;
+                self.browser_session_wait_for_download(params)
+            }
+            "browser.session.list_tabs" => {
+                let params: BrowserSessionInput = serde_json::from_value(input)?;
+                self.browser_session_list_tabs(params)
+            }
+            "browser.session.new_tab" => {
+                let params: BrowserNewTabInput = serde_json::from_value(input)?;
+                self.browser_session_new_tab(params)
+            }
+            "browser.session.switch_tab" => {
+                let params: BrowserSwitchTabInput = serde_json::from_value(input)?;
+                self.browser_session_switch_tab(params)
+            }
+            "browser.session.enter_frame" => {
+                let params: BrowserEnterFrameInput = serde_json::from_value(input)?;
+                self.browser_session_enter_frame(params)
+            }
+            "browser.session.exit_frame" => {
+                let params: BrowserSessionInput = serde_json::from_value(input)?;
+                self.browser_session_exit_frame(params)
+            }
+            "browser.session.save_state" => {
+                let params: BrowserSessionInput = serde_json::from_value(input)?;
+                self.browser_session_save_state(params)
+            }
+            "browser.session.load_state" => {
+                let params: BrowserSessionInput = serde_json::from_value(input)?;
+                self.browser_session_load_state(params)
+            }
             _ => Err(anyhow!("unsupported capability `{}`", action.capability)),
         }
     }
@@ -147,7 +489,7 @@ impl ActionExecutor {
             if path.trim().is_empty() {
                 self.config.workspace_root.clone()
             } else {
-                resolve_workspace_child(&self.config.workspace_root, &path)?
+                resolve_workspace_child(&self.config, &path, Access::Read)?
             }
         } else {
             self.config.workspace_root.clone()
@@ -162,6 +504,14 @@ impl ActionExecutor {
                 .file_name()
                 .into_string()
                 .map_err(|_| anyhow!("entry name is not valid UTF-8"))?;
+            let policy = &self.config.policy;
+            let root = &self.config.workspace_root;
+            if policy
+                .path_denial(root, &target.join(&name), Access::Read)
+                .is_some()
+            {
+                continue;
+            }
             entries.push(json!({
                 "name": name,
                 "kind": entry_kind(&metadata),
@@ -179,7 +529,7 @@ impl ActionExecutor {
         if params.path.trim().is_empty() {
             bail!("fs.read_file requires a non-empty `path`");
         }
-        let target = resolve_workspace_child(&self.config.workspace_root, &params.path)?;
+        let target = resolve_workspace_child(&self.config, &params.path, Access::Read)?;
         let limit = params.max_bytes.unwrap_or(4096);
         let mut file = std::fs::File::open(target.as_std_path())
             .with_context(|| format!("failed to open file {}", target))?;
@@ -190,138 +540,374 @@ impl ActionExecutor {
         if truncated {
             buffer.truncate(limit as usize);
         }
-        let (encoding, contents) = match String::from_utf8(buffer.clone()) {
-            Ok(text) => ("utf-8", text),
-            Err(_) => ("base64", Base64.encode(&buffer)),
-        };
+        let encoding = encoding::detect(&buffer, truncated);
+        let contents =
+            encoding::decode(&buffer, encoding).unwrap_or_else(|| Base64.encode(&buffer));
         Ok(json!({
             "path": target.as_str(),
             "truncated": truncated,
-            "encoding": encoding,
+            "encoding": encoding.label(),
             "contents": contents,
         }))
     }
 
+    fn fs_revert(&mut self) -> Result<Value> {
+        let summary = self.rollback()?.ok_or_else(|| {
+            anyhow!("fs.revert requires a workspace snapshot (enable fs.snapshot or --rollback-on-failure)")
+        })?;
+        Ok(json!({
+            "restored": summary.restored,
+            "removed": summary.removed,
+        }))
+    }
+
+    fn archive_extract(&mut self, params: ArchiveExtractInput) -> Result<Value> {
+        if params.path.trim().is_empty() || params.dest.trim().is_empty() {
+            bail!("archive.extract requires non-empty `path` and `dest`");
+        }
+        let source = resolve_workspace_child(&self.config, &params.path, Access::Read)?;
+        let target = self.resolve_write_target(&params.dest)?;
+        let ceiling = &self.config.archive;
+        let limits = ExtractLimits {
+            max_entries: params.max_entries.unwrap_or(ceiling.max_entries),
+            max_bytes: params.max_bytes.unwrap_or(ceiling.max_bytes),
+        }
+        .capped(ceiling);
+        self.budgets.check(BudgetKind::BytesWritten)?;
+        let summary = archive::extract(&source, &target, limits, &mut self.changes)
+            .with_context(|| format!("failed to extract {}", source))?;
+        self.budgets.record(BudgetKind::BytesWritten, summary.bytes);
+        Ok(json!({
+            "path": source.as_str(),
+            "dest": target.as_str(),
+            "entries": summary.entries,
+            "bytes": summary.bytes,
+        }))
+    }
+
+    fn archive_create(&mut self, params: ArchiveCreateInput) -> Result<Value> {
+        if params.paths.is_empty() || params.dest.trim().is_empty() {
+            bail!("archive.create requires `paths` and a non-empty `dest`");
+        }
+        let root = &self.config.workspace_root;
+        let inputs = params
+            .paths
+            .iter()
+            .map(|path| resolve_workspace_child(&self.config, path, Access::Read))
+            .collect::<Result<Vec<_>>>()?;
+        let target = self.resolve_write_target(&params.dest)?;
+        let existed = target.exists();
+        self.budgets.check(BudgetKind::BytesWritten)?;
+        let summary = archive::create(root, &inputs, &target)
+            .with_context(|| format!("failed to create {}", target))?;
+        let size = fs::metadata(&target).map_or(0, |meta| meta.len());
+        self.budgets.record(BudgetKind::BytesWritten, size);
+        self.changes.record_write(&target, existed);
+        Ok(json!({
+            "dest": target.as_str(),
+            "entries": summary.entries,
+            "bytes": summary.bytes,
+        }))
+    }
+
     fn proc_spawn(&self, params: ProcSpawnInput) -> Result<Value> {
         if params.command.trim().is_empty() {
             bail!("proc.spawn requires `command`");
         }
-        if !self.config.is_proc_allowed(&params.command) {
-            bail!("command `{}` is not allowed by policy", params.command);
+        let policy = &self.config.policy;
+        if let Some(denial) = policy
+            .command_denial(&params.command)
+            .filter(|_| !self.grants.allows_command(&params.command))
+        {
+            bail!("command `{}` is {denial}", params.command);
+        }
+        if let Some(denial) = policy.args_denial(&params.command, &params.args) {
+            bail!("arguments for `{}` are {denial}", params.command);
         }
 
         let working_dir = if let Some(cwd) = params.cwd {
             if cwd.trim().is_empty() {
                 self.config.workspace_root.clone()
             } else {
-                resolve_workspace_child(&self.config.workspace_root, &cwd)?
+                resolve_workspace_child(&self.config, &cwd, Access::Read)?
             }
         } else {
             self.config.workspace_root.clone()
         };
 
-        let mut cmd = Command::new(&params.command);
-        cmd.args(&params.args);
-        cmd.current_dir(working_dir.as_std_path());
-        cmd.env_clear();
-        if let Some(env) = params.env {
-            for var in env {
-                cmd.env(var.key, var.value);
-            }
+        let env = checked_env(&self.config, params.env)?;
+        let timeout_ms = params.timeout_ms.or(self.config.proc.default_timeout_ms);
+        if timeout_ms == Some(0) {
+            bail!("proc.spawn `timeout_ms` must be greater than zero");
+        }
+        if self.config.proc.dry_run {
+            return Ok(json!({
+                "dry_run": true,
+                "would_run": process::display_command(&params.command, &params.args),
+                "command": params.command,
+                "args": params.args,
+                "cwd": working_dir.as_str(),
+                "env": env.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+                "status": Value::Null,
+            }));
+        }
+        let command_line = process::display_command(&params.command, &params.args);
+        if let Some(reason) = self.config.policy.command_confirmation(&command_line) {
+            self.confirm("proc.spawn", &command_line, &reason)?;
         }
+        let quotable = [params.command.as_str(), command_line.as_str()];
+        self.check_untrusted(
+            "proc.spawn",
+            &command_line,
+            quotable
+                .into_iter()
+                .chain(params.args.iter().map(String::as_str)),
+        )?;
+        notify::record_command(command_line);
 
-        let output = cmd
-            .output()
+        // Bound for the whole call: dropping it removes the container.
+        let Prepared {
+            command: mut cmd,
+            container: _container,
+        } = prepare_command(
+            &self.config,
+            &working_dir,
+            &params.command,
+            &params.args,
+            &env,
+            params.priority,
+        )?;
+
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let cgroup = match self.config.proc.cgroup.as_ref() {
+            Some(limits) => {
+                Some(Cgroup::attach(&mut cmd, limits).context("failed to set up cgroup")?)
+            }
+            None => None,
+        };
+        process::isolate_group(&mut cmd);
+
+        let mut child = cmd
+            .spawn()
             .with_context(|| format!("failed to execute {}", params.command))?;
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        process::track_group(child.id());
+        let watchdog = deadline::bound_optional(timeout_ms.map(Duration::from_millis))
+            .map(|timeout| Watchdog::arm(&child, timeout));
+        let limit = self.config.proc.max_output_bytes;
+        let stdout_reader = child
+            .stdout
+            .take()
+            .map(|pipe| process::capture_bounded(pipe, limit));
+        let stderr_reader = child
+            .stderr
+            .take()
+            .map(|pipe| process::capture_bounded(pipe, limit));
+        let status = child.wait();
+        process::untrack_group(child.id());
+        let status = status.with_context(|| format!("failed to wait for {}", params.command))?;
+        if let Some(watchdog) = watchdog.as_ref() {
+            watchdog.disarm();
+        }
+        let timed_out = watchdog.is_some_and(|watchdog| watchdog.fired());
+        let stdout = join_capture(stdout_reader);
+        let stderr = join_capture(stderr_reader);
+        let oom_killed = cgroup.is_some_and(|cgroup| cgroup.oom_killed());
         Ok(json!({
             "command": params.command,
             "args": params.args,
             "cwd": working_dir.as_str(),
-            "status": output.status.code(),
-            "stdout": stdout,
-            "stderr": stderr,
+            "status": status.code(),
+            "timed_out": timed_out,
+            "oom_killed": oom_killed,
+            "stdout": String::from_utf8_lossy(&stdout.data),
+            "stdout_truncated": stdout.truncated,
+            "stderr": String::from_utf8_lossy(&stderr.data),
+            "stderr_truncated": stderr.truncated,
         }))
     }
 
+    fn shell_open_session(&mut self, params: ShellOpenSessionInput) -> Result<Value> {
+        let settings = &self.config.shell;
+        if !settings.enabled && !self.grants.allows("shell", None) {
+            bail!("shell sessions are disabled by shell.enabled");
+        }
+        let alias = normalized_alias(&params.alias)?;
+        if self.shell_sessions.contains_key(&alias) {
+            bail!("shell session `{alias}` already exists");
+        }
+        if self.shell_sessions.len() >= settings.max_sessions {
+            bail!(
+                "at most {} shell sessions may be open",
+                settings.max_sessions
+            );
+        }
+        let working_dir = match params.cwd.filter(|cwd| !cwd.trim().is_empty()) {
+            Some(cwd) => resolve_workspace_child(&self.config, &cwd, Access::Read)?,
+            None => self.config.workspace_root.clone(),
+        };
+
+        let env = checked_env(&self.config, params.env)?;
+        let Prepared {
+            command: mut cmd,
+            container,
+        } = prepare_command(
+            &self.config,
+            &working_dir,
+            &settings.program,
+            &settings.kind.args(),
+            &env,
+            Priority::Normal,
+        )?;
+        let cgroup = match self.config.proc.cgroup.as_ref() {
+            Some(limits) => Some(Arc::new(
+                Cgroup::attach(&mut cmd, limits).context("failed to set up cgroup")?,
+            )),
+            None => None,
+        };
+        let session = ShellSession::spawn(
+            cmd,
+            settings.kind,
+            self.config.proc.max_output_bytes,
+            cgroup,
+            container,
+        )
+        .with_context(|| format!("failed to start {}", settings.program))?;
+        self.shell_sessions.insert(alias.clone(), session);
+        Ok(json!({ "session": alias, "cwd": working_dir.as_str() }))
+    }
+
+    fn shell_session_exec(&mut self, params: ShellExecInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let timeout_ms = params
+            .timeout_ms
+            .unwrap_or(self.config.shell.default_timeout_ms);
+        if timeout_ms == 0 {
+            bail!("shell.session.exec `timeout_ms` must be greater than zero");
+        }
+        if !self.shell_sessions.contains_key(&alias) {
+            bail!("unknown shell session `{alias}`");
+        }
+        if self.config.proc.dry_run {
+            return Ok(json!({
+                "dry_run": true,
+                "would_run": params.command,
+                "session": alias,
+                "status": Value::Null,
+            }));
+        }
+        if let Some(reason) = self.config.policy.command_confirmation(&params.command) {
+            self.confirm("shell.exec", &params.command, &reason)?;
+        }
+        self.check_untrusted("shell.exec", &params.command, [params.command.as_str()])?;
+        notify::record_command(params.command.clone());
+        let session = self
+            .shell_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown shell session `{alias}`"))?;
+        let output = session.exec(
+            &params.command,
+            deadline::bound(Duration::from_millis(timeout_ms)),
+        );
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                // The shell is gone after a timeout or exit; free the alias.
+                self.shell_sessions.remove(&alias);
+                return Err(err).with_context(|| format!("shell session `{alias}`"));
+            }
+        };
+        Ok(json!({
+            "session": alias,
+            "command": params.command,
+            "status": output.exit_code,
+            "stdout": String::from_utf8_lossy(&output.stdout),
+            "stdout_truncated": output.stdout_truncated,
+            "stderr": String::from_utf8_lossy(&output.stderr),
+            "stderr_truncated": output.stderr_truncated,
+        }))
+    }
+
+    fn shell_session_close(&mut self, params: ShellSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        if self.shell_sessions.remove(&alias).is_none() {
+            bail!("unknown shell session `{alias}`");
+        }
+        Ok(json!({ "session": alias, "closed": true }))
+    }
+
     fn browser_open_session(&mut self, params: BrowserOpenSessionInput) -> Result<Value> {
         let settings = self.browser_settings()?;
         let alias = normalized_alias(&params.alias)?;
         if self.browser_sessions.contains_key(&alias) {
             bail!("browser session `{alias}` already exists");
         }
-        let webdriver_url = settings.webdriver_url.clone();
-        let headless = params.headless.unwrap_or(true);
-        let profile = params.profile.or_else(|| settings.default_profile.clone());
-        let allow_downloads = params.allow_downloads.unwrap_or(false);
-        let handle = self.tokio.clone();
-        let driver = handle.block_on(async move {
-            let mut caps = DesiredCapabilities::chrome();
-            if headless {
-                caps.add_arg("--headless=new")?;
-                caps.add_arg("--disable-gpu")?;
-            }
-            caps.add_arg("--disable-dev-shm-usage")?;
-            caps.add_arg("--no-sandbox")?;
-            if allow_downloads {
-                let prefs = serde_json::json!({
-                    "download.prompt_for_download": false,
-                });
-                caps.add_experimental_option("prefs", prefs)?;
-            }
-            WebDriver::new(&webdriver_url, caps).await
-        })?;
-
-        self.browser_sessions
-            .insert(alias.clone(), BrowserSessionEntry { driver, profile });
+        let options = browser::SessionOptions {
+            kind: params.kind,
+            profile: params.profile,
+            headless: params.headless.unwrap_or(true),
+            allow_downloads: params.allow_downloads.unwrap_or(false),
+            record_har: params.record_har.unwrap_or(false),
+            ..Default::default()
+        };
+        let session = BrowserSession::open(
+            settings,
+            &self.config.policy.urls,
+            options,
+            &self.config.workspace_root,
+            self.tokio.clone(),
+        )?;
+        self.browser_sessions.insert(alias.clone(), session);
         Ok(json!({ "session": alias }))
     }
 
     fn browser_session_goto(&self, params: BrowserGotoInput) -> Result<Value> {
         let alias = normalized_alias(&params.session)?;
-        let driver = self.session_driver(&alias)?;
-        let url = params.url.clone();
-        let timeout = params.timeout_ms.unwrap_or(5_000);
-        self.tokio.block_on({
-            let driver = driver.clone();
-            async move {
-                driver.goto(&url).await?;
-                tokio::time::sleep(Duration::from_millis(timeout.min(30_000))).await;
-                Ok::<_, WebDriverError>(())
-            }
-        })?;
-        let current_url = self.tokio.block_on({
-            let driver = driver.clone();
-            async move { driver.current_url().await.map(|u| u.to_string()) }
-        })?;
+        let session = self.browser_session(&alias)?;
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let page = session.goto(&params.url, params.wait_for.as_ref(), timeout)?;
+        Ok(json!({
+            "session": alias,
+            "url": page.url,
+        }))
+    }
+
+    fn browser_session_wait_for(&self, params: BrowserWaitForInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let page = self
+            .browser_session(&alias)?
+            .wait_for(&params.condition, timeout)?;
         Ok(json!({
             "session": alias,
-            "url": current_url,
+            "url": page.url,
         }))
     }
 
     fn browser_session_describe(&self, params: BrowserDescribeInput) -> Result<Value> {
         let alias = normalized_alias(&params.session)?;
         let include_html = params.include_html.unwrap_or(false);
-        let driver = self.session_driver(&alias)?;
-        let driver_for_meta = driver.clone();
-        let (url, title) = self.tokio.block_on(async move {
-            let url = driver_for_meta.current_url().await?.to_string();
-            let title = driver_for_meta.title().await.ok();
-            Ok::<_, WebDriverError>((url, title))
-        })?;
-        let html = if include_html {
-            let driver = driver.clone();
-            Some(self.tokio.block_on(async move { driver.source().await })?)
-        } else {
-            None
-        };
+        let include_outline = params.include_outline.unwrap_or(false);
+        let page = self
+            .browser_session(&alias)?
+            .describe(include_html, include_outline)?;
+        for text in [&page.title, &page.html, &page.outline]
+            .into_iter()
+            .flatten()
+        {
+            self.untrusted.mark(text);
+        }
         Ok(json!({
             "session": alias,
-            "url": url,
-            "title": title,
-            "html": html,
+            "url": page.url,
+            "title": page.title,
+            "html": page.html,
+            "outline": page.outline,
         }))
     }
 
@@ -331,14 +917,12 @@ impl ActionExecutor {
         if self.browser_elements.contains_key(&element_alias) {
             bail!("browser element `{element_alias}` already exists");
         }
-        let driver = self.session_driver(&session_alias)?;
-        let selector = selector_to_by(&params.selector)?;
-        let timeout = params.timeout_ms.unwrap_or(5_000);
-        let element = self.tokio.block_on(async move {
-            let mut query = driver.query(selector);
-            query = query.wait(Duration::from_millis(timeout), Duration::from_millis(200));
-            query.first().await
-        })?;
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let element = self
+            .browser_session(&session_alias)?
+            .find(&params.selector, timeout)?;
         self.browser_elements.insert(
             element_alias.clone(),
             BrowserElementEntry {
@@ -352,81 +936,478 @@ impl ActionExecutor {
         }))
     }
 
+    /// Quits the browser and frees every element alias found through it.
+    fn browser_session_close(&mut self, params: BrowserSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let Some((released, har)) = self.close_browser_session(&alias) else {
+            bail!("unknown browser session `{alias}`");
+        };
+        let har = har.map(|path| {
+            path.strip_prefix(&self.config.workspace_root)
+                .unwrap_or(&path)
+                .to_string()
+        });
+        Ok(json!({
+            "session": alias,
+            "closed": true,
+            "released": released,
+            "har": har,
+        }))
+    }
+
+    fn browser_element_release(&mut self, params: BrowserElementActionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.element)?;
+        if self.browser_elements.remove(&alias).is_none() {
+            bail!("unknown browser element `{alias}`");
+        }
+        Ok(json!({ "element": alias, "released": true }))
+    }
+
+    /// Scrolls to `element`, or by `dx`/`dy` pixels, and reports where the
+    /// page ended up so the planner can tell when it reached the bottom.
+    fn browser_session_scroll(&self, params: BrowserScrollInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let session = self.browser_session(&alias)?;
+        let position = match (params.element, params.dx, params.dy) {
+            (Some(element), None, None) => {
+                self.element_handle(&normalized_alias(&element)?)?
+                    .scroll_into_view()?;
+                session.scroll_position()?
+            }
+            (None, dx, dy) if dx.is_some() || dy.is_some() => {
+                session.scroll_by(dx.unwrap_or(0), dy.unwrap_or(0))?
+            }
+            _ => bail!("browser.session.scroll takes either `element` or `dx`/`dy`"),
+        };
+        Ok(json!({
+            "session": alias,
+            "x": position.x,
+            "y": position.y,
+            "max_y": position.max_y,
+            // Fractional device pixels can stop a scroll just short.
+            "at_bottom": position.y + 1.0 >= position.max_y,
+        }))
+    }
+
+    fn browser_session_eval(&self, params: BrowserEvalInput) -> Result<Value> {
+        let session_alias = normalized_alias(&params.session)?;
+        let result = self
+            .browser_session(&session_alias)?
+            .eval(&params.expression)?;
+        self.untrusted.mark_json(&result);
+        Ok(json!({
+            "session": session_alias,
+            "result": result,
+        }))
+    }
+
+    /// Finds every field before touching any, so a missing one leaves the
+    /// form as it was; then clears and types each in selector order and
+    /// clicks `submit` only if every field was filled.
+    fn browser_session_fill_form(&self, params: BrowserFillFormInput) -> Result<Value> {
+        let session_alias = normalized_alias(&params.session)?;
+        if params.fields.is_empty() {
+            bail!("browser.session.fill_form requires `fields`");
+        }
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self.browser_session(&session_alias)?;
+
+        let mut found = Vec::with_capacity(params.fields.len());
+        let mut missing = Vec::new();
+        for (css, value) in &params.fields {
+            let selector = Selector {
+                kind: SelectorKind::Css,
+                value: css.clone(),
+            };
+            match session.find(&selector, timeout) {
+                Ok(element) => found.push((css, value, element)),
+                Err(err) => missing.push(format!("`{css}`: {err}")),
+            }
+        }
+        let submit = match &params.submit {
+            Some(selector) => match session.find(selector, timeout) {
+                Ok(element) => Some(element),
+                Err(err) => {
+                    missing.push(format!("submit {selector}: {err}"));
+                    None
+                }
+            },
+            None => None,
+        };
+        if !missing.is_empty() {
+            bail!("no field was filled; {}", missing.join("; "));
+        }
+
+        let mut filled = Vec::with_capacity(found.len());
+        for (css, value, element) in found {
+            element
+                .clear()
+                .and_then(|()| element.type_text(value, false))
+                .map_err(|err| {
+                    anyhow!(
+                        "field `{css}` failed after filling {:?}: {err}",
+                        filled.as_slice()
+                    )
+                })?;
+            filled.push(css.clone());
+        }
+        if let Some(element) = &submit {
+            element
+                .click()
+                .map_err(|err| anyhow!("all fields filled but submit failed: {err}"))?;
+        }
+        Ok(json!({
+            "session": session_alias,
+            "filled": filled,
+            "submitted": submit.is_some(),
+        }))
+    }
+
+    /// Registers every match as `<alias>-<n>` and summarises each one, so a
+    /// planner can choose among them without a `find` per candidate.
+    fn browser_session_query_all(&mut self, params: BrowserQueryAllInput) -> Result<Value> {
+        let session_alias = normalized_alias(&params.session)?;
+        let prefix = normalized_alias(&params.alias)?;
+        let limit = params
+            .limit
+            .unwrap_or(browser::DEFAULT_QUERY_LIMIT)
+            .clamp(1, browser::MAX_QUERY_LIMIT);
+        let matches = self
+            .browser_session(&session_alias)?
+            .query_all(&params.selector)?;
+        let count = matches.len();
+        let aliases: Vec<String> = (0..count.min(limit))
+            .map(|index| format!("{prefix}-{index}"))
+            .collect();
+        if let Some(existing) = aliases
+            .iter()
+            .find(|alias| self.browser_elements.contains_key(*alias))
+        {
+            bail!("browser element `{existing}` already exists");
+        }
+        let mut elements = Vec::with_capacity(aliases.len());
+        for (element, alias) in matches.into_iter().zip(aliases) {
+            let summary = element.summary()?;
+            self.untrusted.mark(&summary.text);
+            for value in summary.attributes.values() {
+                self.untrusted.mark(value);
+            }
+            elements.push(json!({
+                "element": alias,
+                "tag": summary.tag,
+                "text": summary.text,
+                "attributes": summary.attributes,
+            }));
+            self.browser_elements.insert(
+                alias,
+                BrowserElementEntry {
+                    element,
+                    session: session_alias.clone(),
+                },
+            );
+        }
+        Ok(json!({
+            "session": session_alias,
+            "count": count,
+            "truncated": count > elements.len(),
+            "elements": elements,
+        }))
+    }
+
     fn browser_element_click(&self, params: BrowserElementActionInput) -> Result<Value> {
         let element_alias = normalized_alias(&params.element)?;
-        let element = self.element_handle(&element_alias)?;
-        self.tokio.block_on(async move { element.click().await })?;
+        self.element_handle(&element_alias)?.click()?;
         Ok(json!({ "element": element_alias }))
     }
 
     fn browser_element_type(&self, params: BrowserElementTypeInput) -> Result<Value> {
         let element_alias = normalized_alias(&params.element)?;
-        let element = self.element_handle(&element_alias)?;
         let text = params.text.unwrap_or_default();
-        self.tokio
-            .block_on(async move { element.send_keys(text).await })?;
-        if params.submit.unwrap_or(false) {
-            let element = self.element_handle(&element_alias)?;
-            self.tokio
-                .block_on(async move { element.send_keys(Key::Enter).await })?;
-        }
+        self.element_handle(&element_alias)?
+            .type_text(&text, params.submit.unwrap_or(false))?;
         Ok(json!({ "element": element_alias }))
     }
 
+    fn browser_element_select_option(&self, params: BrowserElementChoiceInput) -> Result<Value> {
+        let element_alias = normalized_alias(&params.element)?;
+        let choice = option_choice("browser.element.select_option", params.value, params.label)?;
+        let value = self
+            .element_handle(&element_alias)?
+            .select_option(&choice)?;
+        self.untrusted.mark(&value);
+        Ok(json!({ "element": element_alias, "value": value }))
+    }
+
+    fn browser_element_set_checked(&self, params: BrowserElementSetCheckedInput) -> Result<Value> {
+        let element_alias = normalized_alias(&params.element)?;
+        self.element_handle(&element_alias)?
+            .set_checked(params.checked)?;
+        Ok(json!({ "element": element_alias, "checked": params.checked }))
+    }
+
+    fn browser_element_choose_radio(&self, params: BrowserElementChoiceInput) -> Result<Value> {
+        let element_alias = normalized_alias(&params.element)?;
+        let choice = option_choice("browser.element.choose_radio", params.value, params.label)?;
+        let value = self.element_handle(&element_alias)?.choose_radio(&choice)?;
+        self.untrusted.mark(&value);
+        Ok(json!({ "element": element_alias, "value": value }))
+    }
+
     fn browser_element_inner_text(&self, params: BrowserElementActionInput) -> Result<Value> {
         let element_alias = normalized_alias(&params.element)?;
-        let element = self.element_handle(&element_alias)?;
-        let text = self.tokio.block_on(async move { element.text().await })?;
+        let text = self.element_handle(&element_alias)?.inner_text()?;
+        self.untrusted.mark(&text);
         Ok(json!({
             "element": element_alias,
             "text": text,
         }))
     }
 
+    fn browser_element_screenshot(&self, params: BrowserElementActionInput) -> Result<Value> {
+        let element_alias = normalized_alias(&params.element)?;
+        let raw = self.element_handle(&element_alias)?.screenshot()?;
+        Ok(json!({
+            "element": element_alias,
+            "kind": ScreenshotKind::Png,
+            "data_base64": Base64.encode(raw),
+        }))
+    }
+
     fn browser_session_screenshot(&self, params: BrowserScreenshotInput) -> Result<Value> {
         let alias = normalized_alias(&params.session)?;
-        let driver = self.session_driver(&alias)?;
-        let raw = self
-            .tokio
-            .block_on(async move { driver.screenshot_as_png().await })?;
+        let options = ScreenshotOptions {
+            kind: params.kind.unwrap_or_default(),
+            quality: params.quality,
+            full_page: params.full_page.unwrap_or(false),
+        };
+        let raw = self.browser_session(&alias)?.screenshot(&options)?;
         let encoded = Base64.encode(raw);
         Ok(json!({
             "session": alias,
-            "kind": params.kind.unwrap_or(ScreenshotKind::Png),
+            "kind": options.kind,
+            "full_page": options.full_page,
             "data_base64": encoded,
         }))
     }
 
+    fn browser_session_print_pdf(&mut self, params: BrowserPrintPdfInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        if params.path.trim().is_empty() {
+            bail!("browser.session.print_pdf requires a non-empty `path`");
+        }
+        let target = self.resolve_write_target(&params.path)?;
+        let options = PdfOptions {
+            landscape: params.landscape.unwrap_or(false),
+            background: params.background.unwrap_or(false),
+        };
+        let pdf = self.browser_session(&alias)?.print_pdf(&options)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {parent}"))?;
+        }
+        let existed = target.exists();
+        fs::write(&target, &pdf).with_context(|| format!("failed to write {target}"))?;
+        self.changes.record_write(&target, existed);
+        Ok(json!({
+            "session": alias,
+            "path": target.as_str(),
+            "bytes": pdf.len(),
+        }))
+    }
+
+    fn browser_session_wait_for_download(
+        &mut self,
+        params: BrowserWaitForDownloadInput,
+    ) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_DOWNLOAD_TIMEOUT, Duration::from_millis);
+        let session = self
+            .browser_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))?;
+        let path = session.wait_for_download(timeout)?;
+        self.changes.record(&path, ChangeKind::Created);
+        let relative = path
+            .strip_prefix(&self.config.workspace_root)
+            .unwrap_or(&path);
+        Ok(json!({
+            "session": alias,
+            "path": relative.as_str(),
+        }))
+    }
+
+    fn browser_session_list_tabs(&self, params: BrowserSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let tabs: Vec<Value> = self
+            .browser_session(&alias)?
+            .tabs()?
+            .into_iter()
+            .map(|tab| {
+                if let Some(title) = &tab.title {
+                    self.untrusted.mark(title);
+                }
+                json!({
+                    "id": tab.id,
+                    "url": tab.url,
+                    "title": tab.title,
+                    "active": tab.active,
+                })
+            })
+            .collect();
+        Ok(json!({
+            "session": alias,
+            "tabs": tabs,
+        }))
+    }
+
+    fn browser_session_new_tab(&mut self, params: BrowserNewTabInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let timeout = params
+            .timeout_ms
+            .map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self
+            .browser_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))?;
+        let tab = session.new_tab(params.url.as_deref(), timeout)?;
+        Ok(json!({
+            "session": alias,
+            "tab": tab,
+        }))
+    }
+
+    fn browser_session_switch_tab(&mut self, params: BrowserSwitchTabInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let session = self
+            .browser_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))?;
+        let page = session.switch_tab(&params.tab)?;
+        Ok(json!({
+            "session": alias,
+            "tab": params.tab,
+            "url": page.url,
+            "title": page.title,
+        }))
+    }
+
+    fn browser_session_enter_frame(&mut self, params: BrowserEnterFrameInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let target = match (params.selector, params.index) {
+            (Some(selector), None) => FrameTarget::Selector(selector),
+            (None, Some(index)) => FrameTarget::Index(index),
+            _ => bail!("browser.session.enter_frame takes exactly one of `selector` or `index`"),
+        };
+        let session = self
+            .browser_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))?;
+        session.enter_frame(&target)?;
+        Ok(json!({
+            "session": alias,
+            "frame": target.to_string(),
+        }))
+    }
+
+    fn browser_session_exit_frame(&mut self, params: BrowserSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let session = self
+            .browser_sessions
+            .get_mut(&alias)
+            .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))?;
+        session.exit_frame()?;
+        Ok(json!({ "session": alias }))
+    }
+
+    fn browser_session_save_state(&mut self, params: BrowserSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let session = self.browser_session(&alias)?;
+        let existed = session.state_path().is_some_and(|path| path.exists());
+        let path = session.save_state()?;
+        let profile = session.profile().map(str::to_string);
+        if path.starts_with(&self.config.workspace_root) {
+            self.changes.record_write(&path, existed);
+        }
+        Ok(json!({
+            "session": alias,
+            "profile": profile,
+        }))
+    }
+
+    fn browser_session_load_state(&self, params: BrowserSessionInput) -> Result<Value> {
+        let alias = normalized_alias(&params.session)?;
+        let session = self.browser_session(&alias)?;
+        let loaded = session.load_state()?;
+        Ok(json!({
+            "session": alias,
+            "profile": session.profile(),
+            "loaded": loaded,
+        }))
+    }
+
     fn browser_settings(&self) -> Result<&BrowserSettings> {
-        self.config
-            .browser
-            .as_ref()
-            .ok_or_else(|| anyhow!("browser capability is disabled in host configuration"))
+        self.config.browser.as_ref().ok_or_else(|| {
+            anyhow!("browser capability is disabled: the config has no [browser] table")
+        })
     }
 
-    fn session_driver(&self, alias: &str) -> Result<WebDriver> {
+    fn browser_session(&self, alias: &str) -> Result<&BrowserSession> {
         self.browser_sessions
             .get(alias)
-            .map(|entry| entry.driver.clone())
             .ok_or_else(|| anyhow!("unknown browser session `{alias}`"))
     }
 
-    fn element_handle(&self, alias: &str) -> Result<WebElement> {
-        self.browser_elements
+    fn element_handle(&self, alias: &str) -> Result<&BrowserElement> {
+        let entry = self
+            .browser_elements
             .get(alias)
-            .map(|entry| entry.element.clone())
-            .ok_or_else(|| anyhow!("unknown browser element `{alias}`"))
+            .ok_or_else(|| anyhow!("unknown browser element `{alias}`"))?;
+        if let Some(session) = self.browser_sessions.get(&entry.session) {
+            session.touch();
+        }
+        Ok(&entry.element)
     }
-}
 
-impl Drop for ActionExecutor {
-    fn drop(&mut self) {
-        let handle = self.tokio.clone();
-        for (_, entry) in self.browser_sessions.drain() {
-            let driver = entry.driver.clone();
-            let _ = handle.block_on(async move { driver.quit().await });
+    /// Closes the session, frees its element aliases and records its HAR,
+    /// returning how many aliases were freed and where the HAR went.
+    fn close_browser_session(&mut self, alias: &str) -> Option<(usize, Option<Utf8PathBuf>)> {
+        let mut session = self.browser_sessions.remove(alias)?;
+        session.close();
+        let har = session.har_path().map(Utf8Path::to_path_buf);
+        if let Some(path) = har.as_deref().filter(|path| path.exists()) {
+            self.changes.record(path, ChangeKind::Created);
+        }
+        Some((self.release_browser_elements(alias), har))
+    }
+
+    /// Drops the element aliases belonging to `session`, returning how many.
+    fn release_browser_elements(&mut self, session: &str) -> usize {
+        let before = self.browser_elements.len();
+        self.browser_elements
+            .retain(|_, entry| entry.session != session);
+        before - self.browser_elements.len()
+    }
+
+    /// Closes planner sessions idle past `browser.idle_timeout_ms`, so a
+    /// forgotten session does not hold a browser for the rest of the step.
+    fn close_idle_browser_sessions(&mut self) {
+        let Some(timeout) = self.config.browser.as_ref().and_then(|b| b.idle_timeout) else {
+            return;
+        };
+        let idle: Vec<String> = self
+            .browser_sessions
+            .iter()
+            .filter(|(_, session)| session.idle_for() >= timeout)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        for alias in idle {
+            if let Some((released, _)) = self.close_browser_session(&alias) {
+                info!(session = %alias, released, "closed idle browser session");
+            }
         }
-        self.browser_elements.clear();
     }
 }
 
@@ -441,6 +1422,20 @@ struct FsReadFileInput {
     max_bytes: Option<u64>,
 }
 
+#[derive(Deserialize)]
+struct ArchiveExtractInput {
+    path: String,
+    dest: String,
+    max_entries: Option<u32>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveCreateInput {
+    paths: Vec<String>,
+    dest: String,
+}
+
 #[derive(Deserialize)]
 struct ProcSpawnInput {
     command: String,
@@ -448,6 +1443,9 @@ struct ProcSpawnInput {
     args: Vec<String>,
     cwd: Option<String>,
     env: Option<Vec<ProcEnvVar>>,
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    priority: Priority,
 }
 
 #[derive(Deserialize)]
@@ -456,18 +1454,48 @@ struct ProcEnvVar {
     value: String,
 }
 
+#[derive(Deserialize)]
+struct ShellOpenSessionInput {
+    alias: String,
+    cwd: Option<String>,
+    env: Option<Vec<ProcEnvVar>>,
+}
+
+#[derive(Deserialize)]
+struct ShellExecInput {
+    session: String,
+    command: String,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ShellSessionInput {
+    session: String,
+}
+
 #[derive(Deserialize)]
 struct BrowserOpenSessionInput {
     alias: String,
+    kind: Option<BrowserKind>,
     profile: Option<String>,
     headless: Option<bool>,
     allow_downloads: Option<bool>,
+    record_har: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct BrowserGotoInput {
     session: String,
     url: String,
+    /// Waited for after the document loads, within the same timeout.
+    wait_for: Option<WaitCondition>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BrowserWaitForInput {
+    session: String,
+    condition: WaitCondition,
     timeout_ms: Option<u64>,
 }
 
@@ -475,21 +1503,66 @@ struct BrowserGotoInput {
 struct BrowserDescribeInput {
     session: String,
     include_html: Option<bool>,
+    include_outline: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct BrowserFindInput {
     session: String,
-    selector: BrowserSelector,
+    selector: Selector,
     timeout_ms: Option<u64>,
     alias: String,
 }
 
+#[derive(Deserialize)]
+struct BrowserScrollInput {
+    session: String,
+    element: Option<String>,
+    dx: Option<i32>,
+    dy: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct BrowserEvalInput {
+    session: String,
+    expression: String,
+}
+
+#[derive(Deserialize)]
+struct BrowserFillFormInput {
+    session: String,
+    /// CSS selector to the text to type into it.
+    fields: BTreeMap<String, String>,
+    submit: Option<Selector>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BrowserQueryAllInput {
+    session: String,
+    selector: Selector,
+    alias: String,
+    limit: Option<usize>,
+}
+
 #[derive(Deserialize)]
 struct BrowserElementActionInput {
     element: String,
 }
 
+#[derive(Deserialize)]
+struct BrowserElementChoiceInput {
+    element: String,
+    value: Option<String>,
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BrowserElementSetCheckedInput {
+    element: String,
+    checked: bool,
+}
+
 #[derive(Deserialize)]
 struct BrowserElementTypeInput {
     element: String,
@@ -498,79 +1571,203 @@ struct BrowserElementTypeInput {
 }
 
 #[derive(Deserialize)]
-struct BrowserScreenshotInput {
+struct BrowserWaitForDownloadInput {
     session: String,
-    kind: Option<ScreenshotKind>,
+    timeout_ms: Option<u64>,
 }
 
 #[derive(Deserialize)]
-struct BrowserSelector {
-    kind: BrowserSelectorKind,
-    value: String,
+struct BrowserSessionInput {
+    session: String,
 }
 
-#[derive(Deserialize, Clone, Copy)]
-#[serde(rename_all = "lowercase")]
-enum BrowserSelectorKind {
-    Css,
-    XPath,
-    Text,
+#[derive(Deserialize)]
+struct BrowserNewTabInput {
+    session: String,
+    url: Option<String>,
+    timeout_ms: Option<u64>,
 }
 
-#[derive(Deserialize, Clone, Copy, Serialize)]
-#[serde(rename_all = "lowercase")]
-enum ScreenshotKind {
-    Png,
-    Jpeg,
+#[derive(Deserialize)]
+struct BrowserSwitchTabInput {
+    session: String,
+    tab: String,
 }
 
-fn normalized_alias(input: &str) -> Result<String> {
-    if input.trim().is_empty() {
-        bail!("alias must be non-empty");
-    }
-    Ok(input.trim().to_string())
+#[derive(Deserialize)]
+struct BrowserEnterFrameInput {
+    session: String,
+    selector: Option<Selector>,
+    index: Option<u16>,
 }
 
-fn selector_to_by(selector: &BrowserSelector) -> Result<By> {
-    match selector.kind {
-        BrowserSelectorKind::Css => Ok(By::Css(selector.value.clone())),
-        BrowserSelectorKind::XPath => Ok(By::XPath(selector.value.clone())),
-        BrowserSelectorKind::Text => {
-            let text_literal = serde_json::to_string(&selector.value)?;
-            let xpath = format!("//*[normalize-space(text()) = {}]", text_literal);
-            Ok(By::XPath(xpath))
+#[derive(Deserialize)]
+struct BrowserPrintPdfInput {
+    session: String,
+    /// Workspace-relative destination.
+    path: String,
+    landscape: Option<bool>,
+    background: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct BrowserScreenshotInput {
+    session: String,
+    kind: Option<ScreenshotKind>,
+    quality: Option<u8>,
+    full_page: Option<bool>,
+}
+
+/// A copy of `value` that takes about `budget` bytes serialized, taking
+/// them out of `budget`.
+fn fit_json(value: &Value, key: Option<&str>, budget: &mut usize) -> Value {
+    match value {
+        Value::String(text) => {
+            let cost = text.len() + 2;
+            if cost <= *budget {
+                *budget -= cost;
+                return value.clone();
+            }
+            let room = std::mem::take(budget).saturating_sub(2);
+            if key.is_some_and(|key| key.ends_with("_base64")) {
+                return Value::Null;
+            }
+            Value::String(text[..char_boundary(text, room)].to_string())
+        }
+        Value::Array(items) => {
+            *budget = budget.saturating_sub(2);
+            let mut kept = Vec::new();
+            for item in items {
+                if *budget == 0 {
+                    break;
+                }
+                kept.push(fit_json(item, None, budget));
+            }
+            Value::Array(kept)
+        }
+        Value::Object(fields) => {
+            *budget = budget.saturating_sub(2);
+            let mut kept = serde_json::Map::new();
+            for (name, field) in fields {
+                if *budget == 0 {
+                    break;
+                }
+                *budget = budget.saturating_sub(name.len() + 4);
+                kept.insert(name.clone(), fit_json(field, Some(name), budget));
+            }
+            Value::Object(kept)
+        }
+        scalar => {
+            *budget = budget.saturating_sub(scalar.to_string().len());
+            scalar.clone()
         }
     }
 }
 
-fn resolve_workspace_child(root: &Utf8Path, relative: &str) -> Result<Utf8PathBuf> {
-    if relative.is_empty() {
-        return Ok(root.to_path_buf());
+/// The largest char boundary in `text` at or below `index`.
+fn char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
     }
-    let rel_path = Path::new(relative);
-    if rel_path.is_absolute() {
-        bail!("absolute paths are not allowed");
+    (0..=index)
+        .rev()
+        .find(|at| text.is_char_boundary(*at))
+        .unwrap_or(0)
+}
+
+fn option_choice(
+    capability: &str,
+    value: Option<String>,
+    label: Option<String>,
+) -> Result<OptionChoice> {
+    match (value, label) {
+        (Some(value), None) => Ok(OptionChoice::Value(value)),
+        (None, Some(label)) => Ok(OptionChoice::Label(label)),
+        _ => bail!("{capability} takes exactly one of `value` or `label`"),
     }
-    let mut candidate = root.as_std_path().to_path_buf();
-    for component in rel_path.components() {
-        match component {
-            Component::CurDir => {}
-            Component::Normal(seg) => candidate.push(seg),
-            _ => bail!("path traversal segments are not allowed"),
+}
+
+/// Checks the guest's env vars against the `[proc]` env policy.
+/// The counted budget an action spends one unit of, if any.
+fn action_budget(capability: &str) -> Option<BudgetKind> {
+    match capability {
+        "proc.spawn" | "shell.open_session" => Some(BudgetKind::Processes),
+        _ if capability.starts_with("fs.") || capability.starts_with("archive.") => {
+            Some(BudgetKind::FilesystemOps)
         }
+        _ if capability.starts_with("browser.") => Some(BudgetKind::BrowserActions),
+        _ => None,
     }
-    let candidate =
-        Utf8PathBuf::from_path_buf(candidate).map_err(|_| anyhow!("path is not valid UTF-8"))?;
-    ensure_within_workspace(root, &candidate)?;
-    Ok(candidate)
 }
 
-fn ensure_within_workspace(root: &Utf8Path, candidate: &Utf8Path) -> Result<()> {
-    if candidate.as_std_path().starts_with(root.as_std_path()) {
-        Ok(())
+fn checked_env(config: &HostConfig, env: Option<Vec<ProcEnvVar>>) -> Result<Vec<(String, String)>> {
+    env.unwrap_or_default()
+        .into_iter()
+        .map(|var| {
+            if var.key.is_empty() || var.key.contains(['=', '\0']) {
+                bail!("invalid env var name `{}`", var.key);
+            }
+            if let Some(denial) = config.proc.env_denial(&var.key) {
+                bail!("env var `{}` is {denial}", var.key);
+            }
+            Ok((var.key, var.value))
+        })
+        .collect()
+}
+
+/// Builds the command through the configured `proc.backend`.
+fn prepare_command(
+    config: &HostConfig,
+    cwd: &Utf8Path,
+    program: &str,
+    args: &[String],
+    env: &[(String, String)],
+    priority: Priority,
+) -> Result<Prepared> {
+    let spec = CommandSpec {
+        workspace: &config.workspace_root,
+        cwd,
+        program,
+        args,
+        env,
+        tty: false,
+        priority,
+    };
+    executor::for_settings(&config.proc)?
+        .prepare(&spec)
+        .context("failed to prepare command")
+}
+
+fn normalized_alias(input: &str) -> Result<String> {
+    if input.trim().is_empty() {
+        bail!("alias must be non-empty");
+    }
+    Ok(input.trim().to_string())
+}
+
+fn join_capture(reader: Option<JoinHandle<CapturedOutput>>) -> CapturedOutput {
+    reader
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default()
+}
+
+fn resolve_workspace_child(
+    config: &HostConfig,
+    relative: &str,
+    access: Access,
+) -> Result<Utf8PathBuf> {
+    let root = &config.workspace_root;
+    let candidate = if relative.is_empty() {
+        root.to_path_buf()
     } else {
-        bail!("path `{}` escapes workspace root", candidate)
+        let candidate = workspace::join_relative(root, relative)?;
+        workspace::ensure_contained(root, &candidate)?;
+        candidate
+    };
+    if let Some(reason) = config.policy.path_denial(root, &candidate, access) {
+        bail!("`{relative}` is {reason}");
     }
+    Ok(candidate)
 }
 
 fn entry_kind(meta: &fs::Metadata) -> &'static str {
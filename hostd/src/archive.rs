@@ -0,0 +1,386 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tar::EntryType;
+use thiserror::Error;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::changes::ChangeSet;
+use crate::config::ArchiveSettings;
+
+pub const DEFAULT_MAX_ENTRIES: u32 = 10_000;
+pub const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("unsupported archive format for `{0}` (expected .zip, .tar, .tar.gz or .tgz)")]
+    UnsupportedFormat(String),
+    #[error("archive entry `{0}` escapes the destination")]
+    UnsafeEntry(String),
+    #[error("archive exceeds {0}")]
+    Limit(String),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    fn from_path(path: &Utf8Path) -> Result<Self, ArchiveError> {
+        let name = path.file_name().unwrap_or_default().to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Ok(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Ok(Self::Tar)
+        } else {
+            Err(ArchiveError::UnsupportedFormat(path.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArchiveSummary {
+    pub entries: u32,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractLimits {
+    pub max_entries: u32,
+    pub max_bytes: u64,
+}
+
+impl ExtractLimits {
+    /// The limits a caller asked for, cut to the `[archive]` ceilings.
+    pub fn capped(self, settings: &ArchiveSettings) -> Self {
+        Self {
+            max_entries: self.max_entries.min(settings.max_entries),
+            max_bytes: self.max_bytes.min(settings.max_bytes),
+        }
+    }
+}
+
+/// Unpacks `archive` into `dest`. Entry names are re-rooted under `dest`;
+/// absolute names, `..` segments, links, and writes through existing
+/// symlinks are refused so an archive cannot plant files outside the target.
+/// Every file and directory written is recorded in `changes`.
+pub fn extract(
+    archive: &Utf8Path,
+    dest: &Utf8Path,
+    limits: ExtractLimits,
+    changes: &mut ChangeSet,
+) -> Result<ArchiveSummary, ArchiveError> {
+    let format = ArchiveFormat::from_path(archive)?;
+    let file = File::open(archive.as_std_path())?;
+    if !dest.exists() {
+        fs::create_dir_all(dest.as_std_path())?;
+        changes.record_write(dest, false);
+    }
+    let mut sink = ExtractSink {
+        dest,
+        limits,
+        summary: ArchiveSummary::default(),
+        changes,
+    };
+    match format {
+        ArchiveFormat::Zip => extract_zip(file, &mut sink)?,
+        ArchiveFormat::Tar => extract_tar(file, &mut sink)?,
+        ArchiveFormat::TarGz => extract_tar(GzDecoder::new(file), &mut sink)?,
+    }
+    Ok(sink.summary)
+}
+
+/// Packs `inputs` into `dest`, naming entries relative to `root`. Symlinks are
+/// skipped rather than followed, and `dest` itself is never added.
+pub fn create(
+    root: &Utf8Path,
+    inputs: &[Utf8PathBuf],
+    dest: &Utf8Path,
+) -> Result<ArchiveSummary, ArchiveError> {
+    let format = ArchiveFormat::from_path(dest)?;
+    let mut entries = Vec::new();
+    for input in inputs {
+        collect_entries(root, input, dest, &mut entries)?;
+    }
+    let file = File::create(dest.as_std_path())?;
+    match format {
+        ArchiveFormat::Zip => write_zip(file, &entries),
+        ArchiveFormat::Tar => write_tar(file, &entries).map(|_| ()),
+        ArchiveFormat::TarGz => write_tar(GzEncoder::new(file, Compression::default()), &entries)?
+            .finish()
+            .map(|_| ())
+            .map_err(ArchiveError::from),
+    }?;
+    let mut summary = ArchiveSummary::default();
+    for entry in &entries {
+        summary.entries += 1;
+        summary.bytes += entry.size;
+    }
+    Ok(summary)
+}
+
+struct ExtractSink<'a> {
+    dest: &'a Utf8Path,
+    limits: ExtractLimits,
+    summary: ArchiveSummary,
+    changes: &'a mut ChangeSet,
+}
+
+impl ExtractSink<'_> {
+    fn begin_entry(&mut self) -> Result<(), ArchiveError> {
+        if self.summary.entries >= self.limits.max_entries {
+            return Err(ArchiveError::Limit(format!(
+                "{} entries",
+                self.limits.max_entries
+            )));
+        }
+        self.summary.entries += 1;
+        Ok(())
+    }
+
+    fn target(&self, name: &Path) -> Result<Utf8PathBuf, ArchiveError> {
+        let unsafe_entry = || ArchiveError::UnsafeEntry(name.display().to_string());
+        let mut target = self.dest.as_std_path().to_path_buf();
+        for component in name.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(seg) => {
+                    target.push(seg);
+                    if fs::symlink_metadata(&target).is_ok_and(|meta| meta.is_symlink()) {
+                        return Err(unsafe_entry());
+                    }
+                }
+                _ => return Err(unsafe_entry()),
+            }
+        }
+        Utf8PathBuf::from_path_buf(target).map_err(|_| unsafe_entry())
+    }
+
+    fn write_dir(&mut self, name: &Path) -> Result<(), ArchiveError> {
+        self.begin_entry()?;
+        let target = self.target(name)?;
+        if !target.exists() {
+            fs::create_dir_all(&target)?;
+            self.changes.record_write(&target, false);
+        }
+        Ok(())
+    }
+
+    fn write_file(&mut self, name: &Path, reader: &mut dyn Read) -> Result<(), ArchiveError> {
+        self.begin_entry()?;
+        let target = self.target(name)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let remaining = self.limits.max_bytes.saturating_sub(self.summary.bytes);
+        let existed = target.exists();
+        let mut out = File::create(&target)?;
+        // Declared sizes in headers are untrusted; count what is actually inflated.
+        let copied = io::copy(&mut reader.take(remaining.saturating_add(1)), &mut out)?;
+        if copied > remaining {
+            drop(out);
+            let _ = fs::remove_file(&target);
+            return Err(ArchiveError::Limit(format!(
+                "{} extracted bytes",
+                self.limits.max_bytes
+            )));
+        }
+        self.summary.bytes += copied;
+        self.changes.record_write(&target, existed);
+        Ok(())
+    }
+}
+
+fn extract_zip<R: Read + Seek>(reader: R, sink: &mut ExtractSink<'_>) -> Result<(), ArchiveError> {
+    let mut archive = ZipArchive::new(reader)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = PathBuf::from(entry.name());
+        if entry.is_symlink() {
+            return Err(ArchiveError::UnsafeEntry(name.display().to_string()));
+        }
+        if entry.is_dir() {
+            sink.write_dir(&name)?;
+        } else {
+            sink.write_file(&name, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_tar<R: Read>(reader: R, sink: &mut ExtractSink<'_>) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => sink.write_file(&name, &mut entry)?,
+            EntryType::Directory => sink.write_dir(&name)?,
+            EntryType::XGlobalHeader => {}
+            _ => return Err(ArchiveError::UnsafeEntry(name.display().to_string())),
+        }
+    }
+    Ok(())
+}
+
+struct PackEntry {
+    name: String,
+    path: Utf8PathBuf,
+    is_dir: bool,
+    size: u64,
+}
+
+fn collect_entries(
+    root: &Utf8Path,
+    path: &Utf8Path,
+    dest: &Utf8Path,
+    out: &mut Vec<PackEntry>,
+) -> Result<(), ArchiveError> {
+    if path == dest {
+        return Ok(());
+    }
+    let meta = fs::symlink_metadata(path.as_std_path())?;
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    let name = path
+        .strip_prefix(root)
+        .map_err(|_| ArchiveError::UnsafeEntry(path.to_string()))?
+        .as_str()
+        .to_string();
+    if meta.is_dir() {
+        if !name.is_empty() {
+            out.push(PackEntry {
+                name: format!("{name}/"),
+                path: path.to_path_buf(),
+                is_dir: true,
+                size: 0,
+            });
+        }
+        let mut children = fs::read_dir(path.as_std_path())?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        children.sort();
+        for child in children {
+            let child = Utf8PathBuf::from_path_buf(child)
+                .map_err(|p| ArchiveError::UnsafeEntry(p.to_string_lossy().into_owned()))?;
+            collect_entries(root, &child, dest, out)?;
+        }
+    } else if meta.is_file() {
+        out.push(PackEntry {
+            name,
+            path: path.to_path_buf(),
+            is_dir: false,
+            size: meta.len(),
+        });
+    }
+    Ok(())
+}
+
+fn write_zip(file: File, entries: &[PackEntry]) -> Result<(), ArchiveError> {
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for entry in entries {
+        if entry.is_dir {
+            zip.add_directory(entry.name.as_str(), options)?;
+        } else {
+            zip.start_file(entry.name.as_str(), options)?;
+            let mut source = File::open(entry.path.as_std_path())?;
+            io::copy(&mut source, &mut zip)?;
+        }
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[PackEntry]) -> Result<W, ArchiveError> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry in entries {
+        if entry.is_dir {
+            builder.append_dir(&entry.name, entry.path.as_std_path())?;
+        } else {
+            let mut source = File::open(entry.path.as_std_path())?;
+            builder.append_file(&entry.name, &mut source)?;
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A zip holding one file of `size` zero bytes, which deflate shrinks
+    /// to almost nothing.
+    fn zip_bomb(dir: &Utf8Path, size: usize) -> Utf8PathBuf {
+        let path = dir.join("bomb.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("zeros.bin", options).unwrap();
+        zip.write_all(&vec![0; size]).unwrap();
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn caps_requested_limits_to_settings() {
+        let settings = ArchiveSettings {
+            max_entries: 10,
+            max_bytes: 1024,
+        };
+        let requested = ExtractLimits {
+            max_entries: u32::MAX,
+            max_bytes: u64::MAX,
+        };
+        assert_eq!(
+            requested.capped(&settings),
+            ExtractLimits {
+                max_entries: 10,
+                max_bytes: 1024,
+            }
+        );
+        let smaller = ExtractLimits {
+            max_entries: 2,
+            max_bytes: 16,
+        };
+        assert_eq!(smaller.capped(&settings), smaller);
+    }
+
+    #[test]
+    fn oversized_request_stops_at_host_limit() {
+        let temp = TempDir::new().unwrap();
+        let dir = Utf8Path::from_path(temp.path()).unwrap();
+        let archive = zip_bomb(dir, 64 * 1024);
+        let settings = ArchiveSettings {
+            max_entries: 10,
+            max_bytes: 1024,
+        };
+        let limits = ExtractLimits {
+            max_entries: u32::MAX,
+            max_bytes: u64::MAX,
+        }
+        .capped(&settings);
+        let dest = dir.join("out");
+        let result = extract(&archive, &dest, limits, &mut ChangeSet::default());
+        assert!(matches!(result, Err(ArchiveError::Limit(_))));
+        assert!(!dest.join("zeros.bin").exists());
+    }
+}
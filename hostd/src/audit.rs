@@ -0,0 +1,230 @@
+//! Per-task audit trail, written when `policy.audit_dir` is set: the guest's
+//! own `policy.log-event` records plus a host record for every capability
+//! call and planner action, each stamped with the time and planner step.
+//!
+//! Guest capability calls are picked up from the spans the component
+//! bindings emit around every import, so no handler has to remember to log.
+//!
+//! A task runs on one thread from start to end, so the open file is kept
+//! per thread; tasks `hostd serve` runs side by side each write their own.
+//! Threads a task starts, such as a browser's event reader, record through
+//! a [`Handle`] taken on the task's thread.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde_json::{Value, json};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Metadata, Subscriber, info, warn};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Name of the span the bindings open around each import call.
+const IMPORT_SPAN: &str = "wit-bindgen import";
+
+/// Audit logs open on any thread.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+static NEXT_LOG: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static LOG: RefCell<Handle> = const { RefCell::new(Handle(None)) };
+}
+
+struct AuditLog {
+    path: Utf8PathBuf,
+    file: File,
+    step: u32,
+}
+
+/// Starts this task's file under `dir`; on unix only the host user can
+/// read it.
+pub fn open(dir: &Utf8Path) -> io::Result<Utf8PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}-{}-{}.jsonl",
+        now_ms(),
+        std::process::id(),
+        NEXT_LOG.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut open = fs::OpenOptions::new();
+    open.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+    let file = open.open(&path)?;
+    info!(path = %path, "writing audit log");
+    let log = AuditLog {
+        path: path.clone(),
+        file,
+        step: 0,
+    };
+    let previous = LOG.replace(Handle(Some(Arc::new(Mutex::new(log)))));
+    if previous.0.is_none() {
+        OPEN.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(path)
+}
+
+/// Ends the task's file, so records from a later task on this thread do
+/// not land in it.
+pub fn close() {
+    if LOG.take().0.is_some() {
+        OPEN.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether any thread has an audit log open.
+pub fn enabled() -> bool {
+    OPEN.load(Ordering::Relaxed) > 0
+}
+
+/// Step stamped on host records, and on guest records that carry none.
+pub fn set_step(step: u32) {
+    if let Some(log) = &handle().0 {
+        lock(log).step = step;
+    }
+}
+
+/// Appends one record to this thread's file. A failed write is logged
+/// rather than returned; the task goes on without it.
+pub fn record(source: &str, event: &str, step: Option<u32>, payload: Value) {
+    handle().record(source, event, step, payload);
+}
+
+/// This thread's audit log, for a thread the task starts to record into.
+pub fn handle() -> Handle {
+    LOG.with_borrow(Handle::clone)
+}
+
+/// The audit log of the task that took it, or nothing when that task had
+/// none open.
+#[derive(Clone, Default)]
+pub struct Handle(Option<Arc<Mutex<AuditLog>>>);
+
+impl Handle {
+    /// Like [`record`], into the task's file from any thread.
+    pub fn record(&self, source: &str, event: &str, step: Option<u32>, payload: Value) {
+        let Some(log) = &self.0 else {
+            return;
+        };
+        let mut log = lock(log);
+        let entry = json!({
+            "at_ms": now_ms(),
+            "step": step.unwrap_or(log.step),
+            "source": source,
+            "event": event,
+            "payload": payload,
+        });
+        if let Err(err) = writeln!(log.file, "{entry}") {
+            warn!(path = %log.path, error = %err, "failed to write audit log");
+        }
+    }
+}
+
+fn lock(log: &Mutex<AuditLog>) -> std::sync::MutexGuard<'_, AuditLog> {
+    log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis())
+}
+
+/// Filter for [`CallLayer`]: the bindings' import spans and events for the
+/// osagent interfaces, only while an audit log is open. WASI imports are
+/// left out.
+pub fn wants(metadata: &Metadata<'_>) -> bool {
+    enabled() && metadata.target().starts_with("hostd::bindings")
+}
+
+/// Writes a `capability_call` record when an import returns.
+pub struct CallLayer;
+
+#[derive(Default)]
+struct ImportCall {
+    interface: String,
+    function: String,
+}
+
+impl Visit for ImportCall {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "module" => self.interface = value.to_string(),
+            "function" => self.function = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+#[derive(Default)]
+struct ImportReturn {
+    message: String,
+    result: Option<String>,
+}
+
+impl Visit for ImportReturn {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            "result" => self.result = Some(format!("{value:?}")),
+            _ => {}
+        }
+    }
+}
+
+impl<S> Layer<S> for CallLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != IMPORT_SPAN {
+            return;
+        }
+        let mut call = ImportCall::default();
+        attrs.record(&mut call);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(call);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(call) = extensions.get::<ImportCall>() else {
+            return;
+        };
+        let mut returned = ImportReturn::default();
+        event.record(&mut returned);
+        if returned.message != "return" {
+            return;
+        }
+        let mut payload = json!({
+            "interface": call.interface,
+            "function": call.function,
+        });
+        // Results holding lists are elided by the bindings, so their
+        // outcome is unknown here.
+        match returned.result.as_deref() {
+            Some("\"...\"") | None => {}
+            Some(result) => match result.strip_prefix("Err(") {
+                Some(err) => {
+                    payload["outcome"] = json!("error");
+                    payload["error"] = json!(err.strip_suffix(')').unwrap_or(err));
+                }
+                None => payload["outcome"] = json!("ok"),
+            },
+        }
+        record("host", "capability_call", None, payload);
+    }
+}
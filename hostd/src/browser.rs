@@ -0,0 +1,1800 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as Base64};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use thirtyfour::common::capabilities::firefox::FirefoxPreferences;
+use thirtyfour::common::print::{PrintOrientation, PrintParameters};
+use thirtyfour::extensions::cdp::ChromeDevTools;
+use thirtyfour::prelude::*;
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tracing::warn;
+use url::Url;
+
+use crate::audit;
+use crate::cdp::CdpSession;
+use crate::config::BrowserSettings;
+use crate::deadline;
+use crate::driver;
+use crate::har::NetworkLog;
+use crate::policy::{Denial, UrlRule};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5_000);
+const MAX_NAVIGATION_TIMEOUT: Duration = Duration::from_millis(30_000);
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long the page must fetch nothing new to count as network idle.
+const NETWORK_IDLE: Duration = Duration::from_millis(500);
+pub const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_millis(30_000);
+/// Workspace-relative directory sessions with `allow_downloads` save into.
+pub const DOWNLOAD_DIR: &str = "downloads";
+/// Workspace-relative directory sessions with `record_har` write into.
+pub const HAR_DIR: &str = "har";
+/// Suffixes browsers give files they are still writing.
+const PARTIAL_SUFFIXES: &[&str] = &[".crdownload", ".part", ".download"];
+/// How many matches `browser.session.query_all` summarises by default.
+pub const DEFAULT_QUERY_LIMIT: usize = 20;
+/// Upper bound on a caller-supplied `query_all` limit.
+pub const MAX_QUERY_LIMIT: usize = 100;
+/// Summarises `this` for [`BrowserElement::summary`]: tag, clipped text and
+/// the attributes that identify it, leaving out password values.
+const SUMMARY_FUNCTION: &str = "function() {
+    const KEEP = ['id', 'name', 'type', 'href', 'role', 'aria-label', 'title', 'placeholder',
+                  'alt', 'value'];
+    const attributes = {};
+    for (const name of KEEP) {
+        const value = this.getAttribute(name);
+        if (value) attributes[name] = value.slice(0, 200);
+    }
+    if (this.type === 'password') delete attributes.value;
+    const text = (this.innerText ?? this.textContent ?? '').replace(/\\s+/g, ' ').trim();
+    return { tag: this.tagName.toLowerCase(), attributes,
+             text: text.length > 120 ? text.slice(0, 119) + '…' : text };
+}";
+/// `this` is a `<select>`; `(by, want)` picks the option by `value` or
+/// `label`. Returns the chosen value, or null when nothing matches.
+const SELECT_OPTION_FUNCTION: &str = "function(by, want) {
+    if (this.tagName !== 'SELECT') throw new Error('element is not a <select>');
+    const option = Array.from(this.options).find(option => by === 'value'
+        ? option.value === want
+        : option.label.trim() === want || option.text.trim() === want);
+    if (!option) return null;
+    option.selected = true;
+    this.dispatchEvent(new Event('input', { bubbles: true }));
+    this.dispatchEvent(new Event('change', { bubbles: true }));
+    return option.value;
+}";
+/// Clicks `this` checkbox or radio until it matches `want`, setting it
+/// directly if a handler cancels the click.
+const SET_CHECKED_FUNCTION: &str = "function(want) {
+    if (this.tagName !== 'INPUT' || (this.type !== 'checkbox' && this.type !== 'radio')) {
+        throw new Error('element is not a checkbox or radio button');
+    }
+    if (this.checked !== want) {
+        this.scrollIntoView({ block: 'center', inline: 'center' });
+        this.click();
+    }
+    if (this.checked !== want) {
+        this.checked = want;
+        this.dispatchEvent(new Event('input', { bubbles: true }));
+        this.dispatchEvent(new Event('change', { bubbles: true }));
+    }
+    return this.checked;
+}";
+/// Chooses a radio in `this` radio's group, or below `this`, by `value` or
+/// label text. Returns its value, or null when nothing matches.
+const CHOOSE_RADIO_FUNCTION: &str = "function(by, want) {
+    const isRadio = el => el.tagName === 'INPUT' && el.type === 'radio';
+    const radios = isRadio(this)
+        ? Array.from((this.form ?? this.ownerDocument).querySelectorAll('input[type=radio]'))
+            .filter(radio => radio.name === this.name && radio.form === this.form)
+        : Array.from(this.querySelectorAll('input[type=radio]'));
+    const label = radio => Array.from(radio.labels ?? [], l => l.innerText.trim()).join(' ');
+    const radio = radios.find(radio => by === 'value' ? radio.value === want : label(radio) === want);
+    if (!radio) return null;
+    if (!radio.checked) {
+        radio.scrollIntoView({ block: 'center', inline: 'center' });
+        radio.click();
+    }
+    if (!radio.checked) {
+        radio.checked = true;
+        radio.dispatchEvent(new Event('input', { bubbles: true }));
+        radio.dispatchEvent(new Event('change', { bubbles: true }));
+    }
+    return radio.value;
+}";
+/// Evaluates to the page outline described in `outline.js`.
+const OUTLINE_SCRIPT: &str = include_str!("outline.js");
+/// Workspace-relative directory profile state is saved in when
+/// `browser.profile_dir` is unset.
+pub const PROFILE_STATE_DIR: &str = ".browser-profiles";
+/// Evaluates to the current origin's localStorage, or null where the page
+/// has none (such as `about:blank`).
+const STORAGE_SCRIPT: &str = "(() => { try { return { origin: location.origin, \
+     items: Object.fromEntries(Object.entries(localStorage)) }; } catch (e) { return null; } })()";
+
+#[derive(Debug, Error)]
+pub enum BrowserError {
+    #[error("browser session is closed")]
+    Closed,
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("no element matches {0}")]
+    NotFound(String),
+    #[error("no tab with id {0}")]
+    NoSuchTab(String),
+    #[error("{0} is not in browser.allowed_domains")]
+    Blocked(String),
+    /// A `policy.urls` rule refused the URL; the message names the rule.
+    #[error("{url} is {denial}")]
+    UrlDenied { url: String, denial: Denial },
+    #[error("script failed: {0}")]
+    Script(String),
+    #[error("eval is disabled by browser.allow_eval")]
+    EvalDisabled,
+    #[error("eval result is {size} bytes, over the {limit} byte limit")]
+    TooLarge { size: usize, limit: usize },
+    #[error("attaching to the browser at {0} needs --allow-browser-attach")]
+    AttachNotConfirmed(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("{0}")]
+    Launch(String),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("devtools: {0}")]
+    Protocol(String),
+    #[error("{0}")]
+    WebDriver(Box<WebDriverError>),
+}
+
+impl From<WebDriverError> for BrowserError {
+    fn from(err: WebDriverError) -> Self {
+        Self::WebDriver(Box::new(err))
+    }
+}
+
+/// How the host talks to the browser.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserBackend {
+    /// A separately running WebDriver server at `browser.webdriver_url`.
+    #[default]
+    WebDriver,
+    /// Chrome launched by the host and driven over the DevTools protocol.
+    Cdp,
+}
+
+/// Which browser a WebDriver session asks for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserKind {
+    #[default]
+    Chrome,
+    Firefox,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelectorKind {
+    Css,
+    XPath,
+    Text,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Selector {
+    pub kind: SelectorKind,
+    pub value: String,
+}
+
+impl Selector {
+    /// The selector as XPath, or `None` for CSS.
+    pub fn xpath(&self) -> Option<String> {
+        match self.kind {
+            SelectorKind::Css => None,
+            SelectorKind::XPath => Some(self.value.clone()),
+            SelectorKind::Text => Some(format!(
+                "//*[normalize-space(text()) = {}]",
+                xpath_literal(&self.value)
+            )),
+        }
+    }
+
+    /// A JS expression evaluating to the array of matching elements.
+    pub(crate) fn query_expression(&self) -> String {
+        match self.xpath() {
+            Some(xpath) => format!(
+                "(() => {{ \
+                   const found = document.evaluate({}, document, null, \
+                     XPathResult.ORDERED_NODE_SNAPSHOT_TYPE, null); \
+                   return Array.from({{ length: found.snapshotLength }}, (_, i) => found.snapshotItem(i)); \
+                 }})()",
+                Value::String(xpath)
+            ),
+            None => format!(
+                "Array.from(document.querySelectorAll({}))",
+                Value::String(self.value.clone())
+            ),
+        }
+    }
+
+    fn to_by(&self) -> By {
+        match self.xpath() {
+            Some(xpath) => By::XPath(xpath),
+            None => By::Css(self.value.clone()),
+        }
+    }
+}
+
+/// Hosts sessions may load anything from (`browser.allowed_domains`); each
+/// domain also covers its subdomains.
+#[derive(Debug, Clone)]
+pub struct DomainAllowlist(Vec<String>);
+
+impl DomainAllowlist {
+    pub fn new(domains: impl IntoIterator<Item = String>) -> Self {
+        Self(
+            domains
+                .into_iter()
+                .map(|domain| {
+                    let domain = domain.trim().to_ascii_lowercase();
+                    let domain = domain.strip_prefix("*.").unwrap_or(&domain);
+                    domain.trim_matches('.').to_string()
+                })
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        )
+    }
+
+    pub fn domains(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Whether the browser may fetch `url`. `about:`, `data:` and `blob:`
+    /// URLs never leave the browser, so they are always allowed; schemes
+    /// other than http(s) and ws(s), such as `file:`, never are.
+    pub fn allows(&self, url: &str) -> bool {
+        let Ok(url) = Url::parse(url) else {
+            return false;
+        };
+        match url.scheme() {
+            "about" | "data" | "blob" => true,
+            "http" | "https" | "ws" | "wss" => url.host_str().is_some_and(|host| {
+                let host = host.trim_end_matches('.');
+                self.0.iter().any(|domain| {
+                    host == domain
+                        || host
+                            .strip_suffix(domain.as_str())
+                            .is_some_and(|rest| rest.ends_with('.'))
+                })
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// An `<iframe>` or `<frame>` in the current frame, by selector or by its
+/// position among the frame elements in document order.
+#[derive(Debug, Clone)]
+pub enum FrameTarget {
+    Selector(Selector),
+    Index(u16),
+}
+
+impl std::fmt::Display for FrameTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Selector(selector) => write!(f, "frame {selector}"),
+            Self::Index(index) => write!(f, "frame #{index}"),
+        }
+    }
+}
+
+/// Something to wait for on the current page.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WaitCondition {
+    /// `document.readyState` is `complete`.
+    DocumentReady,
+    /// An element matching `selector` is rendered and not hidden.
+    SelectorVisible { selector: Selector },
+    /// The URL contains `pattern`, where `*` matches any run of characters.
+    UrlMatches { pattern: String },
+    /// The document has loaded and fetched no new resources for
+    /// [`NETWORK_IDLE`]. Requests still in flight are not visible to the
+    /// page, so this is a heuristic.
+    NetworkIdle,
+}
+
+impl WaitCondition {
+    /// A JS expression for [`WaitCondition::SelectorVisible`].
+    fn visible_expression(selector: &Selector) -> String {
+        format!(
+            "({}).some(el => {{ const style = getComputedStyle(el); \
+               return el.getClientRects().length > 0 && style.visibility !== 'hidden'; }})",
+            selector.query_expression()
+        )
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} `{}`", self.kind, self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenshotKind {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ScreenshotKind {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// How to render the page for [`BrowserSession::print_pdf`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PdfOptions {
+    pub landscape: bool,
+    /// Include background colours and images, which print media drops.
+    pub background: bool,
+}
+
+/// How to capture a page screenshot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScreenshotOptions {
+    pub kind: ScreenshotKind,
+    /// JPEG quality from 1 to 100; the browser picks when unset.
+    pub quality: Option<u8>,
+    /// Capture the whole document instead of just the viewport.
+    pub full_page: bool,
+}
+
+impl ScreenshotOptions {
+    fn validate(&self) -> Result<(), BrowserError> {
+        match self.quality {
+            Some(_) if self.kind != ScreenshotKind::Jpeg => Err(BrowserError::Unsupported(
+                "screenshot quality only applies to jpeg".to_string(),
+            )),
+            Some(quality) if !(1..=100).contains(&quality) => Err(BrowserError::Unsupported(
+                "screenshot quality must be between 1 and 100".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parameters for CDP's `Page.captureScreenshot`; `metrics` is the
+    /// `Page.getLayoutMetrics` result, needed only for full-page captures.
+    pub(crate) fn capture_params(&self, metrics: Option<&Value>) -> Value {
+        let format = match self.kind {
+            ScreenshotKind::Png => "png",
+            ScreenshotKind::Jpeg => "jpeg",
+        };
+        let mut params = serde_json::json!({ "format": format });
+        if let Some(quality) = self.quality {
+            params["quality"] = quality.into();
+        }
+        if let Some(metrics) = metrics {
+            let size = metrics
+                .get("cssContentSize")
+                .or_else(|| metrics.get("contentSize"))
+                .cloned()
+                .unwrap_or(Value::Null);
+            params["captureBeyondViewport"] = true.into();
+            params["clip"] = serde_json::json!({
+                "x": 0,
+                "y": 0,
+                "width": size["width"].as_f64().unwrap_or(0.0),
+                "height": size["height"].as_f64().unwrap_or(0.0),
+                "scale": 1,
+            });
+        }
+        params
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Overrides `browser.kind` for this session.
+    pub kind: Option<BrowserKind>,
+    pub profile: Option<String>,
+    pub headless: bool,
+    pub allow_downloads: bool,
+    /// Set by [`BrowserSession::open`] when downloads are allowed.
+    pub download_dir: Option<Utf8PathBuf>,
+    /// Record request and response metadata, written as a HAR on close.
+    pub record_har: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PageState {
+    pub url: String,
+    pub title: Option<String>,
+    pub html: Option<String>,
+    /// Landmarks, headings and interactive elements, one per line; much
+    /// smaller than `html`.
+    pub outline: Option<String>,
+}
+
+/// A tab or window of a session; `id` is only meaningful to that session.
+#[derive(Debug, Clone)]
+pub struct TabInfo {
+    pub id: String,
+    pub url: String,
+    pub title: Option<String>,
+    /// Whether later calls act on this tab.
+    pub active: bool,
+}
+
+/// Where the page is scrolled to, in CSS pixels.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ScrollPosition {
+    pub x: f64,
+    pub y: f64,
+    /// The furthest `y` can go; `y == max_y` means the bottom is in view.
+    pub max_y: f64,
+}
+
+/// One browser session's connection, as implemented by each backend.
+pub(crate) trait SessionBackend: Send {
+    /// Starts navigating to `url`, giving up after `timeout`.
+    fn navigate(&self, url: &str, timeout: Duration) -> Result<(), BrowserError>;
+    fn describe(&self, include_html: bool) -> Result<PageState, BrowserError>;
+    fn screenshot(&self, options: &ScreenshotOptions) -> Result<Vec<u8>, BrowserError>;
+    fn print_pdf(&self, options: &PdfOptions) -> Result<Vec<u8>, BrowserError>;
+    /// Evaluates `expression` in the page and returns its JSON value.
+    fn eval(&self, expression: &str) -> Result<Value, BrowserError>;
+    fn find(
+        &self,
+        selector: &Selector,
+        timeout: Duration,
+    ) -> Result<Box<dyn ElementBackend>, BrowserError>;
+    fn query_all(&self, selector: &Selector) -> Result<Vec<Box<dyn ElementBackend>>, BrowserError>;
+    /// The browser's cookies, shaped like CDP's `Network.Cookie`.
+    fn cookies(&self) -> Result<Vec<Value>, BrowserError>;
+    /// Adds cookies previously returned by [`SessionBackend::cookies`].
+    fn set_cookies(&self, cookies: &[Value]) -> Result<(), BrowserError>;
+    /// Every open tab, including windows the page opened itself.
+    fn tabs(&self) -> Result<Vec<TabInfo>, BrowserError>;
+    /// Opens a blank tab, makes it the active one and returns its id.
+    fn new_tab(&mut self) -> Result<String, BrowserError>;
+    fn switch_tab(&mut self, id: &str) -> Result<(), BrowserError>;
+    /// Directs later calls into a frame of the current frame, until
+    /// [`SessionBackend::exit_frame`] or the next navigation.
+    fn enter_frame(&mut self, target: &FrameTarget) -> Result<(), BrowserError>;
+    /// Returns to the parent frame; does nothing at the top-level document.
+    fn exit_frame(&mut self) -> Result<(), BrowserError>;
+    /// Shuts the browser down; must be safe to call more than once.
+    fn close(&mut self);
+}
+
+pub(crate) trait ElementBackend: Send {
+    fn click(&self) -> Result<(), BrowserError>;
+    fn type_text(&self, text: &str, submit: bool) -> Result<(), BrowserError>;
+    fn clear(&self) -> Result<(), BrowserError>;
+    fn attribute(&self, name: &str) -> Result<Option<String>, BrowserError>;
+    fn inner_text(&self) -> Result<String, BrowserError>;
+    fn html(&self) -> Result<String, BrowserError>;
+    /// A png cropped to the element's box.
+    fn screenshot(&self) -> Result<Vec<u8>, BrowserError>;
+    /// Calls the JS function declaration `function` with the element as
+    /// `this` and `args` as its arguments, and returns its JSON value.
+    fn call(&self, function: &str, args: &[Value]) -> Result<Value, BrowserError>;
+}
+
+/// Picks an `<option>` or radio button by its value or its visible label.
+#[derive(Debug, Clone)]
+pub enum OptionChoice {
+    Value(String),
+    Label(String),
+}
+
+impl OptionChoice {
+    fn args(&self) -> [Value; 2] {
+        match self {
+            Self::Value(value) => ["value".into(), value.as_str().into()],
+            Self::Label(label) => ["label".into(), label.as_str().into()],
+        }
+    }
+}
+
+impl std::fmt::Display for OptionChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "value `{value}`"),
+            Self::Label(label) => write!(f, "label `{label}`"),
+        }
+    }
+}
+
+/// What `browser.session.query_all` reports for each match.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ElementSummary {
+    pub tag: String,
+    pub text: String,
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// A browser session on the configured backend. The browser is shut down
+/// when the session is closed or dropped.
+pub struct BrowserSession {
+    backend: Box<dyn SessionBackend>,
+    closed: bool,
+    profile: Option<String>,
+    /// Where `save_state` writes; set when the session has a profile.
+    state_path: Option<Utf8PathBuf>,
+    allowed_domains: Option<DomainAllowlist>,
+    url_rule: UrlRule,
+    allow_eval: bool,
+    max_eval_bytes: usize,
+    /// Loaded localStorage, by origin, not yet written into a page.
+    pending_storage: Mutex<BTreeMap<String, BTreeMap<String, String>>>,
+    downloads: Option<Downloads>,
+    /// The traffic being recorded and where it is written on close.
+    har: Option<(Arc<NetworkLog>, Utf8PathBuf)>,
+    /// When the browser was last driven, for `browser.idle_timeout_ms`.
+    last_used: Mutex<Instant>,
+}
+
+impl BrowserSession {
+    /// Opens a session. With `allow_downloads`, the browser saves into
+    /// `<workspace>/downloads` instead of its own default directory, which
+    /// for a WebDriver server must be on the same filesystem as the host.
+    /// With `record_har`, the session's traffic is written to
+    /// `<workspace>/har/` when it closes; only the CDP backend can see it.
+    /// With `browser.attach`, the session drives the operator's own browser,
+    /// so profiles and downloads are refused.
+    pub fn open(
+        settings: &BrowserSettings,
+        url_rule: &UrlRule,
+        mut options: SessionOptions,
+        workspace: &Utf8Path,
+        tokio: Handle,
+    ) -> Result<Self, BrowserError> {
+        let profile = options
+            .profile
+            .clone()
+            .or_else(|| settings.default_profile.clone());
+        if let Some(address) = &settings.attach {
+            if !settings.attach_confirmed {
+                return Err(BrowserError::AttachNotConfirmed(address.clone()));
+            }
+            if options.kind.is_some_and(|kind| kind != BrowserKind::Chrome) {
+                return Err(BrowserError::Unsupported(
+                    "browser.attach only drives Chrome".to_string(),
+                ));
+            }
+            if profile.is_some() || options.allow_downloads {
+                return Err(BrowserError::Unsupported(
+                    "profiles and downloads are unavailable in the operator's browser".to_string(),
+                ));
+            }
+            warn!(target: "audit", address, "attaching to the operator's browser");
+            audit::record(
+                "host",
+                "browser_attached",
+                None,
+                json!({ "address": address }),
+            );
+        }
+        let state_path = match &profile {
+            Some(profile) => Some(profile_state_path(settings, workspace, profile)?),
+            None => None,
+        };
+        let downloads = if options.allow_downloads {
+            let downloads = Downloads::new(workspace.join(DOWNLOAD_DIR))?;
+            options.download_dir = Some(downloads.dir.clone());
+            Some(downloads)
+        } else {
+            options.download_dir = None;
+            None
+        };
+        if options.record_har && settings.backend != BrowserBackend::Cdp {
+            return Err(BrowserError::Unsupported(
+                "recording a HAR needs browser.backend = \"cdp\"".to_string(),
+            ));
+        }
+        let har = if options.record_har {
+            Some((Arc::default(), har_path(workspace)))
+        } else {
+            None
+        };
+        let backend: Box<dyn SessionBackend> = match settings.backend {
+            BrowserBackend::WebDriver => {
+                Box::new(WebDriverSession::open(settings, &options, tokio)?)
+            }
+            BrowserBackend::Cdp => {
+                if options.kind.is_some_and(|kind| kind != BrowserKind::Chrome) {
+                    return Err(BrowserError::Unsupported(
+                        "the cdp browser backend only drives Chrome".to_string(),
+                    ));
+                }
+                let network = har.as_ref().map(|(log, _)| Arc::clone(log));
+                Box::new(CdpSession::launch(settings, &options, network)?)
+            }
+        };
+        Ok(Self {
+            backend,
+            closed: false,
+            profile,
+            state_path,
+            allowed_domains: settings.allowed_domains.clone(),
+            url_rule: url_rule.clone(),
+            allow_eval: settings.allow_eval,
+            max_eval_bytes: settings.max_eval_bytes,
+            pending_storage: Mutex::default(),
+            downloads,
+            har,
+            last_used: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    fn backend(&self) -> Result<&dyn SessionBackend, BrowserError> {
+        if self.closed {
+            return Err(BrowserError::Closed);
+        }
+        self.touch();
+        Ok(self.backend.as_ref())
+    }
+
+    fn backend_mut(&mut self) -> Result<&mut dyn SessionBackend, BrowserError> {
+        if self.closed {
+            return Err(BrowserError::Closed);
+        }
+        self.touch();
+        Ok(self.backend.as_mut())
+    }
+
+    /// Marks the session as in use; elements are driven without going
+    /// through the session, so their callers touch it themselves.
+    pub fn touch(&self) {
+        *self
+            .last_used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+
+    /// How long since the session was last used.
+    pub fn idle_for(&self) -> Duration {
+        self.last_used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .elapsed()
+    }
+
+    /// Navigates to `url` and waits, within `timeout` overall, for the
+    /// document to finish loading and then for `until`, if given.
+    pub fn goto(
+        &self,
+        url: &str,
+        until: Option<&WaitCondition>,
+        timeout: Duration,
+    ) -> Result<PageState, BrowserError> {
+        let backend = self.backend()?;
+        if let Some(denial) = self.url_rule.denial(url) {
+            warn!(target: "audit", url, rule = %denial, "blocked browser navigation");
+            audit::record(
+                "host",
+                "browser_navigation_blocked",
+                None,
+                json!({ "url": url, "rule": denial.rule, "pattern": denial.pattern }),
+            );
+            return Err(BrowserError::UrlDenied {
+                url: url.to_string(),
+                denial,
+            });
+        }
+        if let Some(allowlist) = &self.allowed_domains
+            && !allowlist.allows(url)
+        {
+            warn!(target: "audit", url, "blocked browser navigation outside browser.allowed_domains");
+            audit::record(
+                "host",
+                "browser_navigation_blocked",
+                None,
+                json!({ "url": url, "rule": "browser.allowed_domains" }),
+            );
+            return Err(BrowserError::Blocked(url.to_string()));
+        }
+        let timeout = timeout.min(MAX_NAVIGATION_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+        backend.navigate(url, timeout)?;
+        wait_until(backend, &WaitCondition::DocumentReady, deadline, timeout)?;
+        self.restore_storage(backend)?;
+        if let Some(until) = until {
+            wait_until(backend, until, deadline, timeout)?;
+        }
+        backend.describe(false)
+    }
+
+    /// Scrolls the current frame by a delta, so lazy-loaded content below
+    /// the fold gets rendered.
+    pub fn scroll_by(&self, dx: i32, dy: i32) -> Result<ScrollPosition, BrowserError> {
+        self.backend()?
+            .eval(&format!("window.scrollBy({dx}, {dy})"))?;
+        self.scroll_position()
+    }
+
+    pub fn scroll_position(&self) -> Result<ScrollPosition, BrowserError> {
+        let position = self.backend()?.eval(
+            "({ x: window.scrollX, y: window.scrollY,
+                max_y: Math.max(0, document.documentElement.scrollHeight - window.innerHeight) })",
+        )?;
+        serde_json::from_value(position)
+            .map_err(|err| BrowserError::Script(format!("unexpected scroll position: {err}")))
+    }
+
+    /// Polls until `condition` holds, failing after `timeout`.
+    pub fn wait_for(
+        &self,
+        condition: &WaitCondition,
+        timeout: Duration,
+    ) -> Result<PageState, BrowserError> {
+        let backend = self.backend()?;
+        let timeout = timeout.min(MAX_NAVIGATION_TIMEOUT);
+        wait_until(backend, condition, Instant::now() + timeout, timeout)?;
+        backend.describe(false)
+    }
+
+    pub fn describe(
+        &self,
+        include_html: bool,
+        include_outline: bool,
+    ) -> Result<PageState, BrowserError> {
+        let backend = self.backend()?;
+        let mut state = backend.describe(include_html)?;
+        if include_outline {
+            let outline = backend.eval(OUTLINE_SCRIPT)?;
+            state.outline = Some(outline.as_str().unwrap_or_default().to_string());
+        }
+        Ok(state)
+    }
+
+    pub fn screenshot(&self, options: &ScreenshotOptions) -> Result<Vec<u8>, BrowserError> {
+        options.validate()?;
+        self.backend()?.screenshot(options)
+    }
+
+    /// Renders the current page as a PDF document.
+    pub fn print_pdf(&self, options: &PdfOptions) -> Result<Vec<u8>, BrowserError> {
+        self.backend()?.print_pdf(options)
+    }
+
+    /// Evaluates a caller's expression, subject to `browser.allow_eval` and
+    /// `browser.max_eval_bytes` on the serialized result.
+    pub fn eval(&self, expression: &str) -> Result<Value, BrowserError> {
+        if !self.allow_eval {
+            return Err(BrowserError::EvalDisabled);
+        }
+        let value = self.backend()?.eval(expression)?;
+        let size = value.to_string().len();
+        if size > self.max_eval_bytes {
+            return Err(BrowserError::TooLarge {
+                size,
+                limit: self.max_eval_bytes,
+            });
+        }
+        Ok(value)
+    }
+
+    /// Waits up to `timeout` for the first element matching `selector`.
+    pub fn find(
+        &self,
+        selector: &Selector,
+        timeout: Duration,
+    ) -> Result<BrowserElement, BrowserError> {
+        let element = self.backend()?.find(selector, timeout)?;
+        Ok(BrowserElement(element))
+    }
+
+    pub fn query_all(&self, selector: &Selector) -> Result<Vec<BrowserElement>, BrowserError> {
+        let elements = self.backend()?.query_all(selector)?;
+        Ok(elements.into_iter().map(BrowserElement).collect())
+    }
+
+    pub fn tabs(&self) -> Result<Vec<TabInfo>, BrowserError> {
+        self.backend()?.tabs()
+    }
+
+    /// Opens a tab, switches to it and, given a `url`, navigates there as
+    /// [`BrowserSession::goto`] does. Returns the new tab's id.
+    pub fn new_tab(
+        &mut self,
+        url: Option<&str>,
+        timeout: Duration,
+    ) -> Result<String, BrowserError> {
+        let id = self.backend_mut()?.new_tab()?;
+        if let Some(url) = url {
+            self.goto(url, None, timeout)?;
+        }
+        Ok(id)
+    }
+
+    /// Makes `id` the tab later calls act on. Elements found in the previous
+    /// tab should not be used afterwards.
+    pub fn switch_tab(&mut self, id: &str) -> Result<PageState, BrowserError> {
+        self.backend_mut()?.switch_tab(id)?;
+        self.describe(false, false)
+    }
+
+    /// Makes later calls (find, eval, describe and so on) act inside the
+    /// frame `target` picks. Navigating returns to the top-level document.
+    pub fn enter_frame(&mut self, target: &FrameTarget) -> Result<(), BrowserError> {
+        self.backend_mut()?.enter_frame(target)
+    }
+
+    pub fn exit_frame(&mut self) -> Result<(), BrowserError> {
+        self.backend_mut()?.exit_frame()
+    }
+
+    /// Waits up to `timeout` for a download that finished after the session
+    /// opened and has not been returned before, and returns its path.
+    pub fn wait_for_download(&mut self, timeout: Duration) -> Result<Utf8PathBuf, BrowserError> {
+        if self.closed {
+            return Err(BrowserError::Closed);
+        }
+        let downloads = self.downloads.as_mut().ok_or_else(|| {
+            BrowserError::Unsupported("downloads are not allowed in this session".to_string())
+        })?;
+        downloads.wait(timeout)
+    }
+
+    /// Saves the browser's cookies, and localStorage for the current origin,
+    /// to the profile's state file, keeping what was saved before for other
+    /// sites. Returns the file's path.
+    pub fn save_state(&self) -> Result<Utf8PathBuf, BrowserError> {
+        let backend = self.backend()?;
+        let path = self.require_state_path()?;
+        let mut state = ProfileState::read(path)?.unwrap_or_default();
+        let cookies = backend.cookies()?;
+        let domains: BTreeSet<&str> = cookies
+            .iter()
+            .filter_map(|cookie| cookie["domain"].as_str())
+            .collect();
+        state.cookies.retain(|cookie| {
+            !cookie["domain"]
+                .as_str()
+                .is_some_and(|d| domains.contains(d))
+        });
+        state.cookies.extend(cookies.iter().cloned());
+        let storage: Option<OriginStorage> = serde_json::from_value(backend.eval(STORAGE_SCRIPT)?)
+            .map_err(|err| BrowserError::Script(format!("unexpected localStorage: {err}")))?;
+        if let Some(storage) = storage.filter(|storage| storage.origin != "null") {
+            if storage.items.is_empty() {
+                state.local_storage.remove(&storage.origin);
+            } else {
+                state.local_storage.insert(storage.origin, storage.items);
+            }
+        }
+        state.write(path)?;
+        Ok(path.to_path_buf())
+    }
+
+    /// Restores the profile's saved cookies. Saved localStorage is written
+    /// into each origin the next time the session is on it. Returns false
+    /// when the profile has nothing saved.
+    pub fn load_state(&self) -> Result<bool, BrowserError> {
+        let backend = self.backend()?;
+        let Some(state) = ProfileState::read(self.require_state_path()?)? else {
+            return Ok(false);
+        };
+        backend.set_cookies(&state.cookies)?;
+        *self.pending_storage() = state.local_storage;
+        self.restore_storage(backend)?;
+        Ok(true)
+    }
+
+    /// The profile's state file, which may not exist yet.
+    pub fn state_path(&self) -> Option<&Utf8Path> {
+        self.state_path.as_deref()
+    }
+
+    fn require_state_path(&self) -> Result<&Utf8Path, BrowserError> {
+        self.state_path().ok_or_else(|| {
+            BrowserError::Unsupported("session has no profile to keep state for".to_string())
+        })
+    }
+
+    fn pending_storage(
+        &self,
+    ) -> std::sync::MutexGuard<'_, BTreeMap<String, BTreeMap<String, String>>> {
+        self.pending_storage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Writes loaded localStorage for the page's origin, if any is pending.
+    fn restore_storage(&self, backend: &dyn SessionBackend) -> Result<(), BrowserError> {
+        let mut pending = self.pending_storage();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let origin = backend.eval("location.origin")?;
+        let Some(items) = origin.as_str().and_then(|origin| pending.remove(origin)) else {
+            return Ok(());
+        };
+        backend.eval(&format!(
+            "(items => {{ for (const [key, value] of Object.entries(items)) \
+             localStorage.setItem(key, value); }})({})",
+            serde_json::json!(items)
+        ))?;
+        Ok(())
+    }
+
+    /// Shuts the browser down. Later calls on the session, and on its
+    /// elements, fail.
+    /// Shuts the browser down and writes the HAR, if one was recorded.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.backend.close();
+        if let Some((log, path)) = &self.har {
+            log.write(path)
+                .unwrap_or_else(|err| warn!(path = %path, "failed to write browser HAR: {err}"));
+        }
+    }
+
+    /// Where the session's HAR is written on close, if it records one.
+    pub fn har_path(&self) -> Option<&Utf8Path> {
+        self.har.as_ref().map(|(_, path)| path.as_path())
+    }
+}
+
+impl Drop for BrowserSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// An element found through a [`BrowserSession`]; stale once the page
+/// navigates away or the session closes.
+pub struct BrowserElement(Box<dyn ElementBackend>);
+
+impl BrowserElement {
+    pub fn click(&self) -> Result<(), BrowserError> {
+        self.0.click()
+    }
+
+    pub fn type_text(&self, text: &str, submit: bool) -> Result<(), BrowserError> {
+        self.0.type_text(text, submit)
+    }
+
+    pub fn clear(&self) -> Result<(), BrowserError> {
+        self.0.clear()
+    }
+
+    pub fn attribute(&self, name: &str) -> Result<Option<String>, BrowserError> {
+        self.0.attribute(name)
+    }
+
+    pub fn inner_text(&self) -> Result<String, BrowserError> {
+        self.0.inner_text()
+    }
+
+    pub fn html(&self) -> Result<String, BrowserError> {
+        self.0.html()
+    }
+
+    /// A png of just this element, much smaller than a page screenshot.
+    pub fn screenshot(&self) -> Result<Vec<u8>, BrowserError> {
+        self.0.screenshot()
+    }
+
+    pub fn summary(&self) -> Result<ElementSummary, BrowserError> {
+        serde_json::from_value(self.0.call(SUMMARY_FUNCTION, &[])?)
+            .map_err(|err| BrowserError::Script(format!("unexpected element summary: {err}")))
+    }
+
+    pub fn scroll_into_view(&self) -> Result<(), BrowserError> {
+        self.0.call(
+            "function() { this.scrollIntoView({ block: 'center', inline: 'center' }); }",
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// Selects an option of this `<select>`, returning its value.
+    pub fn select_option(&self, choice: &OptionChoice) -> Result<String, BrowserError> {
+        match self.0.call(SELECT_OPTION_FUNCTION, &choice.args())? {
+            Value::String(value) => Ok(value),
+            _ => Err(BrowserError::NotFound(format!("option with {choice}"))),
+        }
+    }
+
+    /// Checks or unchecks this checkbox or radio button, clicking it so the
+    /// page's handlers run.
+    pub fn set_checked(&self, checked: bool) -> Result<(), BrowserError> {
+        self.0.call(SET_CHECKED_FUNCTION, &[checked.into()])?;
+        Ok(())
+    }
+
+    /// Chooses a radio button in this element's group (when it is a radio
+    /// button itself) or among its descendants, returning its value.
+    pub fn choose_radio(&self, choice: &OptionChoice) -> Result<String, BrowserError> {
+        match self.0.call(CHOOSE_RADIO_FUNCTION, &choice.args())? {
+            Value::String(value) => Ok(value),
+            _ => Err(BrowserError::NotFound(format!(
+                "radio button with {choice}"
+            ))),
+        }
+    }
+}
+
+/// Polls `condition` until it holds or `deadline` passes, reporting the
+/// latter as a timeout of `timeout`.
+fn wait_until(
+    backend: &dyn SessionBackend,
+    condition: &WaitCondition,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<(), BrowserError> {
+    let mut idle = IdleTracker::default();
+    loop {
+        let met = match condition {
+            WaitCondition::DocumentReady => {
+                backend.eval("document.readyState")?.as_str() == Some("complete")
+            }
+            WaitCondition::SelectorVisible { selector } => {
+                backend.eval(&WaitCondition::visible_expression(selector))? == Value::Bool(true)
+            }
+            WaitCondition::UrlMatches { pattern } => {
+                let url = backend.eval("location.href")?;
+                url_matches(url.as_str().unwrap_or_default(), pattern)
+            }
+            WaitCondition::NetworkIdle => {
+                let count = backend.eval(
+                    "document.readyState === 'complete' \
+                     ? performance.getEntriesByType('resource').length : -1",
+                )?;
+                idle.settled(count.as_i64().unwrap_or(-1))
+            }
+        };
+        if met {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(BrowserError::Timeout(timeout));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Tracks how long the page's resource count has held still, for
+/// [`WaitCondition::NetworkIdle`].
+#[derive(Default)]
+struct IdleTracker {
+    last: Option<(i64, Instant)>,
+}
+
+impl IdleTracker {
+    /// Records the latest resource count (-1 while loading) and reports
+    /// whether it has been unchanged for [`NETWORK_IDLE`].
+    fn settled(&mut self, count: i64) -> bool {
+        let now = Instant::now();
+        match self.last {
+            Some((last, since)) if last == count => count >= 0 && now - since >= NETWORK_IDLE,
+            _ => {
+                self.last = Some((count, now));
+                false
+            }
+        }
+    }
+}
+
+/// Whether `url` contains `pattern`, with `*` in the pattern matching any
+/// run of characters.
+fn url_matches(url: &str, pattern: &str) -> bool {
+    let mut rest = url;
+    for part in pattern.split('*').filter(|part| !part.is_empty()) {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Runs `future` to completion from synchronous host code. Host calls already
+/// run on a runtime worker, where `Handle::block_on` would panic, so the
+/// worker is handed back to the scheduler while this one blocks. Within a
+/// planner action the request fails once the action's deadline passes.
+fn block_on<T, E>(
+    tokio: &Handle,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, BrowserError>
+where
+    BrowserError: From<E>,
+{
+    let limit = deadline::remaining();
+    tokio::task::block_in_place(|| {
+        tokio.block_on(async move {
+            match limit {
+                Some(limit) => tokio::time::timeout(limit, future)
+                    .await
+                    .map_err(|_| BrowserError::Timeout(limit))?
+                    .map_err(BrowserError::from),
+                None => future.await.map_err(BrowserError::from),
+            }
+        })
+    })
+}
+
+/// A session on a WebDriver server such as chromedriver.
+struct WebDriverSession {
+    driver: Option<WebDriver>,
+    kind: BrowserKind,
+    tokio: Handle,
+}
+
+impl WebDriverSession {
+    fn open(
+        settings: &BrowserSettings,
+        options: &SessionOptions,
+        tokio: Handle,
+    ) -> Result<Self, BrowserError> {
+        let kind = options.kind.unwrap_or(settings.kind);
+        let webdriver_url = if settings.manage_driver {
+            driver::url(settings, kind)?
+        } else {
+            settings.webdriver_url.clone()
+        };
+        let caps = match kind {
+            BrowserKind::Chrome => chrome_capabilities(options, settings.attach.as_deref())?,
+            BrowserKind::Firefox => firefox_capabilities(options)?,
+        };
+        let driver = block_on(
+            &tokio,
+            async move { WebDriver::new(&webdriver_url, caps).await },
+        )?;
+        Ok(Self {
+            driver: Some(driver),
+            kind,
+            tokio,
+        })
+    }
+
+    fn driver(&self) -> Result<WebDriver, BrowserError> {
+        self.driver.clone().ok_or(BrowserError::Closed)
+    }
+
+    fn element(&self, element: WebElement) -> Box<dyn ElementBackend> {
+        Box::new(WebDriverElement {
+            element,
+            tokio: self.tokio.clone(),
+        })
+    }
+}
+
+impl SessionBackend for WebDriverSession {
+    fn navigate(&self, url: &str, timeout: Duration) -> Result<(), BrowserError> {
+        let driver = self.driver()?;
+        let url = url.to_string();
+        block_on(&self.tokio, async move {
+            tokio::time::timeout(timeout, driver.goto(&url))
+                .await
+                .map_err(|_| BrowserError::Timeout(timeout))?
+                .map_err(BrowserError::from)
+        })
+    }
+
+    fn describe(&self, include_html: bool) -> Result<PageState, BrowserError> {
+        let driver = self.driver()?;
+        let state = block_on(&self.tokio, async move {
+            let url = driver.current_url().await?.to_string();
+            let title = driver.title().await.ok().filter(|title| !title.is_empty());
+            let html = if include_html {
+                Some(driver.source().await?)
+            } else {
+                None
+            };
+            Ok::<_, WebDriverError>(PageState {
+                url,
+                title,
+                html,
+                outline: None,
+            })
+        })?;
+        Ok(state)
+    }
+
+    fn print_pdf(&self, options: &PdfOptions) -> Result<Vec<u8>, BrowserError> {
+        let driver = self.driver()?;
+        let parameters = PrintParameters {
+            orientation: if options.landscape {
+                PrintOrientation::Landscape
+            } else {
+                PrintOrientation::Portrait
+            },
+            background: options.background,
+            ..Default::default()
+        };
+        block_on(
+            &self.tokio,
+            async move { driver.print_page(parameters).await },
+        )
+    }
+
+    /// Chrome captures through its DevTools passthrough, which also gives
+    /// JPEG and full-page; Firefox only offers the png viewport.
+    fn screenshot(&self, options: &ScreenshotOptions) -> Result<Vec<u8>, BrowserError> {
+        let driver = self.driver()?;
+        if self.kind == BrowserKind::Chrome {
+            let options = *options;
+            let result = block_on(&self.tokio, async move {
+                let devtools = ChromeDevTools::new(driver.handle.clone());
+                let metrics = if options.full_page {
+                    Some(devtools.execute_cdp("Page.getLayoutMetrics").await?)
+                } else {
+                    None
+                };
+                devtools
+                    .execute_cdp_with_params(
+                        "Page.captureScreenshot",
+                        options.capture_params(metrics.as_ref()),
+                    )
+                    .await
+            })?;
+            let data = result["data"].as_str().unwrap_or_default();
+            return Base64
+                .decode(data)
+                .map_err(|err| BrowserError::Protocol(format!("invalid screenshot data: {err}")));
+        }
+        if options.kind != ScreenshotKind::Png || options.full_page {
+            return Err(BrowserError::Unsupported(
+                "Firefox over WebDriver only captures png screenshots of the viewport".to_string(),
+            ));
+        }
+        block_on(&self.tokio, async move { driver.screenshot_as_png().await })
+    }
+
+    fn eval(&self, expression: &str) -> Result<Value, BrowserError> {
+        let driver = self.driver()?;
+        let args = vec![Value::String(expression.to_string())];
+        let result = block_on(&self.tokio, async move {
+            driver.execute("return eval(arguments[0]);", args).await
+        })?;
+        Ok(result.json().clone())
+    }
+
+    fn find(
+        &self,
+        selector: &Selector,
+        timeout: Duration,
+    ) -> Result<Box<dyn ElementBackend>, BrowserError> {
+        let driver = self.driver()?;
+        let by = selector.to_by();
+        let element = block_on(&self.tokio, async move {
+            driver.query(by).wait(timeout, POLL_INTERVAL).first().await
+        })?;
+        Ok(self.element(element))
+    }
+
+    fn query_all(&self, selector: &Selector) -> Result<Vec<Box<dyn ElementBackend>>, BrowserError> {
+        let driver = self.driver()?;
+        let by = selector.to_by();
+        let elements = block_on(&self.tokio, async move { driver.find_all(by).await })?;
+        Ok(elements
+            .into_iter()
+            .map(|element| self.element(element))
+            .collect())
+    }
+
+    /// chromedriver passes CDP through, which reaches every site's cookies;
+    /// plain WebDriver only sees the current site's.
+    fn cookies(&self) -> Result<Vec<Value>, BrowserError> {
+        let driver = self.driver()?;
+        if self.kind == BrowserKind::Chrome {
+            let result = block_on(&self.tokio, async move {
+                let devtools = ChromeDevTools::new(driver.handle.clone());
+                devtools.execute_cdp("Network.getAllCookies").await
+            })?;
+            return Ok(result["cookies"].as_array().cloned().unwrap_or_default());
+        }
+        let cookies = block_on(&self.tokio, async move { driver.get_all_cookies().await })?;
+        Ok(cookies
+            .into_iter()
+            .map(|cookie| {
+                serde_json::json!({
+                    "name": cookie.name,
+                    "value": cookie.value,
+                    "domain": cookie.domain,
+                    "path": cookie.path,
+                    "secure": cookie.secure.unwrap_or(false),
+                    "expires": cookie.expiry.unwrap_or(-1),
+                    "sameSite": cookie.same_site,
+                })
+            })
+            .collect())
+    }
+
+    /// Without CDP, cookies can only be added for the current site, so
+    /// others are skipped.
+    fn set_cookies(&self, cookies: &[Value]) -> Result<(), BrowserError> {
+        let driver = self.driver()?;
+        if self.kind == BrowserKind::Chrome {
+            let params = serde_json::json!({ "cookies": cookie_params(cookies) });
+            block_on(&self.tokio, async move {
+                let devtools = ChromeDevTools::new(driver.handle.clone());
+                devtools
+                    .execute_cdp_with_params("Network.setCookies", params)
+                    .await
+            })?;
+            return Ok(());
+        }
+        let cookies: Vec<Cookie> = cookie_params(cookies)
+            .into_iter()
+            .filter_map(|mut cookie| {
+                let expires = cookie.as_object_mut()?.remove("expires");
+                cookie["expiry"] = expires
+                    .and_then(|e| e.as_f64())
+                    .map_or(Value::Null, |e| Value::from(e as i64));
+                serde_json::from_value(cookie).ok()
+            })
+            .collect();
+        block_on(&self.tokio, async move {
+            let host = driver
+                .current_url()
+                .await?
+                .host_str()
+                .unwrap_or_default()
+                .to_string();
+            for cookie in cookies {
+                let domain = cookie.domain.as_deref().unwrap_or_default();
+                let domain = domain.trim_start_matches('.');
+                if host == domain || host.ends_with(&format!(".{domain}")) {
+                    driver.add_cookie(cookie).await?;
+                }
+            }
+            Ok::<_, WebDriverError>(())
+        })?;
+        Ok(())
+    }
+
+    /// WebDriver only reports a window's url and title while it is current,
+    /// so each one is visited in turn before switching back.
+    fn tabs(&self) -> Result<Vec<TabInfo>, BrowserError> {
+        let driver = self.driver()?;
+        block_on(&self.tokio, async move {
+            // The current window may have been closed by the page.
+            let current = driver.window().await.ok();
+            let mut tabs = Vec::new();
+            for handle in driver.windows().await? {
+                driver.switch_to_window(handle.clone()).await?;
+                let title = driver.title().await.ok().filter(|title| !title.is_empty());
+                tabs.push(TabInfo {
+                    id: handle.to_string(),
+                    url: driver.current_url().await?.to_string(),
+                    title,
+                    active: current.as_ref() == Some(&handle),
+                });
+            }
+            if let Some(current) = current {
+                driver.switch_to_window(current).await?;
+            }
+            Ok::<_, WebDriverError>(tabs)
+        })
+    }
+
+    fn new_tab(&mut self) -> Result<String, BrowserError> {
+        let driver = self.driver()?;
+        block_on(&self.tokio, async move {
+            let handle = driver.new_tab().await?;
+            driver.switch_to_window(handle.clone()).await?;
+            Ok::<_, WebDriverError>(handle.to_string())
+        })
+    }
+
+    fn switch_tab(&mut self, id: &str) -> Result<(), BrowserError> {
+        let driver = self.driver()?;
+        let id = id.to_string();
+        block_on(&self.tokio, async move {
+            let handle = WindowHandle::from(id.clone());
+            if !driver.windows().await?.contains(&handle) {
+                return Err(BrowserError::NoSuchTab(id));
+            }
+            driver.switch_to_window(handle).await?;
+            Ok(())
+        })
+    }
+
+    fn enter_frame(&mut self, target: &FrameTarget) -> Result<(), BrowserError> {
+        let driver = self.driver()?;
+        let target = target.clone();
+        block_on(&self.tokio, async move {
+            match &target {
+                FrameTarget::Selector(selector) => {
+                    let found = driver.find_all(selector.to_by()).await?;
+                    let frame = found
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| BrowserError::NotFound(target.to_string()))?;
+                    frame.enter_frame().await?;
+                }
+                FrameTarget::Index(index) => driver.enter_frame(*index).await?,
+            }
+            Ok::<_, BrowserError>(())
+        })
+    }
+
+    fn exit_frame(&mut self) -> Result<(), BrowserError> {
+        let driver = self.driver()?;
+        block_on(
+            &self.tokio,
+            async move { driver.enter_parent_frame().await },
+        )
+    }
+
+    /// chromedriver leaves a browser it attached to running when the session
+    /// quits, so this never closes the operator's browser.
+    fn close(&mut self) {
+        if let Some(driver) = self.driver.take() {
+            let _ = block_on(&self.tokio, async move { driver.quit().await });
+        }
+    }
+}
+
+struct WebDriverElement {
+    element: WebElement,
+    tokio: Handle,
+}
+
+impl ElementBackend for WebDriverElement {
+    fn click(&self) -> Result<(), BrowserError> {
+        let element = self.element.clone();
+        block_on(&self.tokio, async move {
+            element.scroll_into_view().await?;
+            element.click().await
+        })
+    }
+
+    fn type_text(&self, text: &str, submit: bool) -> Result<(), BrowserError> {
+        let element = self.element.clone();
+        let text = text.to_string();
+        block_on(&self.tokio, async move {
+            element.send_keys(text).await?;
+            if submit {
+                element.send_keys(Key::Enter).await?;
+            }
+            Ok::<_, WebDriverError>(())
+        })
+    }
+
+    fn clear(&self) -> Result<(), BrowserError> {
+        let element = self.element.clone();
+        block_on(&self.tokio, async move { element.clear().await })
+    }
+
+    fn attribute(&self, name: &str) -> Result<Option<String>, BrowserError> {
+        let element = self.element.clone();
+        let name = name.to_string();
+        block_on(&self.tokio, async move { element.attr(name).await })
+    }
+
+    fn inner_text(&self) -> Result<String, BrowserError> {
+        let element = self.element.clone();
+        block_on(&self.tokio, async move { element.text().await })
+    }
+
+    fn html(&self) -> Result<String, BrowserError> {
+        let element = self.element.clone();
+        block_on(&self.tokio, async move { element.outer_html().await })
+    }
+
+    fn screenshot(&self) -> Result<Vec<u8>, BrowserError> {
+        let element = self.element.clone();
+        block_on(&self.tokio, async move {
+            element.scroll_into_view().await?;
+            element.screenshot_as_png().await
+        })
+    }
+
+    fn call(&self, function: &str, args: &[Value]) -> Result<Value, BrowserError> {
+        let element = self.element.clone();
+        let script =
+            format!("return ({function}).apply(arguments[0], Array.from(arguments).slice(1));");
+        let args = args.to_vec();
+        let result = block_on(&self.tokio, async move {
+            let handle = element.handle.clone();
+            let mut arguments = vec![element.to_json()?];
+            arguments.extend(args);
+            handle.execute(&script, arguments).await
+        })?;
+        Ok(result.json().clone())
+    }
+}
+
+/// With `attach`, chromedriver connects to that debugging address instead of
+/// launching Chrome, and launch flags would not apply.
+fn chrome_capabilities(
+    options: &SessionOptions,
+    attach: Option<&str>,
+) -> Result<Capabilities, BrowserError> {
+    let mut caps = DesiredCapabilities::chrome();
+    if let Some(address) = attach {
+        caps.set_debugger_address(address)?;
+        return Ok(caps.into());
+    }
+    if options.headless {
+        caps.add_arg("--headless=new")?;
+        caps.add_arg("--disable-gpu")?;
+    }
+    caps.add_arg("--disable-dev-shm-usage")?;
+    caps.add_arg("--no-sandbox")?;
+    if options.allow_downloads {
+        let prefs = serde_json::json!({
+            "download.prompt_for_download": false,
+        });
+        caps.add_experimental_option("prefs", prefs)?;
+    }
+    Ok(caps.into())
+}
+
+fn firefox_capabilities(options: &SessionOptions) -> Result<Capabilities, BrowserError> {
+    let mut caps = DesiredCapabilities::firefox();
+    if options.headless {
+        caps.set_headless()?;
+    }
+    if let Some(dir) = &options.download_dir {
+        let mut prefs = FirefoxPreferences::new();
+        // 2 means "use browser.download.dir".
+        prefs.set("browser.download.folderList", 2)?;
+        prefs.set("browser.download.dir", dir.as_str())?;
+        prefs.set("browser.download.useDownloadDir", true)?;
+        prefs.set(
+            "browser.download.always_ask_before_handling_new_types",
+            false,
+        )?;
+        prefs.set(
+            "browser.helperApps.neverAsk.saveToDisk",
+            "application/octet-stream,application/pdf,application/zip,text/csv,text/plain",
+        )?;
+        prefs.set("pdfjs.disabled", true)?;
+        caps.set_preferences(prefs)?;
+    }
+    Ok(caps.into())
+}
+
+/// The session's download directory and the files already accounted for.
+struct Downloads {
+    dir: Utf8PathBuf,
+    seen: BTreeSet<String>,
+}
+
+impl Downloads {
+    fn new(dir: Utf8PathBuf) -> Result<Self, BrowserError> {
+        fs::create_dir_all(&dir)?;
+        let seen = Self::list(&dir)?.into_keys().collect();
+        Ok(Self { dir, seen })
+    }
+
+    fn list(dir: &Utf8Path) -> Result<BTreeMap<String, u64>, BrowserError> {
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.insert(name, metadata.len());
+            }
+        }
+        Ok(files)
+    }
+
+    /// A file counts as finished once no partial-download file for it
+    /// remains and its size held steady across one poll.
+    fn wait(&mut self, timeout: Duration) -> Result<Utf8PathBuf, BrowserError> {
+        let deadline = Instant::now() + timeout;
+        let mut previous = BTreeMap::new();
+        loop {
+            let files = Self::list(&self.dir)?;
+            let finished = files.iter().find(|&(name, size)| {
+                !self.seen.contains(name)
+                    && !PARTIAL_SUFFIXES.iter().any(|suffix| {
+                        name.ends_with(suffix) || files.contains_key(&format!("{name}{suffix}"))
+                    })
+                    && previous.get(name) == Some(size)
+            });
+            if let Some((name, _)) = finished {
+                self.seen.insert(name.clone());
+                return Ok(self.dir.join(name));
+            }
+            if Instant::now() >= deadline {
+                return Err(BrowserError::Timeout(timeout));
+            }
+            previous = files;
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Quotes `value` as an XPath 1.0 string literal, which has no escapes.
+/// A profile's saved state, as `save_state` writes it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProfileState {
+    #[serde(default)]
+    cookies: Vec<Value>,
+    /// localStorage items by origin.
+    #[serde(default)]
+    local_storage: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ProfileState {
+    fn read(path: &Utf8Path) -> Result<Option<Self>, BrowserError> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let state = serde_json::from_slice(&data).map_err(io::Error::from)?;
+        Ok(Some(state))
+    }
+
+    /// Replaces the file at `path`. It holds login cookies, so on unix only
+    /// the host user can read it.
+    fn write(&self, path: &Utf8Path) -> Result<(), BrowserError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        let tmp = path.with_extension("json.tmp");
+        let mut open = fs::OpenOptions::new();
+        open.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+        io::Write::write_all(&mut open.open(&tmp)?, &data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct OriginStorage {
+    origin: String,
+    items: BTreeMap<String, String>,
+}
+
+/// `<workspace>/har/<unix-millis>-<n>.har`, where `n` counts the sessions
+/// this host has opened, so sessions opened together get their own files.
+fn har_path(workspace: &Utf8Path) -> Utf8PathBuf {
+    static NEXT_HAR: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let n = NEXT_HAR.fetch_add(1, Ordering::Relaxed);
+    workspace.join(HAR_DIR).join(format!("{millis}-{n}.har"))
+}
+
+/// `<profile_dir>/<profile>.json`, where `profile_dir` defaults to
+/// `<workspace>/.browser-profiles`.
+fn profile_state_path(
+    settings: &BrowserSettings,
+    workspace: &Utf8Path,
+    profile: &str,
+) -> Result<Utf8PathBuf, BrowserError> {
+    let valid = !profile.is_empty()
+        && !profile.starts_with('.')
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(BrowserError::Unsupported(format!(
+            "invalid profile name {profile:?}"
+        )));
+    }
+    let dir = match &settings.profile_dir {
+        Some(dir) => dir.clone(),
+        None => workspace.join(PROFILE_STATE_DIR),
+    };
+    Ok(dir.join(format!("{profile}.json")))
+}
+
+/// Narrows saved cookies to the fields of CDP's `Network.CookieParam`,
+/// leaving session cookies without an expiry.
+pub(crate) fn cookie_params(cookies: &[Value]) -> Vec<Value> {
+    const FIELDS: &[&str] = &[
+        "name", "value", "domain", "path", "secure", "httpOnly", "sameSite",
+    ];
+    cookies
+        .iter()
+        .filter_map(|cookie| {
+            let mut param: serde_json::Map<String, Value> = FIELDS
+                .iter()
+                .filter_map(|field| {
+                    let value = cookie.get(*field).filter(|value| !value.is_null())?;
+                    Some((field.to_string(), value.clone()))
+                })
+                .collect();
+            if let Some(expires) = cookie["expires"].as_f64().filter(|e| *e > 0.0) {
+                param.insert("expires".to_string(), Value::from(expires));
+            }
+            (param.contains_key("name") && param.contains_key("value"))
+                .then_some(Value::Object(param))
+        })
+        .collect()
+}
+
+fn xpath_literal(value: &str) -> String {
+    if !value.contains('"') {
+        return format!("\"{value}\"");
+    }
+    if !value.contains('\'') {
+        return format!("'{value}'");
+    }
+    let parts: Vec<String> = value.split('"').map(|part| format!("\"{part}\"")).collect();
+    format!("concat({})", parts.join(", '\"', "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(domains: &[&str]) -> DomainAllowlist {
+        DomainAllowlist::new(domains.iter().map(|domain| domain.to_string()))
+    }
+
+    #[test]
+    fn allows_listed_domain_and_subdomains() {
+        let list = allowlist(&["example.com"]);
+        assert!(list.allows("https://example.com/path"));
+        assert!(list.allows("http://docs.example.com/"));
+        assert!(list.allows("wss://a.b.example.com/socket"));
+        assert!(list.allows("https://EXAMPLE.com./"));
+    }
+
+    #[test]
+    fn denies_domain_sharing_a_suffix() {
+        let list = allowlist(&["example.com"]);
+        assert!(!list.allows("https://evilexample.com/"));
+        assert!(!list.allows("https://example.com.evil.net/"));
+        assert!(!list.allows("https://example.com@evil.net/"));
+    }
+
+    #[test]
+    fn normalizes_configured_domains() {
+        let list = allowlist(&[" *.Example.COM. ", ""]);
+        assert_eq!(list.domains(), ["example.com"]);
+        assert!(list.allows("https://www.example.com/"));
+    }
+
+    #[test]
+    fn allows_local_schemes() {
+        let list = allowlist(&[]);
+        assert!(list.allows("about:blank"));
+        assert!(list.allows("data:text/html,hi"));
+        assert!(list.allows("blob:https://example.com/0b8e"));
+    }
+
+    #[test]
+    fn denies_other_schemes_and_garbage() {
+        let list = allowlist(&["example.com"]);
+        assert!(!list.allows("file:///etc/passwd"));
+        assert!(!list.allows("ftp://example.com/"));
+        assert!(!list.allows("javascript:alert(1)"));
+        assert!(!list.allows("not a url"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What a task's `[policy.budgets]` limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BudgetKind {
+    /// Planner steps the host drives.
+    Steps,
+    /// Time since the task started.
+    WallclockMs,
+    /// Prompt plus completion tokens across every llm call.
+    LlmTokens,
+    /// Children spawned, shell sessions included.
+    Processes,
+    /// Time children ran, counted once they exit.
+    ProcessMs,
+    FilesystemOps,
+    /// Bytes written into the workspace by file handles and archives.
+    BytesWritten,
+    BrowserActions,
+}
+
+impl BudgetKind {
+    pub const ALL: [BudgetKind; 8] = [
+        BudgetKind::Steps,
+        BudgetKind::WallclockMs,
+        BudgetKind::LlmTokens,
+        BudgetKind::Processes,
+        BudgetKind::ProcessMs,
+        BudgetKind::FilesystemOps,
+        BudgetKind::BytesWritten,
+        BudgetKind::BrowserActions,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BudgetKind::Steps => "steps",
+            BudgetKind::WallclockMs => "wallclock_ms",
+            BudgetKind::LlmTokens => "llm_tokens",
+            BudgetKind::Processes => "processes",
+            BudgetKind::ProcessMs => "process_ms",
+            BudgetKind::FilesystemOps => "filesystem_ops",
+            BudgetKind::BytesWritten => "bytes_written",
+            BudgetKind::BrowserActions => "browser_actions",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetExceeded {
+    pub kind: BudgetKind,
+    pub limit: u64,
+    pub used: u64,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the task has used its {} budget of {} ({} spent)",
+            self.kind.name(),
+            self.limit,
+            self.used
+        )
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// Per-task counters, shared by the guest's capability calls and the
+/// planner's actions so both draw on the same limits.
+#[derive(Debug)]
+pub struct Budgets {
+    limits: Mutex<BTreeMap<BudgetKind, u64>>,
+    started: Instant,
+    used: Mutex<BTreeMap<BudgetKind, u64>>,
+}
+
+impl Budgets {
+    pub fn new(limits: BTreeMap<BudgetKind, u64>) -> Self {
+        Self {
+            limits: Mutex::new(limits),
+            started: Instant::now(),
+            used: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Continues from what an earlier invocation of the same session used,
+    /// wall-clock time included.
+    pub fn resume(limits: BTreeMap<BudgetKind, u64>, used: BTreeMap<BudgetKind, u64>) -> Self {
+        let elapsed = Duration::from_millis(
+            used.get(&BudgetKind::WallclockMs)
+                .copied()
+                .unwrap_or_default(),
+        );
+        let now = Instant::now();
+        Self {
+            limits: Mutex::new(limits),
+            started: now.checked_sub(elapsed).unwrap_or(now),
+            used: Mutex::new(used),
+        }
+    }
+
+    pub fn limit(&self, kind: BudgetKind) -> Option<u64> {
+        let limits = self
+            .limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        limits.get(&kind).copied()
+    }
+
+    /// Replaces the limits, e.g. after a policy reload; what was already
+    /// spent still counts against the new ones.
+    pub fn set_limits(&self, limits: BTreeMap<BudgetKind, u64>) {
+        *self
+            .limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = limits;
+    }
+
+    /// Wall-clock time is measured rather than spent, so it only grows.
+    pub fn used(&self, kind: BudgetKind) -> u64 {
+        if kind == BudgetKind::WallclockMs {
+            return self.started.elapsed().as_millis() as u64;
+        }
+        let used = self
+            .used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        used.get(&kind).copied().unwrap_or_default()
+    }
+
+    pub fn remaining(&self, kind: BudgetKind) -> Option<u64> {
+        self.limit(kind)
+            .map(|limit| limit.saturating_sub(self.used(kind)))
+    }
+
+    /// Spends `units` up front, refusing (and spending nothing) when that
+    /// would go over the limit.
+    pub fn claim(&self, kind: BudgetKind, units: u64) -> Result<u64, BudgetExceeded> {
+        if kind == BudgetKind::WallclockMs {
+            self.check(kind)?;
+            return Ok(self.used(kind));
+        }
+        let mut used = self
+            .used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let spent = used.entry(kind).or_default();
+        let total = spent.saturating_add(units);
+        if let Some(limit) = self.limit(kind).filter(|limit| total > *limit) {
+            return Err(BudgetExceeded {
+                kind,
+                limit,
+                used: *spent,
+            });
+        }
+        *spent = total;
+        Ok(total)
+    }
+
+    /// Adds what an operation already spent, e.g. the tokens of a reply;
+    /// the next `claim` or `check` refuses once that went over.
+    pub fn record(&self, kind: BudgetKind, units: u64) -> u64 {
+        let mut used = self
+            .used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let spent = used.entry(kind).or_default();
+        *spent = spent.saturating_add(units);
+        *spent
+    }
+
+    /// Fails once nothing is left of the budget for `kind`.
+    pub fn check(&self, kind: BudgetKind) -> Result<(), BudgetExceeded> {
+        let Some(limit) = self.limit(kind) else {
+            return Ok(());
+        };
+        let used = self.used(kind);
+        if used >= limit {
+            return Err(BudgetExceeded { kind, limit, used });
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,43 @@
 use std::fs::{self, OpenOptions};
-use std::io::{Read, Write};
-use std::path::{Component, Path};
-use std::process::{Command, Stdio};
-use std::time::{Duration, UNIX_EPOCH};
+use std::io::{Read, Seek, Write};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use camino::{Utf8Path, Utf8PathBuf};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use ureq::Agent;
+use serde_json::{Value, json};
+use thirtyfour::prelude::WebDriverError;
+use tracing::debug;
 use wasmtime::component::{Resource, ResourceTableError};
 
+use crate::archive::{self, ArchiveError, ArchiveSummary, ExtractLimits};
+use crate::audit;
 use crate::bindings;
+use crate::browser::{
+    self, BrowserError, BrowserKind, BrowserSession, FrameTarget, OptionChoice, PdfOptions,
+    ScreenshotKind, ScreenshotOptions, Selector, SelectorKind, WaitCondition,
+};
+use crate::budget::{BudgetExceeded, BudgetKind, Budgets};
+use crate::cgroup::Cgroup;
+use crate::changes::ChangeKind;
 use crate::config::{HostConfig, LlmSettings};
-use crate::resources::{DirHandleResource, FileHandleResource, ProcessResource};
+use crate::encoding::{self, TextEncoding};
+use crate::executor::{self, CommandSpec, Prepared};
+use crate::grant::{self, GrantDecision, Grants};
+use crate::input::{Desktop, InputError, Modifier, MouseButton};
+use crate::llm::{self, CompletionStream, LlmError};
+use crate::notify;
+use crate::policy::{Access, Denial};
+use crate::process::{self, Priority, ProcessInfo, Pty, Signal, Watchdog};
+use crate::resources::{
+    DirHandleResource, FileHandleResource, OutputBuffer, ProcessResource, StdinWriter,
+};
+use crate::screen::{self, ScreenError};
+use crate::shell::{ExecOutput, ShellError, ShellSession};
 use crate::state::HostState;
+use crate::taint::TaintMode;
+use crate::workspace::{self, PathError};
 use bindings::osagent::llm::llm::Role as MessageRole;
 
 type CapabilityError = bindings::osagent::common::types::CapabilityError;
@@ -21,6 +45,14 @@ type CapabilityErrorCode = bindings::osagent::common::types::CapabilityErrorCode
 type DirHandle = bindings::osagent::fs::fs::DirHandle;
 type FileHandle = bindings::osagent::fs::fs::FileHandle;
 type ProcHandle = bindings::osagent::proc::proc::Process;
+type ShellHandle = bindings::osagent::shell::shell::Session;
+type BrowserHandle = bindings::osagent::browser::browser::Session;
+type ElementHandle = bindings::osagent::browser::browser::ElementHandle;
+type CompletionStreamHandle = bindings::osagent::llm::llm::CompletionStream;
+
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_TERMINAL_ROWS: u16 = 24;
+const DEFAULT_TERMINAL_COLS: u16 = 80;
 
 fn capability_error(code: CapabilityErrorCode, message: impl Into<String>) -> CapabilityError {
     CapabilityError {
@@ -45,130 +77,96 @@ fn table_error(err: ResourceTableError) -> CapabilityError {
     }
 }
 
-fn require_llm_settings<'a>(config: &'a HostConfig) -> Result<&'a LlmSettings, CapabilityError> {
-    config.llm.as_ref().ok_or_else(|| {
-        capability_error(
-            CapabilityErrorCode::Unavailable,
-            "llm capability is not configured",
-        )
-    })
-}
-
-fn http_agent() -> Agent {
-    ureq::AgentBuilder::new()
-        .timeout(Duration::from_secs(60))
-        .build()
-}
-
-fn chat_endpoint(base: &str) -> String {
-    format!("{}/chat/completions", base.trim_end_matches('/'))
-}
-
-#[derive(Serialize)]
-struct ChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    tools: Option<Vec<ChatTool>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    tool_choice: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    presence_penalty: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    frequency_penalty: Option<f32>,
-}
-
-#[derive(Serialize)]
-struct ChatMessage {
-    role: String,
-    content: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
-}
-
-#[derive(Serialize)]
-struct ChatTool {
-    #[serde(rename = "type")]
-    kind: &'static str,
-    function: ChatToolFunction,
-}
-
-#[derive(Serialize)]
-struct ChatToolFunction {
-    name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    description: Option<String>,
-    parameters: Value,
-}
-
-#[derive(Deserialize)]
-struct ChatResponse {
-    choices: Vec<ChatChoice>,
-    usage: Option<ChatUsage>,
+/// Refuses calls into an interface `enabled_capabilities` leaves out.
+fn ensure_capability_enabled(config: &HostConfig, capability: &str) -> Result<(), CapabilityError> {
+    if config.is_capability_enabled(capability) {
+        return Ok(());
+    }
+    Err(setting_error(
+        format!("the {capability} capability is not in enabled_capabilities"),
+        "enabled_capabilities",
+    ))
 }
 
-#[derive(Deserialize)]
-struct ChatChoice {
-    message: ChatChoiceMessage,
-    #[serde(rename = "finish_reason")]
-    finish_reason: Option<String>,
+/// A `denied` error for a capability a config setting turns off.
+fn setting_error(message: impl Into<String>, setting: &str) -> CapabilityError {
+    CapabilityError {
+        code: CapabilityErrorCode::Denied,
+        message: message.into(),
+        detail: Some(json!({ "rule": setting }).to_string()),
+    }
 }
 
-#[derive(Deserialize)]
-struct ChatChoiceMessage {
-    content: Option<String>,
-    #[serde(default)]
-    tool_calls: Vec<ToolCall>,
+/// A `denied` error whose `detail` names the policy rule behind it, as
+/// `{"rule": ..., "pattern": ...}`.
+fn denial_error(message: String, denial: &Denial) -> CapabilityError {
+    CapabilityError {
+        code: CapabilityErrorCode::Denied,
+        message,
+        detail: Some(denial_detail(denial)),
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct ToolCall {
-    #[serde(rename = "type")]
-    kind: Option<String>,
-    id: Option<String>,
-    function: FunctionCall,
+fn denial_detail(denial: &Denial) -> String {
+    json!({ "rule": denial.rule, "pattern": denial.pattern }).to_string()
 }
 
-#[derive(Deserialize, Serialize)]
-struct FunctionCall {
-    name: String,
-    arguments: String,
+/// Counts the call against `[policy.rate_limits]`; a refusal says when to
+/// retry in its detail.
+fn admit_call(state: &HostState, interface: &str, function: &str) -> Result<(), CapabilityError> {
+    state
+        .rate_limits
+        .admit(interface, function)
+        .map_err(|limited| CapabilityError {
+            code: CapabilityErrorCode::Limit,
+            message: limited.to_string(),
+            detail: Some(
+                json!({
+                    "rule": limited.rule,
+                    "per_minute": limited.per_minute,
+                    "retry_after_ms": limited.retry_after.as_millis() as u64,
+                })
+                .to_string(),
+            ),
+        })
 }
 
-#[derive(Deserialize)]
-struct ChatUsage {
-    prompt_tokens: Option<u32>,
-    completion_tokens: Option<u32>,
-    total_tokens: Option<u32>,
+fn require_llm_settings(config: &HostConfig) -> Result<&LlmSettings, CapabilityError> {
+    config.llm.as_ref().ok_or_else(|| {
+        capability_error(
+            CapabilityErrorCode::Unavailable,
+            "llm capability is not configured",
+        )
+    })
 }
 
-fn convert_usage(usage: Option<ChatUsage>) -> Option<bindings::osagent::llm::llm::TokenUsage> {
-    usage.map(|u| bindings::osagent::llm::llm::TokenUsage {
-        prompt_tokens: u.prompt_tokens.unwrap_or_default(),
-        completion_tokens: u.completion_tokens.unwrap_or_default(),
-        total_tokens: u.total_tokens.unwrap_or_default(),
+/// The profile a request names, or the top-level `[llm]` settings.
+fn llm_profile<'a>(
+    settings: &'a LlmSettings,
+    profile: Option<&str>,
+) -> Result<&'a LlmSettings, CapabilityError> {
+    let Some(name) = profile else {
+        return Ok(settings);
+    };
+    settings.profiles.get(name).ok_or_else(|| {
+        capability_error(
+            CapabilityErrorCode::NotFound,
+            format!("no llm profile named `{name}`"),
+        )
     })
 }
 
-fn messages_to_chat(
+fn to_llm_messages(
     messages: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::Message>,
-) -> Vec<ChatMessage> {
+) -> Vec<llm::Message> {
     messages
         .into_iter()
-        .map(|msg| ChatMessage {
+        .map(|msg| llm::Message {
             role: match msg.role {
-                MessageRole::System => "system".to_string(),
-                MessageRole::User => "user".to_string(),
-                MessageRole::Assistant => "assistant".to_string(),
-                MessageRole::Tool => "tool".to_string(),
+                MessageRole::System => llm::Role::System,
+                MessageRole::User => llm::Role::User,
+                MessageRole::Assistant => llm::Role::Assistant,
+                MessageRole::Tool => llm::Role::Tool,
             },
             content: msg.content,
             name: msg.name.filter(|n| !n.trim().is_empty()),
@@ -176,9 +174,9 @@ fn messages_to_chat(
         .collect()
 }
 
-fn tools_to_chat(
+fn to_llm_tools(
     tools: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::ToolSchema>,
-) -> Result<Vec<ChatTool>, CapabilityError> {
+) -> Result<Vec<llm::Tool>, CapabilityError> {
     let mut result = Vec::with_capacity(tools.len());
     for tool in tools {
         let parameters: Value = serde_json::from_str(&tool.schema_json).map_err(|_| {
@@ -187,82 +185,88 @@ fn tools_to_chat(
                 format!("invalid tool schema for {}", tool.name),
             )
         })?;
-        result.push(ChatTool {
-            kind: "function",
-            function: ChatToolFunction {
-                name: tool.name,
-                description: Some(tool.description),
-                parameters,
-            },
+        result.push(llm::Tool {
+            name: tool.name,
+            description: tool.description,
+            parameters,
         });
     }
     Ok(result)
 }
 
-fn build_chat_request(
-    model: &str,
-    messages: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::Message>,
+fn to_llm_options(
     options: bindings::osagent::llm::llm::Options,
-    tools: Option<Vec<ChatTool>>,
-) -> ChatRequest {
-    let stop = if options.stop.is_empty() {
-        None
-    } else {
-        Some(options.stop)
+) -> Result<llm::Options, CapabilityError> {
+    let json_schema = match &options.json_schema {
+        Some(schema) => Some(serde_json::from_str(schema).map_err(|_| {
+            capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                "invalid response json schema",
+            )
+        })?),
+        None => None,
     };
-    ChatRequest {
-        model: model.to_string(),
-        messages: messages_to_chat(messages),
-        tool_choice: tools.as_ref().map(|_| "auto".to_string()),
-        tools,
+    Ok(llm::Options {
         max_tokens: options.max_tokens,
         temperature: options.temperature,
         top_p: options.top_p,
-        stop,
+        stop: options.stop,
         presence_penalty: options.presence_penalty,
         frequency_penalty: options.frequency_penalty,
-    }
+        json: options.json,
+        json_schema,
+    })
 }
 
-fn execute_chat_request(
-    settings: &LlmSettings,
-    body: &ChatRequest,
-) -> Result<ChatResponse, CapabilityError> {
-    let url = chat_endpoint(&settings.api_base);
-    let agent = http_agent();
-    let token = format!("Bearer {}", settings.api_key);
-    let payload = serde_json::to_value(body).map_err(|err| {
-        capability_error(
-            CapabilityErrorCode::Internal,
-            format!("failed to encode llm request: {err}"),
-        )
-    })?;
-    let response = agent
-        .post(&url)
-        .set("Authorization", &token)
-        .set("Content-Type", "application/json")
-        .send_json(payload);
-    let response = response.map_err(|err| match err {
-        ureq::Error::Status(code, resp) => {
-            let body = resp.into_string().unwrap_or_default();
-            capability_error(
-                CapabilityErrorCode::Unavailable,
-                format!("llm error {code}: {body}"),
-            )
-        }
-        ureq::Error::Transport(tr) => capability_error(
-            CapabilityErrorCode::Unavailable,
-            format!("llm transport error: {tr}"),
-        ),
-    })?;
-    response.into_json().map_err(|err| {
-        capability_error(
-            CapabilityErrorCode::Internal,
-            format!("failed to parse llm response: {err}"),
-        )
+/// Refuses a request once the task has spent its `llm_tokens` budget, and
+/// caps the reply at what is left of it.
+fn admit_llm_request(budgets: &Budgets, options: &mut llm::Options) -> Result<(), CapabilityError> {
+    budgets.check(BudgetKind::LlmTokens).map_err(budget_error)?;
+    let Some(left) = budgets.remaining(BudgetKind::LlmTokens) else {
+        return Ok(());
+    };
+    let left = u32::try_from(left).unwrap_or(u32::MAX);
+    options.max_tokens = Some(options.max_tokens.map_or(left, |max| max.min(left)));
+    Ok(())
+}
+
+fn record_llm_usage(state: &HostState, tokens: u64) {
+    let total = state.budgets.record(BudgetKind::LlmTokens, tokens);
+    let budget = state.budgets.limit(BudgetKind::LlmTokens);
+    debug!(tokens, total, ?budget, "llm tokens used");
+}
+
+fn budget_error(err: BudgetExceeded) -> CapabilityError {
+    capability_error(CapabilityErrorCode::Limit, err.to_string())
+}
+
+fn to_wit_usage(usage: Option<llm::Usage>) -> Option<bindings::osagent::llm::llm::TokenUsage> {
+    usage.map(|u| bindings::osagent::llm::llm::TokenUsage {
+        prompt_tokens: u.prompt_tokens,
+        completion_tokens: u.completion_tokens,
+        total_tokens: u.total_tokens,
     })
 }
 
+/// Maps a provider's HTTP status to the capability error a guest can act
+/// on: fix the request, back off, or give up.
+fn llm_error(op: &str, err: LlmError) -> CapabilityError {
+    let code = match &err {
+        LlmError::Status { status, .. } => match status {
+            401 | 403 => CapabilityErrorCode::Denied,
+            404 => CapabilityErrorCode::NotFound,
+            400 | 413 | 422 => CapabilityErrorCode::InvalidArgument,
+            _ => CapabilityErrorCode::Unavailable,
+        },
+        LlmError::Transport(_) | LlmError::Stream(_) => CapabilityErrorCode::Unavailable,
+        LlmError::Unsupported(_) => CapabilityErrorCode::InvalidArgument,
+        LlmError::Encode(_) | LlmError::Decode(_) | LlmError::Script(_) => {
+            CapabilityErrorCode::Internal
+        }
+    };
+    capability_error(code, format!("{op} failed: {err}"))
+}
+
 fn io_error(op: &str, err: std::io::Error) -> CapabilityError {
     let code = match err.kind() {
         std::io::ErrorKind::NotFound => CapabilityErrorCode::NotFound,
@@ -272,37 +276,53 @@ fn io_error(op: &str, err: std::io::Error) -> CapabilityError {
     capability_error(code, format!("{op} failed: {err}"))
 }
 
-fn resolve_child(parent: &Utf8Path, relative: &str) -> Result<Utf8PathBuf, CapabilityError> {
-    let rel_path = Path::new(relative);
-    if rel_path.is_absolute() {
-        return Err(capability_error(
-            CapabilityErrorCode::InvalidArgument,
-            "absolute paths are not allowed",
-        ));
-    }
+fn shell_error(op: &str, err: ShellError) -> CapabilityError {
+    let code = match err {
+        ShellError::Io(err) => return io_error(op, err),
+        ShellError::Exited => CapabilityErrorCode::Conflict,
+        ShellError::Timeout(_) => CapabilityErrorCode::Timeout,
+    };
+    capability_error(code, format!("{op} failed: {err}"))
+}
 
-    let mut result = parent.as_std_path().to_path_buf();
-    for component in rel_path.components() {
-        match component {
-            Component::Prefix(_) | Component::RootDir => {
-                return Err(capability_error(
-                    CapabilityErrorCode::InvalidArgument,
-                    "absolute paths are not allowed",
-                ));
-            }
-            Component::CurDir => {}
-            Component::ParentDir => {
-                return Err(capability_error(
-                    CapabilityErrorCode::InvalidArgument,
-                    "parent segments are not allowed",
-                ));
-            }
-            Component::Normal(seg) => result.push(seg),
+fn archive_error(op: &str, err: ArchiveError) -> CapabilityError {
+    let code = match err {
+        ArchiveError::Io(err) => return io_error(op, err),
+        ArchiveError::UnsafeEntry(_) => CapabilityErrorCode::Denied,
+        ArchiveError::Limit(_) => CapabilityErrorCode::Limit,
+        ArchiveError::UnsupportedFormat(_) | ArchiveError::Zip(_) => {
+            CapabilityErrorCode::InvalidArgument
         }
+    };
+    capability_error(code, format!("{op} failed: {err}"))
+}
+
+fn to_archive_summary(
+    summary: ArchiveSummary,
+) -> bindings::osagent::archive::archive::ArchiveSummary {
+    bindings::osagent::archive::archive::ArchiveSummary {
+        entries: summary.entries,
+        bytes: summary.bytes,
     }
+}
+
+fn path_error(err: PathError) -> CapabilityError {
+    let code = match &err {
+        PathError::Absolute | PathError::ParentSegment | PathError::NotUtf8 => {
+            CapabilityErrorCode::InvalidArgument
+        }
+        PathError::Escapes(_) | PathError::DanglingSymlink(_) => CapabilityErrorCode::Denied,
+        PathError::Resolve { source, .. } => match source.kind() {
+            std::io::ErrorKind::NotFound => CapabilityErrorCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => CapabilityErrorCode::Denied,
+            _ => CapabilityErrorCode::Internal,
+        },
+    };
+    capability_error(code, err.to_string())
+}
 
-    Utf8PathBuf::from_path_buf(result)
-        .map_err(|_| capability_error(CapabilityErrorCode::InvalidArgument, "path is not UTF-8"))
+fn resolve_child(parent: &Utf8Path, relative: &str) -> Result<Utf8PathBuf, CapabilityError> {
+    workspace::join_relative(parent, relative).map_err(path_error)
 }
 
 fn dir_path<'a>(
@@ -323,21 +343,38 @@ fn dir_path_buf(
     dir_path(state, handle).map(|p| p.to_path_buf())
 }
 
+fn reserve_fs_handle(state: &HostState) -> Result<(), CapabilityError> {
+    let limit = state.config.fs.max_open_handles;
+    if state.open_fs_handles >= limit {
+        return Err(capability_error(
+            CapabilityErrorCode::Limit,
+            format!("fs handle limit of {limit} reached; close unused handles"),
+        ));
+    }
+    Ok(())
+}
+
 fn insert_dir(
     state: &mut HostState,
     path: Utf8PathBuf,
 ) -> Result<Resource<DirHandle>, CapabilityError> {
-    state
+    reserve_fs_handle(state)?;
+    let handle = state
         .resources
         .push(DirHandleResource { path })
-        .map_err(table_error)
+        .map_err(table_error)?;
+    state.open_fs_handles += 1;
+    Ok(handle)
 }
 
 fn insert_file(
     state: &mut HostState,
     entry: FileHandleResource,
 ) -> Result<Resource<FileHandle>, CapabilityError> {
-    state.resources.push(entry).map_err(table_error)
+    reserve_fs_handle(state)?;
+    let handle = state.resources.push(entry).map_err(table_error)?;
+    state.open_fs_handles += 1;
+    Ok(handle)
 }
 
 fn file_entry_mut<'a>(
@@ -349,14 +386,27 @@ fn file_entry_mut<'a>(
 
 fn delete_dir(state: &mut HostState, handle: Resource<DirHandle>) -> Result<(), CapabilityError> {
     let _ = state.resources.delete(handle).map_err(table_error)?;
+    state.open_fs_handles = state.open_fs_handles.saturating_sub(1);
     Ok(())
 }
 
 fn delete_file(state: &mut HostState, handle: Resource<FileHandle>) -> Result<(), CapabilityError> {
     let _ = state.resources.delete(handle).map_err(table_error)?;
+    state.open_fs_handles = state.open_fs_handles.saturating_sub(1);
     Ok(())
 }
 
+fn file_size_limit(max_file_bytes: u64, size: u64) -> Result<(), CapabilityError> {
+    if size > max_file_bytes {
+        Err(capability_error(
+            CapabilityErrorCode::Limit,
+            format!("file size {size} exceeds fs.max_file_bytes ({max_file_bytes})"),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 fn process_entry_mut<'a>(
     state: &'a mut HostState,
     handle: &Resource<ProcHandle>,
@@ -368,10 +418,154 @@ fn delete_process(
     state: &mut HostState,
     handle: Resource<ProcHandle>,
 ) -> Result<(), CapabilityError> {
-    let _ = state.resources.delete(handle).map_err(table_error)?;
+    let process = state.resources.delete(handle).map_err(table_error)?;
+    state.processes.release(process.pid());
+    Ok(())
+}
+
+fn delete_session(
+    state: &mut HostState,
+    handle: Resource<ShellHandle>,
+) -> Result<(), CapabilityError> {
+    let session = state.resources.delete(handle).map_err(table_error)?;
+    state.processes.release(session.pid());
+    state.open_shell_sessions = state.open_shell_sessions.saturating_sub(1);
+    Ok(())
+}
+
+fn browser_error(op: &str, err: BrowserError) -> CapabilityError {
+    let mut error = capability_error(browser_error_code(&err), format!("{op} failed: {err}"));
+    if let BrowserError::UrlDenied { denial, .. } = &err {
+        error.detail = Some(denial_detail(denial));
+    }
+    error
+}
+
+/// Whether a planner action failed the way a guest call reports as
+/// `unavailable`: the browser or its driver did not answer, which may pass.
+pub fn is_unavailable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<BrowserError>())
+        .any(|err| matches!(browser_error_code(err), CapabilityErrorCode::Unavailable))
+}
+
+fn browser_error_code(err: &BrowserError) -> CapabilityErrorCode {
+    match err {
+        BrowserError::Io(_) => CapabilityErrorCode::Internal,
+        BrowserError::Closed => CapabilityErrorCode::Conflict,
+        BrowserError::Timeout(_) => CapabilityErrorCode::Timeout,
+        BrowserError::NotFound(_) | BrowserError::NoSuchTab(_) => CapabilityErrorCode::NotFound,
+        BrowserError::Script(_) | BrowserError::Unsupported(_) => {
+            CapabilityErrorCode::InvalidArgument
+        }
+        BrowserError::Blocked(_)
+        | BrowserError::UrlDenied { .. }
+        | BrowserError::EvalDisabled
+        | BrowserError::AttachNotConfirmed(_) => CapabilityErrorCode::Denied,
+        BrowserError::TooLarge { .. } => CapabilityErrorCode::Limit,
+        BrowserError::Launch(_) | BrowserError::Protocol(_) => CapabilityErrorCode::Unavailable,
+        BrowserError::WebDriver(err) => match err.as_ref() {
+            WebDriverError::NoSuchElement(_)
+            | WebDriverError::NoSuchWindow(_)
+            | WebDriverError::NoSuchFrame(_)
+            | WebDriverError::NotFound(..) => CapabilityErrorCode::NotFound,
+            WebDriverError::Timeout(_)
+            | WebDriverError::WebDriverTimeout(_)
+            | WebDriverError::ScriptTimeout(_) => CapabilityErrorCode::Timeout,
+            WebDriverError::InvalidArgument(_)
+            | WebDriverError::InvalidSelector(_)
+            | WebDriverError::InvalidUrl(_)
+            | WebDriverError::JavascriptError(_) => CapabilityErrorCode::InvalidArgument,
+            WebDriverError::StaleElementReference(_)
+            | WebDriverError::ElementNotInteractable(_)
+            | WebDriverError::ElementClickIntercepted(_)
+            | WebDriverError::InvalidElementState(_)
+            | WebDriverError::InvalidSessionId(_) => CapabilityErrorCode::Conflict,
+            _ => CapabilityErrorCode::Unavailable,
+        },
+    }
+}
+
+fn to_page_state(state: browser::PageState) -> bindings::osagent::browser::browser::PageState {
+    bindings::osagent::browser::browser::PageState {
+        url: state.url,
+        title: state.title,
+        html: state.html,
+        outline: state.outline,
+    }
+}
+
+fn to_selector(selector: bindings::osagent::browser::browser::Selector) -> Selector {
+    let kind = match selector.kind {
+        bindings::osagent::browser::browser::SelectorKind::Css => SelectorKind::Css,
+        bindings::osagent::browser::browser::SelectorKind::Xpath => SelectorKind::XPath,
+        bindings::osagent::browser::browser::SelectorKind::Text => SelectorKind::Text,
+    };
+    Selector {
+        kind,
+        value: selector.value,
+    }
+}
+
+fn to_option_choice(choice: bindings::osagent::browser::browser::OptionChoice) -> OptionChoice {
+    match choice {
+        bindings::osagent::browser::browser::OptionChoice::Value(value) => {
+            OptionChoice::Value(value)
+        }
+        bindings::osagent::browser::browser::OptionChoice::Label(label) => {
+            OptionChoice::Label(label)
+        }
+    }
+}
+
+fn to_exec_result(output: ExecOutput) -> bindings::osagent::shell::shell::ExecResult {
+    bindings::osagent::shell::shell::ExecResult {
+        exit_code: output.exit_code,
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stdout_truncated: output.stdout_truncated,
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        stderr_truncated: output.stderr_truncated,
+    }
+}
+
+fn ensure_process_capacity(state: &mut HostState) -> Result<(), CapabilityError> {
+    let limit = state.config.proc.max_concurrent;
+    if state.processes.live_count() >= limit {
+        return Err(capability_error(
+            CapabilityErrorCode::Limit,
+            format!("at most {limit} processes may run concurrently"),
+        ));
+    }
+    charge_process_time(state)?;
+    state
+        .budgets
+        .claim(BudgetKind::Processes, 1)
+        .map_err(budget_error)?;
     Ok(())
 }
 
+/// Charges the time the guest's children ran since the last call, and
+/// fails once the `process_ms` budget is spent.
+fn charge_process_time(state: &mut HostState) -> Result<(), CapabilityError> {
+    let elapsed = state.processes.take_run_time();
+    state
+        .budgets
+        .record(BudgetKind::ProcessMs, elapsed.as_millis() as u64);
+    state
+        .budgets
+        .check(BudgetKind::ProcessMs)
+        .map_err(budget_error)
+}
+
+fn to_process_info(info: ProcessInfo) -> bindings::osagent::proc::proc::ProcessInfo {
+    bindings::osagent::proc::proc::ProcessInfo {
+        pid: info.pid,
+        command: info.command,
+        started_ms: info.started_ms,
+        handle_open: info.handle_open,
+    }
+}
+
 fn metadata_to_entry(
     entry_name: String,
     meta: fs::Metadata,
@@ -403,15 +597,98 @@ fn file_time_ms(meta: &fs::Metadata) -> Option<u64> {
         .map(|dur| dur.as_millis() as u64)
 }
 
-fn ensure_within_workspace(root: &Utf8Path, candidate: &Utf8Path) -> Result<(), CapabilityError> {
-    if candidate.as_str().starts_with(root.as_str()) {
-        Ok(())
-    } else {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "path escapes workspace root",
-        ))
+fn created_time_ms(meta: &fs::Metadata) -> Option<u64> {
+    meta.created()
+        .ok()
+        .and_then(|ts| ts.duration_since(UNIX_EPOCH).ok())
+        .map(|dur| dur.as_millis() as u64)
+}
+
+/// Mode bits, file identity, and link count where the platform exposes them.
+type UnixMetadata = (
+    Option<u32>,
+    Option<bindings::osagent::fs::fs::FileIdentity>,
+    Option<u64>,
+);
+
+#[cfg(unix)]
+fn unix_metadata(meta: &fs::Metadata) -> UnixMetadata {
+    use std::os::unix::fs::MetadataExt;
+    let identity = bindings::osagent::fs::fs::FileIdentity {
+        device: meta.dev(),
+        inode: meta.ino(),
+    };
+    (Some(meta.mode()), Some(identity), Some(meta.nlink()))
+}
+
+#[cfg(not(unix))]
+fn unix_metadata(_meta: &fs::Metadata) -> UnixMetadata {
+    (None, None, None)
+}
+
+/// Keeps `candidate` inside the workspace and within `[policy.fs]`, and
+/// holds writes `[policy.confirm]` flags for approval.
+fn ensure_path_allowed(
+    state: &HostState,
+    candidate: &Utf8Path,
+    access: Access,
+) -> Result<(), CapabilityError> {
+    let config = &state.config;
+    let root = &config.workspace_root;
+    workspace::ensure_contained(root, candidate).map_err(path_error)?;
+    let relative = candidate.strip_prefix(root).unwrap_or(candidate);
+    if let Some(denial) = config.policy.path_denial(root, candidate, access) {
+        return Err(denial_error(format!("`{relative}` is {denial}"), &denial));
+    }
+    let confirmation = Some(access)
+        .filter(|access| *access == Access::Write)
+        .and_then(|_| config.policy.write_confirmation(root, candidate));
+    if let Some(reason) = confirmation {
+        confirm(state, "fs.write", relative.as_str(), &reason)?;
+    }
+    if access == Access::Write {
+        check_untrusted(state, "fs.write", relative.as_str(), [relative.as_str()])?;
     }
+    Ok(())
+}
+
+/// Holds an operation that quotes text the browser returned, per
+/// `policy.taint`.
+fn check_untrusted<'a>(
+    state: &HostState,
+    capability: &str,
+    scope: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Result<(), CapabilityError> {
+    let mode = state.config.policy.taint;
+    if mode == TaintMode::Off {
+        return Ok(());
+    }
+    let Some(quoted) = state.untrusted.find(candidates) else {
+        return Ok(());
+    };
+    let reason = format!("quotes web content `{quoted}` (policy.taint)");
+    if mode == TaintMode::Deny {
+        return Err(setting_error(format!("`{scope}` {reason}"), "policy.taint"));
+    }
+    confirm(state, capability, scope, &reason)
+}
+
+/// Waits for approval of an operation `[policy.confirm]` flagged.
+fn confirm(
+    state: &HostState,
+    capability: &str,
+    scope: &str,
+    reason: &str,
+) -> Result<(), CapabilityError> {
+    let answer =
+        blocking(|| grant::confirm(&state.config, &state.grants, capability, scope, reason));
+    answer.map_err(|why| {
+        capability_error(
+            CapabilityErrorCode::Denied,
+            format!("`{scope}` needs confirmation ({reason}): {why}"),
+        )
+    })
 }
 
 fn read_file_bytes(
@@ -429,144 +706,451 @@ fn read_file_bytes(
     Ok(buf)
 }
 
+fn read_file_detected(
+    state: &mut HostState,
+    handle: &Resource<FileHandle>,
+    max_bytes: u64,
+) -> Result<bindings::osagent::fs::fs::DetectedRead, CapabilityError> {
+    let op = "fs.file.read-detected";
+    let entry = file_entry_mut(state, handle)?;
+    let mut data = Vec::new();
+    (&mut entry.file)
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut data)
+        .map_err(|err| io_error(op, err))?;
+    let truncated = data.len() as u64 > max_bytes;
+    if truncated {
+        // Hand the probe byte back so the next read starts where this one stopped.
+        data.truncate(max_bytes as usize);
+        entry
+            .file
+            .seek(std::io::SeekFrom::Current(-1))
+            .map_err(|err| io_error(op, err))?;
+    }
+    let encoding = match encoding::detect(&data, truncated) {
+        TextEncoding::Utf8 => bindings::osagent::fs::fs::DetectedEncoding::Utf8,
+        TextEncoding::Utf16Le => bindings::osagent::fs::fs::DetectedEncoding::Utf16le,
+        TextEncoding::Utf16Be => bindings::osagent::fs::fs::DetectedEncoding::Utf16be,
+        TextEncoding::Binary => bindings::osagent::fs::fs::DetectedEncoding::Binary,
+    };
+    Ok(bindings::osagent::fs::fs::DetectedRead {
+        data,
+        encoding,
+        truncated,
+    })
+}
+
 fn write_file_bytes(
     state: &mut HostState,
     handle: &Resource<FileHandle>,
     data: &[u8],
     op: &str,
 ) -> Result<u64, CapabilityError> {
+    let max_file_bytes = state.config.fs.max_file_bytes;
+    state
+        .budgets
+        .check(BudgetKind::BytesWritten)
+        .map_err(budget_error)?;
     let entry = file_entry_mut(state, handle)?;
-    entry
+    let len = entry
+        .file
+        .metadata()
+        .map_err(|err| io_error(op, err))?
+        .len();
+    let start = if entry.append {
+        len
+    } else {
+        entry
+            .file
+            .stream_position()
+            .map_err(|err| io_error(op, err))?
+    };
+    file_size_limit(
+        max_file_bytes,
+        start.saturating_add(data.len() as u64).max(len),
+    )?;
+    let written = entry
         .file
         .write(data)
         .map(|written| written as u64)
-        .map_err(|err| io_error(op, err))
+        .map_err(|err| io_error(op, err))?;
+    let path = entry.path.clone();
+    state.budgets.record(BudgetKind::BytesWritten, written);
+    state.changes.record(&path, ChangeKind::Modified);
+    Ok(written)
 }
 
-fn ensure_command_allowed(config: &HostConfig, program: &str) -> Result<(), CapabilityError> {
-    if config.is_proc_allowed(program) {
-        Ok(())
-    } else {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            format!("command `{program}` is not allowed"),
-        ))
+fn ensure_command_allowed(
+    state: &HostState,
+    program: &str,
+    args: &[String],
+) -> Result<(), CapabilityError> {
+    let policy = &state.config.policy;
+    if let Some(denial) = policy
+        .command_denial(program)
+        .filter(|_| !state.grants.allows_command(program))
+    {
+        return Err(denial_error(
+            format!("command `{program}` is {denial}"),
+            &denial,
+        ));
+    }
+    if let Some(denial) = policy.args_denial(program, args) {
+        return Err(denial_error(
+            format!("arguments for `{program}` are {denial}"),
+            &denial,
+        ));
+    }
+    Ok(())
+}
+
+fn ensure_env_allowed(
+    config: &HostConfig,
+    env: &[bindings::osagent::proc::proc::EnvVar],
+) -> Result<(), CapabilityError> {
+    for var in env {
+        if var.key.is_empty() || var.key.contains(['=', '\0']) {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                format!("invalid env var name `{}`", var.key),
+            ));
+        }
+        if let Some(denial) = config.proc.env_denial(&var.key) {
+            return Err(denial_error(
+                format!("env var `{}` is {denial}", var.key),
+                &denial,
+            ));
+        }
     }
+    Ok(())
 }
 
 fn to_exit_status(resource: &ProcessResource) -> bindings::osagent::proc::proc::ExitStatus {
     bindings::osagent::proc::proc::ExitStatus {
-        code: resource.exit_code,
-        signal: None,
-        timed_out: resource.timed_out,
+        code: resource.status.and_then(|status| status.code()),
+        signal: resource
+            .status
+            .as_ref()
+            .and_then(process::exit_signal)
+            .map(to_process_signal),
+        timed_out: resource
+            .watchdog
+            .as_ref()
+            .is_some_and(|watchdog| watchdog.fired()),
+        oom_killed: resource
+            .cgroup
+            .as_ref()
+            .is_some_and(|cgroup| cgroup.oom_killed()),
+    }
+}
+
+fn to_process_signal(signal: Signal) -> bindings::osagent::proc::proc::ProcessSignal {
+    match signal {
+        Signal::Term => bindings::osagent::proc::proc::ProcessSignal::Term,
+        Signal::Kill => bindings::osagent::proc::proc::ProcessSignal::Kill,
+        Signal::Interrupt => bindings::osagent::proc::proc::ProcessSignal::Interrupt,
+        Signal::Hangup => bindings::osagent::proc::proc::ProcessSignal::Hangup,
+    }
+}
+
+fn from_process_signal(kind: bindings::osagent::proc::proc::ProcessSignal) -> Signal {
+    match kind {
+        bindings::osagent::proc::proc::ProcessSignal::Term => Signal::Term,
+        bindings::osagent::proc::proc::ProcessSignal::Kill => Signal::Kill,
+        bindings::osagent::proc::proc::ProcessSignal::Interrupt => Signal::Interrupt,
+        bindings::osagent::proc::proc::ProcessSignal::Hangup => Signal::Hangup,
     }
 }
 
 fn read_process_stream(
-    data: &[u8],
-    offset: &mut usize,
+    buffer: &OutputBuffer,
     max_bytes: u32,
 ) -> bindings::osagent::proc::proc::StreamRead {
-    let max = max_bytes as usize;
-    let remaining = data.len().saturating_sub(*offset);
-    let take = remaining.min(max);
-    let start = *offset;
-    let end = start + take;
-    let chunk = data[start..end].to_vec();
-    *offset = end;
+    let chunk = buffer.read(max_bytes as usize);
     bindings::osagent::proc::proc::StreamRead {
-        data: chunk,
-        eof: *offset >= data.len(),
+        data: chunk.data,
+        eof: chunk.eof,
+        truncated: chunk.truncated,
     }
 }
 
-impl bindings::osagent::common::types::Host for HostState {}
-
-impl bindings::osagent::fs::fs::Host for HostState {
-    fn open_workspace(&mut self) -> Result<Resource<DirHandle>, CapabilityError> {
-        insert_dir(self, self.config.workspace_root.clone())
-    }
-
-    fn open_dir(
-        &mut self,
-        parent: Resource<DirHandle>,
-        relative_path: wasmtime::component::__internal::String,
-    ) -> Result<Resource<DirHandle>, CapabilityError> {
-        let parent_path = dir_path_buf(self, &parent)?;
-        let candidate = resolve_child(&parent_path, &relative_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &candidate)?;
-        let metadata =
-            fs::metadata(candidate.as_std_path()).map_err(|err| io_error("fs.open-dir", err))?;
-        if !metadata.is_dir() {
+/// Blocks until the child exits or `timeout` elapses. A `None` timeout waits
+/// indefinitely; an elapsed timeout leaves the child running.
+fn wait_for_exit(
+    process: &mut ProcessResource,
+    timeout: Option<Duration>,
+) -> Result<(), CapabilityError> {
+    if process.status.is_some() {
+        return Ok(());
+    }
+    // Polled even without a timeout: a blocking `wait` would hold the child
+    // lock, and with it `kill`, until the process exits.
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if process
+            .poll_status()
+            .map_err(|err| io_error("proc.wait", err))?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let Some(deadline) = deadline else {
+            thread::sleep(PROCESS_POLL_INTERVAL);
+            continue;
+        };
+        let now = Instant::now();
+        if now >= deadline {
             return Err(capability_error(
-                CapabilityErrorCode::InvalidArgument,
-                "path is not a directory",
+                CapabilityErrorCode::Timeout,
+                format!("process `{}` is still running", process.command),
             ));
         }
-        insert_dir(self, candidate)
+        thread::sleep(PROCESS_POLL_INTERVAL.min(deadline - now));
     }
+}
+
+/// Writes as much of `chunk` as the pipe accepts without blocking.
+fn write_process_stdin(
+    process: &mut ProcessResource,
+    chunk: &[u8],
+) -> Result<usize, CapabilityError> {
+    let Some(stdin) = process.stdin.as_mut() else {
+        return Err(capability_error(
+            CapabilityErrorCode::Conflict,
+            "stdin is not piped or was already closed",
+        ));
+    };
+    let mut written = 0;
+    while written < chunk.len() {
+        match stdin.write(&chunk[written..]) {
+            Ok(0) => break,
+            Ok(count) => written += count,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(io_error("proc.write_stdin", err)),
+        }
+    }
+    Ok(written)
+}
+
+/// Builds the command through the configured `proc.backend`.
+fn prepare_command(
+    config: &HostConfig,
+    cwd: &Utf8Path,
+    program: &str,
+    args: &[String],
+    env: Vec<bindings::osagent::proc::proc::EnvVar>,
+    tty: bool,
+    priority: Priority,
+) -> Result<Prepared, CapabilityError> {
+    let env: Vec<(String, String)> = env.into_iter().map(|var| (var.key, var.value)).collect();
+    let spec = CommandSpec {
+        workspace: &config.workspace_root,
+        cwd,
+        program,
+        args,
+        env: &env,
+        tty,
+        priority,
+    };
+    executor::for_settings(&config.proc)
+        .and_then(|executor| executor.prepare(&spec))
+        .map_err(|err| {
+            capability_error(
+                CapabilityErrorCode::Internal,
+                format!("failed to prepare command: {err:#}"),
+            )
+        })
+}
+
+fn from_process_priority(priority: bindings::osagent::proc::proc::ProcessPriority) -> Priority {
+    match priority {
+        bindings::osagent::proc::proc::ProcessPriority::Low => Priority::Low,
+        bindings::osagent::proc::proc::ProcessPriority::Normal => Priority::Normal,
+    }
+}
+
+/// Reported instead of running anything while `proc.dry_run` is set.
+fn dry_run_error(command_line: &str) -> CapabilityError {
+    capability_error(
+        CapabilityErrorCode::Unavailable,
+        format!("dry run: would run `{command_line}`"),
+    )
+}
+
+fn pty_error(err: std::io::Error) -> CapabilityError {
+    if err.kind() == std::io::ErrorKind::Unsupported {
+        return capability_error(CapabilityErrorCode::InvalidArgument, err.to_string());
+    }
+    io_error("proc.spawn", err)
+}
 
-    fn ensure_dir(
+fn child_stdio(
+    mode: bindings::osagent::proc::proc::StdioMode,
+    stream: &str,
+) -> Result<Stdio, CapabilityError> {
+    match mode {
+        bindings::osagent::proc::proc::StdioMode::Pipe => Ok(Stdio::piped()),
+        bindings::osagent::proc::proc::StdioMode::Null => Ok(Stdio::null()),
+        bindings::osagent::proc::proc::StdioMode::Inherit => Err(capability_error(
+            CapabilityErrorCode::InvalidArgument,
+            format!("{stream} cannot inherit the host stream"),
+        )),
+    }
+}
+
+/// Runs host code that can wait a long time, such as an LLM request, a
+/// process, a macro or an approval, from an async host call. The runtime
+/// worker is handed back to the scheduler meanwhile, as `browser` does for
+/// its requests. The work stays on the task's thread, where its audit log
+/// and other per-task state live, which `spawn_blocking` would not keep.
+fn blocking<T>(work: impl FnOnce() -> T) -> T {
+    tokio::task::block_in_place(work)
+}
+
+impl bindings::osagent::common::types::Host for HostState {}
+
+impl bindings::osagent::fs::fs::Host for HostState {
+    async fn open_workspace(&mut self) -> Result<Resource<DirHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "open_workspace")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        insert_dir(self, self.config.workspace_root.clone())
+    }
+
+    async fn open_dir(
         &mut self,
         parent: Resource<DirHandle>,
         relative_path: wasmtime::component::__internal::String,
     ) -> Result<Resource<DirHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "open_dir")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let parent_path = dir_path_buf(self, &parent)?;
         let candidate = resolve_child(&parent_path, &relative_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &candidate)?;
-        fs::create_dir_all(candidate.as_std_path())
-            .map_err(|err| io_error("fs.ensure-dir", err))?;
+        ensure_path_allowed(self, &candidate, Access::Read)?;
+        let metadata =
+            fs::metadata(candidate.as_std_path()).map_err(|err| io_error("fs.open-dir", err))?;
+        if !metadata.is_dir() {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                "path is not a directory",
+            ));
+        }
         insert_dir(self, candidate)
     }
 
-    fn remove_dir(
+    async fn ensure_dir(
+        &mut self,
+        parent: Resource<DirHandle>,
+        relative_path: wasmtime::component::__internal::String,
+    ) -> Result<Resource<DirHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "ensure_dir")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        let parent_path = dir_path_buf(self, &parent)?;
+        let candidate = resolve_child(&parent_path, &relative_path)?;
+        ensure_path_allowed(self, &candidate, Access::Write)?;
+        if !candidate.exists() {
+            fs::create_dir_all(candidate.as_std_path())
+                .map_err(|err| io_error("fs.ensure-dir", err))?;
+            self.changes.record(&candidate, ChangeKind::Created);
+        }
+        insert_dir(self, candidate)
+    }
+
+    async fn remove_dir(
         &mut self,
         parent: Resource<DirHandle>,
         relative_path: wasmtime::component::__internal::String,
         recursive: bool,
     ) -> Result<(), CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "remove_dir")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let parent_path = dir_path_buf(self, &parent)?;
         let target = resolve_child(&parent_path, &relative_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &target)?;
+        ensure_path_allowed(self, &target, Access::Write)?;
+        if recursive && self.config.policy.confirm.recursive_remove {
+            let relative = target
+                .strip_prefix(&self.config.workspace_root)
+                .unwrap_or(&target);
+            confirm(
+                self,
+                "fs.remove_dir",
+                relative.as_str(),
+                "recursive remove (policy.confirm.recursive_remove)",
+            )?;
+        }
         if recursive {
-            fs::remove_dir_all(target.as_std_path()).map_err(|err| io_error("fs.remove-dir", err))
+            fs::remove_dir_all(target.as_std_path())
+                .map_err(|err| io_error("fs.remove-dir", err))?;
         } else {
-            fs::remove_dir(target.as_std_path()).map_err(|err| io_error("fs.remove-dir", err))
+            fs::remove_dir(target.as_std_path()).map_err(|err| io_error("fs.remove-dir", err))?;
         }
+        self.changes.record(&target, ChangeKind::Deleted);
+        Ok(())
     }
 
-    fn remove_file(
+    async fn remove_file(
         &mut self,
         parent: Resource<DirHandle>,
         relative_path: wasmtime::component::__internal::String,
     ) -> Result<(), CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "remove_file")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let parent_path = dir_path_buf(self, &parent)?;
         let target = resolve_child(&parent_path, &relative_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &target)?;
-        fs::remove_file(target.as_std_path()).map_err(|err| io_error("fs.remove-file", err))
+        ensure_path_allowed(self, &target, Access::Write)?;
+        fs::remove_file(target.as_std_path()).map_err(|err| io_error("fs.remove-file", err))?;
+        self.changes.record(&target, ChangeKind::Deleted);
+        Ok(())
     }
 
-    fn rename(
+    async fn rename(
         &mut self,
         parent: Resource<DirHandle>,
         old_path: wasmtime::component::__internal::String,
         new_path: wasmtime::component::__internal::String,
     ) -> Result<(), CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "rename")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let parent_path = dir_path_buf(self, &parent)?;
         let from = resolve_child(&parent_path, &old_path)?;
         let to = resolve_child(&parent_path, &new_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &from)?;
-        ensure_within_workspace(&self.config.workspace_root, &to)?;
-        fs::rename(from.as_std_path(), to.as_std_path()).map_err(|err| io_error("fs.rename", err))
+        ensure_path_allowed(self, &from, Access::Write)?;
+        ensure_path_allowed(self, &to, Access::Write)?;
+        let replaced = to.exists();
+        fs::rename(from.as_std_path(), to.as_std_path())
+            .map_err(|err| io_error("fs.rename", err))?;
+        self.changes.record(&from, ChangeKind::Deleted);
+        self.changes.record_write(&to, replaced);
+        Ok(())
     }
 
-    fn list_dir(
+    async fn list_dir(
         &mut self,
         target: Resource<DirHandle>,
     ) -> Result<
         wasmtime::component::__internal::Vec<bindings::osagent::fs::fs::DirEntry>,
         CapabilityError,
     > {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "list_dir")?;
         let dir_path = dir_path(self, &target)?.to_path_buf();
         let mut entries = Vec::new();
         let read = fs::read_dir(&dir_path).map_err(|err| io_error("fs.list-dir", err))?;
@@ -576,6 +1160,18 @@ impl bindings::osagent::fs::fs::Host for HostState {
                 .file_name()
                 .into_string()
                 .unwrap_or_else(|os| os.to_string_lossy().into_owned());
+            // Entries the policy hides are left out rather than failing
+            // the whole listing.
+            let path = dir_path.join(&name);
+            let root = &self.config.workspace_root;
+            if self
+                .config
+                .policy
+                .path_denial(root, &path, Access::Read)
+                .is_some()
+            {
+                continue;
+            }
             let metadata = entry
                 .metadata()
                 .map_err(|err| io_error("fs.list-dir", err))?;
@@ -584,21 +1180,27 @@ impl bindings::osagent::fs::fs::Host for HostState {
         Ok(entries)
     }
 
-    fn metadata(
+    async fn metadata(
         &mut self,
         parent: Resource<DirHandle>,
         relative_path: Option<wasmtime::component::__internal::String>,
     ) -> Result<bindings::osagent::fs::fs::EntryMetadata, CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "metadata")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let base = dir_path(self, &parent)?.to_path_buf();
         let path = if let Some(rel) = relative_path {
             let joined = resolve_child(&base, &rel)?;
-            ensure_within_workspace(&self.config.workspace_root, &joined)?;
+            ensure_path_allowed(self, &joined, Access::Read)?;
             joined
         } else {
             base
         };
         let metadata =
             fs::metadata(path.as_std_path()).map_err(|err| io_error("fs.metadata", err))?;
+        let (mode, identity, link_count) = unix_metadata(&metadata);
         Ok(bindings::osagent::fs::fs::EntryMetadata {
             name: path
                 .file_name()
@@ -608,62 +1210,95 @@ impl bindings::osagent::fs::fs::Host for HostState {
             size_bytes: Some(metadata.len()),
             modified_ms: file_time_ms(&metadata),
             readonly: metadata.permissions().readonly(),
+            created_ms: created_time_ms(&metadata),
+            mode,
+            identity,
+            link_count,
         })
     }
 
-    fn open_file(
+    async fn open_file(
         &mut self,
         parent: Resource<DirHandle>,
         relative_path: wasmtime::component::__internal::String,
         options: bindings::osagent::fs::fs::FileOpenOptions,
     ) -> Result<Resource<FileHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "fs")?;
+        admit_call(self, "fs", "open_file")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let parent_path = dir_path_buf(self, &parent)?;
         let file_path = resolve_child(&parent_path, &relative_path)?;
-        ensure_within_workspace(&self.config.workspace_root, &file_path)?;
+        let access = if options.write || options.append || options.create || options.truncate {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        ensure_path_allowed(self, &file_path, access)?;
         let mut open_opts = OpenOptions::new();
         open_opts.read(options.read);
         open_opts.write(options.write || options.append);
         open_opts.append(options.append);
         open_opts.create(options.create);
         open_opts.truncate(options.truncate);
+        reserve_fs_handle(self)?;
+        let existed = file_path.exists();
         let file = open_opts
             .open(file_path.as_std_path())
             .map_err(|err| io_error("fs.open-file", err))?;
+        let size = file
+            .metadata()
+            .map_err(|err| io_error("fs.open-file", err))?
+            .len();
+        file_size_limit(self.config.fs.max_file_bytes, size)?;
+        if !existed || (options.truncate && (options.write || options.append)) {
+            self.changes.record_write(&file_path, existed);
+        }
         insert_file(
             self,
             FileHandleResource {
                 path: file_path,
                 file,
+                append: options.append,
             },
         )
     }
 }
 
 impl bindings::osagent::fs::fs::HostDirHandle for HostState {
-    fn close(&mut self, handle: Resource<DirHandle>) -> () {
+    async fn close(&mut self, handle: Resource<DirHandle>) -> () {
         let _ = delete_dir(self, handle);
     }
 
-    fn drop(&mut self, handle: Resource<DirHandle>) -> wasmtime::Result<()> {
+    async fn drop(&mut self, handle: Resource<DirHandle>) -> wasmtime::Result<()> {
         let _ = delete_dir(self, handle);
         Ok(())
     }
 }
 
 impl bindings::osagent::fs::fs::HostFileHandle for HostState {
-    fn read(
+    async fn read(
         &mut self,
         handle: Resource<FileHandle>,
         max_bytes: u64,
     ) -> Result<wasmtime::component::__internal::Vec<u8>, CapabilityError> {
+        admit_call(self, "fs", "read")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         read_file_bytes(self, &handle, max_bytes, "fs.file.read")
     }
 
-    fn read_to_string(
+    async fn read_to_string(
         &mut self,
         handle: Resource<FileHandle>,
         max_bytes: u64,
     ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "fs", "read_to_string")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let bytes = read_file_bytes(self, &handle, max_bytes, "fs.file.read-to-string")?;
         String::from_utf8(bytes).map_err(|_| {
             capability_error(
@@ -673,20 +1308,40 @@ impl bindings::osagent::fs::fs::HostFileHandle for HostState {
         })
     }
 
-    fn write(
+    async fn read_detected(
+        &mut self,
+        handle: Resource<FileHandle>,
+        max_bytes: u64,
+    ) -> Result<bindings::osagent::fs::fs::DetectedRead, CapabilityError> {
+        admit_call(self, "fs", "read_detected")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        read_file_detected(self, &handle, max_bytes)
+    }
+
+    async fn write(
         &mut self,
         handle: Resource<FileHandle>,
         bytes: wasmtime::component::__internal::Vec<u8>,
     ) -> Result<u64, CapabilityError> {
+        admit_call(self, "fs", "write")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         write_file_bytes(self, &handle, &bytes, "fs.file.write")
     }
 
-    fn write_string(
+    async fn write_string(
         &mut self,
         handle: Resource<FileHandle>,
         contents: wasmtime::component::__internal::String,
         newline: bool,
     ) -> Result<u64, CapabilityError> {
+        admit_call(self, "fs", "write_string")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let mut data = contents.into_bytes();
         if newline {
             data.push(b'\n');
@@ -694,109 +1349,285 @@ impl bindings::osagent::fs::fs::HostFileHandle for HostState {
         write_file_bytes(self, &handle, &data, "fs.file.write-string")
     }
 
-    fn set_len(
+    async fn set_len(
         &mut self,
         handle: Resource<FileHandle>,
         new_len: u64,
     ) -> Result<(), CapabilityError> {
+        admit_call(self, "fs", "set_len")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        file_size_limit(self.config.fs.max_file_bytes, new_len)?;
         let file = file_entry_mut(self, &handle)?;
         file.file
             .set_len(new_len)
-            .map_err(|err| io_error("fs.file.set-len", err))
+            .map_err(|err| io_error("fs.file.set-len", err))?;
+        let path = file.path.clone();
+        self.changes.record(&path, ChangeKind::Modified);
+        Ok(())
     }
 
-    fn flush(&mut self, handle: Resource<FileHandle>) -> Result<(), CapabilityError> {
+    async fn flush(&mut self, handle: Resource<FileHandle>) -> Result<(), CapabilityError> {
+        admit_call(self, "fs", "flush")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
         let file = file_entry_mut(self, &handle)?;
         file.file
             .flush()
             .map_err(|err| io_error("fs.file.flush", err))
     }
 
-    fn close(&mut self, handle: Resource<FileHandle>) -> () {
+    async fn close(&mut self, handle: Resource<FileHandle>) -> () {
         let _ = delete_file(self, handle);
     }
 
-    fn drop(&mut self, handle: Resource<FileHandle>) -> wasmtime::Result<()> {
+    async fn drop(&mut self, handle: Resource<FileHandle>) -> wasmtime::Result<()> {
         let _ = delete_file(self, handle);
         Ok(())
     }
 }
 
+impl bindings::osagent::archive::archive::Host for HostState {
+    async fn extract(
+        &mut self,
+        parent: Resource<DirHandle>,
+        path: wasmtime::component::__internal::String,
+        dest: wasmtime::component::__internal::String,
+        max_entries: u32,
+        max_bytes: u64,
+    ) -> Result<bindings::osagent::archive::archive::ArchiveSummary, CapabilityError> {
+        ensure_capability_enabled(&self.config, "archive")?;
+        admit_call(self, "archive", "extract")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        let parent_path = dir_path_buf(self, &parent)?;
+        let source = resolve_child(&parent_path, &path)?;
+        let target = resolve_child(&parent_path, &dest)?;
+        ensure_path_allowed(self, &source, Access::Read)?;
+        ensure_path_allowed(self, &target, Access::Write)?;
+        let limits = ExtractLimits {
+            max_entries,
+            max_bytes,
+        }
+        .capped(&self.config.archive);
+        self.budgets
+            .check(BudgetKind::BytesWritten)
+            .map_err(budget_error)?;
+        let summary = archive::extract(&source, &target, limits, &mut self.changes)
+            .map_err(|err| archive_error("archive.extract", err))?;
+        self.budgets.record(BudgetKind::BytesWritten, summary.bytes);
+        Ok(to_archive_summary(summary))
+    }
+
+    async fn create(
+        &mut self,
+        parent: Resource<DirHandle>,
+        paths: wasmtime::component::__internal::Vec<wasmtime::component::__internal::String>,
+        dest: wasmtime::component::__internal::String,
+    ) -> Result<bindings::osagent::archive::archive::ArchiveSummary, CapabilityError> {
+        ensure_capability_enabled(&self.config, "archive")?;
+        admit_call(self, "archive", "create")?;
+        self.budgets
+            .claim(BudgetKind::FilesystemOps, 1)
+            .map_err(budget_error)?;
+        let parent_path = dir_path_buf(self, &parent)?;
+        let mut inputs = Vec::with_capacity(paths.len());
+        for path in paths {
+            let input = resolve_child(&parent_path, &path)?;
+            ensure_path_allowed(self, &input, Access::Read)?;
+            inputs.push(input);
+        }
+        let target = resolve_child(&parent_path, &dest)?;
+        ensure_path_allowed(self, &target, Access::Write)?;
+        let existed = target.exists();
+        self.budgets
+            .check(BudgetKind::BytesWritten)
+            .map_err(budget_error)?;
+        let summary = archive::create(&parent_path, &inputs, &target)
+            .map_err(|err| archive_error("archive.create", err))?;
+        let size = fs::metadata(&target).map_or(0, |meta| meta.len());
+        self.budgets.record(BudgetKind::BytesWritten, size);
+        self.changes.record_write(&target, existed);
+        Ok(to_archive_summary(summary))
+    }
+}
+
 impl bindings::osagent::proc::proc::Host for HostState {
-    fn spawn(
+    async fn spawn(
         &mut self,
         command: wasmtime::component::__internal::String,
         options: bindings::osagent::proc::proc::SpawnOptions,
     ) -> Result<Resource<ProcHandle>, CapabilityError> {
-        ensure_command_allowed(&self.config, &command)?;
+        ensure_capability_enabled(&self.config, "proc")?;
+        admit_call(self, "proc", "spawn")?;
+        ensure_command_allowed(self, &command, &options.argv)?;
+        ensure_env_allowed(&self.config, &options.env)?;
 
-        if options.timeout_ms.is_some() {
+        let timeout_ms = options.timeout_ms.or(self.config.proc.default_timeout_ms);
+        if timeout_ms == Some(0) {
             return Err(capability_error(
                 CapabilityErrorCode::InvalidArgument,
-                "timeout is not supported yet",
+                "timeout must be greater than zero",
             ));
         }
 
-        if !matches!(
-            options.stdin,
-            bindings::osagent::proc::proc::StdioMode::Null
-        ) {
-            return Err(capability_error(
-                CapabilityErrorCode::InvalidArgument,
-                "stdin must be null for now",
-            ));
-        }
-        if !matches!(
-            options.stdout,
-            bindings::osagent::proc::proc::StdioMode::Pipe
-        ) || !matches!(
-            options.stderr,
-            bindings::osagent::proc::proc::StdioMode::Pipe
-        ) {
-            return Err(capability_error(
-                CapabilityErrorCode::InvalidArgument,
-                "stdout/stderr must be pipe",
-            ));
-        }
-
-        let mut cmd = Command::new(&command);
-        for arg in options.argv {
-            cmd.arg(arg);
-        }
+        ensure_process_capacity(self)?;
+        let pty = if options.pty {
+            let size =
+                options
+                    .terminal_size
+                    .unwrap_or(bindings::osagent::proc::proc::TerminalSize {
+                        rows: DEFAULT_TERMINAL_ROWS,
+                        cols: DEFAULT_TERMINAL_COLS,
+                    });
+            if size.rows == 0 || size.cols == 0 {
+                return Err(capability_error(
+                    CapabilityErrorCode::InvalidArgument,
+                    "terminal size must be non-zero",
+                ));
+            }
+            Some(Pty::open(size.rows, size.cols).map_err(pty_error)?)
+        } else {
+            None
+        };
 
         let working_dir = if let Some(dir) = options.working_dir {
             let resolved = resolve_child(&self.config.workspace_root, &dir)?;
-            ensure_within_workspace(&self.config.workspace_root, &resolved)?;
-            Some(resolved)
+            ensure_path_allowed(self, &resolved, Access::Read)?;
+            resolved
         } else {
-            None
+            self.config.workspace_root.clone()
         };
-        if let Some(dir) = working_dir.as_ref() {
-            cmd.current_dir(dir.as_std_path());
-        } else {
-            cmd.current_dir(self.config.workspace_root.as_std_path());
+        let command_line = process::display_command(&command, &options.argv);
+        if self.config.proc.dry_run {
+            return Err(dry_run_error(&command_line));
         }
-
-        cmd.stdin(Stdio::null());
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
-        cmd.env_clear();
-        for env in options.env {
-            cmd.env(env.key, env.value);
+        if let Some(reason) = self.config.policy.command_confirmation(&command_line) {
+            confirm(self, "proc.spawn", &command_line, &reason)?;
         }
+        let quotable = [command.as_str(), command_line.as_str()];
+        check_untrusted(
+            self,
+            "proc.spawn",
+            &command_line,
+            quotable
+                .into_iter()
+                .chain(options.argv.iter().map(String::as_str)),
+        )?;
+        notify::record_command(command_line);
+        let Prepared {
+            command: mut cmd,
+            container,
+        } = prepare_command(
+            &self.config,
+            &working_dir,
+            &command,
+            &options.argv,
+            options.env,
+            pty.is_some(),
+            options
+                .priority
+                .map_or(Priority::Normal, from_process_priority),
+        )?;
+
+        match pty.as_ref() {
+            Some(pty) => pty.attach(&mut cmd).map_err(pty_error)?,
+            None => {
+                cmd.stdin(child_stdio(options.stdin, "stdin")?);
+                cmd.stdout(child_stdio(options.stdout, "stdout")?);
+                cmd.stderr(child_stdio(options.stderr, "stderr")?);
+            }
+        }
+
+        let cgroup = match self.config.proc.cgroup.as_ref() {
+            Some(limits) => Some(Arc::new(Cgroup::attach(&mut cmd, limits).map_err(
+                |err| {
+                    capability_error(
+                        CapabilityErrorCode::Unavailable,
+                        format!("failed to set up cgroup: {err:#}"),
+                    )
+                },
+            )?)),
+            None => None,
+        };
 
-        let output = cmd.output().map_err(|err| io_error("proc.spawn", err))?;
+        if pty.is_none() {
+            // A pty child leads its own session, which already isolates it.
+            process::isolate_group(&mut cmd);
+        }
+        let mut child = cmd.spawn().map_err(|err| io_error("proc.spawn", err))?;
+        // Drop the parent's slave copies so the master reports EOF on exit.
+        drop(cmd);
+        let watchdog = timeout_ms.map(|ms| Watchdog::arm(&child, Duration::from_millis(ms)));
+        let output_limit = self.config.proc.max_output_bytes;
+        let (stdin, stdout, stderr) = match pty {
+            Some(pty) => {
+                let master = pty.into_master();
+                process::set_nonblocking(&master).map_err(|err| io_error("proc.spawn", err))?;
+                let reader = master
+                    .try_clone()
+                    .map_err(|err| io_error("proc.spawn", err))?;
+                (
+                    Some(StdinWriter::Pty(master)),
+                    OutputBuffer::spawn_reader(reader, output_limit),
+                    OutputBuffer::closed(),
+                )
+            }
+            None => {
+                let stdin = child.stdin.take();
+                if let Some(pipe) = stdin.as_ref() {
+                    process::set_nonblocking(pipe).map_err(|err| io_error("proc.spawn", err))?;
+                }
+                let stdout = child
+                    .stdout
+                    .take()
+                    .map_or_else(OutputBuffer::closed, |pipe| {
+                        OutputBuffer::spawn_reader(pipe, output_limit)
+                    });
+                let stderr = child
+                    .stderr
+                    .take()
+                    .map_or_else(OutputBuffer::closed, |pipe| {
+                        OutputBuffer::spawn_reader(pipe, output_limit)
+                    });
+                (stdin.map(StdinWriter::Pipe), stdout, stderr)
+            }
+        };
+        let child = Arc::new(Mutex::new(child));
+        self.processes.register(
+            &command,
+            Arc::clone(&child),
+            watchdog.clone(),
+            cgroup.clone(),
+            container.clone(),
+        );
         let resource = ProcessResource {
             command: command.clone(),
-            stdout: output.stdout,
-            stderr: output.stderr,
-            stdout_pos: 0,
-            stderr_pos: 0,
-            exit_code: output.status.code(),
-            timed_out: false,
+            child,
+            stdin,
+            stdout,
+            stderr,
+            status: None,
+            watchdog,
+            cgroup,
+            container,
         };
         insert_process(self, resource)
     }
+
+    async fn list_processes(&mut self) -> Vec<bindings::osagent::proc::proc::ProcessInfo> {
+        if !self.config.is_capability_enabled("proc") {
+            return Vec::new();
+        }
+        self.processes
+            .live()
+            .into_iter()
+            .map(to_process_info)
+            .collect()
+    }
 }
 
 fn insert_process(
@@ -807,313 +1638,1012 @@ fn insert_process(
 }
 
 impl bindings::osagent::proc::proc::HostProcess for HostState {
-    fn write_stdin(
+    async fn write_stdin(
         &mut self,
-        _: Resource<ProcHandle>,
-        _chunk: wasmtime::component::__internal::Vec<u8>,
-        _eof: bool,
+        handle: Resource<ProcHandle>,
+        chunk: wasmtime::component::__internal::Vec<u8>,
+        eof: bool,
     ) -> Result<u32, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "process capability is not implemented",
-        ))
+        admit_call(self, "proc", "write_stdin")?;
+        let process = process_entry_mut(self, &handle)?;
+        let written = write_process_stdin(process, &chunk)?;
+        if eof && written == chunk.len() {
+            process.stdin = None;
+        }
+        Ok(written as u32)
     }
 
-    fn read_stdout(
+    async fn read_stdout(
         &mut self,
         handle: Resource<ProcHandle>,
         max_bytes: u32,
     ) -> Result<bindings::osagent::proc::proc::StreamRead, CapabilityError> {
+        admit_call(self, "proc", "read_stdout")?;
         let process = process_entry_mut(self, &handle)?;
-        Ok(read_process_stream(
-            &process.stdout,
-            &mut process.stdout_pos,
-            max_bytes,
-        ))
+        Ok(read_process_stream(&process.stdout, max_bytes))
     }
 
-    fn read_stderr(
+    async fn read_stderr(
         &mut self,
         handle: Resource<ProcHandle>,
         max_bytes: u32,
     ) -> Result<bindings::osagent::proc::proc::StreamRead, CapabilityError> {
+        admit_call(self, "proc", "read_stderr")?;
         let process = process_entry_mut(self, &handle)?;
-        Ok(read_process_stream(
-            &process.stderr,
-            &mut process.stderr_pos,
-            max_bytes,
-        ))
+        Ok(read_process_stream(&process.stderr, max_bytes))
     }
 
-    fn wait(
+    async fn wait(
         &mut self,
         handle: Resource<ProcHandle>,
-        _timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
     ) -> Result<bindings::osagent::proc::proc::ExitStatus, CapabilityError> {
+        admit_call(self, "proc", "wait")?;
         let process = process_entry_mut(self, &handle)?;
+        blocking(|| wait_for_exit(process, timeout_ms.map(Duration::from_millis)))?;
         Ok(to_exit_status(process))
     }
 
-    fn signal(
+    async fn signal(
         &mut self,
-        _rep: Resource<ProcHandle>,
-        _kind: bindings::osagent::proc::proc::ProcessSignal,
+        handle: Resource<ProcHandle>,
+        kind: bindings::osagent::proc::proc::ProcessSignal,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "signaling processes is not supported",
-        ))
+        admit_call(self, "proc", "signal")?;
+        let process = process_entry_mut(self, &handle)?;
+        let exited = process
+            .poll_status()
+            .map_err(|err| io_error("proc.signal", err))?
+            .is_some();
+        if exited {
+            return Err(capability_error(
+                CapabilityErrorCode::Conflict,
+                format!("process `{}` has already exited", process.command),
+            ));
+        }
+        process::send_signal(
+            &mut process::lock_child(&process.child),
+            from_process_signal(kind),
+        )
+        .map_err(|err| io_error("proc.signal", err))
     }
 
-    fn close(&mut self, handle: Resource<ProcHandle>) -> () {
+    async fn close(&mut self, handle: Resource<ProcHandle>) -> () {
         let _ = delete_process(self, handle);
     }
 
-    fn drop(&mut self, handle: Resource<ProcHandle>) -> wasmtime::Result<()> {
+    async fn drop(&mut self, handle: Resource<ProcHandle>) -> wasmtime::Result<()> {
         let _ = delete_process(self, handle);
         Ok(())
     }
 }
 
+impl bindings::osagent::shell::shell::Host for HostState {
+    async fn open_session(
+        &mut self,
+        options: bindings::osagent::shell::shell::SessionOptions,
+    ) -> Result<Resource<ShellHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "shell")?;
+        admit_call(self, "shell", "open_session")?;
+        let settings = self.config.shell.clone();
+        if !settings.enabled && !self.grants.allows("shell", None) {
+            return Err(setting_error(
+                "shell sessions are disabled by shell.enabled",
+                "shell.enabled",
+            ));
+        }
+        if self.open_shell_sessions >= settings.max_sessions {
+            return Err(capability_error(
+                CapabilityErrorCode::Limit,
+                format!(
+                    "at most {} shell sessions may be open",
+                    settings.max_sessions
+                ),
+            ));
+        }
+        ensure_env_allowed(&self.config, &options.env)?;
+        ensure_process_capacity(self)?;
+
+        let working_dir = if let Some(dir) = options.working_dir {
+            let resolved = resolve_child(&self.config.workspace_root, &dir)?;
+            ensure_path_allowed(self, &resolved, Access::Read)?;
+            resolved
+        } else {
+            self.config.workspace_root.clone()
+        };
+        let Prepared {
+            command: mut cmd,
+            container,
+        } = prepare_command(
+            &self.config,
+            &working_dir,
+            &settings.program,
+            &settings.kind.args(),
+            options.env,
+            false,
+            Priority::Normal,
+        )?;
+        let cgroup = match self.config.proc.cgroup.as_ref() {
+            Some(limits) => Some(Arc::new(Cgroup::attach(&mut cmd, limits).map_err(
+                |err| {
+                    capability_error(
+                        CapabilityErrorCode::Unavailable,
+                        format!("failed to set up cgroup: {err:#}"),
+                    )
+                },
+            )?)),
+            None => None,
+        };
+
+        let session = ShellSession::spawn(
+            cmd,
+            settings.kind,
+            self.config.proc.max_output_bytes,
+            cgroup,
+            container,
+        )
+        .map_err(|err| io_error("shell.open_session", err))?;
+        self.processes.register_session(
+            &settings.program,
+            Arc::clone(session.child()),
+            session.cgroup().cloned(),
+            session.container().cloned(),
+        );
+        let handle = self.resources.push(session).map_err(table_error)?;
+        self.open_shell_sessions += 1;
+        Ok(handle)
+    }
+}
+
+impl bindings::osagent::shell::shell::HostSession for HostState {
+    async fn exec(
+        &mut self,
+        handle: Resource<ShellHandle>,
+        command: String,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+    ) -> Result<bindings::osagent::shell::shell::ExecResult, CapabilityError> {
+        admit_call(self, "shell", "exec")?;
+        let timeout_ms = timeout_ms.unwrap_or(self.config.shell.default_timeout_ms);
+        if timeout_ms == 0 {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                "timeout must be greater than zero",
+            ));
+        }
+        charge_process_time(self)?;
+        self.resources.get(&handle).map_err(table_error)?;
+        if self.config.proc.dry_run {
+            return Err(dry_run_error(&command));
+        }
+        if let Some(reason) = self.config.policy.command_confirmation(&command) {
+            confirm(self, "shell.exec", &command, &reason)?;
+        }
+        check_untrusted(self, "shell.exec", &command, [command.as_str()])?;
+        notify::record_command(command.clone());
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        let started = Instant::now();
+        let result = blocking(|| session.exec(&command, Duration::from_millis(timeout_ms)));
+        self.budgets
+            .record(BudgetKind::ProcessMs, started.elapsed().as_millis() as u64);
+        result
+            .map(to_exec_result)
+            .map_err(|err| shell_error("shell.exec", err))
+    }
+
+    async fn close(&mut self, handle: Resource<ShellHandle>) {
+        let _ = delete_session(self, handle);
+    }
+
+    async fn drop(&mut self, handle: Resource<ShellHandle>) -> wasmtime::Result<()> {
+        let _ = delete_session(self, handle);
+        Ok(())
+    }
+}
+
 impl bindings::osagent::browser::browser::Host for HostState {
-    fn open_session(
+    async fn open_session(
         &mut self,
-        _options: bindings::osagent::browser::browser::SessionOptions,
-    ) -> Result<Resource<bindings::osagent::browser::browser::Session>, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        options: bindings::osagent::browser::browser::SessionOptions,
+    ) -> Result<Resource<BrowserHandle>, CapabilityError> {
+        ensure_capability_enabled(&self.config, "browser")?;
+        admit_call(self, "browser", "open_session")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let settings = self.config.browser.as_ref().ok_or_else(|| {
+            setting_error(
+                "browser capability is disabled: the config has no [browser] table",
+                "browser",
+            )
+        })?;
+        let options = browser::SessionOptions {
+            kind: options.kind.map(|kind| match kind {
+                bindings::osagent::browser::browser::BrowserKind::Chrome => BrowserKind::Chrome,
+                bindings::osagent::browser::browser::BrowserKind::Firefox => BrowserKind::Firefox,
+            }),
+            profile: options.profile.filter(|profile| !profile.trim().is_empty()),
+            headless: options.headless,
+            allow_downloads: options.allow_downloads,
+            record_har: options.record_har,
+            ..Default::default()
+        };
+        let session = BrowserSession::open(
+            settings,
+            &self.config.policy.urls,
+            options,
+            &self.config.workspace_root,
+            self.tokio.clone(),
+        )
+        .map_err(|err| browser_error("browser.open_session", err))?;
+        self.resources.push(session).map_err(table_error)
     }
 }
 
 impl bindings::osagent::browser::browser::HostSession for HostState {
-    fn close(&mut self, _rep: Resource<bindings::osagent::browser::browser::Session>) -> () {}
+    async fn close(&mut self, handle: Resource<BrowserHandle>) -> () {
+        if let Ok(session) = self.resources.get_mut(&handle) {
+            session.close();
+            let har = session.har_path().map(|path| path.to_path_buf());
+            if let Some(path) = har.filter(|path| path.exists()) {
+                self.changes.record(&path, ChangeKind::Created);
+            }
+        }
+    }
 
-    fn drop(
-        &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-    ) -> wasmtime::Result<()> {
+    async fn drop(&mut self, handle: Resource<BrowserHandle>) -> wasmtime::Result<()> {
+        self.resources.delete(handle)?;
         Ok(())
     }
 
-    fn goto(
+    async fn goto(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _url: wasmtime::component::__internal::String,
-        _timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+        handle: Resource<BrowserHandle>,
+        url: wasmtime::component::__internal::String,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
     ) -> Result<bindings::osagent::browser::browser::PageState, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        admit_call(self, "browser", "goto")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let timeout = timeout_ms.map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        session
+            .goto(&url, None, timeout)
+            .map(to_page_state)
+            .map_err(|err| browser_error("browser.goto", err))
+    }
+
+    async fn wait_for(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        condition: bindings::osagent::browser::browser::WaitCondition,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+    ) -> Result<bindings::osagent::browser::browser::PageState, CapabilityError> {
+        admit_call(self, "browser", "wait_for")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let condition = match condition {
+            bindings::osagent::browser::browser::WaitCondition::DocumentReady => {
+                WaitCondition::DocumentReady
+            }
+            bindings::osagent::browser::browser::WaitCondition::SelectorVisible(selector) => {
+                WaitCondition::SelectorVisible {
+                    selector: to_selector(selector),
+                }
+            }
+            bindings::osagent::browser::browser::WaitCondition::UrlMatches(pattern) => {
+                WaitCondition::UrlMatches { pattern }
+            }
+            bindings::osagent::browser::browser::WaitCondition::NetworkIdle => {
+                WaitCondition::NetworkIdle
+            }
+        };
+        let timeout = timeout_ms.map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        session
+            .wait_for(&condition, timeout)
+            .map(to_page_state)
+            .map_err(|err| browser_error("browser.wait_for", err))
     }
 
-    fn describe_page(
+    async fn describe_page(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _include_html: bool,
+        handle: Resource<BrowserHandle>,
+        include_html: bool,
+        include_outline: bool,
     ) -> Result<bindings::osagent::browser::browser::PageState, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        admit_call(self, "browser", "describe_page")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let page = session
+            .describe(include_html, include_outline)
+            .map_err(|err| browser_error("browser.describe_page", err))?;
+        for text in [&page.title, &page.html, &page.outline]
+            .into_iter()
+            .flatten()
+        {
+            self.untrusted.mark(text);
+        }
+        Ok(to_page_state(page))
     }
 
-    fn screenshot(
+    async fn screenshot(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _kind: bindings::osagent::browser::browser::ScreenshotKind,
+        handle: Resource<BrowserHandle>,
+        options: bindings::osagent::browser::browser::ScreenshotOptions,
     ) -> Result<bindings::osagent::browser::browser::Screenshot, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        admit_call(self, "browser", "screenshot")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let options = ScreenshotOptions {
+            kind: match options.kind {
+                bindings::osagent::browser::browser::ScreenshotKind::Png => ScreenshotKind::Png,
+                bindings::osagent::browser::browser::ScreenshotKind::Jpeg => ScreenshotKind::Jpeg,
+            },
+            quality: options.quality,
+            full_page: options.full_page,
+        };
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let data = session
+            .screenshot(&options)
+            .map_err(|err| browser_error("browser.screenshot", err))?;
+        Ok(bindings::osagent::browser::browser::Screenshot {
+            mime_type: options.kind.mime_type().to_string(),
+            data,
+        })
     }
 
-    fn eval(
+    async fn print_pdf(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _expression: wasmtime::component::__internal::String,
-    ) -> Result<bindings::osagent::common::types::Json, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<BrowserHandle>,
+        landscape: bool,
+        background: bool,
+    ) -> Result<bindings::osagent::common::types::Bytes, CapabilityError> {
+        admit_call(self, "browser", "print_pdf")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        session
+            .print_pdf(&PdfOptions {
+                landscape,
+                background,
+            })
+            .map_err(|err| browser_error("browser.print_pdf", err))
+    }
+
+    async fn wait_for_download(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "browser", "wait_for_download")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let timeout = timeout_ms.map_or(browser::DEFAULT_DOWNLOAD_TIMEOUT, Duration::from_millis);
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        let path = session
+            .wait_for_download(timeout)
+            .map_err(|err| browser_error("browser.wait_for_download", err))?;
+        self.changes.record(&path, ChangeKind::Created);
+        Ok(path
+            .strip_prefix(&self.config.workspace_root)
+            .unwrap_or(&path)
+            .to_string())
+    }
+
+    async fn list_tabs(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+    ) -> Result<Vec<bindings::osagent::browser::browser::TabInfo>, CapabilityError> {
+        admit_call(self, "browser", "list_tabs")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let tabs = session
+            .tabs()
+            .map_err(|err| browser_error("browser.list_tabs", err))?;
+        for title in tabs.iter().filter_map(|tab| tab.title.as_deref()) {
+            self.untrusted.mark(title);
+        }
+        Ok(tabs
+            .into_iter()
+            .map(|tab| bindings::osagent::browser::browser::TabInfo {
+                id: tab.id,
+                url: tab.url,
+                title: tab.title,
+                active: tab.active,
+            })
+            .collect())
+    }
+
+    async fn new_tab(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        url: Option<wasmtime::component::__internal::String>,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "browser", "new_tab")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let timeout = timeout_ms.map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        session
+            .new_tab(url.as_deref(), timeout)
+            .map_err(|err| browser_error("browser.new_tab", err))
+    }
+
+    async fn switch_tab(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        id: wasmtime::component::__internal::String,
+    ) -> Result<bindings::osagent::browser::browser::PageState, CapabilityError> {
+        admit_call(self, "browser", "switch_tab")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        session
+            .switch_tab(&id)
+            .map(to_page_state)
+            .map_err(|err| browser_error("browser.switch_tab", err))
+    }
+
+    async fn enter_frame(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        frame: bindings::osagent::browser::browser::FrameTarget,
+    ) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "enter_frame")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let target = match frame {
+            bindings::osagent::browser::browser::FrameTarget::Selector(selector) => {
+                FrameTarget::Selector(to_selector(selector))
+            }
+            bindings::osagent::browser::browser::FrameTarget::Index(index) => {
+                FrameTarget::Index(index)
+            }
+        };
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        session
+            .enter_frame(&target)
+            .map_err(|err| browser_error("browser.enter_frame", err))
     }
 
-    fn find(
+    async fn scroll_by(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _selector: bindings::osagent::browser::browser::Selector,
-        _timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
-    ) -> Result<Resource<bindings::osagent::browser::browser::ElementHandle>, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<BrowserHandle>,
+        dx: i32,
+        dy: i32,
+    ) -> Result<bindings::osagent::browser::browser::ScrollPosition, CapabilityError> {
+        admit_call(self, "browser", "scroll_by")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let position = session
+            .scroll_by(dx, dy)
+            .map_err(|err| browser_error("browser.scroll_by", err))?;
+        Ok(bindings::osagent::browser::browser::ScrollPosition {
+            x: position.x,
+            y: position.y,
+            max_y: position.max_y,
+        })
+    }
+
+    async fn exit_frame(&mut self, handle: Resource<BrowserHandle>) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "exit_frame")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get_mut(&handle).map_err(table_error)?;
+        session
+            .exit_frame()
+            .map_err(|err| browser_error("browser.exit_frame", err))
+    }
+
+    async fn save_state(&mut self, handle: Resource<BrowserHandle>) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "save_state")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let existed = session.state_path().is_some_and(|path| path.exists());
+        let path = session
+            .save_state()
+            .map_err(|err| browser_error("browser.save_state", err))?;
+        if path.starts_with(&self.config.workspace_root) {
+            self.changes.record_write(&path, existed);
+        }
+        Ok(())
     }
 
-    fn query_all(
+    async fn load_state(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::Session>,
-        _selector: bindings::osagent::browser::browser::Selector,
-    ) -> Result<
-        wasmtime::component::__internal::Vec<
-            Resource<bindings::osagent::browser::browser::ElementHandle>,
-        >,
-        CapabilityError,
-    > {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<BrowserHandle>,
+    ) -> Result<bool, CapabilityError> {
+        admit_call(self, "browser", "load_state")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        session
+            .load_state()
+            .map_err(|err| browser_error("browser.load_state", err))
+    }
+
+    async fn eval(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        expression: wasmtime::component::__internal::String,
+    ) -> Result<bindings::osagent::common::types::Json, CapabilityError> {
+        admit_call(self, "browser", "eval")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let value = session
+            .eval(&expression)
+            .map_err(|err| browser_error("browser.eval", err))?;
+        self.untrusted.mark_json(&value);
+        Ok(value.to_string())
+    }
+
+    async fn find(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        selector: bindings::osagent::browser::browser::Selector,
+        timeout_ms: Option<bindings::osagent::common::types::Milliseconds>,
+    ) -> Result<Resource<ElementHandle>, CapabilityError> {
+        admit_call(self, "browser", "find")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let timeout = timeout_ms.map_or(browser::DEFAULT_TIMEOUT, Duration::from_millis);
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let element = session
+            .find(&to_selector(selector), timeout)
+            .map_err(|err| browser_error("browser.find", err))?;
+        self.resources.push(element).map_err(table_error)
+    }
+
+    async fn query_all(
+        &mut self,
+        handle: Resource<BrowserHandle>,
+        selector: bindings::osagent::browser::browser::Selector,
+    ) -> Result<wasmtime::component::__internal::Vec<Resource<ElementHandle>>, CapabilityError>
+    {
+        admit_call(self, "browser", "query_all")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let session = self.resources.get(&handle).map_err(table_error)?;
+        let elements = session
+            .query_all(&to_selector(selector))
+            .map_err(|err| browser_error("browser.query_all", err))?;
+        elements
+            .into_iter()
+            .map(|element| self.resources.push(element).map_err(table_error))
+            .collect()
     }
 }
 
 impl bindings::osagent::browser::browser::HostElementHandle for HostState {
-    fn click(
+    async fn click(&mut self, handle: Resource<ElementHandle>) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "click")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        element
+            .click()
+            .map_err(|err| browser_error("browser.click", err))
+    }
+
+    async fn type_text(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
+        handle: Resource<ElementHandle>,
+        text: wasmtime::component::__internal::String,
+        submit: bool,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
-    }
-
-    fn type_text(
+        admit_call(self, "browser", "type_text")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        element
+            .type_text(&text, submit)
+            .map_err(|err| browser_error("browser.type_text", err))
+    }
+
+    async fn clear(&mut self, handle: Resource<ElementHandle>) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "clear")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        element
+            .clear()
+            .map_err(|err| browser_error("browser.clear", err))
+    }
+
+    async fn attribute(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
-        _text: wasmtime::component::__internal::String,
-        _submit: bool,
-    ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<ElementHandle>,
+        name: wasmtime::component::__internal::String,
+    ) -> Result<Option<wasmtime::component::__internal::String>, CapabilityError> {
+        admit_call(self, "browser", "attribute")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let value = element
+            .attribute(&name)
+            .map_err(|err| browser_error("browser.attribute", err))?;
+        if let Some(value) = &value {
+            self.untrusted.mark(value);
+        }
+        Ok(value)
     }
 
-    fn clear(
+    async fn inner_text(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
-    ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
-    }
-
-    fn attribute(
+        handle: Resource<ElementHandle>,
+    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "browser", "inner_text")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let text = element
+            .inner_text()
+            .map_err(|err| browser_error("browser.inner_text", err))?;
+        self.untrusted.mark(&text);
+        Ok(text)
+    }
+
+    async fn html(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
-        _name: wasmtime::component::__internal::String,
-    ) -> Result<Option<wasmtime::component::__internal::String>, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<ElementHandle>,
+    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "browser", "html")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let html = element
+            .html()
+            .map_err(|err| browser_error("browser.html", err))?;
+        self.untrusted.mark(&html);
+        Ok(html)
+    }
+
+    async fn screenshot(
+        &mut self,
+        handle: Resource<ElementHandle>,
+    ) -> Result<bindings::osagent::browser::browser::Screenshot, CapabilityError> {
+        admit_call(self, "browser", "screenshot")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let data = element
+            .screenshot()
+            .map_err(|err| browser_error("browser.element_screenshot", err))?;
+        Ok(bindings::osagent::browser::browser::Screenshot {
+            mime_type: ScreenshotKind::Png.mime_type().to_string(),
+            data,
+        })
     }
 
-    fn inner_text(
+    async fn scroll_into_view(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
-    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        handle: Resource<ElementHandle>,
+    ) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "scroll_into_view")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        element
+            .scroll_into_view()
+            .map_err(|err| browser_error("browser.scroll_into_view", err))
     }
 
-    fn html(
+    async fn select_option(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
+        handle: Resource<ElementHandle>,
+        choice: bindings::osagent::browser::browser::OptionChoice,
     ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "browser capability is not implemented",
-        ))
+        admit_call(self, "browser", "select_option")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let value = element
+            .select_option(&to_option_choice(choice))
+            .map_err(|err| browser_error("browser.select_option", err))?;
+        self.untrusted.mark(&value);
+        Ok(value)
+    }
+
+    async fn set_checked(
+        &mut self,
+        handle: Resource<ElementHandle>,
+        checked: bool,
+    ) -> Result<(), CapabilityError> {
+        admit_call(self, "browser", "set_checked")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        element
+            .set_checked(checked)
+            .map_err(|err| browser_error("browser.set_checked", err))
     }
 
-    fn drop(
+    async fn choose_radio(
         &mut self,
-        _rep: Resource<bindings::osagent::browser::browser::ElementHandle>,
-    ) -> wasmtime::Result<()> {
+        handle: Resource<ElementHandle>,
+        choice: bindings::osagent::browser::browser::OptionChoice,
+    ) -> Result<wasmtime::component::__internal::String, CapabilityError> {
+        admit_call(self, "browser", "choose_radio")?;
+        self.budgets
+            .claim(BudgetKind::BrowserActions, 1)
+            .map_err(budget_error)?;
+        let element = self.resources.get(&handle).map_err(table_error)?;
+        let value = element
+            .choose_radio(&to_option_choice(choice))
+            .map_err(|err| browser_error("browser.choose_radio", err))?;
+        self.untrusted.mark(&value);
+        Ok(value)
+    }
+
+    async fn drop(&mut self, handle: Resource<ElementHandle>) -> wasmtime::Result<()> {
+        self.resources.delete(handle)?;
         Ok(())
     }
 }
 
 impl bindings::osagent::input::input::Host for HostState {
-    fn key_sequence(
+    async fn key_sequence(
         &mut self,
-        _text: wasmtime::component::__internal::String,
+        text: wasmtime::component::__internal::String,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "input capability is not implemented",
-        ))
+        admit_call(self, "input", "key_sequence")?;
+        desktop(self, "input.key_sequence")?
+            .type_text(&text)
+            .map_err(|err| input_error("input.key_sequence", err))
     }
 
-    fn send_key_chord(
+    async fn send_key_chord(
         &mut self,
-        _chord: bindings::osagent::input::input::KeyChord,
+        chord: bindings::osagent::input::input::KeyChord,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "input capability is not implemented",
-        ))
+        admit_call(self, "input", "send_key_chord")?;
+        use bindings::osagent::input::input::KeyModifier;
+        let modifiers: Vec<Modifier> = chord
+            .modifiers
+            .iter()
+            .map(|modifier| match modifier {
+                KeyModifier::Shift => Modifier::Shift,
+                KeyModifier::Ctrl => Modifier::Ctrl,
+                KeyModifier::Alt => Modifier::Alt,
+                KeyModifier::Meta => Modifier::Meta,
+            })
+            .collect();
+        let hold = chord.hold_ms.map(Duration::from_millis);
+        desktop(self, "input.send_key_chord")?
+            .chord(&chord.key, &modifiers, hold)
+            .map_err(|err| input_error("input.send_key_chord", err))
+    }
+
+    async fn mouse_move(
+        &mut self,
+        motion: bindings::osagent::input::input::PointerMove,
+    ) -> Result<(), CapabilityError> {
+        admit_call(self, "input", "mouse_move")?;
+        desktop(self, "input.mouse_move")?
+            .move_pointer(motion.x, motion.y, motion.relative)
+            .map_err(|err| input_error("input.mouse_move", err))
     }
 
-    fn mouse_move(
+    async fn mouse_click(
         &mut self,
-        _motion: bindings::osagent::input::input::PointerMove,
+        button: bindings::osagent::input::input::MouseButton,
+        hold_ms: Option<bindings::osagent::common::types::Milliseconds>,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "input capability is not implemented",
-        ))
+        admit_call(self, "input", "mouse_click")?;
+        use bindings::osagent::input::input::MouseButton as WitButton;
+        let button = match button {
+            WitButton::Left => MouseButton::Left,
+            WitButton::Right => MouseButton::Right,
+            WitButton::Middle => MouseButton::Middle,
+        };
+        desktop(self, "input.mouse_click")?
+            .click(button, hold_ms.map(Duration::from_millis))
+            .map_err(|err| input_error("input.mouse_click", err))
     }
 
-    fn mouse_click(
+    async fn mouse_scroll(
         &mut self,
-        _button: bindings::osagent::input::input::MouseButton,
-        _hold_ms: Option<bindings::osagent::common::types::Milliseconds>,
+        delta: bindings::osagent::input::input::ScrollDelta,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "input capability is not implemented",
-        ))
+        admit_call(self, "input", "mouse_scroll")?;
+        desktop(self, "input.mouse_scroll")?
+            .scroll(delta.horizontal, delta.vertical)
+            .map_err(|err| input_error("input.mouse_scroll", err))
     }
 
-    fn mouse_scroll(
+    async fn play_macro(
         &mut self,
-        _delta: bindings::osagent::input::input::ScrollDelta,
+        name: wasmtime::component::__internal::String,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "input capability is not implemented",
+        admit_call(self, "input", "play_macro")?;
+        let desktop = desktop(self, "input.play_macro")?;
+        blocking(|| desktop.play(&name)).map_err(|err| input_error("input.play_macro", err))
+    }
+}
+
+/// The desktop connection, opened on the first input call once
+/// `input.enabled` allows it.
+fn desktop<'a>(state: &'a mut HostState, op: &str) -> Result<&'a mut Desktop, CapabilityError> {
+    ensure_capability_enabled(&state.config, "input")?;
+    if !state.config.input.enabled && !state.grants.allows("input", None) {
+        return Err(setting_error(
+            "input is disabled by input.enabled",
+            "input.enabled",
+        ));
+    }
+    let desktop = match state.desktop.take() {
+        Some(desktop) => desktop,
+        None => Desktop::open(&state.config.input).map_err(|err| input_error(op, err))?,
+    };
+    Ok(state.desktop.insert(desktop))
+}
+
+fn input_error(op: &str, err: InputError) -> CapabilityError {
+    let code = match err {
+        InputError::Connect(_) | InputError::Unsupported(_) => CapabilityErrorCode::Unavailable,
+        InputError::UnknownKey(_) | InputError::InvalidMacro(_) => {
+            CapabilityErrorCode::InvalidArgument
+        }
+        InputError::NoSuchMacro(_) => CapabilityErrorCode::NotFound,
+        InputError::HoldTooLong(_) | InputError::RateLimited(_) => CapabilityErrorCode::Limit,
+        InputError::Aborted | InputError::Forbidden(_) => CapabilityErrorCode::Denied,
+        InputError::Simulate(_) => CapabilityErrorCode::Internal,
+    };
+    capability_error(code, format!("{op} failed: {err}"))
+}
+
+impl bindings::osagent::screen::screen::Host for HostState {
+    async fn list_monitors(
+        &mut self,
+    ) -> Result<Vec<bindings::osagent::screen::screen::Monitor>, CapabilityError> {
+        admit_call(self, "screen", "list_monitors")?;
+        ensure_screen_enabled(self)?;
+        let monitors =
+            screen::list_monitors().map_err(|err| screen_error("screen.list_monitors", err))?;
+        Ok(monitors.into_iter().map(to_wit_monitor).collect())
+    }
+
+    async fn list_windows(
+        &mut self,
+    ) -> Result<Vec<bindings::osagent::screen::screen::WindowInfo>, CapabilityError> {
+        admit_call(self, "screen", "list_windows")?;
+        ensure_screen_enabled(self)?;
+        let windows =
+            screen::list_windows().map_err(|err| screen_error("screen.list_windows", err))?;
+        Ok(windows
+            .into_iter()
+            .map(|window| bindings::osagent::screen::screen::WindowInfo {
+                id: window.id,
+                title: window.title,
+                geometry: to_wit_rect(window.geometry),
+            })
+            .collect())
+    }
+
+    async fn capture_screen(
+        &mut self,
+        monitor: Option<wasmtime::component::__internal::String>,
+    ) -> Result<bindings::osagent::screen::screen::Capture, CapabilityError> {
+        admit_call(self, "screen", "capture_screen")?;
+        ensure_screen_enabled(self)?;
+        let capture = screen::capture_screen(monitor.as_deref())
+            .map_err(|err| screen_error("screen.capture_screen", err))?;
+        Ok(to_wit_capture(capture))
+    }
+
+    async fn capture_window(
+        &mut self,
+        id: u64,
+    ) -> Result<bindings::osagent::screen::screen::Capture, CapabilityError> {
+        admit_call(self, "screen", "capture_window")?;
+        ensure_screen_enabled(self)?;
+        let capture =
+            screen::capture_window(id).map_err(|err| screen_error("screen.capture_window", err))?;
+        Ok(to_wit_capture(capture))
+    }
+}
+
+fn ensure_screen_enabled(state: &HostState) -> Result<(), CapabilityError> {
+    ensure_capability_enabled(&state.config, "screen")?;
+    if state.config.screen.enabled || state.grants.allows("screen", None) {
+        Ok(())
+    } else {
+        Err(setting_error(
+            "screen capture is disabled by screen.enabled",
+            "screen.enabled",
         ))
     }
 }
 
+fn screen_error(op: &str, err: ScreenError) -> CapabilityError {
+    let code = match err {
+        ScreenError::Connect(_) | ScreenError::Unsupported => CapabilityErrorCode::Unavailable,
+        ScreenError::NoSuchMonitor(_) | ScreenError::NoSuchWindow(_) => {
+            CapabilityErrorCode::NotFound
+        }
+        ScreenError::Offscreen(_) => CapabilityErrorCode::Conflict,
+        ScreenError::Capture(_) => CapabilityErrorCode::Internal,
+    };
+    capability_error(code, format!("{op} failed: {err}"))
+}
+
+fn to_wit_rect(rect: screen::Rect) -> bindings::osagent::screen::screen::Rect {
+    bindings::osagent::screen::screen::Rect {
+        x: rect.x,
+        y: rect.y,
+        width: rect.width,
+        height: rect.height,
+    }
+}
+
+fn to_wit_monitor(monitor: screen::Monitor) -> bindings::osagent::screen::screen::Monitor {
+    bindings::osagent::screen::screen::Monitor {
+        name: monitor.name,
+        geometry: to_wit_rect(monitor.geometry),
+        primary: monitor.primary,
+    }
+}
+
+fn to_wit_capture(capture: screen::Capture) -> bindings::osagent::screen::screen::Capture {
+    bindings::osagent::screen::screen::Capture {
+        png: capture.png,
+        geometry: to_wit_rect(capture.geometry),
+        monitors: capture.monitors.into_iter().map(to_wit_monitor).collect(),
+    }
+}
+
 impl bindings::osagent::llm::llm::Host for HostState {
-    fn complete(
+    async fn complete(
         &mut self,
         messages: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::Message>,
         options: bindings::osagent::llm::llm::Options,
     ) -> Result<bindings::osagent::llm::llm::CompletionResponse, CapabilityError> {
+        admit_call(self, "llm", "complete")?;
         let settings = require_llm_settings(&self.config)?;
-        let request = build_chat_request(&settings.model, messages, options, None);
-        let response = execute_chat_request(settings, &request)?;
-        let usage = convert_usage(response.usage);
-        let mut choices = response.choices.into_iter();
-        let choice = choices.next().ok_or_else(|| {
-            capability_error(
-                CapabilityErrorCode::Internal,
-                "llm response missing choices",
-            )
-        })?;
-        let content = choice.message.content.ok_or_else(|| {
+        let settings = llm_profile(settings, options.profile.as_deref())?;
+        let mut options = to_llm_options(options)?;
+        admit_llm_request(&self.budgets, &mut options)?;
+        let messages = to_llm_messages(messages);
+        let result = blocking(|| llm::chat_logged(settings, &messages, None, &options));
+        if let Some(transcript) = &mut self.llm_transcript {
+            transcript.record("llm.complete", settings, &messages, None, &options, &result);
+        }
+        let completion = result.map_err(|err| llm_error("llm.complete", err))?;
+        record_llm_usage(self, completion.tokens_used(llm::prompt_chars(&messages)));
+        let content = completion.content.ok_or_else(|| {
             capability_error(
                 CapabilityErrorCode::Internal,
                 "llm response missing content",
@@ -1121,36 +2651,59 @@ impl bindings::osagent::llm::llm::Host for HostState {
         })?;
         Ok(bindings::osagent::llm::llm::CompletionResponse {
             content,
-            finish_reason: choice.finish_reason,
-            usage,
+            finish_reason: completion.finish_reason,
+            usage: to_wit_usage(completion.usage),
         })
     }
 
-    fn call_tools(
+    async fn stream_complete(
+        &mut self,
+        messages: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::Message>,
+        options: bindings::osagent::llm::llm::Options,
+    ) -> Result<Resource<CompletionStreamHandle>, CapabilityError> {
+        admit_call(self, "llm", "stream_complete")?;
+        let settings = require_llm_settings(&self.config)?;
+        let settings = llm_profile(settings, options.profile.as_deref())?;
+        let mut options = to_llm_options(options)?;
+        admit_llm_request(&self.budgets, &mut options)?;
+        let stream = CompletionStream::start(settings.clone(), to_llm_messages(messages), options);
+        self.resources.push(stream).map_err(table_error)
+    }
+
+    async fn call_tools(
         &mut self,
         messages: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::Message>,
         tools: wasmtime::component::__internal::Vec<bindings::osagent::llm::llm::ToolSchema>,
         options: bindings::osagent::llm::llm::Options,
     ) -> Result<bindings::osagent::llm::llm::ToolResponse, CapabilityError> {
+        admit_call(self, "llm", "call_tools")?;
         let settings = require_llm_settings(&self.config)?;
-        let chat_tools = tools_to_chat(tools)?;
-        let request = build_chat_request(&settings.model, messages, options, Some(chat_tools));
-        let response = execute_chat_request(settings, &request)?;
-        let usage = convert_usage(response.usage);
-        let mut choices = response.choices.into_iter();
-        let choice = choices.next().ok_or_else(|| {
-            capability_error(
-                CapabilityErrorCode::Internal,
-                "llm response missing choices",
-            )
-        })?;
-        if choice.message.tool_calls.is_empty() {
+        let settings = llm_profile(settings, options.profile.as_deref())?;
+        let tools = to_llm_tools(tools)?;
+        let mut options = to_llm_options(options)?;
+        admit_llm_request(&self.budgets, &mut options)?;
+        let messages = to_llm_messages(messages);
+        let result = blocking(|| llm::chat_logged(settings, &messages, Some(&tools), &options));
+        if let Some(transcript) = &mut self.llm_transcript {
+            let tools = Some(tools.as_slice());
+            transcript.record(
+                "llm.call_tools",
+                settings,
+                &messages,
+                tools,
+                &options,
+                &result,
+            );
+        }
+        let completion = result.map_err(|err| llm_error("llm.call_tools", err))?;
+        record_llm_usage(self, completion.tokens_used(llm::prompt_chars(&messages)));
+        if completion.tool_calls.is_empty() {
             return Err(capability_error(
                 CapabilityErrorCode::Internal,
                 "llm response missing tool calls",
             ));
         }
-        let tool_calls_json = serde_json::to_string(&choice.message.tool_calls).map_err(|err| {
+        let tool_calls_json = serde_json::to_string(&completion.tool_calls).map_err(|err| {
             capability_error(
                 CapabilityErrorCode::Internal,
                 format!("failed to encode tool calls: {err}"),
@@ -1158,50 +2711,376 @@ impl bindings::osagent::llm::llm::Host for HostState {
         })?;
         Ok(bindings::osagent::llm::llm::ToolResponse {
             tool_calls_json,
-            finish_reason: choice.finish_reason,
-            usage,
+            finish_reason: completion.finish_reason,
+            usage: to_wit_usage(completion.usage),
         })
     }
+
+    async fn embed(
+        &mut self,
+        texts: wasmtime::component::__internal::Vec<String>,
+        profile: Option<String>,
+    ) -> Result<Vec<Vec<f32>>, CapabilityError> {
+        admit_call(self, "llm", "embed")?;
+        let settings = require_llm_settings(&self.config)?;
+        let settings = llm_profile(settings, profile.as_deref())?;
+        self.budgets
+            .check(BudgetKind::LlmTokens)
+            .map_err(budget_error)?;
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let embeddings =
+            blocking(|| llm::embed(settings, &texts)).map_err(|err| llm_error("llm.embed", err))?;
+        record_llm_usage(self, embeddings.tokens_used(&texts));
+        Ok(embeddings.vectors)
+    }
+}
+
+impl bindings::osagent::llm::llm::HostCompletionStream for HostState {
+    async fn next(
+        &mut self,
+        handle: Resource<CompletionStreamHandle>,
+    ) -> Option<wasmtime::component::__internal::String> {
+        let stream = self.resources.get_mut(&handle).ok()?;
+        let text = blocking(|| stream.next());
+        if let Some(tokens) = stream.take_tokens_used() {
+            record_llm_usage(self, tokens);
+        }
+        text
+    }
+
+    async fn finish(
+        &mut self,
+        handle: Resource<CompletionStreamHandle>,
+    ) -> Result<bindings::osagent::llm::llm::CompletionResponse, CapabilityError> {
+        let stream = self.resources.get_mut(&handle).map_err(table_error)?;
+        let result = blocking(|| stream.finish());
+        if let Some(tokens) = stream.take_tokens_used() {
+            record_llm_usage(self, tokens);
+        }
+        let completion = result.map_err(|err| llm_error("llm.stream_complete", err))?;
+        Ok(bindings::osagent::llm::llm::CompletionResponse {
+            content: completion.content.unwrap_or_default(),
+            finish_reason: completion.finish_reason,
+            usage: to_wit_usage(completion.usage),
+        })
+    }
+
+    /// A reply that already ended still counts against the budget; one
+    /// dropped mid-stream is not waited for.
+    async fn drop(&mut self, handle: Resource<CompletionStreamHandle>) -> wasmtime::Result<()> {
+        let mut stream = self.resources.delete(handle)?;
+        stream.drain_ready();
+        if let Some(tokens) = stream.take_tokens_used() {
+            record_llm_usage(self, tokens);
+        }
+        Ok(())
+    }
+}
+
+/// Interfaces the current config and grants let the guest use at all, then
+/// `skill.<name>` for each loaded skill.
+pub fn enabled_capabilities(config: &HostConfig, grants: &Grants) -> Vec<String> {
+    let enabled = [
+        ("fs", config.is_capability_enabled("fs")),
+        ("archive", config.is_capability_enabled("archive")),
+        (
+            "proc",
+            config.is_capability_enabled("proc") && !config.policy.commands.is_empty(),
+        ),
+        ("shell", capability_allowed(config, grants, "shell", None)),
+        (
+            "browser",
+            config.is_capability_enabled("browser") && config.browser.is_some(),
+        ),
+        ("input", capability_allowed(config, grants, "input", None)),
+        ("screen", capability_allowed(config, grants, "screen", None)),
+        ("llm", config.llm.is_some()),
+        ("policy", true),
+    ];
+    enabled
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name.to_string())
+        .chain(
+            config
+                .skills
+                .iter()
+                .map(|skill| format!("skill.{}", skill.name)),
+        )
+        .collect()
+}
+
+/// Whether the config or an operator grant lets the guest use one of the
+/// grantable capabilities; `proc` is asked about the command in `scope`.
+fn capability_allowed(
+    config: &HostConfig,
+    grants: &Grants,
+    capability: &str,
+    scope: Option<&str>,
+) -> bool {
+    if !config.is_capability_enabled(capability) {
+        return false;
+    }
+    match capability {
+        "proc" => scope.is_some_and(|program| {
+            config.policy.is_proc_allowed(program) || grants.allows_command(program)
+        }),
+        "shell" => config.shell.enabled || grants.allows("shell", None),
+        "input" => config.input.enabled || grants.allows("input", None),
+        "screen" => config.screen.enabled || grants.allows("screen", None),
+        _ => false,
+    }
+}
+
+fn grant_response(
+    approved: bool,
+    reason: &str,
+) -> bindings::osagent::policy::policy::GrantResponse {
+    bindings::osagent::policy::policy::GrantResponse {
+        approved,
+        reason: Some(reason.to_string()),
+    }
+}
+
+fn command_rule(config: &HostConfig, name: &str) -> bindings::osagent::policy::policy::CommandRule {
+    let mut notes = Vec::new();
+    if config.policy.arg_rules.contains_key(name) {
+        notes.push("arguments checked against proc.args");
+    }
+    if config.proc.dry_run {
+        notes.push("dry run: reported, not executed");
+    }
+    bindings::osagent::policy::policy::CommandRule {
+        name: name.to_string(),
+        description: (!notes.is_empty()).then(|| notes.join("; ")),
+    }
+}
+
+fn from_wit_budget_kind(kind: bindings::osagent::policy::policy::BudgetKind) -> BudgetKind {
+    use bindings::osagent::policy::policy::BudgetKind as Wit;
+    match kind {
+        Wit::Steps => BudgetKind::Steps,
+        Wit::WallclockMs => BudgetKind::WallclockMs,
+        Wit::LlmTokens => BudgetKind::LlmTokens,
+        Wit::Processes => BudgetKind::Processes,
+        Wit::ProcessMs => BudgetKind::ProcessMs,
+        Wit::FilesystemOps => BudgetKind::FilesystemOps,
+        Wit::BytesWritten => BudgetKind::BytesWritten,
+        Wit::BrowserActions => BudgetKind::BrowserActions,
+    }
+}
+
+fn budget_snapshot(
+    budgets: &Budgets,
+    kind: BudgetKind,
+) -> bindings::osagent::policy::policy::BudgetSnapshot {
+    use bindings::osagent::policy::policy::BudgetKind as Wit;
+    let wit_kind = match kind {
+        BudgetKind::Steps => Wit::Steps,
+        BudgetKind::WallclockMs => Wit::WallclockMs,
+        BudgetKind::LlmTokens => Wit::LlmTokens,
+        BudgetKind::Processes => Wit::Processes,
+        BudgetKind::ProcessMs => Wit::ProcessMs,
+        BudgetKind::FilesystemOps => Wit::FilesystemOps,
+        BudgetKind::BytesWritten => Wit::BytesWritten,
+        BudgetKind::BrowserActions => Wit::BrowserActions,
+    };
+    bindings::osagent::policy::policy::BudgetSnapshot {
+        kind: wit_kind,
+        limit: budgets.limit(kind),
+        used: budgets.used(kind),
+    }
 }
 
 impl bindings::osagent::policy::policy::Host for HostState {
-    fn describe(
+    async fn describe(
         &mut self,
     ) -> Result<bindings::osagent::policy::policy::PolicySnapshot, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "policy capability is not implemented",
-        ))
+        admit_call(self, "policy", "describe")?;
+        let elapsed = self.processes.take_run_time();
+        self.budgets
+            .record(BudgetKind::ProcessMs, elapsed.as_millis() as u64);
+        let config = &self.config;
+        let workspace = bindings::osagent::policy::policy::WorkspaceRule {
+            label: config.workspace_root.to_string(),
+            relative_root: ".".to_string(),
+            read_only: false,
+            max_file_bytes: Some(config.fs.max_file_bytes),
+        };
+        let browser = config.browser.as_ref().map(|settings| {
+            let allowed_hosts = match &settings.allowed_domains {
+                Some(domains) => domains.domains().to_vec(),
+                None => vec!["*".to_string()],
+            };
+            bindings::osagent::policy::policy::BrowserRule {
+                allowed_hosts,
+                allow_screenshots: true,
+                allow_file_uploads: false,
+            }
+        });
+        let budgets = BudgetKind::ALL
+            .into_iter()
+            .map(|kind| budget_snapshot(&self.budgets, kind))
+            .collect();
+        Ok(bindings::osagent::policy::policy::PolicySnapshot {
+            capabilities: enabled_capabilities(config, &self.grants),
+            workspaces: vec![workspace],
+            commands: config
+                .policy
+                .commands
+                .iter()
+                .map(|name| command_rule(config, name))
+                .collect(),
+            browser,
+            budgets,
+        })
     }
 
-    fn claim_budget(
+    async fn claim_budget(
         &mut self,
-        _kind: bindings::osagent::policy::policy::BudgetKind,
-        _units: u64,
+        kind: bindings::osagent::policy::policy::BudgetKind,
+        units: u64,
     ) -> Result<bindings::osagent::policy::policy::BudgetSnapshot, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "policy capability is not implemented",
-        ))
+        admit_call(self, "policy", "claim_budget")?;
+        let kind = from_wit_budget_kind(kind);
+        if kind == BudgetKind::ProcessMs {
+            charge_process_time(self)?;
+        }
+        self.budgets.claim(kind, units).map_err(budget_error)?;
+        Ok(budget_snapshot(&self.budgets, kind))
     }
 
-    fn request_capability(
+    /// Blocks until the operator answers on the terminal. Only `proc` takes
+    /// a scope: the command to allow.
+    async fn request_capability(
         &mut self,
-        _request: bindings::osagent::policy::policy::GrantRequest,
+        request: bindings::osagent::policy::policy::GrantRequest,
     ) -> Result<bindings::osagent::policy::policy::GrantResponse, CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "policy capability is not implemented",
+        admit_call(self, "policy", "request_capability")?;
+        let capability = request.capability.trim();
+        if !grant::GRANTABLE.contains(&capability) {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                format!(
+                    "`{capability}` cannot be granted at run time (expected one of {})",
+                    grant::GRANTABLE.join(", ")
+                ),
+            ));
+        }
+        let scope = match capability {
+            "proc" => request
+                .scope
+                .as_deref()
+                .map(str::trim)
+                .filter(|scope| !scope.is_empty()),
+            _ => None,
+        };
+        if capability == "proc" && scope.is_none() {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                "proc requests must name the command as their scope",
+            ));
+        }
+        if !self.config.is_capability_enabled(capability) {
+            return Ok(grant_response(
+                false,
+                "the capability is not in enabled_capabilities",
+            ));
+        }
+        if capability_allowed(&self.config, &self.grants, capability, scope) {
+            return Ok(grant_response(true, "already allowed"));
+        }
+        if !self.config.policy.grant_prompts {
+            return Ok(grant_response(
+                false,
+                "capability requests are denied by policy",
+            ));
+        }
+        let pending = grant::Pending {
+            kind: "capability",
+            capability,
+            scope,
+            detail: &request.justification,
+        };
+        let answer = match blocking(|| grant::approve(&self.config, &pending)) {
+            Ok(answer) => answer,
+            Err(reason) => return Ok(grant_response(false, &reason)),
+        };
+        self.grants.insert(capability, scope, answer.decision);
+        let reason = match answer.decision {
+            GrantDecision::Deny => "denied by the reviewer",
+            GrantDecision::Step => "approved for this step",
+            GrantDecision::Session => "approved for this task",
+        };
+        let reason = answer.reason.unwrap_or_else(|| reason.to_string());
+        Ok(grant_response(
+            answer.decision != GrantDecision::Deny,
+            &reason,
         ))
     }
 
-    fn log_event(
+    async fn log_event(
         &mut self,
-        _event: bindings::osagent::common::types::AuditEvent,
+        event: bindings::osagent::common::types::AuditEvent,
     ) -> Result<(), CapabilityError> {
-        Err(capability_error(
-            CapabilityErrorCode::Denied,
-            "policy capability is not implemented",
-        ))
+        admit_call(self, "policy", "log_event")?;
+        if event.event_type.trim().is_empty() {
+            return Err(capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                "audit events need an event type",
+            ));
+        }
+        let payload: Value = serde_json::from_str(&event.payload).map_err(|err| {
+            capability_error(
+                CapabilityErrorCode::InvalidArgument,
+                format!("audit payload is not valid JSON: {err}"),
+            )
+        })?;
+        if !audit::enabled() {
+            debug!(event = event.event_type, %payload, "audit event (no policy.audit_dir)");
+            return Ok(());
+        }
+        audit::record("guest", &event.event_type, event.step, payload);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn untimed_wait_leaves_child_lock_free() {
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        let mut process = ProcessResource {
+            command: "sleep".to_string(),
+            child: Arc::new(Mutex::new(child)),
+            stdin: None,
+            stdout: OutputBuffer::closed(),
+            stderr: OutputBuffer::closed(),
+            status: None,
+            watchdog: None,
+            cgroup: None,
+            container: None,
+        };
+        let child = Arc::clone(&process.child);
+        let waiter = thread::spawn(move || {
+            let waited = wait_for_exit(&mut process, None).is_ok();
+            (waited, process.status)
+        });
+        // A blocking `wait` would hold the lock until `sleep` exits, so
+        // `proc.kill` could not reach it.
+        let locked = (0..100).find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            child.try_lock().ok().map(|mut child| child.kill())
+        });
+        assert!(matches!(locked, Some(Ok(()))));
+        let (waited, status) = waiter.join().unwrap();
+        assert!(waited);
+        assert!(status.is_some_and(|status| !status.success()));
     }
 }
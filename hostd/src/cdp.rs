@@ -0,0 +1,1140 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as Base64};
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::audit;
+use crate::browser::{
+    BrowserError, DomainAllowlist, ElementBackend, FrameTarget, POLL_INTERVAL, PageState,
+    PdfOptions, ScreenshotOptions, Selector, SessionBackend, SessionOptions, TabInfo,
+    cookie_params,
+};
+use crate::config::BrowserSettings;
+use crate::deadline;
+use crate::har::NetworkLog;
+use crate::process;
+
+/// Tried in order when `browser.chrome_path` is unset.
+const CHROME_CANDIDATES: &[&str] = &[
+    "google-chrome",
+    "google-chrome-stable",
+    "chromium",
+    "chromium-browser",
+];
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+static NEXT_PROFILE: AtomicU64 = AtomicU64::new(0);
+
+type Reply = Result<Value, String>;
+
+/// The pipe to one Chrome process, shared by its session and elements.
+struct Connection {
+    outgoing: Arc<Outgoing>,
+    pending: Arc<Mutex<HashMap<u64, Sender<Reply>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+/// The command half of the pipe, which the reader thread also writes to
+/// when it answers events.
+struct Outgoing {
+    writer: Mutex<File>,
+    next_id: AtomicU64,
+}
+
+impl Outgoing {
+    /// Writes a command without waiting for its reply, returning its id.
+    fn send(&self, session: Option<&str>, method: &str, params: Value) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut message = json!({ "id": id, "method": method, "params": params });
+        if let Some(session) = session {
+            message["sessionId"] = Value::String(session.to_string());
+        }
+        let mut bytes = message.to_string().into_bytes();
+        bytes.push(0);
+        lock(&self.writer).write_all(&bytes)?;
+        Ok(id)
+    }
+}
+
+/// What the reader thread does with events; see [`on_event`].
+#[derive(Default)]
+struct EventHandlers {
+    /// Screens the requests of every target that enables interception.
+    allowlist: Option<DomainAllowlist>,
+    /// Records the traffic of every target that enables `Network`.
+    network: Option<Arc<NetworkLog>>,
+    /// The launching task's audit log, which blocked requests go to.
+    audit: audit::Handle,
+}
+
+impl Connection {
+    fn new(writer: File, reader: File, handlers: EventHandlers) -> Self {
+        let pending: Arc<Mutex<HashMap<u64, Sender<Reply>>>> = Arc::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let outgoing = Arc::new(Outgoing {
+            writer: Mutex::new(writer),
+            next_id: AtomicU64::new(1),
+        });
+        {
+            let pending = Arc::clone(&pending);
+            let closed = Arc::clone(&closed);
+            let outgoing = Arc::clone(&outgoing);
+            thread::spawn(move || read_messages(reader, &pending, &closed, &outgoing, &handlers));
+        }
+        Self {
+            outgoing,
+            pending,
+            closed,
+        }
+    }
+
+    /// Sends `method` to the target attached as `session`, or to the browser
+    /// itself, and waits up to `timeout` for the reply.
+    fn call(
+        &self,
+        session: Option<&str>,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value, BrowserError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(BrowserError::Closed);
+        }
+        let (sender, reply) = mpsc::channel();
+        // Registered under the lock so the reply cannot arrive first.
+        let id = {
+            let mut pending = lock(&self.pending);
+            let id = self
+                .outgoing
+                .send(session, method, params)
+                .map_err(|_| BrowserError::Closed)?;
+            pending.insert(id, sender);
+            id
+        };
+        let timeout = deadline::bound(timeout);
+        match reply.recv_timeout(timeout) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(message)) => Err(BrowserError::Protocol(format!("{method}: {message}"))),
+            Err(RecvTimeoutError::Disconnected) => Err(BrowserError::Closed),
+            Err(RecvTimeoutError::Timeout) => {
+                lock(&self.pending).remove(&id);
+                Err(BrowserError::Timeout(timeout))
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Routes replies to their callers until Chrome closes the pipe. Events
+/// other than those [`on_event`] handles are dropped.
+fn read_messages(
+    mut reader: impl Read,
+    pending: &Mutex<HashMap<u64, Sender<Reply>>>,
+    closed: &AtomicBool,
+    outgoing: &Outgoing,
+    handlers: &EventHandlers,
+) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+        while let Some(end) = buffer.iter().position(|&byte| byte == 0) {
+            let message: Vec<u8> = buffer.drain(..=end).collect();
+            let Ok(message) = serde_json::from_slice::<Value>(&message[..end]) else {
+                continue;
+            };
+            let Some(id) = message.get("id").and_then(Value::as_u64) else {
+                on_event(outgoing, handlers, &message);
+                continue;
+            };
+            let reply = match message.get("error") {
+                Some(error) => Err(error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error")
+                    .to_string()),
+                None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+            };
+            if let Some(sender) = lock(pending).remove(&id) {
+                let _ = sender.send(reply);
+            }
+        }
+    }
+    closed.store(true, Ordering::Release);
+    lock(pending).clear();
+}
+
+/// Records `Network.*` events for a HAR, and enforces
+/// `browser.allowed_domains`: every target the browser attaches to starts
+/// paused, enables request interception and auto-attaches its own children
+/// (frames, workers) before resuming, so no request escapes screening. Runs
+/// on the reader thread, so it only sends commands and never waits for
+/// their replies.
+fn on_event(outgoing: &Outgoing, handlers: &EventHandlers, message: &Value) {
+    let params = &message["params"];
+    let session = message["sessionId"].as_str();
+    let method = message["method"].as_str().unwrap_or_default();
+    if method.starts_with("Network.") {
+        if let Some(network) = &handlers.network {
+            network.record(session, method, params);
+        }
+        return;
+    }
+    let Some(allowlist) = &handlers.allowlist else {
+        return;
+    };
+    let sent = match method {
+        "Fetch.requestPaused" => {
+            let request_id = params["requestId"].clone();
+            let url = params["request"]["url"].as_str().unwrap_or_default();
+            if allowlist.allows(url) {
+                outgoing.send(
+                    session,
+                    "Fetch.continueRequest",
+                    json!({ "requestId": request_id }),
+                )
+            } else {
+                warn!(target: "audit", url, "blocked browser request outside browser.allowed_domains");
+                handlers.audit.record(
+                    "host",
+                    "browser_request_blocked",
+                    None,
+                    json!({ "url": url, "rule": "browser.allowed_domains" }),
+                );
+                outgoing.send(
+                    session,
+                    "Fetch.failRequest",
+                    json!({ "requestId": request_id, "errorReason": "BlockedByClient" }),
+                )
+            }
+        }
+        "Target.attachedToTarget" => {
+            let Some(child) = params["sessionId"].as_str() else {
+                return;
+            };
+            let child = Some(child);
+            let network = match handlers.network {
+                Some(_) => outgoing.send(child, "Network.enable", json!({})),
+                None => Ok(0),
+            };
+            network
+                .and_then(|_| {
+                    outgoing.send(
+                        child,
+                        "Fetch.enable",
+                        json!({ "patterns": [{ "urlPattern": "*" }] }),
+                    )
+                })
+                .and_then(|_| {
+                    outgoing.send(
+                        child,
+                        "Target.setAutoAttach",
+                        json!({ "autoAttach": true, "waitForDebuggerOnStart": true, "flatten": true }),
+                    )
+                })
+                .and_then(|_| outgoing.send(child, "Runtime.runIfWaitingForDebugger", json!({})))
+        }
+        _ => return,
+    };
+    if let Err(err) = sent {
+        debug!("failed to answer devtools event: {err}");
+    }
+}
+
+/// A Chrome process launched for one session, with a single page target.
+/// Chrome is started with `--remote-debugging-pipe`, which speaks the
+/// DevTools protocol as NUL-terminated JSON over fds 3 (commands) and 4
+/// (replies and events), so no WebDriver server is involved.
+pub struct CdpSession {
+    connection: Arc<Connection>,
+    /// The active tab's target, and the CDP session page commands go to.
+    target_id: String,
+    page_session: String,
+    /// Sessions of tabs and frames attached to so far, by target id.
+    attached: HashMap<String, String>,
+    /// Whether `browser.allowed_domains` is enforced on this browser.
+    intercept: bool,
+    /// Whether attached targets report their traffic for a HAR.
+    record_network: bool,
+    /// Frames entered in the active tab, innermost last. Cleared when the
+    /// tab navigates or another tab becomes active.
+    frames: Mutex<Vec<FrameContext>>,
+    chrome: Option<Child>,
+    profile_dir: PathBuf,
+}
+
+/// Where scripts for an entered frame run: a frame in the page's own
+/// process gets an isolated world of its own, while a cross-site frame
+/// runs out of process and is reached through its own target's session.
+#[derive(Debug, Clone)]
+struct FrameContext {
+    session: String,
+    context_id: Option<i64>,
+}
+
+impl CdpSession {
+    /// With `network`, every tab's requests are recorded into it.
+    pub fn launch(
+        settings: &BrowserSettings,
+        options: &SessionOptions,
+        network: Option<Arc<NetworkLog>>,
+    ) -> Result<Self, BrowserError> {
+        let profile_dir = std::env::temp_dir().join(format!(
+            "wasi-warden-chrome-{}-{}",
+            std::process::id(),
+            NEXT_PROFILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Created fresh and private: a directory someone else made first at
+        // this predictable path could plant or read the browser's state.
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&profile_dir).map_err(|err| {
+            BrowserError::Launch(format!(
+                "failed to create chrome profile dir {}: {err}",
+                profile_dir.display()
+            ))
+        })?;
+        let record_network = network.is_some();
+        let (chrome, connection) = match spawn_chrome(settings, options, &profile_dir, network) {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                let _ = fs::remove_dir_all(&profile_dir);
+                return Err(err);
+            }
+        };
+        let mut session = Self {
+            connection: Arc::new(connection),
+            target_id: String::new(),
+            page_session: String::new(),
+            attached: HashMap::new(),
+            intercept: settings.allowed_domains.is_some(),
+            record_network,
+            frames: Mutex::default(),
+            chrome: Some(chrome),
+            profile_dir,
+        };
+        if session.intercept {
+            // Pages opened from now on, popups included, start paused until
+            // interception is on.
+            session.connection.call(
+                None,
+                "Target.setAutoAttach",
+                json!({ "autoAttach": true, "waitForDebuggerOnStart": true, "flatten": true }),
+                COMMAND_TIMEOUT,
+            )?;
+        }
+        let target_id = match session.page_targets()?.first() {
+            Some(target) => string_field(target, "targetId")?,
+            None => session.create_target()?,
+        };
+        session.switch_tab(&target_id)?;
+        let behavior = match &options.download_dir {
+            Some(dir) => json!({ "behavior": "allow", "downloadPath": dir.as_str() }),
+            None => json!({ "behavior": "deny" }),
+        };
+        session.connection.call(
+            None,
+            "Browser.setDownloadBehavior",
+            behavior,
+            COMMAND_TIMEOUT,
+        )?;
+        Ok(session)
+    }
+
+    /// The browser's tabs, as CDP `TargetInfo`s.
+    fn page_targets(&self) -> Result<Vec<Value>, BrowserError> {
+        let result = self
+            .connection
+            .call(None, "Target.getTargets", json!({}), COMMAND_TIMEOUT)?;
+        Ok(result["targetInfos"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|target| target["type"] == "page")
+            .cloned()
+            .collect())
+    }
+
+    fn create_target(&self) -> Result<String, BrowserError> {
+        let created = self.connection.call(
+            None,
+            "Target.createTarget",
+            json!({ "url": "about:blank" }),
+            COMMAND_TIMEOUT,
+        )?;
+        string_field(&created, "targetId")
+    }
+
+    /// Attaches to a tab in flattened mode, returning the CDP session id
+    /// used for its page-level commands.
+    fn attach(&mut self, target_id: &str) -> Result<String, BrowserError> {
+        if let Some(session) = self.attached.get(target_id) {
+            return Ok(session.clone());
+        }
+        let attached = self.connection.call(
+            None,
+            "Target.attachToTarget",
+            json!({ "targetId": target_id, "flatten": true }),
+            COMMAND_TIMEOUT,
+        )?;
+        let session = string_field(&attached, "sessionId")?;
+        if self.record_network {
+            self.connection
+                .call(Some(&session), "Network.enable", json!({}), COMMAND_TIMEOUT)?;
+        }
+        if self.intercept {
+            self.connection.call(
+                Some(&session),
+                "Fetch.enable",
+                json!({ "patterns": [{ "urlPattern": "*" }] }),
+                COMMAND_TIMEOUT,
+            )?;
+        }
+        self.attached.insert(target_id.to_string(), session.clone());
+        Ok(session)
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, BrowserError> {
+        self.connection
+            .call(Some(&self.page_session), method, params, COMMAND_TIMEOUT)
+    }
+
+    fn frames(&self) -> MutexGuard<'_, Vec<FrameContext>> {
+        self.frames
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The innermost entered frame, or the tab's top-level document.
+    fn frame(&self) -> FrameContext {
+        self.frames()
+            .last()
+            .cloned()
+            .unwrap_or_else(|| FrameContext {
+                session: self.page_session.clone(),
+                context_id: None,
+            })
+    }
+
+    /// Evaluates `expression` in the current frame and returns the raw
+    /// remote object.
+    fn evaluate(&self, expression: &str, return_by_value: bool) -> Result<Value, BrowserError> {
+        let frame = self.frame();
+        let mut params = json!({
+            "expression": expression,
+            "returnByValue": return_by_value,
+            "awaitPromise": true,
+        });
+        if let Some(context_id) = frame.context_id {
+            params["contextId"] = json!(context_id);
+        }
+        let result = self.connection.call(
+            Some(&frame.session),
+            "Runtime.evaluate",
+            params,
+            COMMAND_TIMEOUT,
+        )?;
+        remote_result(result)
+    }
+
+    /// Evaluates `expression`, returning the remote object rather than its
+    /// value.
+    fn evaluate_object(&self, expression: &str) -> Result<Value, BrowserError> {
+        self.evaluate(expression, false)
+    }
+
+    fn element(&self, object_id: String) -> Box<dyn ElementBackend> {
+        Box::new(CdpElement {
+            connection: Arc::clone(&self.connection),
+            page_session: self.frame().session,
+            object_id,
+        })
+    }
+}
+
+impl SessionBackend for CdpSession {
+    fn navigate(&self, url: &str, timeout: Duration) -> Result<(), BrowserError> {
+        self.frames().clear();
+        let result = self.connection.call(
+            Some(&self.page_session),
+            "Page.navigate",
+            json!({ "url": url }),
+            timeout,
+        )?;
+        match result.get("errorText").and_then(Value::as_str) {
+            Some(error) => Err(BrowserError::Protocol(format!(
+                "navigation to {url} failed: {error}"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn describe(&self, include_html: bool) -> Result<PageState, BrowserError> {
+        let state = self.eval(&format!(
+            "({{ url: location.href, title: document.title, \
+             html: {include_html} ? document.documentElement.outerHTML : null }})"
+        ))?;
+        Ok(PageState {
+            url: state["url"].as_str().unwrap_or_default().to_string(),
+            title: state["title"]
+                .as_str()
+                .filter(|title| !title.is_empty())
+                .map(str::to_string),
+            html: state["html"].as_str().map(str::to_string),
+            outline: None,
+        })
+    }
+
+    fn print_pdf(&self, options: &PdfOptions) -> Result<Vec<u8>, BrowserError> {
+        let result = self.call(
+            "Page.printToPDF",
+            json!({ "landscape": options.landscape, "printBackground": options.background }),
+        )?;
+        Base64
+            .decode(string_field(&result, "data")?)
+            .map_err(|err| BrowserError::Protocol(format!("invalid pdf data: {err}")))
+    }
+
+    fn screenshot(&self, options: &ScreenshotOptions) -> Result<Vec<u8>, BrowserError> {
+        let metrics = if options.full_page {
+            Some(self.call("Page.getLayoutMetrics", json!({}))?)
+        } else {
+            None
+        };
+        let result = self.call(
+            "Page.captureScreenshot",
+            options.capture_params(metrics.as_ref()),
+        )?;
+        Base64
+            .decode(string_field(&result, "data")?)
+            .map_err(|err| BrowserError::Protocol(format!("invalid screenshot data: {err}")))
+    }
+
+    fn eval(&self, expression: &str) -> Result<Value, BrowserError> {
+        Ok(self
+            .evaluate(expression, true)?
+            .get("value")
+            .cloned()
+            .unwrap_or(Value::Null))
+    }
+
+    fn find(
+        &self,
+        selector: &Selector,
+        timeout: Duration,
+    ) -> Result<Box<dyn ElementBackend>, BrowserError> {
+        let expression = format!("({})[0] ?? null", selector.query_expression());
+        let deadline = Instant::now() + timeout;
+        loop {
+            let object = self.evaluate_object(&expression)?;
+            if let Some(object_id) = object.get("objectId").and_then(Value::as_str) {
+                return Ok(self.element(object_id.to_string()));
+            }
+            if Instant::now() >= deadline {
+                return Err(BrowserError::NotFound(selector.to_string()));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn query_all(&self, selector: &Selector) -> Result<Vec<Box<dyn ElementBackend>>, BrowserError> {
+        let array = self.evaluate_object(&selector.query_expression())?;
+        let array_id = string_field(&array, "objectId")?;
+        let properties = self.connection.call(
+            Some(&self.frame().session),
+            "Runtime.getProperties",
+            json!({ "objectId": array_id, "ownProperties": true }),
+            COMMAND_TIMEOUT,
+        )?;
+        let mut elements: Vec<(usize, String)> = properties["result"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|property| {
+                let index = property["name"].as_str()?.parse().ok()?;
+                let object_id = property["value"]["objectId"].as_str()?;
+                Some((index, object_id.to_string()))
+            })
+            .collect();
+        elements.sort_by_key(|(index, _)| *index);
+        Ok(elements
+            .into_iter()
+            .map(|(_, object_id)| self.element(object_id))
+            .collect())
+    }
+
+    fn cookies(&self) -> Result<Vec<Value>, BrowserError> {
+        let result = self.call("Network.getAllCookies", json!({}))?;
+        Ok(result["cookies"].as_array().cloned().unwrap_or_default())
+    }
+
+    fn set_cookies(&self, cookies: &[Value]) -> Result<(), BrowserError> {
+        self.call(
+            "Network.setCookies",
+            json!({ "cookies": cookie_params(cookies) }),
+        )?;
+        Ok(())
+    }
+
+    fn tabs(&self) -> Result<Vec<TabInfo>, BrowserError> {
+        self.page_targets()?
+            .iter()
+            .map(|target| {
+                let id = string_field(target, "targetId")?;
+                Ok(TabInfo {
+                    active: id == self.target_id,
+                    url: target["url"].as_str().unwrap_or_default().to_string(),
+                    title: target["title"]
+                        .as_str()
+                        .filter(|title| !title.is_empty())
+                        .map(str::to_string),
+                    id,
+                })
+            })
+            .collect()
+    }
+
+    fn new_tab(&mut self) -> Result<String, BrowserError> {
+        let target_id = self.create_target()?;
+        self.switch_tab(&target_id)?;
+        Ok(target_id)
+    }
+
+    fn switch_tab(&mut self, id: &str) -> Result<(), BrowserError> {
+        let known = self
+            .page_targets()?
+            .iter()
+            .any(|target| target["targetId"] == id);
+        if !known {
+            self.attached.remove(id);
+            return Err(BrowserError::NoSuchTab(id.to_string()));
+        }
+        self.page_session = self.attach(id)?;
+        self.target_id = id.to_string();
+        self.frames().clear();
+        self.connection.call(
+            None,
+            "Target.activateTarget",
+            json!({ "targetId": id }),
+            COMMAND_TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    fn enter_frame(&mut self, target: &FrameTarget) -> Result<(), BrowserError> {
+        let expression = match target {
+            FrameTarget::Selector(selector) => {
+                format!("({})[0] ?? null", selector.query_expression())
+            }
+            FrameTarget::Index(index) => {
+                format!("document.querySelectorAll('iframe, frame')[{index}] ?? null")
+            }
+        };
+        let object = self.evaluate_object(&expression)?;
+        let object_id = object
+            .get("objectId")
+            .and_then(Value::as_str)
+            .ok_or_else(|| BrowserError::NotFound(target.to_string()))?;
+        let parent = self.frame();
+        let node = self.connection.call(
+            Some(&parent.session),
+            "DOM.describeNode",
+            json!({ "objectId": object_id }),
+            COMMAND_TIMEOUT,
+        )?;
+        let frame_id = node["node"]["frameId"]
+            .as_str()
+            .ok_or_else(|| BrowserError::Script(format!("{target} is not a frame")))?;
+        let world = self.connection.call(
+            Some(&parent.session),
+            "Page.createIsolatedWorld",
+            json!({ "frameId": frame_id, "worldName": "wasi-warden" }),
+            COMMAND_TIMEOUT,
+        );
+        let frame = match world {
+            Ok(world) => FrameContext {
+                session: parent.session,
+                context_id: world["executionContextId"].as_i64(),
+            },
+            // The parent's process does not host the frame; it is its own
+            // target, whose id is the frame id.
+            Err(_) => FrameContext {
+                session: self.attach(frame_id)?,
+                context_id: None,
+            },
+        };
+        self.frames().push(frame);
+        Ok(())
+    }
+
+    fn exit_frame(&mut self) -> Result<(), BrowserError> {
+        self.frames().pop();
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let Some(mut chrome) = self.chrome.take() else {
+            return;
+        };
+        let _ = self.connection.call(
+            None,
+            "Browser.close",
+            Value::Object(Default::default()),
+            Duration::from_secs(2),
+        );
+        // Chrome's helper processes share its group; take them down too.
+        let _ = process::kill_group(chrome.id());
+        let _ = chrome.wait();
+        process::untrack_group(chrome.id());
+        if let Err(err) = fs::remove_dir_all(&self.profile_dir) {
+            debug!(dir = %self.profile_dir.display(), "failed to remove chrome profile: {err}");
+        }
+    }
+}
+
+impl Drop for CdpSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// An element held as a remote object id in the page's JS heap.
+struct CdpElement {
+    connection: Arc<Connection>,
+    page_session: String,
+    object_id: String,
+}
+
+impl CdpElement {
+    /// Calls `function` with the element as `this` and returns its value.
+    fn call_function(&self, function: &str, args: &[Value]) -> Result<Value, BrowserError> {
+        let arguments: Vec<Value> = args.iter().map(|arg| json!({ "value": arg })).collect();
+        let result = self.dispatch(
+            "Runtime.callFunctionOn",
+            json!({
+                "objectId": self.object_id,
+                "functionDeclaration": function,
+                "arguments": arguments,
+                "returnByValue": true,
+                "awaitPromise": true,
+            }),
+        )?;
+        Ok(remote_result(result)?
+            .get("value")
+            .cloned()
+            .unwrap_or(Value::Null))
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> Result<Value, BrowserError> {
+        self.connection
+            .call(Some(&self.page_session), method, params, COMMAND_TIMEOUT)
+    }
+
+    fn string_function(&self, function: &str) -> Result<String, BrowserError> {
+        Ok(self
+            .call_function(function, &[])?
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    }
+}
+
+impl ElementBackend for CdpElement {
+    fn click(&self) -> Result<(), BrowserError> {
+        self.call_function(
+            "function() { this.scrollIntoView({ block: 'center', inline: 'center' }); this.click(); }",
+            &[],
+        )?;
+        Ok(())
+    }
+
+    fn type_text(&self, text: &str, submit: bool) -> Result<(), BrowserError> {
+        self.call_function("function() { this.focus(); }", &[])?;
+        self.dispatch("Input.insertText", json!({ "text": text }))?;
+        if submit {
+            for kind in ["keyDown", "keyUp"] {
+                self.dispatch(
+                    "Input.dispatchKeyEvent",
+                    json!({
+                        "type": kind,
+                        "key": "Enter",
+                        "code": "Enter",
+                        "windowsVirtualKeyCode": 13,
+                        "text": if kind == "keyDown" { "\r" } else { "" },
+                    }),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), BrowserError> {
+        self.call_function(
+            "function() {
+                if ('value' in this) {
+                    this.value = '';
+                    this.dispatchEvent(new Event('input', { bubbles: true }));
+                    this.dispatchEvent(new Event('change', { bubbles: true }));
+                } else if (this.isContentEditable) {
+                    this.textContent = '';
+                }
+            }",
+            &[],
+        )?;
+        Ok(())
+    }
+
+    fn attribute(&self, name: &str) -> Result<Option<String>, BrowserError> {
+        let value = self.call_function(
+            "function(name) { return this.getAttribute(name); }",
+            &[Value::String(name.to_string())],
+        )?;
+        Ok(value.as_str().map(str::to_string))
+    }
+
+    fn inner_text(&self) -> Result<String, BrowserError> {
+        self.string_function("function() { return this.innerText ?? this.textContent ?? ''; }")
+    }
+
+    fn html(&self) -> Result<String, BrowserError> {
+        self.string_function("function() { return this.outerHTML; }")
+    }
+
+    fn call(&self, function: &str, args: &[Value]) -> Result<Value, BrowserError> {
+        self.call_function(function, args)
+    }
+
+    /// Clips a page capture to the element's box, which CDP measures from
+    /// the document origin rather than the viewport.
+    fn screenshot(&self) -> Result<Vec<u8>, BrowserError> {
+        let rect = self.call_function(
+            "function() {
+                this.scrollIntoView({ block: 'center', inline: 'center' });
+                const rect = this.getBoundingClientRect();
+                let x = rect.x, y = rect.y, view = window;
+                // Inside same-origin frames, offset by each frame's position.
+                while (view.frameElement) {
+                    const frame = view.frameElement;
+                    const box = frame.getBoundingClientRect();
+                    x += box.x + frame.clientLeft;
+                    y += box.y + frame.clientTop;
+                    view = view.parent;
+                }
+                return { x: x + view.scrollX, y: y + view.scrollY,
+                         width: rect.width, height: rect.height };
+            }",
+            &[],
+        )?;
+        let width = rect["width"].as_f64().unwrap_or_default();
+        let height = rect["height"].as_f64().unwrap_or_default();
+        if width < 1.0 || height < 1.0 {
+            return Err(BrowserError::Script(
+                "element has no visible box to capture".to_string(),
+            ));
+        }
+        let result = self.dispatch(
+            "Page.captureScreenshot",
+            json!({
+                "format": "png",
+                "clip": {
+                    "x": rect["x"],
+                    "y": rect["y"],
+                    "width": width,
+                    "height": height,
+                    "scale": 1,
+                },
+            }),
+        )?;
+        Base64
+            .decode(string_field(&result, "data")?)
+            .map_err(|err| BrowserError::Protocol(format!("invalid screenshot data: {err}")))
+    }
+}
+
+/// Unwraps a `Runtime.evaluate`/`callFunctionOn` result, turning a thrown
+/// exception into an error.
+fn remote_result(mut result: Value) -> Result<Value, BrowserError> {
+    if let Some(details) = result.get("exceptionDetails") {
+        let message = details["exception"]["description"]
+            .as_str()
+            .or_else(|| details["text"].as_str())
+            .unwrap_or("uncaught exception");
+        return Err(BrowserError::Script(message.to_string()));
+    }
+    Ok(result["result"].take())
+}
+
+fn string_field(value: &Value, field: &str) -> Result<String, BrowserError> {
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| BrowserError::Protocol(format!("reply is missing `{field}`")))
+}
+
+fn chrome_args(options: &SessionOptions, profile_dir: &std::path::Path) -> Vec<String> {
+    let mut args = vec![
+        "--remote-debugging-pipe".to_string(),
+        format!("--user-data-dir={}", profile_dir.display()),
+        "--no-first-run".to_string(),
+        "--no-default-browser-check".to_string(),
+        "--disable-dev-shm-usage".to_string(),
+        "--no-sandbox".to_string(),
+    ];
+    if options.headless {
+        args.extend(["--headless=new".to_string(), "--disable-gpu".to_string()]);
+    }
+    args.push("about:blank".to_string());
+    args
+}
+
+#[cfg(unix)]
+fn spawn_chrome(
+    settings: &BrowserSettings,
+    options: &SessionOptions,
+    profile_dir: &std::path::Path,
+    network: Option<Arc<NetworkLog>>,
+) -> Result<(Child, Connection), BrowserError> {
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+
+    let candidates: Vec<&str> = match settings.chrome_path.as_deref() {
+        Some(path) => vec![path],
+        None => CHROME_CANDIDATES.to_vec(),
+    };
+    let launch_error =
+        |err: io::Error| BrowserError::Launch(format!("failed to start chrome: {err}"));
+    for program in candidates {
+        // Chrome reads commands from fd 3 and writes replies to fd 4.
+        let (commands_read, commands_write) = io::pipe().map_err(launch_error)?;
+        let (replies_read, replies_write) = io::pipe().map_err(launch_error)?;
+        let child_in = high_fd(OwnedFd::from(commands_read)).map_err(launch_error)?;
+        let child_out = high_fd(OwnedFd::from(replies_write)).map_err(launch_error)?;
+        let (child_in_fd, child_out_fd) = (child_in.as_raw_fd(), child_out.as_raw_fd());
+
+        let mut command = Command::new(program);
+        command
+            .args(chrome_args(options, profile_dir))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        process::isolate_group(&mut command);
+        // SAFETY: dup2 is async-signal-safe; both sources are above fd 4, so
+        // neither is clobbered before it is copied, and dup2 clears cloexec
+        // on the copies.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::dup2(child_in_fd, 3) < 0 || libc::dup2(child_out_fd, 4) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        match command.spawn() {
+            Ok(child) => {
+                process::track_group(child.id());
+                drop((child_in, child_out));
+                let connection = Connection::new(
+                    File::from(OwnedFd::from(commands_write)),
+                    File::from(OwnedFd::from(replies_read)),
+                    EventHandlers {
+                        allowlist: settings.allowed_domains.clone(),
+                        network: network.clone(),
+                        audit: audit::handle(),
+                    },
+                );
+                return Ok((child, connection));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(launch_error(err)),
+        }
+    }
+    Err(BrowserError::Launch(
+        "no chrome binary found; set browser.chrome_path".to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn spawn_chrome(
+    _settings: &BrowserSettings,
+    _options: &SessionOptions,
+    _profile_dir: &std::path::Path,
+    _network: Option<Arc<NetworkLog>>,
+) -> Result<(Child, Connection), BrowserError> {
+    Err(BrowserError::Unsupported(
+        "the cdp browser backend is only supported on unix hosts".to_string(),
+    ))
+}
+
+/// Moves `fd` to a number above 4 (close-on-exec) so the child's dup2 onto
+/// fds 3 and 4 cannot overwrite it.
+#[cfg(unix)]
+fn high_fd(fd: std::os::fd::OwnedFd) -> io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    // SAFETY: F_DUPFD_CLOEXEC returns a new descriptor we take ownership of;
+    // the original is closed when `fd` drops.
+    let duplicate = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 5) };
+    if duplicate < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(duplicate) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+
+    /// Hands out at most `chunk` bytes per read, so messages arrive split.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn outgoing() -> Outgoing {
+        Outgoing {
+            writer: Mutex::new(tempfile::tempfile().unwrap()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// The NUL-terminated messages written so far.
+    fn sent(outgoing: &Outgoing) -> Vec<Value> {
+        let mut file = lock(&outgoing.writer);
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes.last(), Some(&0), "every message ends with NUL");
+        bytes
+            .split(|&byte| byte == 0)
+            .filter(|message| !message.is_empty())
+            .map(|message| serde_json::from_slice(message).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frames_commands_with_nul_and_session() {
+        let outgoing = outgoing();
+        let first = outgoing.send(None, "Target.getTargets", json!({})).unwrap();
+        let second = outgoing
+            .send(Some("S1"), "Page.navigate", json!({ "url": "about:blank" }))
+            .unwrap();
+        assert_eq!((first, second), (1, 2));
+        let messages = sent(&outgoing);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["method"], "Target.getTargets");
+        assert!(messages[0].get("sessionId").is_none());
+        assert_eq!(messages[1]["id"], 2);
+        assert_eq!(messages[1]["sessionId"], "S1");
+        assert_eq!(messages[1]["params"]["url"], "about:blank");
+    }
+
+    #[test]
+    fn routes_split_replies_to_their_callers() {
+        let pending = Mutex::new(HashMap::new());
+        let (first_sender, first) = mpsc::channel();
+        let (second_sender, second) = mpsc::channel();
+        lock(&pending).insert(1, first_sender);
+        lock(&pending).insert(2, second_sender);
+        let stream = b"{\"id\":2,\"result\":{\"value\":\"two\"}}\0\
+            not json\0\
+            {\"method\":\"Page.loadEventFired\",\"params\":{}}\0\
+            {\"id\":9,\"result\":{}}\0\
+            {\"id\":1,\"error\":{\"message\":\"boom\"}}\0";
+        let closed = AtomicBool::new(false);
+
+        read_messages(
+            Trickle {
+                data: stream,
+                chunk: 5,
+            },
+            &pending,
+            &closed,
+            &outgoing(),
+            &EventHandlers::default(),
+        );
+
+        assert_eq!(second.try_recv().unwrap(), Ok(json!({ "value": "two" })));
+        assert_eq!(first.try_recv().unwrap(), Err("boom".to_string()));
+        assert!(closed.load(Ordering::Acquire));
+        assert!(lock(&pending).is_empty());
+    }
+
+    #[test]
+    fn closing_pipe_disconnects_waiting_callers() {
+        let pending = Mutex::new(HashMap::new());
+        let (sender, reply) = mpsc::channel();
+        lock(&pending).insert(7, sender);
+        let closed = AtomicBool::new(false);
+
+        read_messages(
+            io::empty(),
+            &pending,
+            &closed,
+            &outgoing(),
+            &EventHandlers::default(),
+        );
+
+        assert!(closed.load(Ordering::Acquire));
+        assert_eq!(reply.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn fails_paused_requests_outside_allowlist() {
+        let outgoing = outgoing();
+        let handlers = EventHandlers {
+            allowlist: Some(DomainAllowlist::new(["example.com".to_string()])),
+            ..EventHandlers::default()
+        };
+        for (request, url) in [("r1", "https://example.com/"), ("r2", "https://evil.test/")] {
+            on_event(
+                &outgoing,
+                &handlers,
+                &json!({
+                    "method": "Fetch.requestPaused",
+                    "sessionId": "S1",
+                    "params": { "requestId": request, "request": { "url": url } },
+                }),
+            );
+        }
+        let messages = sent(&outgoing);
+        assert_eq!(messages[0]["method"], "Fetch.continueRequest");
+        assert_eq!(messages[0]["params"]["requestId"], "r1");
+        assert_eq!(messages[1]["method"], "Fetch.failRequest");
+        assert_eq!(messages[1]["params"]["requestId"], "r2");
+        assert_eq!(messages[1]["sessionId"], "S1");
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use tracing::debug;
+
+const CPU_PERIOD_US: u64 = 100_000;
+
+static NEXT_CGROUP: AtomicU64 = AtomicU64::new(0);
+
+/// Resource limits applied through a transient cgroup v2 per spawn. `parent`
+/// must be a cgroup delegated to this user with no processes of its own, so
+/// the cpu and memory controllers can be enabled for its children.
+#[derive(Debug, Clone)]
+pub struct CgroupLimits {
+    pub parent: Utf8PathBuf,
+    /// CPU time as a number of cores, e.g. `1.5`.
+    pub cpu_quota: Option<f64>,
+    pub memory_limit_mb: Option<u64>,
+}
+
+/// Transient cgroup holding one spawned process tree; removed on drop once
+/// the tree has exited.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: Utf8PathBuf,
+}
+
+impl Cgroup {
+    /// Creates a cgroup under `limits.parent` and arranges for the child to
+    /// join it before exec, so every process it forks is accounted too.
+    pub fn attach(cmd: &mut Command, limits: &CgroupLimits) -> Result<Self> {
+        enable_controllers(limits)?;
+        let name = format!(
+            "proc-{}-{}",
+            std::process::id(),
+            NEXT_CGROUP.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = limits.parent.join(name);
+        fs::create_dir(path.as_std_path())
+            .with_context(|| format!("failed to create cgroup {path}"))?;
+        let cgroup = Self { path };
+        if let Some(mb) = limits.memory_limit_mb {
+            cgroup.write("memory.max", &(mb * 1024 * 1024).to_string())?;
+            // Kill the whole tree on OOM rather than one arbitrary member.
+            cgroup.write("memory.oom.group", "1")?;
+        }
+        if let Some(cores) = limits.cpu_quota {
+            let quota = (cores * CPU_PERIOD_US as f64) as u64;
+            cgroup.write("cpu.max", &format!("{quota} {CPU_PERIOD_US}"))?;
+        }
+        cgroup.join_before_exec(cmd)?;
+        Ok(cgroup)
+    }
+
+    /// Whether the kernel OOM-killed anything in the cgroup.
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events").as_std_path())
+            .map(|events| {
+                events.lines().any(|line| {
+                    line.strip_prefix("oom_kill ")
+                        .and_then(|count| count.trim().parse::<u64>().ok())
+                        .is_some_and(|count| count > 0)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        let path = self.path.join(file);
+        fs::write(path.as_std_path(), value).with_context(|| format!("failed to write {path}"))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn join_before_exec(&self, cmd: &mut Command) -> Result<()> {
+        use std::fs::OpenOptions;
+        use std::io;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::process::CommandExt;
+
+        let path = self.path.join("cgroup.procs");
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.as_std_path())
+            .with_context(|| format!("failed to open {path}"))?;
+        // SAFETY: a single write(2) of a static buffer to a descriptor opened
+        // in the parent; nothing is allocated between fork and exec. Writing
+        // "0" moves the writing process itself.
+        unsafe {
+            cmd.pre_exec(move || {
+                let written = libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1);
+                if written == 1 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn join_before_exec(&self, _cmd: &mut Command) -> Result<()> {
+        anyhow::bail!("cgroup limits are only supported on Linux")
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir(self.path.as_std_path()) {
+            debug!(cgroup = %self.path, error = %err, "failed to remove cgroup");
+        }
+    }
+}
+
+fn enable_controllers(limits: &CgroupLimits) -> Result<()> {
+    let mut controllers = Vec::new();
+    if limits.cpu_quota.is_some() {
+        controllers.push("+cpu");
+    }
+    if limits.memory_limit_mb.is_some() {
+        controllers.push("+memory");
+    }
+    let control = limits.parent.join("cgroup.subtree_control");
+    fs::write(control.as_std_path(), controllers.join(" "))
+        .with_context(|| format!("failed to enable cgroup controllers in {control}"))
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+/// Workspace paths touched by the agent, collapsed to one net change per path.
+#[derive(Debug, Default, Clone)]
+pub struct ChangeSet {
+    entries: BTreeMap<Utf8PathBuf, ChangeKind>,
+}
+
+impl ChangeSet {
+    pub fn record(&mut self, path: &Utf8Path, kind: ChangeKind) {
+        let merged = match (self.entries.get(path).copied(), kind) {
+            (None, kind) => Some(kind),
+            (Some(ChangeKind::Created), ChangeKind::Deleted) => None,
+            (Some(ChangeKind::Created), _) => Some(ChangeKind::Created),
+            (Some(ChangeKind::Deleted), ChangeKind::Created) => Some(ChangeKind::Modified),
+            (Some(_), kind) => Some(kind),
+        };
+        match merged {
+            Some(kind) => {
+                self.entries.insert(path.to_path_buf(), kind);
+            }
+            None => {
+                self.entries.remove(path);
+            }
+        }
+    }
+
+    /// Records a write to `path`, classifying it by whether it existed beforehand.
+    pub fn record_write(&mut self, path: &Utf8Path, existed: bool) {
+        let kind = if existed {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        };
+        self.record(path, kind);
+    }
+
+    pub fn merge(&mut self, other: &ChangeSet) {
+        for (path, kind) in &other.entries {
+            self.record(path, *kind);
+        }
+    }
+
+    pub fn take(&mut self) -> ChangeSet {
+        std::mem::take(self)
+    }
+
+    /// Renders entries with paths relative to `root` for observations and summaries.
+    pub fn to_json(&self, root: &Utf8Path) -> Value {
+        let files: Vec<Value> = self
+            .entries
+            .iter()
+            .map(|(path, kind)| {
+                let display = path.strip_prefix(root).unwrap_or(path);
+                json!({ "path": display.as_str(), "change": kind })
+            })
+            .collect();
+        Value::Array(files)
+    }
+}
@@ -1,4 +1,6 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{ArgAction, Parser, Subcommand};
 
@@ -14,6 +16,14 @@ pub struct Cli {
 pub enum Commands {
     /// Run a single planning step with the configured component.
     Step(StepArgs),
+    /// Run a task to completion and print the planner's result as JSON.
+    Run(StepArgs),
+    /// Serve an HTTP API that runs submitted tasks one at a time.
+    Serve(ServeArgs),
+    /// Speak line-delimited JSON-RPC on stdin/stdout, for editors and apps that embed hostd.
+    Stdio(StdioArgs),
+    /// Record keyboard and mouse input into a macro agents can replay.
+    RecordInput(RecordInputArgs),
 }
 
 #[derive(clap::Args, Debug)]
@@ -30,9 +40,17 @@ pub struct StepArgs {
     #[arg(long)]
     pub workspace: Option<PathBuf>,
 
-    /// Human task description supplied to the planner.
-    #[arg(long)]
-    pub task: String,
+    /// Human task description supplied to the planner; a saved session supplies its own.
+    #[arg(long, required_unless_present_any = ["session", "resume"])]
+    pub task: Option<String>,
+
+    /// Save progress as session ID, continuing it if it exists. `step` then runs one planner step.
+    #[arg(long, value_name = "ID")]
+    pub session: Option<String>,
+
+    /// Continue session ID where a crashed or interrupted run stopped; unlike --session, it must exist.
+    #[arg(long, value_name = "ID", conflicts_with = "session")]
+    pub resume: Option<String>,
 
     /// JSON observation from the previous step.
     #[arg(long, default_value = "{}")]
@@ -45,4 +63,142 @@ pub struct StepArgs {
     /// Commands the proc capability may execute (repeat flag to allow multiple, overrides config).
     #[arg(long = "allow-proc", value_name = "CMD", action = ArgAction::Append)]
     pub allow_proc: Vec<String>,
+
+    /// Snapshot the workspace first and restore it if the task fails.
+    #[arg(long)]
+    pub rollback_on_failure: bool,
+
+    /// Report the commands proc.spawn and shell sessions would run without running them.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Confirm that browser sessions may drive the already-open browser named by browser.attach.
+    #[arg(long)]
+    pub allow_browser_attach: bool,
+
+    /// Send every LLM request to the provider even when llm.cache is on.
+    #[arg(long)]
+    pub no_llm_cache: bool,
+
+    /// Planner steps the task may take (overrides policy.budgets.steps).
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_steps: Option<u64>,
+
+    /// Time the task may run, e.g. `90s`, `15m` or `2h`; a bare number is seconds
+    /// (overrides policy.budgets.wallclock_ms).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_wall_clock: Option<Duration>,
+
+    /// Planner actions the task may run in all (overrides planner.max_actions).
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_actions: Option<u64>,
+
+    /// Stop after this many steps in a row in which every action failed
+    /// (overrides planner.max_failed_steps).
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_failed_steps: Option<u32>,
+
+    /// Reload the component before a step whenever the file changed, for iterating on agent-core.
+    #[arg(long)]
+    pub watch: bool,
+
+    /// A directory inside the workspace to use as the task's workspace, for queued tasks.
+    #[arg(skip)]
+    pub workspace_subdir: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on. Keep it on loopback or behind a TLS proxy: the token travels in clear.
+    #[arg(long, default_value = "127.0.0.1:8787")]
+    pub listen: SocketAddr,
+
+    /// Bearer token every API request must send as `Authorization: Bearer <token>`.
+    #[arg(long, env = "HOSTD_API_TOKEN", hide_env_values = true)]
+    pub token: String,
+
+    /// Slack app signing secret; puts approve/deny buttons on `[notify]` Slack posts, answered at `/slack/actions`.
+    #[arg(long, env = "HOSTD_SLACK_SIGNING_SECRET", hide_env_values = true)]
+    pub slack_signing_secret: Option<String>,
+
+    /// Also serve the gRPC API in proto/warden/v1/control.proto on this address.
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    pub grpc_listen: Option<SocketAddr>,
+
+    /// Tasks that may run at the same time; later ones wait in the queue.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent_tasks: u64,
+
+    #[command(flatten)]
+    pub host: TaskHostArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StdioArgs {
+    #[command(flatten)]
+    pub host: TaskHostArgs,
+}
+
+/// What every task submitted to `serve` or `stdio` runs with.
+#[derive(clap::Args, Debug)]
+pub struct TaskHostArgs {
+    /// Path to the compiled agent-core component (.wasm/.cwasm).
+    #[arg(long, default_value = "./target/wasm32-wasip2/release/agent_core.wasm")]
+    pub component: PathBuf,
+
+    /// Path to the host configuration file (TOML) every task runs under.
+    #[arg(long, default_value = "hostd.toml")]
+    pub config: PathBuf,
+
+    /// Confirm that browser sessions may drive the already-open browser named by browser.attach.
+    #[arg(long)]
+    pub allow_browser_attach: bool,
+}
+
+impl StepArgs {
+    /// The session named by `--session` or `--resume`.
+    pub fn session_id(&self) -> Option<&str> {
+        self.session.as_deref().or(self.resume.as_deref())
+    }
+}
+
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("`{value}` does not start with a number"))?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.saturating_mul(60)),
+        "h" => Duration::from_secs(number.saturating_mul(3600)),
+        _ => return Err(format!("unknown unit `{unit}`; use ms, s, m or h")),
+    };
+    if duration.is_zero() {
+        return Err("must be greater than zero".to_string());
+    }
+    Ok(duration)
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RecordInputArgs {
+    /// Name to save the macro under (letters, digits, `-` and `_`).
+    pub name: String,
+
+    /// Path to a host configuration file (TOML), read for input.macro_dir.
+    #[arg(long, default_value = "hostd.toml")]
+    pub config: PathBuf,
+
+    /// Key that ends the recording without being recorded (F1-F12, Escape, Pause or ScrollLock).
+    #[arg(long, default_value = "F12")]
+    pub stop_key: String,
+
+    /// Replace an existing macro with the same name.
+    #[arg(long)]
+    pub force: bool,
 }
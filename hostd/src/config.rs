@@ -1,37 +1,491 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
 use serde::Deserialize;
+use url::Url;
 
+use crate::archive;
+use crate::browser::{BrowserBackend, BrowserKind, DomainAllowlist};
+use crate::budget::BudgetKind;
+use crate::cgroup::CgroupLimits;
 use crate::cli::StepArgs;
+use crate::executor::{ContainerSettings, ProcBackend};
+use crate::input::InputBackend;
+use crate::llm::LlmProvider;
+use crate::macros;
+use crate::policy::{ArgRule, ConfirmRule, Denial, Glob, PathRule, Policy, program_base_name};
+use crate::sandbox::{LandlockSettings, Rlimits, SandboxMode, SeccompProfile, SyscallFilter};
+use crate::screen::Rect;
+use crate::shell::ShellKind;
+use crate::taint::TaintMode;
+
+/// Capability interfaces `enabled_capabilities` can name. `llm` and
+/// `policy` are always available, since the planner itself runs on them.
+pub const CAPABILITIES: &[&str] = &[
+    "fs", "archive", "proc", "shell", "browser", "input", "screen",
+];
+
+/// Interfaces enabled when the config has no `enabled_capabilities`.
+const DEFAULT_CAPABILITIES: &[&str] = &["fs"];
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct HostConfig {
     pub workspace_root: Utf8PathBuf,
-    pub allowed_proc_commands: Vec<String>,
+    /// Interfaces the guest and planner may use at all; each one's own
+    /// settings still apply on top. Only `fs` when unset.
+    pub enabled_capabilities: BTreeSet<String>,
+    pub fs: FsSettings,
+    pub archive: ArchiveSettings,
+    pub proc: ProcSettings,
+    pub shell: ShellSettings,
+    pub input: InputSettings,
+    pub screen: ScreenSettings,
+    pub planner: PlannerSettings,
     pub llm: Option<LlmSettings>,
     pub browser: Option<BrowserSettings>,
+    pub policy: Policy,
+    /// Set when `[notify]` names a webhook.
+    pub notify: Option<NotifySettings>,
+    /// Where `--session` saves task progress; `<workspace>/.warden/sessions`
+    /// unless `session_dir` is set.
+    pub session_dir: Utf8PathBuf,
+    /// Components from `[[skills]]`, loaded beside the planner.
+    pub skills: Vec<SkillSettings>,
+    /// Lowercase hex SHA-256 digests of the only components hostd runs,
+    /// the planner's and the skills'; any component when unset.
+    pub allowed_component_sha256: Option<BTreeSet<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FsSettings {
+    /// Combined cap on live dir and file handles held by the guest.
+    pub max_open_handles: usize,
+    /// Largest file the guest may open or grow through a file handle.
+    pub max_file_bytes: u64,
+    /// Snapshot the workspace before the task so `fs.revert` can restore it.
+    pub snapshot: bool,
+    /// Where snapshots are stored; defaults to the per-user cache dir.
+    pub snapshot_dir: Option<Utf8PathBuf>,
+}
+
+impl Default for FsSettings {
+    fn default() -> Self {
+        Self {
+            max_open_handles: 128,
+            max_file_bytes: 64 * 1024 * 1024,
+            snapshot: false,
+            snapshot_dir: None,
+        }
+    }
+}
+
+/// Ceilings on `archive.extract`; callers may ask for less, never more.
+#[derive(Debug, Clone)]
+pub struct ArchiveSettings {
+    pub max_entries: u32,
+    /// Bytes written across all extracted files.
+    pub max_bytes: u64,
+}
+
+impl Default for ArchiveSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: archive::DEFAULT_MAX_ENTRIES,
+            max_bytes: archive::DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcSettings {
+    /// Deadline applied to spawns that do not set their own timeout.
+    pub default_timeout_ms: Option<u64>,
+    /// Children the guest may have running at once, closed handles included.
+    pub max_concurrent: usize,
+    /// Bytes captured per output stream; the rest is discarded and flagged.
+    pub max_output_bytes: u64,
+    /// Env vars the guest may set on children; `None` allows any not denied.
+    pub env_allowlist: Option<Vec<String>>,
+    /// Env vars the guest may never set, checked before the allowlist.
+    pub env_denylist: Vec<String>,
+    pub sandbox: SandboxMode,
+    /// Keep network access inside the sandbox; off unshares the network.
+    pub sandbox_network: bool,
+    pub bwrap_path: String,
+    /// Seccomp profile selected by `proc.seccomp_profile`, compiled at load.
+    pub seccomp: Option<SyscallFilter>,
+    pub landlock: LandlockSettings,
+    /// Per-spawn cgroup limits; `None` unless a cpu or memory limit is set.
+    pub cgroup: Option<CgroupLimits>,
+    /// rlimits keyed by program name, with `"*"` as the default.
+    pub rlimits: BTreeMap<String, Rlimits>,
+    pub backend: ProcBackend,
+    /// Image and runtime flags; set whenever `backend` is a container.
+    pub container: Option<ContainerSettings>,
+    /// Policy checks still run, but commands are reported instead of executed.
+    pub dry_run: bool,
+}
+
+/// Loader hooks that would let the guest inject code into allowed binaries,
+/// plus PATH, which std uses to resolve the program and so could swap an
+/// allowlisted name for a workspace binary.
+const DEFAULT_ENV_DENYLIST: &[&str] = &[
+    "PATH",
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "DYLD_INSERT_LIBRARIES",
+    "DYLD_LIBRARY_PATH",
+];
+
+impl Default for ProcSettings {
+    fn default() -> Self {
+        Self {
+            default_timeout_ms: None,
+            max_concurrent: 8,
+            max_output_bytes: 1024 * 1024,
+            env_allowlist: None,
+            env_denylist: DEFAULT_ENV_DENYLIST.iter().map(|s| s.to_string()).collect(),
+            sandbox: SandboxMode::None,
+            sandbox_network: false,
+            bwrap_path: "bwrap".to_string(),
+            seccomp: None,
+            landlock: LandlockSettings::default(),
+            cgroup: None,
+            rlimits: BTreeMap::new(),
+            backend: ProcBackend::Host,
+            container: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl ProcSettings {
+    /// Limits for `program`: its own table layered over the `"*"` defaults.
+    pub fn rlimits_for(&self, program: &str) -> Option<Rlimits> {
+        let defaults = self.rlimits.get("*").copied();
+        let specific = self
+            .rlimits
+            .get(program)
+            .or_else(|| self.rlimits.get(program_base_name(program)))
+            .copied();
+        match (specific, defaults) {
+            (Some(specific), Some(defaults)) => Some(specific.or(defaults)),
+            (specific, defaults) => specific.or(defaults),
+        }
+    }
+
+    /// Names match case-insensitively; a trailing `*` matches by prefix, so
+    /// `AWS_*` covers every AWS credential variable.
+    pub fn env_denial(&self, key: &str) -> Option<Denial> {
+        if let Some(pattern) = self
+            .env_denylist
+            .iter()
+            .find(|pattern| env_matches(pattern, key))
+        {
+            return Some(Denial::matched("proc.env_denylist", pattern));
+        }
+        let allowed = self.env_allowlist.as_ref()?;
+        (!allowed.iter().any(|pattern| env_matches(pattern, key)))
+            .then(|| Denial::unmatched("proc.env_allowlist"))
+    }
+}
+
+/// Persistent shell sessions. Enabling them lets the guest run any command the
+/// shell can reach, outside `policy.proc`, so they stay off unless
+/// configured; sessions still run under the `[proc]` sandbox, env policy, and
+/// limits.
+#[derive(Debug, Clone)]
+pub struct ShellSettings {
+    pub enabled: bool,
+    pub program: String,
+    pub kind: ShellKind,
+    /// Sessions the guest may hold open at once.
+    pub max_sessions: usize,
+    /// Deadline for an `exec` that does not set its own timeout.
+    pub default_timeout_ms: u64,
+}
+
+impl Default for ShellSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            program: "bash".to_string(),
+            kind: ShellKind::Posix,
+            max_sessions: 2,
+            default_timeout_ms: 60_000,
+        }
+    }
+}
+
+/// Global keyboard and mouse reach every window on the desktop, so they
+/// stay off unless configured.
+#[derive(Debug, Clone)]
+pub struct InputSettings {
+    pub enabled: bool,
+    pub backend: InputBackend,
+    /// Input calls allowed in any one-second window.
+    pub max_actions_per_second: u32,
+    /// Desktop areas the pointer may not enter and whose windows may not
+    /// receive keystrokes, such as the terminal running hostd.
+    pub forbidden_regions: Vec<Rect>,
+    /// Moving the pointer into a screen corner stops all further input.
+    pub abort_corner: bool,
+    /// Where `hostd record-input` saves macros; defaults to the per-user
+    /// data dir. It may not be inside the workspace.
+    pub macro_dir: Option<Utf8PathBuf>,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            backend: InputBackend::Auto,
+            max_actions_per_second: 10,
+            forbidden_regions: Vec::new(),
+            abort_corner: true,
+            macro_dir: None,
+        }
+    }
+}
+
+impl InputSettings {
+    /// Only the `[input]` table of a config file, for `hostd record-input`.
+    pub fn from_config_file(path: &Path) -> Result<Self> {
+        match FileConfig::load(path)?.input {
+            Some(cfg) => cfg.into_settings(),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn macro_dir(&self) -> Option<Utf8PathBuf> {
+        self.macro_dir.clone().or_else(macros::default_dir)
+    }
+}
+
+/// Screenshots show every window on the desktop, whatever the task, so
+/// they stay off unless configured.
+#[derive(Debug, Clone, Default)]
+pub struct ScreenSettings {
+    pub enabled: bool,
+}
+
+/// How long a planner step waits for an answer when `step_timeout_ms` is
+/// unset. Generous, since it includes the LLM calls the guest makes.
+const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long a planner action may take when `action_timeout_ms` is unset.
+const DEFAULT_ACTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct PlannerSettings {
+    /// Wall-clock deadline for each `planner.step` call.
+    pub step_timeout: Duration,
+    /// Largest any one of the component's linear memories may grow.
+    pub max_memory_bytes: usize,
+    /// Largest any one of its tables may grow.
+    pub max_table_elements: usize,
+    /// Core instances the component may create, its own included.
+    pub max_instances: usize,
+    /// How long each action the planner requests may take.
+    pub action_timeout: Duration,
+    /// Further attempts at a step the planner failed as retryable, or an
+    /// action the browser was unavailable for.
+    pub max_retries: u32,
+    /// Wait before the first retry; doubled for each one after.
+    pub retry_backoff: Duration,
+    /// Planner actions the task may run in all; unlimited when unset.
+    pub max_actions: Option<u64>,
+    /// Steps in a row whose actions all failed before the task stops.
+    pub max_failed_steps: u32,
+    /// Largest serialized action output the next observation carries.
+    pub max_output_bytes: usize,
+    /// `[planner.output_limits]`: `max_output_bytes` for particular
+    /// actions, keyed by capability (`fs.read_file`) or a prefix of one
+    /// (`browser`, `skill.sheets`).
+    pub output_limits: BTreeMap<String, usize>,
+}
+
+impl PlannerSettings {
+    /// Wait before retry number `attempt`, counted from 0.
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        self.retry_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+    }
+
+    /// The output limit for `capability`: its own, else the closest prefix's,
+    /// else `max_output_bytes`.
+    pub fn output_limit(&self, capability: &str) -> usize {
+        let mut key = capability;
+        loop {
+            if let Some(limit) = self.output_limits.get(key) {
+                return *limit;
+            }
+            match key.rsplit_once('.') {
+                Some((prefix, _)) => key = prefix,
+                None => return self.max_output_bytes,
+            }
+        }
+    }
+}
+
+impl Default for PlannerSettings {
+    fn default() -> Self {
+        Self {
+            step_timeout: DEFAULT_STEP_TIMEOUT,
+            max_memory_bytes: 512 << 20,
+            max_table_elements: 100_000,
+            max_instances: 100,
+            action_timeout: DEFAULT_ACTION_TIMEOUT,
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(500),
+            max_actions: None,
+            max_failed_steps: 5,
+            max_output_bytes: 64 * 1024,
+            // Screenshots are of no use cut short.
+            output_limits: [
+                ("browser.element.screenshot".to_string(), 8 << 20),
+                ("browser.session.screenshot".to_string(), 8 << 20),
+            ]
+            .into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkillSettings {
+    /// Planner actions reach the skill as `skill.<name>.<function>`.
+    pub name: String,
+    pub component: Utf8PathBuf,
+}
+
+fn env_matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key
+            .get(..prefix.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(key),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LlmSettings {
+    pub provider: LlmProvider,
     pub api_base: String,
     pub api_key: String,
     pub model: String,
+    /// Model for `llm.embed`; on Azure, the embedding deployment.
+    pub embedding_model: Option<String>,
+    /// Azure deployment serving `model`; `model` itself when unset.
+    pub deployment: Option<String>,
+    /// Azure `api-version` query parameter.
+    pub api_version: String,
+    /// Fixture the script provider replies from.
+    pub script: Option<Utf8PathBuf>,
+    /// Prompt plus completion tokens one task may spend.
+    pub max_tokens_per_task: Option<u64>,
+    /// Reuse replies to identical requests from an on-disk cache; turned
+    /// off for one run with `--no-llm-cache`.
+    pub cache: bool,
+    pub cache_ttl: Duration,
+    /// Defaults to the per-user cache dir.
+    pub cache_dir: Option<Utf8PathBuf>,
+    /// Extra attempts after a rate limit, 5xx, timeout or dropped
+    /// connection, before the error reaches the guest.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled for each one after, with
+    /// jitter.
+    pub retry_backoff: Duration,
+    /// Where each task's `complete`/`call_tools` exchanges are written as
+    /// JSONL; off when unset.
+    pub transcript_dir: Option<Utf8PathBuf>,
+    /// Masked in transcripts, on top of the API keys and the built-in
+    /// secret patterns.
+    pub redact: Vec<Regex>,
+    /// `[llm.profiles.<name>]`, resolved against the settings above, for
+    /// requests that pick a profile by name. Profiles have none of their own.
+    pub profiles: BTreeMap<String, LlmSettings>,
+}
+
+impl LlmSettings {
+    pub fn embedding_model(&self) -> &str {
+        self.embedding_model.as_deref().unwrap_or(&self.model)
+    }
+
+    pub fn deployment(&self) -> &str {
+        self.deployment.as_deref().unwrap_or(&self.model)
+    }
+}
+
+/// Incoming webhooks that receive approval requests and task summaries.
+#[derive(Debug, Clone)]
+pub struct NotifySettings {
+    pub slack_webhook: Option<Url>,
+    pub teams_webhook: Option<Url>,
 }
 
 #[derive(Debug, Clone)]
 pub struct BrowserSettings {
+    pub backend: BrowserBackend,
+    /// Default browser for new sessions; sessions may override it.
+    pub kind: BrowserKind,
+    /// Only used by the WebDriver backend.
     pub webdriver_url: String,
+    /// Start chromedriver/geckodriver on demand instead of connecting to
+    /// `webdriver_url`.
+    pub manage_driver: bool,
+    /// Driver binaries for `manage_driver`; searched for on PATH when unset.
+    pub chromedriver_path: Option<String>,
+    pub geckodriver_path: Option<String>,
+    /// DevTools address (`host:port`) of a Chrome the operator already has
+    /// open; sessions drive it instead of starting a browser of their own.
+    pub attach: Option<String>,
+    /// Set by `--allow-browser-attach`; without it, `attach` is refused.
+    pub attach_confirmed: bool,
+    /// Chrome binary for the CDP backend; searched for on PATH when unset.
+    pub chrome_path: Option<String>,
     pub default_profile: Option<String>,
+    /// Where profiles' saved cookies and localStorage live; defaults to
+    /// `<workspace>/.browser-profiles`.
+    pub profile_dir: Option<Utf8PathBuf>,
+    /// When set, every request the browser makes to another host is blocked.
+    pub allowed_domains: Option<DomainAllowlist>,
+    /// Whether guests may run their own JavaScript in the page; off unless
+    /// the config turns it on.
+    pub allow_eval: bool,
+    /// Largest serialized eval result handed back to the guest.
+    pub max_eval_bytes: usize,
+    /// Planner sessions unused for this long are closed before the next
+    /// action; `None` keeps them until the step ends.
+    pub idle_timeout: Option<Duration>,
 }
 
 impl HostConfig {
+    pub fn is_capability_enabled(&self, capability: &str) -> bool {
+        self.enabled_capabilities.contains(capability)
+    }
+
+    /// Takes `[policy]` and `enabled_capabilities` from a re-read config;
+    /// everything else keeps the values the task started with.
+    pub fn reload_policy(&mut self, fresh: &HostConfig) -> Result<()> {
+        if fresh.policy.commands.iter().any(|entry| entry == "*")
+            && !self.proc.backend.is_container()
+        {
+            anyhow::bail!("allow_proc = [\"*\"] requires a container proc.backend");
+        }
+        self.policy = fresh.policy.clone();
+        self.enabled_capabilities = fresh.enabled_capabilities.clone();
+        Ok(())
+    }
+
     pub fn from_step_args(args: &StepArgs) -> Result<Self> {
-        let file_cfg = FileConfig::load(&args.config)?;
+        let mut file_cfg = FileConfig::load(&args.config)?;
         let workspace_path = args
             .workspace
             .clone()
@@ -43,46 +497,286 @@ impl HostConfig {
                 workspace_path.to_string_lossy()
             )
         })?;
-        let mut allowed_proc_commands = file_cfg.allow_proc.unwrap_or_default();
-        allowed_proc_commands.extend(args.allow_proc.iter().cloned());
-        allowed_proc_commands.sort();
-        allowed_proc_commands.dedup();
-        let llm = match file_cfg.llm {
+        let workspace_root = match &args.workspace_subdir {
+            Some(subdir) => task_workspace(&workspace_root, subdir)?,
+            None => workspace_root,
+        };
+        let enabled_capabilities = match file_cfg.enabled_capabilities.take() {
+            Some(names) => parse_capabilities(names)?,
+            None => DEFAULT_CAPABILITIES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        };
+        let mut commands = file_cfg.allow_proc.take().unwrap_or_default();
+        commands.extend(args.allow_proc.iter().cloned());
+        // Older homes of policy rules, merged into `Policy` below.
+        let proc_args = file_cfg.proc.as_mut().and_then(|cfg| cfg.args.take());
+        let browser_urls = file_cfg.browser.as_mut().and_then(|cfg| cfg.urls.take());
+        let fs = match file_cfg.fs {
+            Some(cfg) => cfg.into_settings()?,
+            None => FsSettings::default(),
+        };
+        let archive = match file_cfg.archive {
+            Some(cfg) => cfg.into_settings(),
+            None => ArchiveSettings::default(),
+        };
+        let mut proc = match file_cfg.proc {
+            Some(cfg) => cfg.into_settings()?,
+            None => ProcSettings::default(),
+        };
+        proc.dry_run |= args.dry_run;
+        let shell = match file_cfg.shell {
+            Some(cfg) => cfg.into_settings()?,
+            None => ShellSettings::default(),
+        };
+        let input = match file_cfg.input {
+            Some(cfg) => cfg.into_settings()?,
+            None => InputSettings::default(),
+        };
+        if input
+            .macro_dir()
+            .is_some_and(|dir| dir.starts_with(&workspace_root))
+        {
+            anyhow::bail!("input.macro_dir may not be inside the workspace");
+        }
+        let screen = ScreenSettings {
+            enabled: file_cfg.screen.and_then(|cfg| cfg.enabled).unwrap_or(false),
+        };
+        let mut planner = match file_cfg.planner {
+            Some(cfg) => cfg.into_settings()?,
+            None => PlannerSettings::default(),
+        };
+        if args.max_actions.is_some() {
+            planner.max_actions = args.max_actions;
+        }
+        if let Some(max) = args.max_failed_steps {
+            planner.max_failed_steps = max;
+        }
+        let mut llm = match file_cfg.llm {
+            Some(cfg) => cfg.into_settings()?,
+            None => None,
+        };
+        if let Some(llm) = &mut llm {
+            llm.cache &= !args.no_llm_cache;
+            for profile in llm.profiles.values_mut() {
+                profile.cache = llm.cache;
+            }
+            if llm
+                .transcript_dir
+                .as_ref()
+                .is_some_and(|dir| dir.starts_with(&workspace_root))
+            {
+                anyhow::bail!("llm.transcript_dir may not be inside the workspace");
+            }
+        }
+        let mut browser = match file_cfg.browser {
             Some(cfg) => cfg.into_settings()?,
             None => None,
         };
-        let browser = match file_cfg.browser {
+        if let Some(browser) = &mut browser {
+            browser.attach_confirmed = args.allow_browser_attach;
+        }
+        let mut policy =
+            file_cfg
+                .policy
+                .unwrap_or_default()
+                .into_policy(commands, proc_args, browser_urls)?;
+        if policy.commands.iter().any(|entry| entry == "*") && !proc.backend.is_container() {
+            anyhow::bail!("allow_proc = [\"*\"] requires a container proc.backend");
+        }
+        if policy
+            .audit_dir
+            .as_ref()
+            .is_some_and(|dir| dir.starts_with(&workspace_root))
+        {
+            anyhow::bail!("policy.audit_dir may not be inside the workspace");
+        }
+        if let Some(tokens) = llm.as_ref().and_then(|llm| llm.max_tokens_per_task) {
+            policy
+                .budgets
+                .entry(BudgetKind::LlmTokens)
+                .or_insert(tokens);
+        }
+        if let Some(steps) = args.max_steps {
+            policy.budgets.insert(BudgetKind::Steps, steps);
+        }
+        if let Some(limit) = args.max_wall_clock {
+            let ms = u64::try_from(limit.as_millis()).unwrap_or(u64::MAX);
+            policy.budgets.insert(BudgetKind::WallclockMs, ms);
+        }
+        let notify = match file_cfg.notify {
             Some(cfg) => cfg.into_settings()?,
             None => None,
         };
+        let session_dir = match file_cfg.session_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => normalize_path(Path::new(&dir))
+                .with_context(|| format!("invalid session_dir {dir}"))?,
+            None => workspace_root.join(".warden").join("sessions"),
+        };
+        let skills = file_cfg
+            .skills
+            .unwrap_or_default()
+            .into_iter()
+            .map(SkillFileSettings::into_settings)
+            .collect::<Result<Vec<_>>>()?;
+        let mut names = BTreeSet::new();
+        for skill in &skills {
+            if !names.insert(skill.name.as_str()) {
+                anyhow::bail!("skill `{}` is listed twice in [[skills]]", skill.name);
+            }
+            if skill.component.starts_with(&workspace_root) {
+                anyhow::bail!(
+                    "skill `{}` component may not be inside the workspace",
+                    skill.name
+                );
+            }
+        }
+        let allowed_component_sha256 = file_cfg
+            .allowed_component_sha256
+            .map(parse_digests)
+            .transpose()?;
         Ok(Self {
             workspace_root,
-            allowed_proc_commands,
+            enabled_capabilities,
+            fs,
+            archive,
+            proc,
+            shell,
+            input,
+            screen,
+            planner,
             llm,
             browser,
+            policy,
+            notify,
+            session_dir,
+            skills,
+            allowed_component_sha256,
         })
     }
+}
 
-    pub fn is_proc_allowed(&self, program: &str) -> bool {
-        if self.allowed_proc_commands.is_empty() {
-            return false;
-        }
-        let base = Path::new(program)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or(program);
-        self.allowed_proc_commands
-            .iter()
-            .any(|entry| entry == program || entry == base)
+/// A queued task's own workspace: an existing directory inside the
+/// configured one, which it can narrow but never leave.
+fn task_workspace(root: &Utf8Path, subdir: &str) -> Result<Utf8PathBuf> {
+    let relative = Path::new(subdir.trim());
+    let inside = relative
+        .components()
+        .all(|part| matches!(part, std::path::Component::Normal(_)));
+    if !inside {
+        anyhow::bail!("task workspace `{subdir}` must be a relative path inside workspace_root");
     }
+    let dir = normalize_path(&root.as_std_path().join(relative))
+        .with_context(|| format!("invalid task workspace {subdir}"))?;
+    if !dir.starts_with(root) || !dir.is_dir() {
+        anyhow::bail!("task workspace `{subdir}` is not a directory inside workspace_root");
+    }
+    Ok(dir)
+}
+
+fn parse_capabilities(names: Vec<String>) -> Result<BTreeSet<String>> {
+    names
+        .into_iter()
+        .map(|name| {
+            let name = name.trim().to_ascii_lowercase();
+            if !CAPABILITIES.contains(&name.as_str()) {
+                anyhow::bail!(
+                    "unknown capability `{name}` in enabled_capabilities (expected one of: {})",
+                    CAPABILITIES.join(", ")
+                );
+            }
+            Ok(name)
+        })
+        .collect()
+}
+
+fn parse_digests(digests: Vec<String>) -> Result<BTreeSet<String>> {
+    if digests.is_empty() {
+        anyhow::bail!("allowed_component_sha256 must list at least one digest");
+    }
+    digests
+        .into_iter()
+        .map(|digest| {
+            let digest = digest.trim().to_ascii_lowercase();
+            if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                anyhow::bail!("`{digest}` in allowed_component_sha256 is not a hex SHA-256 digest");
+            }
+            Ok(digest)
+        })
+        .collect()
 }
 
 #[derive(Default, Deserialize)]
 struct FileConfig {
     workspace_root: Option<String>,
+    enabled_capabilities: Option<Vec<String>>,
     allow_proc: Option<Vec<String>>,
+    fs: Option<FsFileSettings>,
+    archive: Option<ArchiveFileSettings>,
+    proc: Option<ProcFileSettings>,
+    shell: Option<ShellFileSettings>,
+    input: Option<InputFileSettings>,
+    screen: Option<ScreenFileSettings>,
+    planner: Option<PlannerFileSettings>,
     llm: Option<LlmFileSettings>,
     browser: Option<BrowserFileSettings>,
+    policy: Option<PolicyFileSettings>,
+    notify: Option<NotifyFileSettings>,
+    session_dir: Option<String>,
+    skills: Option<Vec<SkillFileSettings>>,
+    allowed_component_sha256: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct SkillFileSettings {
+    name: String,
+    component: String,
+}
+
+impl SkillFileSettings {
+    /// Names are limited to letters, digits, `-` and `_`, so one never
+    /// splits differently in `skill.<name>.<function>`.
+    fn into_settings(self) -> Result<SkillSettings> {
+        let name = self.name.trim().to_string();
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            anyhow::bail!("skill name `{name}` may only use letters, digits, `-` and `_`");
+        }
+        let component = normalize_path(Path::new(self.component.trim()))
+            .with_context(|| format!("invalid component path for skill `{name}`"))?;
+        Ok(SkillSettings { name, component })
+    }
+}
+
+#[derive(Deserialize)]
+struct NotifyFileSettings {
+    slack_webhook: Option<String>,
+    teams_webhook: Option<String>,
+}
+
+impl NotifyFileSettings {
+    fn into_settings(self) -> Result<Option<NotifySettings>> {
+        let webhook = |key: &str, value: Option<String>| -> Result<Option<Url>> {
+            let Some(value) = value.filter(|s| !s.trim().is_empty()) else {
+                return Ok(None);
+            };
+            let url = Url::parse(value.trim()).with_context(|| format!("invalid notify.{key}"))?;
+            if url.scheme() != "https" {
+                anyhow::bail!("notify.{key} must be an https:// url");
+            }
+            Ok(Some(url))
+        };
+        let settings = NotifySettings {
+            slack_webhook: webhook("slack_webhook", self.slack_webhook)?,
+            teams_webhook: webhook("teams_webhook", self.teams_webhook)?,
+        };
+        let any = settings.slack_webhook.is_some() || settings.teams_webhook.is_some();
+        Ok(any.then_some(settings))
+    }
 }
 
 impl FileConfig {
@@ -98,35 +792,835 @@ impl FileConfig {
     }
 }
 
+#[derive(Deserialize)]
+struct FsFileSettings {
+    max_open_handles: Option<usize>,
+    max_file_bytes: Option<u64>,
+    snapshot: Option<bool>,
+    snapshot_dir: Option<String>,
+}
+
+impl FsFileSettings {
+    fn into_settings(self) -> Result<FsSettings> {
+        let defaults = FsSettings::default();
+        let snapshot_dir = match self.snapshot_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid snapshot dir {dir}"))?,
+            ),
+            None => None,
+        };
+        Ok(FsSettings {
+            max_open_handles: self.max_open_handles.unwrap_or(defaults.max_open_handles),
+            max_file_bytes: self.max_file_bytes.unwrap_or(defaults.max_file_bytes),
+            snapshot: self.snapshot.unwrap_or(defaults.snapshot),
+            snapshot_dir,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ArchiveFileSettings {
+    max_entries: Option<u32>,
+    max_bytes: Option<u64>,
+}
+
+impl ArchiveFileSettings {
+    fn into_settings(self) -> ArchiveSettings {
+        let defaults = ArchiveSettings::default();
+        ArchiveSettings {
+            max_entries: self.max_entries.unwrap_or(defaults.max_entries),
+            max_bytes: self.max_bytes.unwrap_or(defaults.max_bytes),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProcFileSettings {
+    default_timeout_ms: Option<u64>,
+    max_concurrent: Option<usize>,
+    max_output_bytes: Option<u64>,
+    env_allowlist: Option<Vec<String>>,
+    env_denylist: Option<Vec<String>>,
+    args: Option<BTreeMap<String, ArgRuleFileSettings>>,
+    sandbox: Option<SandboxMode>,
+    sandbox_network: Option<bool>,
+    bwrap_path: Option<String>,
+    seccomp_profile: Option<String>,
+    seccomp_profiles: Option<BTreeMap<String, SeccompProfile>>,
+    landlock: Option<bool>,
+    landlock_read_only: Option<Vec<String>>,
+    landlock_read_write: Option<Vec<String>>,
+    cpu_quota: Option<f64>,
+    memory_limit_mb: Option<u64>,
+    cgroup_parent: Option<String>,
+    rlimits: Option<BTreeMap<String, Rlimits>>,
+    backend: Option<ProcBackend>,
+    container: Option<ContainerSettings>,
+    dry_run: Option<bool>,
+}
+
+/// Delegated cgroup under which per-spawn cgroups are created.
+const DEFAULT_CGROUP_PARENT: &str = "/sys/fs/cgroup/wasi-warden";
+
+impl ProcFileSettings {
+    fn into_settings(self) -> Result<ProcSettings> {
+        if self.default_timeout_ms == Some(0) {
+            anyhow::bail!("proc.default_timeout_ms must be greater than zero");
+        }
+        let defaults = ProcSettings::default();
+        let sandbox = self.sandbox.unwrap_or(defaults.sandbox);
+        let backend = self.backend.unwrap_or(defaults.backend);
+        // Host-side confinement would wrap the runtime client, not the command.
+        if backend.is_container()
+            && (sandbox != SandboxMode::None
+                || self.seccomp_profile.is_some()
+                || self.landlock == Some(true))
+        {
+            anyhow::bail!(
+                "proc.sandbox, proc.seccomp_profile and proc.landlock do not apply to container backends"
+            );
+        }
+        let seccomp = match self.seccomp_profile.filter(|name| !name.trim().is_empty()) {
+            Some(name) => {
+                // bwrap needs mount/unshare itself, so a filter installed
+                // before it execs would break the sandbox it sets up.
+                if sandbox == SandboxMode::Bwrap {
+                    anyhow::bail!(
+                        "proc.seccomp_profile cannot be combined with sandbox = \"bwrap\""
+                    );
+                }
+                let mut profiles = self.seccomp_profiles.unwrap_or_default();
+                let profile = match profiles.remove(&name) {
+                    Some(profile) => profile,
+                    None if name == "default" => SeccompProfile::builtin_default(),
+                    None => anyhow::bail!("unknown seccomp profile `{name}`"),
+                };
+                Some(
+                    SyscallFilter::compile(&profile)
+                        .with_context(|| format!("invalid seccomp profile `{name}`"))?,
+                )
+            }
+            None => None,
+        };
+        let landlock = LandlockSettings {
+            enabled: self.landlock.unwrap_or(false),
+            read_only: normalize_paths(self.landlock_read_only.unwrap_or_default())?,
+            read_write: normalize_paths(self.landlock_read_write.unwrap_or_default())?,
+        };
+        // Landlock also forbids the mounts bwrap performs after exec.
+        if landlock.enabled && sandbox == SandboxMode::Bwrap {
+            anyhow::bail!("proc.landlock cannot be combined with sandbox = \"bwrap\"");
+        }
+        if landlock.enabled && !cfg!(target_os = "linux") {
+            anyhow::bail!("proc.landlock is only supported on Linux");
+        }
+        let rlimits = self.rlimits.unwrap_or_default();
+        if !rlimits.is_empty() && !cfg!(unix) && !backend.is_container() {
+            anyhow::bail!("proc.rlimits are only supported on unix");
+        }
+        if self
+            .cpu_quota
+            .is_some_and(|cores| cores.is_nan() || cores <= 0.0)
+        {
+            anyhow::bail!("proc.cpu_quota must be greater than zero");
+        }
+        if self.memory_limit_mb == Some(0) {
+            anyhow::bail!("proc.memory_limit_mb must be greater than zero");
+        }
+        let container = if backend.is_container() {
+            let mut container = self
+                .container
+                .context("proc.backend requires a [proc.container] section")?;
+            if container.image.trim().is_empty() {
+                anyhow::bail!("proc.container.image must be set");
+            }
+            if !container.workdir.starts_with('/') {
+                anyhow::bail!("proc.container.workdir must be an absolute path");
+            }
+            // The runtime applies these limits to the container itself.
+            container.cpus = self.cpu_quota;
+            container.memory_limit_mb = self.memory_limit_mb;
+            Some(container)
+        } else {
+            None
+        };
+        let cgroup = if !backend.is_container()
+            && (self.cpu_quota.is_some() || self.memory_limit_mb.is_some())
+        {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!(
+                    "proc.cpu_quota and proc.memory_limit_mb are only supported on Linux"
+                );
+            }
+            let parent = self
+                .cgroup_parent
+                .unwrap_or_else(|| DEFAULT_CGROUP_PARENT.to_string());
+            Some(CgroupLimits {
+                parent: Utf8PathBuf::from(parent),
+                cpu_quota: self.cpu_quota,
+                memory_limit_mb: self.memory_limit_mb,
+            })
+        } else {
+            None
+        };
+        Ok(ProcSettings {
+            default_timeout_ms: self.default_timeout_ms,
+            max_concurrent: self.max_concurrent.unwrap_or(defaults.max_concurrent),
+            max_output_bytes: self.max_output_bytes.unwrap_or(defaults.max_output_bytes),
+            env_allowlist: self.env_allowlist,
+            env_denylist: self.env_denylist.unwrap_or(defaults.env_denylist),
+            sandbox,
+            sandbox_network: self.sandbox_network.unwrap_or(defaults.sandbox_network),
+            bwrap_path: self
+                .bwrap_path
+                .filter(|path| !path.trim().is_empty())
+                .unwrap_or(defaults.bwrap_path),
+            seccomp,
+            landlock,
+            cgroup,
+            rlimits,
+            backend,
+            container,
+            dry_run: self.dry_run.unwrap_or(defaults.dry_run),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ArgRuleFileSettings {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl ArgRuleFileSettings {
+    fn into_rule(self) -> Result<ArgRule> {
+        Ok(ArgRule {
+            allow: compile_patterns(self.allow)?,
+            deny: compile_patterns(self.deny)?,
+        })
+    }
+}
+
+fn compile_globs(patterns: Vec<String>) -> Result<Vec<Glob>> {
+    patterns.iter().map(|pattern| Glob::new(pattern)).collect()
+}
+
+fn compile_patterns(patterns: Vec<String>) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("invalid pattern `{pattern}`")))
+        .collect()
+}
+
+/// `http://host:port` of Chrome's remote debugging port, as chromedriver's
+/// `debuggerAddress` wants it.
+fn debugger_address(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("invalid browser.attach url {url}"))?;
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port()) else {
+        anyhow::bail!("browser.attach must name a host and port, like http://localhost:9222");
+    };
+    if parsed.scheme() != "http" {
+        anyhow::bail!("browser.attach must be an http:// url");
+    }
+    Ok(format!("{host}:{port}"))
+}
+
+#[derive(Deserialize)]
+struct InputFileSettings {
+    enabled: Option<bool>,
+    backend: Option<InputBackend>,
+    max_actions_per_second: Option<u32>,
+    forbidden_regions: Option<Vec<Rect>>,
+    abort_corner: Option<bool>,
+    macro_dir: Option<String>,
+}
+
+impl InputFileSettings {
+    fn into_settings(self) -> Result<InputSettings> {
+        let defaults = InputSettings::default();
+        let backend = self.backend.unwrap_or(defaults.backend);
+        if backend != InputBackend::Auto && !cfg!(target_os = "linux") {
+            anyhow::bail!("input.backend can only be chosen on Linux");
+        }
+        let max_actions_per_second = self
+            .max_actions_per_second
+            .unwrap_or(defaults.max_actions_per_second);
+        if max_actions_per_second == 0 {
+            anyhow::bail!("input.max_actions_per_second must be greater than zero");
+        }
+        let forbidden_regions = self.forbidden_regions.unwrap_or_default();
+        if forbidden_regions
+            .iter()
+            .any(|region| region.width == 0 || region.height == 0)
+        {
+            anyhow::bail!("input.forbidden_regions entries need a non-zero width and height");
+        }
+        let macro_dir = match self.macro_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid input macro dir {dir}"))?,
+            ),
+            None => None,
+        };
+        Ok(InputSettings {
+            enabled: self.enabled.unwrap_or(defaults.enabled),
+            backend,
+            max_actions_per_second,
+            forbidden_regions,
+            abort_corner: self.abort_corner.unwrap_or(defaults.abort_corner),
+            macro_dir,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ScreenFileSettings {
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PlannerFileSettings {
+    step_timeout_ms: Option<u64>,
+    action_timeout_ms: Option<u64>,
+    max_retries: Option<u32>,
+    retry_backoff_ms: Option<u64>,
+    max_actions: Option<u64>,
+    max_failed_steps: Option<u32>,
+    max_memory_bytes: Option<usize>,
+    max_table_elements: Option<usize>,
+    max_instances: Option<usize>,
+    max_output_bytes: Option<usize>,
+    output_limits: Option<BTreeMap<String, usize>>,
+}
+
+impl PlannerFileSettings {
+    fn into_settings(self) -> Result<PlannerSettings> {
+        let mut settings = PlannerSettings::default();
+        match self.step_timeout_ms {
+            Some(0) => anyhow::bail!("planner.step_timeout_ms must be greater than zero"),
+            Some(ms) => settings.step_timeout = Duration::from_millis(ms),
+            None => {}
+        }
+        match self.action_timeout_ms {
+            Some(0) => anyhow::bail!("planner.action_timeout_ms must be greater than zero"),
+            Some(ms) => settings.action_timeout = Duration::from_millis(ms),
+            None => {}
+        }
+        settings.max_retries = self.max_retries.unwrap_or(settings.max_retries);
+        if let Some(ms) = self.retry_backoff_ms {
+            settings.retry_backoff = Duration::from_millis(ms);
+        }
+        match self.max_actions {
+            Some(0) => anyhow::bail!("planner.max_actions must be greater than zero"),
+            max_actions => settings.max_actions = max_actions,
+        }
+        match self.max_failed_steps {
+            Some(0) => anyhow::bail!("planner.max_failed_steps must be greater than zero"),
+            Some(max) => settings.max_failed_steps = max,
+            None => {}
+        }
+        let limits = [
+            (
+                "max_memory_bytes",
+                self.max_memory_bytes,
+                &mut settings.max_memory_bytes,
+            ),
+            (
+                "max_table_elements",
+                self.max_table_elements,
+                &mut settings.max_table_elements,
+            ),
+            (
+                "max_instances",
+                self.max_instances,
+                &mut settings.max_instances,
+            ),
+            (
+                "max_output_bytes",
+                self.max_output_bytes,
+                &mut settings.max_output_bytes,
+            ),
+        ];
+        for (key, limit, setting) in limits {
+            match limit {
+                Some(0) => anyhow::bail!("planner.{key} must be greater than zero"),
+                Some(limit) => *setting = limit,
+                None => {}
+            }
+        }
+        for (key, limit) in self.output_limits.unwrap_or_default() {
+            let rule = key.trim().to_string();
+            let interface = rule.split('.').next().unwrap_or_default();
+            if !CAPABILITIES.contains(&interface) && interface != "skill" {
+                anyhow::bail!("unknown capability in planner.output_limits key `{key}`");
+            }
+            if limit == 0 {
+                anyhow::bail!("planner.output_limits.\"{key}\" must be greater than zero");
+            }
+            settings.output_limits.insert(rule, limit);
+        }
+        Ok(settings)
+    }
+}
+
+#[derive(Deserialize)]
+struct ShellFileSettings {
+    enabled: Option<bool>,
+    program: Option<String>,
+    max_sessions: Option<usize>,
+    default_timeout_ms: Option<u64>,
+}
+
+impl ShellFileSettings {
+    fn into_settings(self) -> Result<ShellSettings> {
+        let defaults = ShellSettings::default();
+        let program = self
+            .program
+            .filter(|program| !program.trim().is_empty())
+            .unwrap_or(defaults.program);
+        let Some(kind) = ShellKind::from_program(program_base_name(&program)) else {
+            anyhow::bail!("unsupported shell `{program}` (expected bash, sh, zsh, dash, or pwsh)");
+        };
+        let default_timeout_ms = self
+            .default_timeout_ms
+            .unwrap_or(defaults.default_timeout_ms);
+        if default_timeout_ms == 0 {
+            anyhow::bail!("shell.default_timeout_ms must be greater than zero");
+        }
+        Ok(ShellSettings {
+            enabled: self.enabled.unwrap_or(defaults.enabled),
+            program,
+            kind,
+            max_sessions: self.max_sessions.unwrap_or(defaults.max_sessions),
+            default_timeout_ms,
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct PolicyFileSettings {
+    fs: Option<PathRuleFileSettings>,
+    confirm: Option<ConfirmFileSettings>,
+    proc: Option<ProcPolicyFileSettings>,
+    urls: Option<ArgRuleFileSettings>,
+    taint: Option<TaintMode>,
+    rate_limits: Option<BTreeMap<String, u32>>,
+    budgets: Option<BudgetFileSettings>,
+    grant_prompts: Option<bool>,
+    approval_webhook: Option<String>,
+    approval_timeout_ms: Option<u64>,
+    approval_required: Option<Vec<String>>,
+    audit_dir: Option<String>,
+    hot_reload: Option<bool>,
+    planner_fuel: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PathRuleFileSettings {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    read_only: Vec<String>,
+}
+
+impl PathRuleFileSettings {
+    fn into_rule(self) -> Result<PathRule> {
+        Ok(PathRule {
+            allow: compile_globs(self.allow).context("invalid policy.fs.allow")?,
+            deny: compile_globs(self.deny).context("invalid policy.fs.deny")?,
+            read_only: compile_globs(self.read_only).context("invalid policy.fs.read_only")?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfirmFileSettings {
+    #[serde(default)]
+    commands: Vec<String>,
+    recursive_remove: Option<bool>,
+    #[serde(default)]
+    writes_outside: Vec<String>,
+}
+
+impl ConfirmFileSettings {
+    fn into_rule(self) -> Result<ConfirmRule> {
+        Ok(ConfirmRule {
+            commands: compile_patterns(self.commands).context("invalid policy.confirm.commands")?,
+            recursive_remove: self.recursive_remove.unwrap_or(false),
+            writes_outside: compile_globs(self.writes_outside)
+                .context("invalid policy.confirm.writes_outside")?,
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct ProcPolicyFileSettings {
+    commands: Option<Vec<String>>,
+    args: Option<BTreeMap<String, ArgRuleFileSettings>>,
+}
+
+#[derive(Deserialize)]
+struct BudgetFileSettings {
+    steps: Option<u64>,
+    wallclock_ms: Option<u64>,
+    llm_tokens: Option<u64>,
+    processes: Option<u64>,
+    process_ms: Option<u64>,
+    filesystem_ops: Option<u64>,
+    bytes_written: Option<u64>,
+    browser_actions: Option<u64>,
+}
+
+impl PolicyFileSettings {
+    /// `commands`, `proc_args` and `browser_urls` come from `allow_proc`,
+    /// `[proc.args]` and `[browser.urls]`, which `[policy]` extends.
+    fn into_policy(
+        self,
+        mut commands: Vec<String>,
+        proc_args: Option<BTreeMap<String, ArgRuleFileSettings>>,
+        browser_urls: Option<ArgRuleFileSettings>,
+    ) -> Result<Policy> {
+        let mut policy = Policy::default();
+        let proc = self.proc.unwrap_or_default();
+        commands.extend(proc.commands.unwrap_or_default());
+        commands.sort();
+        commands.dedup();
+        policy.commands = commands;
+        for (source, rules) in [("proc.args", proc_args), ("policy.proc.args", proc.args)] {
+            for (command, rule) in rules.unwrap_or_default() {
+                let rule = rule
+                    .into_rule()
+                    .with_context(|| format!("invalid {source} rule for `{command}`"))?;
+                if policy.arg_rules.insert(command.clone(), rule).is_some() {
+                    anyhow::bail!(
+                        "argument rules for `{command}` are set in both proc.args and policy.proc.args"
+                    );
+                }
+            }
+        }
+        for (source, rule) in [("browser.urls", browser_urls), ("policy.urls", self.urls)] {
+            let Some(rule) = rule else {
+                continue;
+            };
+            let allow =
+                compile_patterns(rule.allow).with_context(|| format!("invalid {source}.allow"))?;
+            let deny =
+                compile_patterns(rule.deny).with_context(|| format!("invalid {source}.deny"))?;
+            policy.urls.allow.extend(allow);
+            policy.urls.deny.extend(deny);
+        }
+        if let Some(fs) = self.fs {
+            policy.fs = fs.into_rule()?;
+        }
+        if let Some(confirm) = self.confirm {
+            policy.confirm = confirm.into_rule()?;
+        }
+        for (key, per_minute) in self.rate_limits.unwrap_or_default() {
+            let rule = key.trim().to_ascii_lowercase().replace('-', "_");
+            let interface = rule.split('.').next().unwrap_or_default();
+            if !CAPABILITIES.contains(&interface) && !matches!(interface, "llm" | "policy") {
+                anyhow::bail!("unknown capability in policy.rate_limits key `{key}`");
+            }
+            if per_minute == 0 {
+                anyhow::bail!("policy.rate_limits.\"{key}\" must be greater than zero");
+            }
+            policy.rate_limits.insert(rule, per_minute);
+        }
+        policy.taint = self.taint.unwrap_or(policy.taint);
+        policy.grant_prompts = self.grant_prompts.unwrap_or(policy.grant_prompts);
+        if let Some(webhook) = self.approval_webhook.filter(|s| !s.trim().is_empty()) {
+            let url = Url::parse(webhook.trim())
+                .with_context(|| format!("invalid policy.approval_webhook {webhook}"))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                anyhow::bail!("policy.approval_webhook must be an http:// or https:// url");
+            }
+            policy.approval_webhook = Some(url);
+        }
+        match self.approval_timeout_ms {
+            Some(0) => anyhow::bail!("policy.approval_timeout_ms must be greater than zero"),
+            Some(ms) => policy.approval_timeout = Duration::from_millis(ms),
+            None => {}
+        }
+        for action in self.approval_required.unwrap_or_default() {
+            let action = action.trim().to_string();
+            let interface = action.split('.').next().unwrap_or_default();
+            if !CAPABILITIES.contains(&interface) {
+                anyhow::bail!("unknown capability in policy.approval_required entry `{action}`");
+            }
+            policy.approval_required.push(action);
+        }
+        policy.hot_reload = self.hot_reload.unwrap_or(policy.hot_reload);
+        match self.planner_fuel {
+            Some(0) => anyhow::bail!("policy.planner_fuel must be greater than zero"),
+            Some(fuel) => policy.planner_fuel = fuel,
+            None => {}
+        }
+        if let Some(dir) = self.audit_dir.filter(|s| !s.trim().is_empty()) {
+            policy.audit_dir = Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid audit dir {dir}"))?,
+            );
+        }
+        let Some(budgets) = self.budgets else {
+            return Ok(policy);
+        };
+        let limits = [
+            (BudgetKind::Steps, budgets.steps),
+            (BudgetKind::WallclockMs, budgets.wallclock_ms),
+            (BudgetKind::LlmTokens, budgets.llm_tokens),
+            (BudgetKind::Processes, budgets.processes),
+            (BudgetKind::ProcessMs, budgets.process_ms),
+            (BudgetKind::FilesystemOps, budgets.filesystem_ops),
+            (BudgetKind::BytesWritten, budgets.bytes_written),
+            (BudgetKind::BrowserActions, budgets.browser_actions),
+        ];
+        for (kind, limit) in limits {
+            match limit {
+                Some(0) => {
+                    anyhow::bail!("policy.budgets.{} must be greater than zero", kind.name())
+                }
+                Some(limit) => {
+                    policy.budgets.insert(kind, limit);
+                }
+                None => {}
+            }
+        }
+        Ok(policy)
+    }
+}
+
 #[derive(Deserialize)]
 struct LlmFileSettings {
+    provider: Option<LlmProvider>,
     api_base: Option<String>,
     api_key: Option<String>,
     model: Option<String>,
+    embedding_model: Option<String>,
+    deployment: Option<String>,
+    api_version: Option<String>,
+    script: Option<String>,
+    max_tokens_per_task: Option<u64>,
+    cache: Option<bool>,
+    cache_ttl_secs: Option<u64>,
+    cache_dir: Option<String>,
+    max_retries: Option<u32>,
+    retry_backoff_ms: Option<u64>,
+    transcript_dir: Option<String>,
+    #[serde(default)]
+    redact: Vec<String>,
+    profiles: Option<BTreeMap<String, LlmProfileFileSettings>>,
 }
 
 impl LlmFileSettings {
     fn into_settings(self) -> Result<Option<LlmSettings>> {
+        let provider = self.provider.unwrap_or_default();
         let api_key = match self.api_key {
             Some(key) if !key.trim().is_empty() => key,
+            _ if !provider.needs_api_key() => String::new(),
             _ => return Ok(None),
         };
-        let model = match self.model {
-            Some(model) if !model.trim().is_empty() => model,
+        let deployment = self.deployment.filter(|s| !s.trim().is_empty());
+        let model = match (self.model, &deployment) {
+            (Some(model), _) if !model.trim().is_empty() => model,
+            (_, Some(deployment)) if provider == LlmProvider::Azure => deployment.clone(),
+            _ if provider == LlmProvider::Script => "script".to_string(),
             _ => return Ok(None),
         };
-        let api_base = self
-            .api_base
-            .filter(|s| !s.trim().is_empty())
-            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
-        Ok(Some(LlmSettings {
+        let script = match self.script.filter(|s| !s.trim().is_empty()) {
+            Some(path) => Some(
+                normalize_path(Path::new(&path))
+                    .with_context(|| format!("invalid llm script {path}"))?,
+            ),
+            None if provider == LlmProvider::Script => {
+                anyhow::bail!("llm.provider = \"script\" needs llm.script")
+            }
+            None => None,
+        };
+        let api_base = match self.api_base.filter(|s| !s.trim().is_empty()) {
+            Some(api_base) => api_base,
+            None => provider
+                .default_api_base()
+                .context("llm.api_base must name the Azure OpenAI resource")?
+                .to_string(),
+        };
+        if self.max_tokens_per_task == Some(0) {
+            anyhow::bail!("llm.max_tokens_per_task must be greater than zero");
+        }
+        let cache_ttl_secs = self.cache_ttl_secs.unwrap_or(DEFAULT_LLM_CACHE_TTL_SECS);
+        if cache_ttl_secs == 0 {
+            anyhow::bail!("llm.cache_ttl_secs must be greater than zero");
+        }
+        let cache_dir = match self.cache_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid llm cache dir {dir}"))?,
+            ),
+            None => None,
+        };
+        let transcript_dir = match self.transcript_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid llm transcript dir {dir}"))?,
+            ),
+            None => None,
+        };
+        let redact = compile_patterns(self.redact).context("invalid llm.redact pattern")?;
+        let max_retries = self.max_retries.unwrap_or(DEFAULT_LLM_MAX_RETRIES);
+        if max_retries > MAX_LLM_RETRIES {
+            anyhow::bail!("llm.max_retries must be at most {MAX_LLM_RETRIES}");
+        }
+        let mut settings = LlmSettings {
+            provider,
             api_base,
             api_key,
             model,
-        }))
+            embedding_model: self.embedding_model.filter(|s| !s.trim().is_empty()),
+            deployment,
+            api_version: self
+                .api_version
+                .filter(|s| !s.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_AZURE_API_VERSION.to_string()),
+            script,
+            max_tokens_per_task: self.max_tokens_per_task,
+            cache: self.cache.unwrap_or(false),
+            cache_ttl: Duration::from_secs(cache_ttl_secs),
+            cache_dir,
+            max_retries,
+            retry_backoff: Duration::from_millis(
+                self.retry_backoff_ms
+                    .unwrap_or(DEFAULT_LLM_RETRY_BACKOFF_MS),
+            ),
+            transcript_dir,
+            redact,
+            profiles: BTreeMap::new(),
+        };
+        settings.profiles = self
+            .profiles
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, profile)| {
+                let profile = profile
+                    .into_settings(&settings)
+                    .with_context(|| format!("invalid llm profile `{name}`"))?;
+                Ok((name, profile))
+            })
+            .collect::<Result<_>>()?;
+        check_azure_names(&settings).context("invalid llm settings")?;
+        Ok(Some(settings))
     }
 }
 
+#[derive(Deserialize)]
+struct LlmProfileFileSettings {
+    provider: Option<LlmProvider>,
+    api_base: Option<String>,
+    api_key: Option<String>,
+    model: Option<String>,
+    embedding_model: Option<String>,
+    deployment: Option<String>,
+    api_version: Option<String>,
+    script: Option<String>,
+}
+
+impl LlmProfileFileSettings {
+    /// Takes whatever the profile leaves out from `[llm]`, except that a
+    /// profile switching provider starts from that provider's endpoint and
+    /// does not inherit the key.
+    fn into_settings(self, base: &LlmSettings) -> Result<LlmSettings> {
+        let provider = self.provider.unwrap_or(base.provider);
+        let same_provider = provider == base.provider;
+        let api_base = match self.api_base.filter(|s| !s.trim().is_empty()) {
+            Some(api_base) => api_base,
+            None if same_provider => base.api_base.clone(),
+            None => provider
+                .default_api_base()
+                .context("api_base must name the Azure OpenAI resource")?
+                .to_string(),
+        };
+        let api_key = match self.api_key.filter(|s| !s.trim().is_empty()) {
+            Some(key) => key,
+            None if same_provider => base.api_key.clone(),
+            None if !provider.needs_api_key() => String::new(),
+            None => anyhow::bail!("api_key is required when the profile changes provider"),
+        };
+        let model = self.model.filter(|s| !s.trim().is_empty());
+        let own_deployment = self.deployment.filter(|s| !s.trim().is_empty());
+        // A deployment serves one model, so a profile naming another model
+        // does not keep the base deployment.
+        let deployment = match &own_deployment {
+            Some(deployment) => Some(deployment.clone()),
+            None if same_provider && model.is_none() => base.deployment.clone(),
+            None => None,
+        };
+        let model = model
+            .or(own_deployment)
+            .unwrap_or_else(|| base.model.clone());
+        let api_version = match self.api_version.filter(|s| !s.trim().is_empty()) {
+            Some(api_version) => api_version,
+            None => base.api_version.clone(),
+        };
+        let script = match self.script.filter(|s| !s.trim().is_empty()) {
+            Some(path) => Some(
+                normalize_path(Path::new(&path))
+                    .with_context(|| format!("invalid script {path}"))?,
+            ),
+            None => base.script.clone(),
+        };
+        if provider == LlmProvider::Script && script.is_none() {
+            anyhow::bail!("provider = \"script\" needs a script");
+        }
+        let embedding_model = match self.embedding_model.filter(|s| !s.trim().is_empty()) {
+            Some(model) => Some(model),
+            None if same_provider => base.embedding_model.clone(),
+            None => None,
+        };
+        let settings = LlmSettings {
+            provider,
+            api_base,
+            api_key,
+            model,
+            embedding_model,
+            deployment,
+            api_version,
+            script,
+            profiles: BTreeMap::new(),
+            ..base.clone()
+        };
+        check_azure_names(&settings)?;
+        Ok(settings)
+    }
+}
+
+/// Azure deployment names end up in the request path, so they are held to
+/// the characters Azure itself allows.
+fn check_azure_names(settings: &LlmSettings) -> Result<()> {
+    if settings.provider != LlmProvider::Azure {
+        return Ok(());
+    }
+    for name in [settings.deployment(), settings.embedding_model()] {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            anyhow::bail!("invalid Azure deployment name {name:?}");
+        }
+    }
+    Ok(())
+}
+
+fn normalize_paths(paths: Vec<String>) -> Result<Vec<Utf8PathBuf>> {
+    paths
+        .iter()
+        .map(|path| normalize_path(Path::new(path)).with_context(|| format!("invalid path {path}")))
+        .collect()
+}
+
 fn normalize_path(path: &Path) -> Result<Utf8PathBuf> {
     let absolute = if path.is_absolute() {
         path.to_path_buf()
@@ -139,21 +1633,104 @@ fn normalize_path(path: &Path) -> Result<Utf8PathBuf> {
     Utf8PathBuf::from_path_buf(canonical).map_err(|_| anyhow::anyhow!("path is not valid UTF-8"))
 }
 
+const DEFAULT_MAX_EVAL_BYTES: usize = 256 * 1024;
+const DEFAULT_LLM_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+const DEFAULT_LLM_MAX_RETRIES: u32 = 2;
+const MAX_LLM_RETRIES: u32 = 10;
+const DEFAULT_LLM_RETRY_BACKOFF_MS: u64 = 500;
+
 #[derive(Deserialize)]
 struct BrowserFileSettings {
+    backend: Option<BrowserBackend>,
+    kind: Option<BrowserKind>,
     webdriver_url: Option<String>,
+    manage_driver: Option<bool>,
+    chromedriver_path: Option<String>,
+    geckodriver_path: Option<String>,
+    attach: Option<String>,
+    chrome_path: Option<String>,
     default_profile: Option<String>,
+    profile_dir: Option<String>,
+    allowed_domains: Option<Vec<String>>,
+    urls: Option<ArgRuleFileSettings>,
+    allow_eval: Option<bool>,
+    max_eval_bytes: Option<usize>,
+    idle_timeout_ms: Option<u64>,
 }
 
 impl BrowserFileSettings {
     fn into_settings(self) -> Result<Option<BrowserSettings>> {
-        let url = match self.webdriver_url {
-            Some(url) if !url.trim().is_empty() => url,
-            _ => return Ok(None),
+        let backend = self.backend.unwrap_or_default();
+        let url = self.webdriver_url.filter(|url| !url.trim().is_empty());
+        let manage_driver = self.manage_driver.unwrap_or(false);
+        if manage_driver && backend != BrowserBackend::WebDriver {
+            anyhow::bail!("browser.manage_driver only applies to backend = \"webdriver\"");
+        }
+        if manage_driver && url.is_some() {
+            anyhow::bail!("set either browser.webdriver_url or browser.manage_driver, not both");
+        }
+        // Without a server to talk to, the WebDriver backend stays disabled.
+        if backend == BrowserBackend::WebDriver && url.is_none() && !manage_driver {
+            return Ok(None);
+        }
+        let kind = self.kind.unwrap_or_default();
+        if backend == BrowserBackend::Cdp && kind != BrowserKind::Chrome {
+            anyhow::bail!("browser.backend = \"cdp\" only supports kind = \"chrome\"");
+        }
+        // Only the DevTools protocol lets the host see every request.
+        if self.allowed_domains.is_some() && backend != BrowserBackend::Cdp {
+            anyhow::bail!("browser.allowed_domains is only enforced with backend = \"cdp\"");
+        }
+        if self.idle_timeout_ms == Some(0) {
+            anyhow::bail!("browser.idle_timeout_ms must be greater than zero");
+        }
+        let attach = match self.attach.filter(|url| !url.trim().is_empty()) {
+            Some(url) => {
+                // chromedriver is what connects to the operator's browser.
+                if backend != BrowserBackend::WebDriver || kind != BrowserKind::Chrome {
+                    anyhow::bail!(
+                        "browser.attach needs backend = \"webdriver\" and kind = \"chrome\""
+                    );
+                }
+                // Saving a profile would copy the operator's cookies into
+                // the workspace.
+                if self
+                    .default_profile
+                    .as_deref()
+                    .is_some_and(|profile| !profile.trim().is_empty())
+                {
+                    anyhow::bail!("browser.attach can't be combined with browser.default_profile");
+                }
+                Some(debugger_address(&url)?)
+            }
+            None => None,
+        };
+        let profile_dir = match self.profile_dir.filter(|s| !s.trim().is_empty()) {
+            Some(dir) => Some(
+                normalize_path(Path::new(&dir))
+                    .with_context(|| format!("invalid browser profile dir {dir}"))?,
+            ),
+            None => None,
         };
         Ok(Some(BrowserSettings {
-            webdriver_url: url,
+            backend,
+            kind,
+            webdriver_url: url.unwrap_or_default(),
+            manage_driver,
+            chromedriver_path: self
+                .chromedriver_path
+                .filter(|path| !path.trim().is_empty()),
+            geckodriver_path: self.geckodriver_path.filter(|path| !path.trim().is_empty()),
+            attach,
+            attach_confirmed: false,
+            chrome_path: self.chrome_path.filter(|path| !path.trim().is_empty()),
             default_profile: self.default_profile.filter(|p| !p.trim().is_empty()),
+            profile_dir,
+            allowed_domains: self.allowed_domains.map(DomainAllowlist::new),
+            allow_eval: self.allow_eval.unwrap_or(false),
+            max_eval_bytes: self.max_eval_bytes.unwrap_or(DEFAULT_MAX_EVAL_BYTES),
+            idle_timeout: self.idle_timeout_ms.map(Duration::from_millis),
         }))
     }
 }
@@ -0,0 +1,40 @@
+//! The deadline of the planner action running on this thread, from
+//! `planner.action_timeout_ms`. Waits on something outside hostd (WebDriver
+//! and DevTools requests, spawned processes, shell commands) shorten their
+//! own timeouts to it, so one that hangs fails its action instead of
+//! stalling the step.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Runs `work` with a deadline `limit` from now on this thread.
+pub fn within<T>(limit: Duration, work: impl FnOnce() -> T) -> T {
+    let previous = DEADLINE.replace(Instant::now().checked_add(limit));
+    let result = work();
+    DEADLINE.set(previous);
+    result
+}
+
+/// Time left before the deadline; `None` when there is none.
+pub fn remaining() -> Option<Duration> {
+    DEADLINE
+        .get()
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// `timeout`, shortened to the time left before the deadline.
+pub fn bound(timeout: Duration) -> Duration {
+    remaining().map_or(timeout, |left| left.min(timeout))
+}
+
+/// Like `bound`, for waits that may have no timeout of their own.
+pub fn bound_optional(timeout: Option<Duration>) -> Option<Duration> {
+    match (timeout, remaining()) {
+        (Some(timeout), Some(left)) => Some(timeout.min(left)),
+        (timeout, left) => timeout.or(left),
+    }
+}
@@ -0,0 +1,126 @@
+//! Runs chromedriver/geckodriver for `browser.manage_driver`, so no WebDriver
+//! server has to be started by hand. One driver per browser kind is shared by
+//! every session; a driver found dead is restarted when the next session
+//! opens, and all of them are stopped by [`shutdown`].
+
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::browser::{BrowserError, BrowserKind};
+use crate::config::BrowserSettings;
+use crate::process;
+
+/// How long a freshly spawned driver gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const STARTUP_POLL: Duration = Duration::from_millis(50);
+
+static DRIVERS: Mutex<Vec<Driver>> = Mutex::new(Vec::new());
+
+struct Driver {
+    kind: BrowserKind,
+    child: Child,
+    url: String,
+}
+
+fn drivers() -> MutexGuard<'static, Vec<Driver>> {
+    DRIVERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The URL of a running driver for `kind`, starting or restarting it first
+/// if needed.
+pub fn url(settings: &BrowserSettings, kind: BrowserKind) -> Result<String, BrowserError> {
+    let mut drivers = drivers();
+    if let Some(index) = drivers.iter().position(|driver| driver.kind == kind) {
+        let driver = &mut drivers[index];
+        match driver.child.try_wait() {
+            Ok(None) => return Ok(driver.url.clone()),
+            Ok(Some(status)) => {
+                warn!(?kind, %status, "managed WebDriver exited; restarting it");
+            }
+            Err(err) => warn!(
+                ?kind,
+                "lost track of managed WebDriver: {err}; restarting it"
+            ),
+        }
+        let mut driver = drivers.swap_remove(index);
+        stop(&mut driver);
+    }
+    let driver = spawn(settings, kind)?;
+    let url = driver.url.clone();
+    drivers.push(driver);
+    Ok(url)
+}
+
+/// Stops every managed driver; sessions still using one will fail.
+pub fn shutdown() {
+    for mut driver in drivers().drain(..) {
+        stop(&mut driver);
+    }
+}
+
+fn spawn(settings: &BrowserSettings, kind: BrowserKind) -> Result<Driver, BrowserError> {
+    let (configured, default) = match kind {
+        BrowserKind::Chrome => (&settings.chromedriver_path, "chromedriver"),
+        BrowserKind::Firefox => (&settings.geckodriver_path, "geckodriver"),
+    };
+    let program = configured.as_deref().unwrap_or(default);
+    let port = free_port()?;
+    let mut command = Command::new(program);
+    match kind {
+        BrowserKind::Chrome => command.arg(format!("--port={port}")),
+        BrowserKind::Firefox => command.args(["--port", &port.to_string()]),
+    };
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    process::isolate_group(&mut command);
+    let child = command
+        .spawn()
+        .map_err(|err| BrowserError::Launch(format!("failed to start {program}: {err}")))?;
+    process::track_group(child.id());
+    let mut driver = Driver {
+        kind,
+        child,
+        url: format!("http://127.0.0.1:{port}"),
+    };
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok() {
+            info!(?kind, url = %driver.url, "started managed WebDriver");
+            return Ok(driver);
+        }
+        let exited = driver.child.try_wait().ok().flatten();
+        if exited.is_some() || Instant::now() >= deadline {
+            stop(&mut driver);
+            let reason = match exited {
+                Some(status) => format!("exited with {status}"),
+                None => format!("was not listening after {STARTUP_TIMEOUT:?}"),
+            };
+            return Err(BrowserError::Launch(format!("{program} {reason}")));
+        }
+        thread::sleep(STARTUP_POLL);
+    }
+}
+
+/// A port nothing is listening on right now. Another process could take it
+/// before the driver binds it, in which case the driver fails to start and
+/// the session reports that.
+fn free_port() -> Result<u16, BrowserError> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+fn stop(driver: &mut Driver) {
+    let pid = driver.child.id();
+    let _ = process::kill_group(pid);
+    let _ = driver.child.wait();
+    process::untrack_group(pid);
+}
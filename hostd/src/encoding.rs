@@ -0,0 +1,60 @@
+/// Text encodings the host can recognise in file contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Binary,
+}
+
+impl TextEncoding {
+    /// Label used in JSON action output; binary data is shipped as base64.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16Le => "utf-16le",
+            Self::Utf16Be => "utf-16be",
+            Self::Binary => "base64",
+        }
+    }
+}
+
+/// Classifies `bytes`. UTF-16 is only recognised by its BOM. When the read was
+/// truncated, an incomplete trailing UTF-8 sequence does not make it binary.
+pub fn detect(bytes: &[u8], truncated: bool) -> TextEncoding {
+    if bytes.starts_with(&[0xFF, 0xFE]) {
+        return TextEncoding::Utf16Le;
+    }
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        return TextEncoding::Utf16Be;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => TextEncoding::Utf8,
+        Err(err) if truncated && err.error_len().is_none() => TextEncoding::Utf8,
+        Err(_) => TextEncoding::Binary,
+    }
+}
+
+/// Decodes text detected by [`detect`]; returns `None` for binary data.
+pub fn decode(bytes: &[u8], encoding: TextEncoding) -> Option<String> {
+    match encoding {
+        TextEncoding::Utf8 => {
+            let valid = match std::str::from_utf8(bytes) {
+                Ok(_) => bytes.len(),
+                Err(err) => err.valid_up_to(),
+            };
+            Some(String::from_utf8_lossy(&bytes[..valid]).into_owned())
+        }
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+            let units: Vec<u16> = bytes[2..]
+                .chunks_exact(2)
+                .map(|pair| match encoding {
+                    TextEncoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                })
+                .collect();
+            Some(String::from_utf16_lossy(&units))
+        }
+        TextEncoding::Binary => None,
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::config::ProcSettings;
+use crate::process::{self, Priority};
+use crate::sandbox;
+
+static NEXT_CONTAINER: AtomicU64 = AtomicU64::new(0);
+
+const LOW_PRIORITY_CPU_SHARES: u32 = 256;
+
+/// Where guest commands run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcBackend {
+    /// Directly on the host, confined by `proc.sandbox`, seccomp and Landlock.
+    #[default]
+    Host,
+    Docker,
+    Podman,
+}
+
+impl ProcBackend {
+    pub fn is_container(self) -> bool {
+        matches!(self, Self::Docker | Self::Podman)
+    }
+
+    fn runtime(self) -> &'static str {
+        match self {
+            Self::Host | Self::Docker => "docker",
+            Self::Podman => "podman",
+        }
+    }
+}
+
+/// `[proc.container]`: the image and runtime flags for the container backends.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContainerSettings {
+    pub image: String,
+    /// Runtime binary; defaults to `docker` or `podman` per backend.
+    pub runtime_path: Option<String>,
+    /// Where the workspace is mounted inside the container.
+    pub workdir: String,
+    pub network: bool,
+    /// Mount the image read-only, with a tmpfs on /tmp.
+    pub read_only: bool,
+    /// Taken from `proc.cpu_quota`; passed as `--cpus`.
+    #[serde(skip)]
+    pub cpus: Option<f64>,
+    /// Taken from `proc.memory_limit_mb`; passed as `--memory`.
+    #[serde(skip)]
+    pub memory_limit_mb: Option<u64>,
+}
+
+impl Default for ContainerSettings {
+    fn default() -> Self {
+        Self {
+            image: String::new(),
+            runtime_path: None,
+            workdir: "/workspace".to_string(),
+            network: false,
+            read_only: true,
+            cpus: None,
+            memory_limit_mb: None,
+        }
+    }
+}
+
+/// Everything needed to start one guest command. `cwd` must already be
+/// validated to lie inside `workspace`; `env` already checked against policy.
+pub struct CommandSpec<'a> {
+    pub workspace: &'a Utf8Path,
+    pub cwd: &'a Utf8Path,
+    pub program: &'a str,
+    pub args: &'a [String],
+    pub env: &'a [(String, String)],
+    /// The caller will attach a pty to the command's stdio.
+    pub tty: bool,
+    pub priority: Priority,
+}
+
+pub struct Prepared {
+    pub command: Command,
+    /// Removed once every holder drops it; keep it for the child's lifetime.
+    pub container: Option<Arc<Container>>,
+}
+
+/// Turns a guest command into a host `Command`. Callers add stdio, cgroups
+/// and process groups themselves.
+pub trait Executor {
+    fn prepare(&self, spec: &CommandSpec<'_>) -> Result<Prepared>;
+}
+
+/// Picks the executor for `proc.backend`.
+pub fn for_settings(settings: &ProcSettings) -> Result<Box<dyn Executor + '_>> {
+    match settings.backend {
+        ProcBackend::Host => Ok(Box::new(HostExecutor { settings })),
+        backend => {
+            let container = settings
+                .container
+                .as_ref()
+                .context("proc.backend requires a [proc.container] section")?;
+            Ok(Box::new(ContainerExecutor {
+                backend,
+                settings,
+                container,
+            }))
+        }
+    }
+}
+
+pub struct HostExecutor<'a> {
+    settings: &'a ProcSettings,
+}
+
+impl Executor for HostExecutor<'_> {
+    fn prepare(&self, spec: &CommandSpec<'_>) -> Result<Prepared> {
+        let mut command = sandbox::command(
+            self.settings,
+            spec.workspace,
+            spec.cwd,
+            spec.program,
+            spec.args,
+        )?;
+        command.env_clear();
+        command.envs(spec.env.iter().map(|(key, value)| (key, value)));
+        process::apply_priority(&mut command, spec.priority);
+        Ok(Prepared {
+            command,
+            container: None,
+        })
+    }
+}
+
+/// Runs each command in a fresh `--rm` container with the workspace bind
+/// mounted, so the allowlist can be opened up without exposing the host.
+pub struct ContainerExecutor<'a> {
+    backend: ProcBackend,
+    settings: &'a ProcSettings,
+    container: &'a ContainerSettings,
+}
+
+impl ContainerExecutor<'_> {
+    fn runtime_path(&self) -> &str {
+        self.container
+            .runtime_path
+            .as_deref()
+            .unwrap_or(self.backend.runtime())
+    }
+
+    fn run_args(&self, spec: &CommandSpec<'_>, name: &str) -> Vec<String> {
+        let settings = self.container;
+        let relative = spec
+            .cwd
+            .strip_prefix(spec.workspace)
+            .unwrap_or(Utf8Path::new(""));
+        let workdir = Utf8Path::new(&settings.workdir).join(relative);
+        let mut args: Vec<String> = vec![
+            "run".into(),
+            "--rm".into(),
+            "-i".into(),
+            "--init".into(),
+            "--name".into(),
+            name.into(),
+            "--cap-drop".into(),
+            "ALL".into(),
+            "--security-opt".into(),
+            "no-new-privileges".into(),
+        ];
+        if spec.tty {
+            args.push("-t".into());
+        }
+        if !settings.network {
+            args.extend(["--network".into(), "none".into()]);
+        }
+        if settings.read_only {
+            args.extend(["--read-only".into(), "--tmpfs".into(), "/tmp".into()]);
+        }
+        args.extend(self.user_args());
+        args.extend([
+            "-v".into(),
+            format!("{}:{}", spec.workspace, settings.workdir),
+            "-w".into(),
+            workdir.to_string(),
+        ]);
+        if let Some(cpus) = settings.cpus {
+            args.extend(["--cpus".into(), cpus.to_string()]);
+        }
+        if let Some(memory) = settings.memory_limit_mb {
+            args.extend(["--memory".into(), format!("{memory}m")]);
+        }
+        // Nice on the runtime client would not reach the container, so weight
+        // its CPU share instead (the default weight is 1024).
+        if spec.priority == Priority::Low {
+            args.extend(["--cpu-shares".into(), LOW_PRIORITY_CPU_SHARES.to_string()]);
+        }
+        if let Some(limits) = self.settings.rlimits_for(spec.program) {
+            for ulimit in limits.ulimit_args() {
+                args.extend(["--ulimit".into(), ulimit]);
+            }
+        }
+        // Passed as flags rather than through the runtime's own environment,
+        // which must stay the host's so the guest cannot redirect the runtime.
+        for (key, value) in spec.env {
+            args.extend(["-e".into(), format!("{key}={value}")]);
+        }
+        args.push(settings.image.clone());
+        args.push(spec.program.to_string());
+        args.extend(spec.args.iter().cloned());
+        args
+    }
+
+    /// Runs as the host user so files written to the workspace keep their
+    /// owner; rootless podman maps that user through the user namespace.
+    fn user_args(&self) -> Vec<String> {
+        if self.backend == ProcBackend::Podman {
+            return vec!["--userns=keep-id".into()];
+        }
+        #[cfg(unix)]
+        {
+            // SAFETY: getuid and getgid cannot fail and have no preconditions.
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            vec!["--user".into(), format!("{uid}:{gid}")]
+        }
+        #[cfg(not(unix))]
+        Vec::new()
+    }
+}
+
+impl Executor for ContainerExecutor<'_> {
+    fn prepare(&self, spec: &CommandSpec<'_>) -> Result<Prepared> {
+        let name = format!(
+            "wasi-warden-{}-{}",
+            std::process::id(),
+            NEXT_CONTAINER.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut command = Command::new(self.runtime_path());
+        command.args(self.run_args(spec, &name));
+        Ok(Prepared {
+            command,
+            container: Some(Arc::new(Container::new(
+                self.runtime_path().to_string(),
+                name,
+            ))),
+        })
+    }
+}
+
+/// Containers not yet removed, for [`remove_tracked_containers`].
+static TRACKED_CONTAINERS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+fn tracked_containers() -> MutexGuard<'static, BTreeMap<String, String>> {
+    TRACKED_CONTAINERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Force-removes every container still owned by this host, for shutdown paths
+/// that exit without dropping their owners. Returns how many there were.
+pub fn remove_tracked_containers() -> usize {
+    let containers = std::mem::take(&mut *tracked_containers());
+    for (name, runtime) in &containers {
+        remove_container(runtime, name);
+    }
+    containers.len()
+}
+
+fn remove_container(runtime: &str, name: &str) {
+    let removed = Command::new(runtime)
+        .args(["rm", "-f", name])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if let Err(err) = removed {
+        debug!(container = %name, "failed to remove container: {err}");
+    }
+}
+
+/// A named container started by [`ContainerExecutor`]. Killing the runtime
+/// client does not stop the container, so it is force-removed on drop.
+#[derive(Debug)]
+pub struct Container {
+    runtime: String,
+    name: String,
+}
+
+impl Container {
+    fn new(runtime: String, name: String) -> Self {
+        tracked_containers().insert(name.clone(), runtime.clone());
+        Self { runtime, name }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if tracked_containers().remove(&self.name).is_some() {
+            remove_container(&self.runtime, &self.name);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, mpsc};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::audit;
+use crate::config::HostConfig;
+use crate::notify;
+
+/// Capabilities an operator can grant at run time, on top of the config.
+/// `proc` grants name one command in their scope.
+pub const GRANTABLE: &[&str] = &["proc", "shell", "input", "screen"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrantDecision {
+    Deny,
+    /// For the rest of the current planner step, including the actions it
+    /// returns.
+    Step,
+    /// For the rest of the task.
+    Session,
+}
+
+type GrantKey = (String, Option<String>);
+
+/// What the operator approved for this task, shared by the guest's
+/// capability calls and the planner's actions.
+#[derive(Debug, Default)]
+pub struct Grants {
+    step: Mutex<BTreeSet<GrantKey>>,
+    session: Mutex<BTreeSet<GrantKey>>,
+}
+
+impl Grants {
+    pub fn insert(&self, capability: &str, scope: Option<&str>, decision: GrantDecision) {
+        let key = (capability.to_string(), scope.map(str::to_string));
+        let set = match decision {
+            GrantDecision::Deny => return,
+            GrantDecision::Step => &self.step,
+            GrantDecision::Session => &self.session,
+        };
+        set.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(key);
+    }
+
+    /// Whether `capability` (for `scope`, if it takes one) was granted.
+    pub fn allows(&self, capability: &str, scope: Option<&str>) -> bool {
+        let key = (capability.to_string(), scope.map(str::to_string));
+        [&self.session, &self.step].into_iter().any(|set| {
+            set.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .contains(&key)
+        })
+    }
+
+    /// `proc` grants match the program as given or by its file name, like
+    /// `allow_proc`.
+    pub fn allows_command(&self, program: &str) -> bool {
+        let base = Path::new(program)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(program);
+        self.allows("proc", Some(program)) || self.allows("proc", Some(base))
+    }
+
+    /// Drops the grants approved for a single step.
+    pub fn end_step(&self) {
+        self.step
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+}
+
+/// Something waiting on a reviewer's answer.
+pub struct Pending<'a> {
+    /// `capability` for `request-capability`, `action` for a planner action
+    /// listed in `policy.approval_required`.
+    pub kind: &'a str,
+    pub capability: &'a str,
+    pub scope: Option<&'a str>,
+    /// The guest's justification, or the action's JSON input.
+    pub detail: &'a str,
+}
+
+pub struct Answer {
+    pub decision: GrantDecision,
+    /// Shown to the guest along with the decision.
+    pub reason: Option<String>,
+}
+
+/// Answers approval requests in place of the terminal, when a client owns
+/// stdin (`hostd stdio`). Gets `policy.approval_timeout`.
+pub type Approver = dyn Fn(&Pending<'_>, Duration) -> Result<Answer, String> + Send + Sync;
+
+static APPROVER: OnceLock<Box<Approver>> = OnceLock::new();
+
+/// Sends approval requests to `approver` for the rest of the process.
+pub fn set_approver(approver: Box<Approver>) {
+    if APPROVER.set(approver).is_err() {
+        warn!("an approver is already set; keeping it");
+    }
+}
+
+/// Approval requests waiting for an answer by id, from gRPC `Approve` or a
+/// Slack button (`hostd serve`).
+#[derive(Default)]
+pub struct Approvals {
+    next_id: AtomicU64,
+    waiting: Mutex<HashMap<u64, mpsc::Sender<Answer>>>,
+}
+
+impl Approvals {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, mpsc::Sender<Answer>>> {
+        self.waiting
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Registers a request, hands its id to `announce` and waits up to
+    /// `timeout` for `answer`.
+    fn ask(&self, timeout: Duration, announce: impl FnOnce(u64)) -> Result<Answer, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (answer, answered) = mpsc::channel();
+        self.lock().insert(id, answer);
+        announce(id);
+        let answer = answered.recv_timeout(timeout);
+        self.lock().remove(&id);
+        answer.map_err(|_| format!("no answer to approval request {id} within {timeout:?}"))
+    }
+
+    /// Whether a request with this id was waiting.
+    pub fn answer(&self, id: u64, answer: Answer) -> bool {
+        self.lock()
+            .remove(&id)
+            .is_some_and(|waiting| waiting.send(answer).is_ok())
+    }
+}
+
+/// Announces a request to a `hostd serve` client, with the id it answers.
+pub type Announce = dyn Fn(u64, &Pending<'_>) + Send + Sync;
+
+/// How `hostd serve` takes answers in place of the terminal.
+pub struct Remote {
+    pub approvals: Arc<Approvals>,
+    /// Set with the gRPC API, which adds requests to the task's events.
+    pub announce: Option<Box<Announce>>,
+    /// Set with `--slack-signing-secret`: Slack notifications carry approve
+    /// and deny buttons, answered through `POST /slack/actions`.
+    pub slack_buttons: bool,
+}
+
+static REMOTE: OnceLock<Remote> = OnceLock::new();
+
+/// Sends approval requests to `hostd serve` clients for the rest of the
+/// process.
+pub fn set_remote(remote: Remote) {
+    if REMOTE.set(remote).is_err() {
+        warn!("remote approvals are already set up; keeping them");
+    }
+}
+
+/// Asks `policy.approval_webhook` when set, otherwise `hostd serve` clients,
+/// the client that set an approver or the operator on the terminal. `Err`
+/// says why nobody could answer; the caller denies then.
+pub fn approve(config: &HostConfig, pending: &Pending<'_>) -> Result<Answer, String> {
+    let policy = &config.policy;
+    if let Some(remote) = REMOTE.get().filter(|_| policy.approval_webhook.is_none()) {
+        let slack = remote.slack_buttons
+            && config
+                .notify
+                .as_ref()
+                .is_some_and(|settings| settings.slack_webhook.is_some());
+        if slack || remote.announce.is_some() {
+            let answer = remote.approvals.ask(policy.approval_timeout, |id| {
+                if let Some(settings) = &config.notify {
+                    notify::approval_pending(settings, pending, slack.then_some(id));
+                }
+                if let Some(announce) = &remote.announce {
+                    announce(id, pending);
+                }
+            })?;
+            info!(
+                target: "audit",
+                kind = pending.kind,
+                capability = pending.capability,
+                scope = pending.scope,
+                decision = ?answer.decision,
+                reason = answer.reason,
+                "client answered approval request"
+            );
+            record_answer("client", pending, &answer);
+            return Ok(answer);
+        }
+    }
+    if let Some(settings) = &config.notify {
+        notify::approval_pending(settings, pending, None);
+    }
+    if let Some(url) = &policy.approval_webhook {
+        let answer = ask_webhook(url, policy.approval_timeout, pending)?;
+        info!(
+            target: "audit",
+            kind = pending.kind,
+            capability = pending.capability,
+            scope = pending.scope,
+            decision = ?answer.decision,
+            reason = answer.reason,
+            "approval webhook answered"
+        );
+        record_answer("approval_webhook", pending, &answer);
+        return Ok(answer);
+    }
+    if let Some(approver) = APPROVER.get() {
+        let answer = approver(pending, policy.approval_timeout)?;
+        info!(
+            target: "audit",
+            kind = pending.kind,
+            capability = pending.capability,
+            scope = pending.scope,
+            decision = ?answer.decision,
+            reason = answer.reason,
+            "client answered approval request"
+        );
+        record_answer("client", pending, &answer);
+        return Ok(answer);
+    }
+    let decision = prompt(pending.capability, pending.scope, pending.detail)
+        .ok_or_else(|| "no operator terminal to approve the request".to_string())?;
+    info!(
+        target: "audit",
+        kind = pending.kind,
+        capability = pending.capability,
+        scope = pending.scope,
+        detail = pending.detail,
+        ?decision,
+        "operator answered approval request"
+    );
+    let answer = Answer {
+        decision,
+        reason: None,
+    };
+    record_answer("terminal", pending, &answer);
+    Ok(answer)
+}
+
+fn record_answer(approver: &str, pending: &Pending<'_>, answer: &Answer) {
+    audit::record(
+        "host",
+        "approval_answered",
+        None,
+        json!({
+            "approver": approver,
+            "kind": pending.kind,
+            "capability": pending.capability,
+            "scope": pending.scope,
+            "detail": pending.detail,
+            "decision": format!("{:?}", answer.decision).to_lowercase(),
+            "reason": answer.reason,
+        }),
+    );
+}
+
+/// Holds an operation `[policy.confirm]` flagged until a reviewer approves
+/// it; an earlier approval of the same operation for this step or task
+/// covers it. `Err` says why it may not go ahead.
+pub fn confirm(
+    config: &HostConfig,
+    grants: &Grants,
+    capability: &str,
+    scope: &str,
+    reason: &str,
+) -> Result<(), String> {
+    if grants.allows(capability, Some(scope)) {
+        return Ok(());
+    }
+    let pending = Pending {
+        kind: "confirmation",
+        capability,
+        scope: Some(scope),
+        detail: reason,
+    };
+    let answer = approve(config, &pending)?;
+    grants.insert(capability, Some(scope), answer.decision);
+    if answer.decision == GrantDecision::Deny {
+        return Err(answer
+            .reason
+            .unwrap_or_else(|| "denied by the reviewer".to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ReviewerReply {
+    approved: bool,
+    /// `task` approves for the rest of the task; anything else, for the
+    /// current step.
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl ReviewerReply {
+    fn into_answer(self) -> Answer {
+        let decision = match (self.approved, self.scope.as_deref()) {
+            (false, _) => GrantDecision::Deny,
+            (true, Some("task")) => GrantDecision::Session,
+            (true, _) => GrantDecision::Step,
+        };
+        Answer {
+            decision,
+            reason: self.reason,
+        }
+    }
+}
+
+/// Reads a reviewer's `{"approved": bool, "scope": "step" | "task", "reason": ...}`
+/// from a client that is not the webhook.
+pub fn answer_from_json(reply: Value) -> Result<Answer, String> {
+    serde_json::from_value::<ReviewerReply>(reply)
+        .map(ReviewerReply::into_answer)
+        .map_err(|err| format!("invalid approval reply: {err}"))
+}
+
+/// Posts the request and waits up to `timeout` for the reviewer's JSON
+/// reply, `{"approved": bool, "scope": "step" | "task", "reason": ...}`.
+fn ask_webhook(url: &Url, timeout: Duration, pending: &Pending<'_>) -> Result<Answer, String> {
+    let body = json!({
+        "kind": pending.kind,
+        "capability": pending.capability,
+        "scope": pending.scope,
+        "detail": pending.detail,
+        "pid": std::process::id(),
+    });
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = agent
+        .post(url.as_str())
+        .send_json(body)
+        .map_err(|err| format!("approval webhook failed: {err}"))?;
+    let reply: ReviewerReply = response
+        .into_json()
+        .map_err(|err| format!("approval webhook sent an invalid reply: {err}"))?;
+    Ok(reply.into_answer())
+}
+
+/// Asks the operator on the terminal; `None` when hostd has no terminal to
+/// ask on.
+fn prompt(capability: &str, scope: Option<&str>, justification: &str) -> Option<GrantDecision> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() || !io::stderr().is_terminal() {
+        return None;
+    }
+    let mut stderr = io::stderr().lock();
+    let target = match scope {
+        Some(scope) => format!("{capability} ({})", printable(scope)),
+        None => capability.to_string(),
+    };
+    let _ = writeln!(
+        stderr,
+        "\nThe agent asks for `{target}`: {}",
+        printable(justification)
+    );
+    loop {
+        let _ = write!(
+            stderr,
+            "Approve? [y] this step / [s] this task / [n] deny: "
+        );
+        let _ = stderr.flush();
+        let mut answer = String::new();
+        match stdin.lock().read_line(&mut answer) {
+            Ok(0) | Err(_) => return Some(GrantDecision::Deny),
+            Ok(_) => {}
+        }
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Some(GrantDecision::Step),
+            "s" | "session" => return Some(GrantDecision::Session),
+            "n" | "no" | "" => return Some(GrantDecision::Deny),
+            _ => {}
+        }
+    }
+}
+
+/// Guest text with control characters replaced, so it cannot move the
+/// cursor or rewrite the prompt around it.
+fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
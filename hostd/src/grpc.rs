@@ -0,0 +1,260 @@
+//! gRPC front end to the task queue (`hostd serve --grpc-listen`), for
+//! services in Go, Python and the like. `proto/warden/v1/control.proto`
+//! defines it.
+//!
+//! Approval requests from any task, whichever API submitted it, are added to
+//! its events and wait for `Approve` (or a Slack button) instead of the
+//! terminal. `policy.approval_webhook` still wins when set.
+//!
+//! Calls must carry the same bearer token as the HTTP API, in
+//! `authorization` metadata.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::grant::{Announce, Answer, Approvals, GrantDecision, Pending};
+use crate::queue::{CancelError, TaskQueue, TaskRequest, current_task};
+use crate::serve::token_matches;
+
+mod proto {
+    tonic::include_proto!("warden.v1");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+/// Events buffered for a client that reads its stream slower than the task
+/// makes them.
+const EVENT_BUFFER: usize = 16;
+
+pub async fn serve(
+    address: SocketAddr,
+    queue: Arc<TaskQueue>,
+    token: Arc<str>,
+    approvals: Arc<Approvals>,
+) -> Result<()> {
+    info!(%address, "serving the gRPC task API");
+    let authorize = move |request: Request<()>| {
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if presented.is_some_and(|presented| token_matches(presented.trim(), &token)) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated(
+                "send the API token as `authorization: Bearer <token>` metadata",
+            ))
+        }
+    };
+    tonic::transport::Server::builder()
+        .add_service(ControlServer::with_interceptor(
+            Service { queue, approvals },
+            authorize,
+        ))
+        .serve(address)
+        .await
+        .with_context(|| format!("gRPC server on {address} failed"))
+}
+
+/// Adds approval requests to the events of the task asking, which runs on
+/// the calling thread, for `Approve` to answer.
+pub fn announcer(queue: Arc<TaskQueue>) -> Box<Announce> {
+    Box::new(move |id, pending: &Pending<'_>| {
+        let Some(task) = current_task() else {
+            warn!(
+                approval_id = id,
+                "no running task to announce approval request on"
+            );
+            return;
+        };
+        queue.push_event(
+            task,
+            json!({
+                "approval": {
+                    "approval_id": id,
+                    "kind": pending.kind,
+                    "capability": pending.capability,
+                    "scope": pending.scope,
+                    "detail": pending.detail,
+                },
+            }),
+        );
+    })
+}
+
+struct Service {
+    queue: Arc<TaskQueue>,
+    approvals: Arc<Approvals>,
+}
+
+#[tonic::async_trait]
+impl Control for Service {
+    async fn submit_task(
+        &self,
+        request: Request<proto::SubmitTaskRequest>,
+    ) -> Result<Response<proto::Task>, Status> {
+        let request = request.into_inner();
+        let observation = if request.observation_json.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(&request.observation_json).map_err(|err| {
+                Status::invalid_argument(format!("invalid observation_json: {err}"))
+            })?
+        };
+        let task = self
+            .queue
+            .submit(TaskRequest {
+                task: request.task,
+                observation,
+                max_steps: request.max_steps,
+                max_wall_clock: request.max_wall_clock,
+                rollback_on_failure: request.rollback_on_failure,
+                dry_run: request.dry_run,
+                no_llm_cache: request.no_llm_cache,
+                workspace: Some(request.workspace).filter(|dir| !dir.is_empty()),
+            })
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(task_message(&task)))
+    }
+
+    async fn get_task(
+        &self,
+        request: Request<proto::GetTaskRequest>,
+    ) -> Result<Response<proto::Task>, Status> {
+        let id = request.into_inner().task_id;
+        let task = self.queue.get(id).ok_or_else(|| not_found(id))?;
+        Ok(Response::new(task_message(&task)))
+    }
+
+    type StreamEventsStream = ReceiverStream<Result<proto::Event, Status>>;
+
+    async fn stream_events(
+        &self,
+        request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let id = request.into_inner().task_id;
+        if self.queue.get(id).is_none() {
+            return Err(not_found(id));
+        }
+        let (sender, receiver) = tokio::sync::mpsc::channel(EVENT_BUFFER);
+        let queue = Arc::clone(&self.queue);
+        // The queue blocks on a condition variable, so it is followed off
+        // the runtime.
+        thread::spawn(move || {
+            let mut seen = 0;
+            while let Some((events, last)) = queue.wait_events(id, seen) {
+                seen += events.len();
+                let finished = last.iter().map(|task| proto::Event {
+                    event: Some(proto::event::Event::Finished(task_message(task))),
+                });
+                for event in events.iter().map(event_message).chain(finished) {
+                    if sender.blocking_send(Ok(event)).is_err() {
+                        return;
+                    }
+                }
+                if last.is_some() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn approve(
+        &self,
+        request: Request<proto::ApproveRequest>,
+    ) -> Result<Response<proto::ApproveResponse>, Status> {
+        let request = request.into_inner();
+        let decision = match (request.approved, request.whole_task) {
+            (false, _) => GrantDecision::Deny,
+            (true, true) => GrantDecision::Session,
+            (true, false) => GrantDecision::Step,
+        };
+        let answer = Answer {
+            decision,
+            reason: Some(request.reason).filter(|reason| !reason.is_empty()),
+        };
+        if !self.approvals.answer(request.approval_id, answer) {
+            return Err(Status::not_found(format!(
+                "no approval request {} is waiting",
+                request.approval_id
+            )));
+        }
+        Ok(Response::new(proto::ApproveResponse {}))
+    }
+
+    async fn cancel(
+        &self,
+        request: Request<proto::CancelRequest>,
+    ) -> Result<Response<proto::Task>, Status> {
+        let id = request.into_inner().task_id;
+        match self.queue.cancel(id) {
+            Ok(task) => Ok(Response::new(task_message(&task))),
+            Err(CancelError::NotFound) => Err(not_found(id)),
+            Err(CancelError::Finished) => Err(Status::failed_precondition(format!(
+                "task {id} has already finished"
+            ))),
+        }
+    }
+}
+
+fn not_found(id: u64) -> Status {
+    Status::not_found(format!("no task {id}"))
+}
+
+/// From the JSON `TaskQueue` reports tasks as.
+fn task_message(task: &Value) -> proto::Task {
+    let status = match task["status"].as_str() {
+        Some("queued") => proto::TaskStatus::Queued,
+        Some("running") => proto::TaskStatus::Running,
+        Some("succeeded") => proto::TaskStatus::Succeeded,
+        Some("failed") => proto::TaskStatus::Failed,
+        Some("cancelled") => proto::TaskStatus::Cancelled,
+        _ => proto::TaskStatus::Unspecified,
+    };
+    proto::Task {
+        id: task["id"].as_u64().unwrap_or_default(),
+        task: task["task"].as_str().unwrap_or_default().to_string(),
+        status: status as i32,
+        steps: task["steps"].as_u64().unwrap_or_default(),
+        result_json: task
+            .get("result")
+            .filter(|result| !result.is_null())
+            .map(Value::to_string)
+            .unwrap_or_default(),
+        error: task["error"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn event_message(event: &Value) -> proto::Event {
+    let event = match event.get("approval") {
+        Some(approval) => proto::event::Event::Approval(proto::ApprovalRequest {
+            approval_id: approval["approval_id"].as_u64().unwrap_or_default(),
+            kind: approval["kind"].as_str().unwrap_or_default().to_string(),
+            capability: approval["capability"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            scope: approval["scope"].as_str().map(str::to_string),
+            detail: approval["detail"].as_str().unwrap_or_default().to_string(),
+        }),
+        None => proto::event::Event::Step(proto::Step {
+            step: event["step"]
+                .as_u64()
+                .and_then(|step| u32::try_from(step).ok())
+                .unwrap_or_default(),
+            thought: event["thought"].as_str().unwrap_or_default().to_string(),
+            actions_json: event["actions"].to_string(),
+            changed_files_json: event["changed_files"].to_string(),
+        }),
+    };
+    proto::Event { event: Some(event) }
+}
@@ -0,0 +1,287 @@
+//! Builds an HTTP Archive (HAR 1.2) from DevTools `Network.*` events, so a
+//! failed browsing task can be debugged from what the page actually fetched.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+use camino::Utf8Path;
+use serde_json::{Value, json};
+use url::Url;
+
+/// Requests beyond this many are counted but not kept.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Request and response metadata for one browser, fed from the CDP reader
+/// thread. Bodies are never captured.
+#[derive(Default)]
+pub struct NetworkLog {
+    state: Mutex<LogState>,
+}
+
+#[derive(Default)]
+struct LogState {
+    entries: Vec<Entry>,
+    /// Entries still waiting for their response to finish, keyed by CDP
+    /// session and request id.
+    open: HashMap<(String, String), usize>,
+    dropped: usize,
+}
+
+struct Entry {
+    /// Wall-clock start, in seconds since the epoch.
+    started: f64,
+    /// Monotonic timestamps from the browser, in seconds.
+    sent_at: f64,
+    response_at: Option<f64>,
+    finished_at: Option<f64>,
+    request: Value,
+    response: Option<Value>,
+    redirect_url: String,
+    size: Option<f64>,
+    error: Option<String>,
+}
+
+impl NetworkLog {
+    /// Folds one `Network.*` event into the log; other events are ignored.
+    pub fn record(&self, session: Option<&str>, method: &str, params: &Value) {
+        let Some(request_id) = params["requestId"].as_str() else {
+            return;
+        };
+        let key = (
+            session.unwrap_or_default().to_string(),
+            request_id.to_string(),
+        );
+        let timestamp = params["timestamp"].as_f64().unwrap_or(0.0);
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match method {
+            "Network.requestWillBeSent" => {
+                // A redirect reuses the request id: the hop so far ends here.
+                if let Some(index) = state.open.remove(&key) {
+                    let entry = &mut state.entries[index];
+                    if let Some(response) = params.get("redirectResponse") {
+                        entry.response = Some(response.clone());
+                        entry.response_at = Some(timestamp);
+                    }
+                    entry.finished_at = Some(timestamp);
+                    entry.redirect_url = params["request"]["url"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string();
+                }
+                if state.entries.len() >= MAX_ENTRIES {
+                    state.dropped += 1;
+                    return;
+                }
+                state.entries.push(Entry {
+                    started: params["wallTime"].as_f64().unwrap_or(0.0),
+                    sent_at: timestamp,
+                    response_at: None,
+                    finished_at: None,
+                    request: params["request"].clone(),
+                    response: None,
+                    redirect_url: String::new(),
+                    size: None,
+                    error: None,
+                });
+                let index = state.entries.len() - 1;
+                state.open.insert(key, index);
+            }
+            "Network.responseReceived" => {
+                if let Some(&index) = state.open.get(&key) {
+                    let entry = &mut state.entries[index];
+                    entry.response = Some(params["response"].clone());
+                    entry.response_at = Some(timestamp);
+                }
+            }
+            "Network.loadingFinished" => {
+                if let Some(index) = state.open.remove(&key) {
+                    let entry = &mut state.entries[index];
+                    entry.finished_at = Some(timestamp);
+                    entry.size = params["encodedDataLength"].as_f64();
+                }
+            }
+            "Network.loadingFailed" => {
+                if let Some(index) = state.open.remove(&key) {
+                    let entry = &mut state.entries[index];
+                    entry.finished_at = Some(timestamp);
+                    entry.error = params["errorText"].as_str().map(str::to_string);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn to_har(&self) -> Value {
+        let state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entries: Vec<Value> = state.entries.iter().map(Entry::to_har).collect();
+        let mut log = json!({
+            "version": "1.2",
+            "creator": { "name": "wasi-warden", "version": env!("CARGO_PKG_VERSION") },
+            "pages": [],
+            "entries": entries,
+        });
+        if state.dropped > 0 {
+            log["comment"] = format!("{} later requests were not recorded", state.dropped).into();
+        }
+        json!({ "log": log })
+    }
+
+    pub fn write(&self, path: &Utf8Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let bytes = serde_json::to_vec_pretty(&self.to_har()).map_err(io::Error::other)?;
+        fs::write(path, bytes)
+    }
+}
+
+impl Entry {
+    fn to_har(&self) -> Value {
+        let url = self.request["url"].as_str().unwrap_or_default();
+        let request_headers = headers(&self.request["headers"]);
+        let mut request = json!({
+            "method": self.request["method"].as_str().unwrap_or("GET"),
+            "url": url,
+            "httpVersion": self
+                .response
+                .as_ref()
+                .map_or(String::new(), |response| http_version(&response["protocol"])),
+            "cookies": [],
+            "headers": request_headers,
+            "queryString": query_string(url),
+            "headersSize": -1,
+            "bodySize": -1,
+        });
+        if let Some(text) = self.request["postData"].as_str() {
+            let mime_type = header_value(&self.request["headers"], "content-type");
+            request["postData"] = json!({ "mimeType": mime_type, "text": text });
+            request["bodySize"] = text.len().into();
+        }
+        let response = match &self.response {
+            Some(response) => json!({
+                "status": response["status"].as_u64().unwrap_or(0),
+                "statusText": response["statusText"].as_str().unwrap_or_default(),
+                "httpVersion": http_version(&response["protocol"]),
+                "cookies": [],
+                "headers": headers(&response["headers"]),
+                "content": {
+                    "size": self.size.unwrap_or(0.0),
+                    "mimeType": response["mimeType"].as_str().unwrap_or_default(),
+                },
+                "redirectURL": self.redirect_url,
+                "headersSize": -1,
+                "bodySize": self.size.unwrap_or(-1.0),
+            }),
+            None => json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": 0, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            }),
+        };
+        // HAR wants whole-ish milliseconds and no negative timings.
+        let millis = |from: f64, to: Option<f64>| {
+            to.map_or(0.0, |to| {
+                ((to - from) * 1_000_000.0).round().max(0.0) / 1000.0
+            })
+        };
+        let wait = millis(self.sent_at, self.response_at);
+        let receive = match self.response_at {
+            Some(at) => millis(at, self.finished_at),
+            None => 0.0,
+        };
+        let mut entry = json!({
+            "startedDateTime": iso8601(self.started),
+            "time": millis(self.sent_at, self.finished_at),
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": { "send": 0, "wait": wait, "receive": receive },
+        });
+        if let Some(error) = &self.error {
+            entry["_error"] = error.as_str().into();
+        }
+        entry
+    }
+}
+
+/// CDP sends headers as an object, joining repeated ones with newlines.
+fn headers(value: &Value) -> Vec<Value> {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .flat_map(|(name, value)| {
+            value
+                .as_str()
+                .unwrap_or_default()
+                .split('\n')
+                .map(move |value| json!({ "name": name, "value": value }))
+        })
+        .collect()
+}
+
+fn header_value(value: &Value, name: &str) -> String {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn query_string(url: &str) -> Vec<Value> {
+    match Url::parse(url) {
+        Ok(url) => url
+            .query_pairs()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn http_version(protocol: &Value) -> String {
+    match protocol.as_str().unwrap_or_default() {
+        "h2" => "HTTP/2".to_string(),
+        "h3" => "HTTP/3".to_string(),
+        other => other.to_uppercase(),
+    }
+}
+
+/// Formats seconds since the epoch as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn iso8601(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as i64;
+    let (days, day_millis) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    // Days to a proleptic Gregorian date (Howard Hinnant's civil_from_days).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        day_millis / 3_600_000,
+        day_millis / 60_000 % 60,
+        day_millis / 1000 % 60,
+        day_millis % 1000
+    )
+}
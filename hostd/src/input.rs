@@ -0,0 +1,574 @@
+//! Desktop keyboard and mouse for the `osagent:input` capability, through
+//! enigo (X11 or Wayland virtual input on Linux, SendInput on Windows,
+//! CGEvent on macOS). Every call first passes the `[input]` policy: the abort
+//! corner, the rate limit and the forbidden regions.
+
+use std::collections::{HashSet, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use enigo::{Axis, Button, Coordinate, Direction, Enigo, Key, Keyboard, Mouse, Settings};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::audit;
+use crate::config::InputSettings;
+use crate::macros::{self, Event, Macro};
+use crate::screen::{self, Rect};
+
+/// Longest a key or button may be held down for one call.
+pub const MAX_HOLD: Duration = Duration::from_secs(10);
+
+/// Side of the square in each corner of the main display that trips the
+/// abort switch.
+const ABORT_CORNER_SIZE: i32 = 4;
+
+#[derive(Debug, Error)]
+pub enum InputError {
+    #[error("cannot reach the desktop: {0}")]
+    Connect(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("unknown key `{0}`")]
+    UnknownKey(String),
+    #[error("hold of {0:?} is longer than the {MAX_HOLD:?} limit")]
+    HoldTooLong(Duration),
+    #[error("input was aborted by moving the pointer into a screen corner")]
+    Aborted,
+    #[error("more than {0} input actions in one second")]
+    RateLimited(u32),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("no input macro named `{0}`")]
+    NoSuchMacro(String),
+    #[error("invalid input macro: {0}")]
+    InvalidMacro(String),
+    #[error("{0}")]
+    Simulate(String),
+}
+
+impl From<enigo::InputError> for InputError {
+    fn from(err: enigo::InputError) -> Self {
+        Self::Simulate(err.to_string())
+    }
+}
+
+/// Which Linux display protocol input goes through; other platforms have
+/// only their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputBackend {
+    /// Wayland when `WAYLAND_DISPLAY` is set and hostd was built with the
+    /// `wayland` feature, X11 otherwise.
+    #[default]
+    Auto,
+    X11,
+    /// The virtual-keyboard and virtual-pointer protocols, offered by
+    /// wlroots compositors and KDE.
+    Wayland,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+    Meta,
+}
+
+impl Modifier {
+    fn key(self) -> Key {
+        match self {
+            Self::Shift => Key::Shift,
+            Self::Ctrl => Key::Control,
+            Self::Alt => Key::Alt,
+            Self::Meta => Key::Meta,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    fn button(self) -> Button {
+        match self {
+            Self::Left => Button::Left,
+            Self::Right => Button::Right,
+            Self::Middle => Button::Middle,
+        }
+    }
+}
+
+/// A connection to the desktop the host runs on, opened on first use.
+pub struct Desktop {
+    enigo: Enigo,
+    /// The protocol actually in use, never `Auto`.
+    backend: InputBackend,
+    policy: InputSettings,
+    /// Where the agent last moved the pointer, for Wayland, which cannot
+    /// report the pointer position.
+    position: Option<(i32, i32)>,
+    /// When recent calls were admitted, for the rate limit.
+    recent: VecDeque<Instant>,
+    /// Set once the abort corner trips; refuses input from then on.
+    aborted: bool,
+}
+
+impl Desktop {
+    pub fn open(policy: &InputSettings) -> Result<Self, InputError> {
+        let (settings, backend) = connect_settings(policy.backend)?;
+        if backend == InputBackend::Wayland && policy.abort_corner {
+            return Err(InputError::Unsupported(
+                "Wayland does not report the pointer position, so the Wayland input backend \
+                 needs input.abort_corner = false"
+                    .to_string(),
+            ));
+        }
+        let enigo = Enigo::new(&settings).map_err(|err| InputError::Connect(err.to_string()))?;
+        info!(?backend, "connected to the desktop for input");
+        Ok(Self {
+            enigo,
+            backend,
+            policy: policy.clone(),
+            position: None,
+            recent: VecDeque::new(),
+            aborted: false,
+        })
+    }
+
+    /// Types `text` as-is, whatever the keyboard layout.
+    pub fn type_text(&mut self, text: &str) -> Result<(), InputError> {
+        self.admit()?;
+        self.check_keyboard_target()?;
+        if text.is_empty() {
+            return Ok(());
+        }
+        Ok(self.enigo.text(text)?)
+    }
+
+    /// Presses the modifiers, then `key`, and releases them in reverse
+    /// order. Modifiers are released even when pressing `key` fails.
+    pub fn chord(
+        &mut self,
+        key: &str,
+        modifiers: &[Modifier],
+        hold: Option<Duration>,
+    ) -> Result<(), InputError> {
+        let key = parse_key(key)?;
+        check_hold(hold)?;
+        self.admit()?;
+        self.check_keyboard_target()?;
+        let mut pressed = Vec::with_capacity(modifiers.len());
+        let result = (|| {
+            for modifier in modifiers {
+                self.enigo.key(modifier.key(), Direction::Press)?;
+                pressed.push(modifier.key());
+            }
+            self.press(|enigo, direction| enigo.key(key, direction), hold)
+        })();
+        for key in pressed.into_iter().rev() {
+            let _ = self.enigo.key(key, Direction::Release);
+        }
+        result
+    }
+
+    /// Moves to screen coordinates, or by an offset with `relative`. The
+    /// destination may not be in a forbidden region or an abort corner.
+    pub fn move_pointer(&mut self, x: i32, y: i32, relative: bool) -> Result<(), InputError> {
+        self.admit()?;
+        if !self.policy.forbidden_regions.is_empty() || self.policy.abort_corner {
+            let (target_x, target_y) = if relative {
+                let (from_x, from_y) = self.pointer()?;
+                (from_x.saturating_add(x), from_y.saturating_add(y))
+            } else {
+                (x, y)
+            };
+            self.check_point(target_x, target_y, "move the pointer into")?;
+            if self.policy.abort_corner && self.in_abort_corner(target_x, target_y)? {
+                return Err(self.forbid(
+                    target_x,
+                    target_y,
+                    "screen corners are reserved for the input abort switch".to_string(),
+                ));
+            }
+        }
+        let coordinate = if relative {
+            Coordinate::Rel
+        } else {
+            Coordinate::Abs
+        };
+        self.enigo.move_mouse(x, y, coordinate)?;
+        self.position = if relative {
+            self.position
+                .map(|(from_x, from_y)| (from_x.saturating_add(x), from_y.saturating_add(y)))
+        } else {
+            Some((x, y))
+        };
+        Ok(())
+    }
+
+    pub fn click(&mut self, button: MouseButton, hold: Option<Duration>) -> Result<(), InputError> {
+        check_hold(hold)?;
+        self.admit()?;
+        self.check_pointer("click in")?;
+        let button = button.button();
+        self.press(|enigo, direction| enigo.button(button, direction), hold)
+    }
+
+    /// Scrolls by wheel notches; positive values go down and right.
+    pub fn scroll(&mut self, horizontal: i32, vertical: i32) -> Result<(), InputError> {
+        self.admit()?;
+        self.check_pointer("scroll in")?;
+        if horizontal != 0 {
+            self.enigo.scroll(horizontal, Axis::Horizontal)?;
+        }
+        if vertical != 0 {
+            self.enigo.scroll(vertical, Axis::Vertical)?;
+        }
+        Ok(())
+    }
+
+    /// Replays a recorded macro at its recorded pace. It counts as one call
+    /// for the rate limit; the abort corner is checked before every step,
+    /// and the policy checks apply to each pointer position and keystroke
+    /// as they would to the same raw calls. Whatever the macro still holds
+    /// down when it ends or fails is released.
+    pub fn play(&mut self, name: &str) -> Result<(), InputError> {
+        if self.backend != InputBackend::X11 {
+            return Err(InputError::Unsupported(
+                "input macros hold X11 keycodes and need the X11 input backend".to_string(),
+            ));
+        }
+        let recording = macros::load(&self.policy, name)?;
+        self.admit()?;
+        self.check_macro(&recording)?;
+        info!(
+            macro_name = name,
+            steps = recording.steps.len(),
+            "playing input macro"
+        );
+        let mut held_keys = HashSet::new();
+        let mut held_buttons = HashSet::new();
+        let start = Instant::now();
+        let result = (|| {
+            for step in &recording.steps {
+                let due = start + Duration::from_millis(step.at_ms);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                self.check_abort()?;
+                match step.event {
+                    Event::Key { keycode, down } => {
+                        if down {
+                            self.check_keyboard_target()?;
+                            held_keys.insert(keycode);
+                            self.enigo.raw(keycode, Direction::Press)?;
+                        } else if held_keys.remove(&keycode) {
+                            self.enigo.raw(keycode, Direction::Release)?;
+                        }
+                    }
+                    Event::Button { button, down } => {
+                        if down {
+                            self.check_pointer("click in")?;
+                            held_buttons.insert(button);
+                            self.enigo.button(button.button(), Direction::Press)?;
+                        } else if held_buttons.remove(&button) {
+                            self.enigo.button(button.button(), Direction::Release)?;
+                        }
+                    }
+                    Event::Move { x, y } => {
+                        self.enigo.move_mouse(x, y, Coordinate::Abs)?;
+                        self.position = Some((x, y));
+                    }
+                    Event::Scroll {
+                        horizontal,
+                        vertical,
+                    } => {
+                        self.check_pointer("scroll in")?;
+                        if horizontal != 0 {
+                            self.enigo.scroll(horizontal, Axis::Horizontal)?;
+                        }
+                        if vertical != 0 {
+                            self.enigo.scroll(vertical, Axis::Vertical)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })();
+        for keycode in held_keys {
+            let _ = self.enigo.raw(keycode, Direction::Release);
+        }
+        for button in held_buttons {
+            let _ = self.enigo.button(button.button(), Direction::Release);
+        }
+        result
+    }
+
+    /// Refuses a macro before it starts if any of its moves would go where
+    /// a raw mouse-move may not.
+    fn check_macro(&self, recording: &Macro) -> Result<(), InputError> {
+        for step in &recording.steps {
+            if let Event::Move { x, y } = step.event {
+                self.check_point(x, y, "move the pointer into")?;
+                if self.policy.abort_corner && self.in_abort_corner(x, y)? {
+                    return Err(self.forbid(
+                        x,
+                        y,
+                        "the macro moves the pointer into a screen corner reserved for the \
+                         input abort switch"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clicks, or presses, waits `hold` and releases.
+    fn press(
+        &mut self,
+        mut send: impl FnMut(&mut Enigo, Direction) -> enigo::InputResult<()>,
+        hold: Option<Duration>,
+    ) -> Result<(), InputError> {
+        let Some(hold) = hold else {
+            return Ok(send(&mut self.enigo, Direction::Click)?);
+        };
+        send(&mut self.enigo, Direction::Press)?;
+        thread::sleep(hold);
+        Ok(send(&mut self.enigo, Direction::Release)?)
+    }
+
+    /// The checks every call starts with: the abort switch, then the rate
+    /// limit.
+    fn admit(&mut self) -> Result<(), InputError> {
+        self.check_abort()?;
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(1))
+        {
+            self.recent.pop_front();
+        }
+        let limit = self.policy.max_actions_per_second;
+        if self.recent.len() >= limit as usize {
+            return Err(InputError::RateLimited(limit));
+        }
+        self.recent.push_back(now);
+        Ok(())
+    }
+
+    fn check_abort(&mut self) -> Result<(), InputError> {
+        if self.aborted {
+            return Err(InputError::Aborted);
+        }
+        if self.policy.abort_corner {
+            let (x, y) = self.pointer()?;
+            if self.in_abort_corner(x, y)? {
+                self.aborted = true;
+                warn!(target: "audit", x, y, "input aborted from the abort corner");
+                audit::record("host", "input_aborted", None, json!({ "x": x, "y": y }));
+                return Err(InputError::Aborted);
+            }
+        }
+        Ok(())
+    }
+
+    fn in_abort_corner(&self, x: i32, y: i32) -> Result<bool, InputError> {
+        let (width, height) = self.enigo.main_display()?;
+        let near =
+            |value: i32, end: i32| value < ABORT_CORNER_SIZE || value >= end - ABORT_CORNER_SIZE;
+        Ok(near(x, width) && near(y, height))
+    }
+
+    /// Refuses pointer actions at the pointer's current position.
+    fn check_pointer(&mut self, action: &str) -> Result<(), InputError> {
+        if self.policy.forbidden_regions.is_empty() {
+            return Ok(());
+        }
+        let (x, y) = self.pointer()?;
+        self.check_point(x, y, action)
+    }
+
+    /// The pointer position; on Wayland, where the agent last put it.
+    fn pointer(&self) -> Result<(i32, i32), InputError> {
+        if self.backend == InputBackend::Wayland {
+            return self.position.ok_or_else(|| {
+                InputError::Forbidden(
+                    "the pointer position is unknown on Wayland until an absolute mouse-move"
+                        .to_string(),
+                )
+            });
+        }
+        Ok(self.enigo.location()?)
+    }
+
+    fn check_point(&self, x: i32, y: i32, action: &str) -> Result<(), InputError> {
+        match self.forbidden_region(x, y) {
+            Some(region) => Err(self.forbid(
+                x,
+                y,
+                format!("may not {action} forbidden region {}", describe(region)),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses keystrokes while the focused window's centre, or the pointer
+    /// when keys follow it, is in a forbidden region. With regions set and
+    /// no way to find the focused window, keystrokes are refused.
+    fn check_keyboard_target(&mut self) -> Result<(), InputError> {
+        if self.policy.forbidden_regions.is_empty() {
+            return Ok(());
+        }
+        let focused = screen::focused_window().map_err(|err| {
+            InputError::Forbidden(format!(
+                "cannot check the focused window against input.forbidden_regions: {err}"
+            ))
+        })?;
+        let (x, y) = match focused {
+            Some(window) => window.center(),
+            None => self.pointer()?,
+        };
+        self.check_point(x, y, "type into a window in")
+    }
+
+    fn forbidden_region(&self, x: i32, y: i32) -> Option<&Rect> {
+        self.policy
+            .forbidden_regions
+            .iter()
+            .find(|region| region.contains(x, y))
+    }
+
+    fn forbid(&self, x: i32, y: i32, reason: String) -> InputError {
+        warn!(target: "audit", x, y, reason, "blocked input");
+        audit::record(
+            "host",
+            "input_blocked",
+            None,
+            json!({ "x": x, "y": y, "reason": reason }),
+        );
+        InputError::Forbidden(reason)
+    }
+}
+
+/// Resolves `backend` and points enigo at that protocol alone: it sends
+/// input through every display it reaches, and an empty display name never
+/// connects.
+#[cfg(target_os = "linux")]
+fn connect_settings(backend: InputBackend) -> Result<(Settings, InputBackend), InputError> {
+    let wayland_session =
+        std::env::var_os("WAYLAND_DISPLAY").is_some_and(|display| !display.is_empty());
+    let backend = match backend {
+        InputBackend::Auto if wayland_session && cfg!(feature = "wayland") => InputBackend::Wayland,
+        InputBackend::Auto => InputBackend::X11,
+        chosen => chosen,
+    };
+    let mut settings = Settings::default();
+    if backend == InputBackend::Wayland {
+        if !cfg!(feature = "wayland") {
+            return Err(InputError::Unsupported(
+                "the Wayland input backend needs hostd built with the `wayland` feature"
+                    .to_string(),
+            ));
+        }
+        settings.x11_display = Some(String::new());
+    } else {
+        settings.wayland_display = Some(String::new());
+    }
+    Ok((settings, backend))
+}
+
+/// Only Linux has more than one backend; config keeps others on `Auto`.
+#[cfg(not(target_os = "linux"))]
+fn connect_settings(backend: InputBackend) -> Result<(Settings, InputBackend), InputError> {
+    Ok((Settings::default(), backend))
+}
+
+fn describe(region: &Rect) -> String {
+    format!(
+        "{}x{} at ({}, {})",
+        region.width, region.height, region.x, region.y
+    )
+}
+
+fn check_hold(hold: Option<Duration>) -> Result<(), InputError> {
+    match hold {
+        Some(hold) if hold > MAX_HOLD => Err(InputError::HoldTooLong(hold)),
+        _ => Ok(()),
+    }
+}
+
+/// A single character, or a key name such as `Enter`, `PageDown` or `F5`
+/// (case-insensitive).
+fn parse_key(name: &str) -> Result<Key, InputError> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Unicode(c));
+    }
+    let lower = name.to_ascii_lowercase();
+    let key = match lower.as_str() {
+        "enter" | "return" => Key::Return,
+        "tab" => Key::Tab,
+        "space" => Key::Space,
+        "backspace" => Key::Backspace,
+        "delete" | "del" => Key::Delete,
+        "escape" | "esc" => Key::Escape,
+        "up" | "arrowup" => Key::UpArrow,
+        "down" | "arrowdown" => Key::DownArrow,
+        "left" | "arrowleft" => Key::LeftArrow,
+        "right" | "arrowright" => Key::RightArrow,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "capslock" => Key::CapsLock,
+        #[cfg(not(target_os = "macos"))]
+        "insert" => Key::Insert,
+        "shift" => Key::Shift,
+        "ctrl" | "control" => Key::Control,
+        "alt" | "option" => Key::Alt,
+        "meta" | "super" | "cmd" | "command" | "win" => Key::Meta,
+        _ => {
+            let function = lower
+                .strip_prefix('f')
+                .and_then(|n| n.parse::<u8>().ok())
+                .and_then(function_key);
+            return function.ok_or_else(|| InputError::UnknownKey(name.to_string()));
+        }
+    };
+    Ok(key)
+}
+
+fn function_key(n: u8) -> Option<Key> {
+    const KEYS: [Key; 20] = [
+        Key::F1,
+        Key::F2,
+        Key::F3,
+        Key::F4,
+        Key::F5,
+        Key::F6,
+        Key::F7,
+        Key::F8,
+        Key::F9,
+        Key::F10,
+        Key::F11,
+        Key::F12,
+        Key::F13,
+        Key::F14,
+        Key::F15,
+        Key::F16,
+        Key::F17,
+        Key::F18,
+        Key::F19,
+        Key::F20,
+    ];
+    KEYS.get(usize::from(n).checked_sub(1)?).copied()
+}
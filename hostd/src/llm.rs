@@ -0,0 +1,1492 @@
+//! Chat completions for the `osagent:llm` capability. Requests are built in
+//! one provider-neutral shape and sent through the provider chosen by
+//! `llm.provider`; tool calls always come back in the OpenAI
+//! `tool_calls` shape, whichever provider produced them. Replies are always
+//! streamed, so partial output is logged as it arrives instead of the host
+//! going quiet for the length of a long plan.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{info, warn};
+use ureq::Agent;
+
+use crate::config::LlmSettings;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest the provider may go quiet, before the first chunk or between two.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Partial output is logged a line at a time, or in pieces this long.
+const LOG_LINE_CHARS: usize = 120;
+
+/// The API `llm.api_base` speaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProvider {
+    /// `/chat/completions`, as served by OpenAI and most compatible servers.
+    #[default]
+    OpenAi,
+    /// Anthropic's `/messages`.
+    Anthropic,
+    /// Ollama's native `/api/chat`, for local models; needs no API key.
+    Ollama,
+    /// Azure OpenAI: `/chat/completions` of one deployment on the
+    /// resource at `llm.api_base`, authenticated with an `api-key` header.
+    Azure,
+    /// Canned replies from the `llm.script` fixture, for deterministic runs
+    /// without network access.
+    Script,
+}
+
+impl LlmProvider {
+    /// None for Azure, where every resource has its own endpoint.
+    pub fn default_api_base(self) -> Option<&'static str> {
+        match self {
+            Self::OpenAi => Some("https://api.openai.com/v1"),
+            Self::Anthropic => Some("https://api.anthropic.com/v1"),
+            Self::Ollama => Some("http://127.0.0.1:11434"),
+            Self::Azure => None,
+            Self::Script => Some(""),
+        }
+    }
+
+    /// Whether requests are refused without `llm.api_key`.
+    pub fn needs_api_key(self) -> bool {
+        !matches!(self, Self::Ollama | Self::Script)
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum LlmError {
+    #[error("llm error {status}: {message}")]
+    Status { status: u16, message: String },
+    #[error("llm transport error: {0}")]
+    Transport(String),
+    #[error("failed to encode llm request: {0}")]
+    Encode(String),
+    #[error("failed to parse llm response: {0}")]
+    Decode(String),
+    /// An error event in the middle of a streamed reply.
+    #[error("llm stream failed: {0}")]
+    Stream(String),
+    #[error("{0}")]
+    Unsupported(String),
+    /// The `llm.script` fixture is unreadable or has no reply to give.
+    #[error("llm script: {0}")]
+    Script(String),
+}
+
+impl LlmError {
+    /// Rate limits, server errors, timeouts and dropped connections: worth
+    /// sending the same request again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Status { status, .. } => matches!(status, 408 | 429 | 500..=599),
+            Self::Transport(_) | Self::Stream(_) => true,
+            Self::Encode(_) | Self::Decode(_) | Self::Unsupported(_) | Self::Script(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments.
+    pub parameters: Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Options {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Ask for a reply that is a single JSON object.
+    pub json: bool,
+    /// Ask for a reply that validates against this JSON schema.
+    pub json_schema: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub id: Option<String>,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON string.
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Completion {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ToolCall>,
+    /// OpenAI's vocabulary: `stop`, `length`, `tool_calls`, ...
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+    /// Served from `llm.cache` rather than the provider.
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+impl Completion {
+    /// Tokens billed for this reply: none when it came from the cache, else
+    /// the provider's count, or an estimate of four characters per token
+    /// when it reports none.
+    pub fn tokens_used(&self, prompt_chars: usize) -> u64 {
+        if self.cached {
+            return 0;
+        }
+        if let Some(usage) = self.usage {
+            return u64::from(usage.total_tokens);
+        }
+        let reply_chars = self.content.as_deref().map_or(0, str::len)
+            + self
+                .tool_calls
+                .iter()
+                .map(|call| call.function.name.len() + call.function.arguments.len())
+                .sum::<usize>();
+        (prompt_chars + reply_chars).div_ceil(4) as u64
+    }
+}
+
+/// Vectors from [`embed`].
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    /// One per input text, in order.
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Option<Usage>,
+}
+
+impl Embeddings {
+    /// The provider's count, or four characters per token.
+    pub fn tokens_used(&self, texts: &[String]) -> u64 {
+        match self.usage {
+            Some(usage) => u64::from(usage.total_tokens),
+            None => texts.iter().map(String::len).sum::<usize>().div_ceil(4) as u64,
+        }
+    }
+}
+
+/// Length of the prompt, for [`Completion::tokens_used`].
+pub fn prompt_chars(messages: &[Message]) -> usize {
+    messages.iter().map(|message| message.content.len()).sum()
+}
+
+/// Sends one chat request, passing each piece of text and of tool-call
+/// arguments to `on_delta` as it streams in. With `tools`, the model must
+/// call at least one.
+pub fn chat(
+    settings: &LlmSettings,
+    messages: &[Message],
+    tools: Option<&[Tool]>,
+    options: &Options,
+    on_delta: &mut dyn FnMut(&str),
+) -> Result<Completion, LlmError> {
+    let on_delta = &mut |text: &str| {
+        if !text.is_empty() {
+            on_delta(text);
+        }
+    };
+    let cache = settings
+        .cache
+        .then(|| cache::Entry::new(settings, messages, tools, options))
+        .flatten();
+    if let Some(completion) = cache.as_ref().and_then(cache::Entry::load) {
+        on_delta(completion.content.as_deref().unwrap_or_default());
+        for call in &completion.tool_calls {
+            on_delta(&call.function.arguments);
+        }
+        return Ok(completion);
+    }
+    let mut attempt = 0;
+    let completion = loop {
+        // Once part of the reply has been passed on, sending the request
+        // again would repeat it, so only failures before the first delta
+        // are retried.
+        let mut streamed = false;
+        let on_delta = &mut |text: &str| {
+            streamed |= !text.is_empty();
+            on_delta(text);
+        };
+        let result = match settings.provider {
+            LlmProvider::OpenAi | LlmProvider::Azure => {
+                openai::chat(settings, messages, tools, options, on_delta)
+            }
+            LlmProvider::Anthropic => anthropic::chat(settings, messages, tools, options, on_delta),
+            LlmProvider::Ollama => ollama::chat(settings, messages, tools, options, on_delta),
+            LlmProvider::Script => script::chat(settings, messages, on_delta),
+        };
+        let err = match result {
+            Ok(completion) => break completion,
+            Err(err) => err,
+        };
+        if streamed || !wait_to_retry(settings, &mut attempt, &err) {
+            return Err(err);
+        }
+    };
+    if let Some(cache) = &cache {
+        cache.store(&completion);
+    }
+    Ok(completion)
+}
+
+/// Embeds each of `texts` with `llm.embedding_model`, retrying transient
+/// failures like [`chat`].
+pub fn embed(settings: &LlmSettings, texts: &[String]) -> Result<Embeddings, LlmError> {
+    let mut attempt = 0;
+    let embeddings = loop {
+        let result = match settings.provider {
+            LlmProvider::OpenAi | LlmProvider::Azure => openai::embed(settings, texts),
+            LlmProvider::Anthropic => Err(LlmError::Unsupported(
+                "the anthropic provider has no embeddings API".to_string(),
+            )),
+            LlmProvider::Ollama => ollama::embed(settings, texts),
+            LlmProvider::Script => Err(LlmError::Unsupported(
+                "the script provider has no embeddings".to_string(),
+            )),
+        };
+        let err = match result {
+            Ok(embeddings) => break embeddings,
+            Err(err) => err,
+        };
+        if !wait_to_retry(settings, &mut attempt, &err) {
+            return Err(err);
+        }
+    };
+    if embeddings.vectors.len() != texts.len() {
+        return Err(LlmError::Decode(format!(
+            "expected {} embeddings, got {}",
+            texts.len(),
+            embeddings.vectors.len()
+        )));
+    }
+    Ok(embeddings)
+}
+
+/// [`chat`] with partial output logged as it arrives.
+pub fn chat_logged(
+    settings: &LlmSettings,
+    messages: &[Message],
+    tools: Option<&[Tool]>,
+    options: &Options,
+) -> Result<Completion, LlmError> {
+    let mut log = PartialLog::default();
+    let result = chat(settings, messages, tools, options, &mut |text| {
+        log.push(text)
+    });
+    log.flush();
+    result
+}
+
+/// A completion running on its own thread, read a chunk at a time through
+/// the `osagent:llm` `completion-stream` resource.
+pub struct CompletionStream {
+    events: Receiver<StreamEvent>,
+    result: Option<Result<Completion, LlmError>>,
+    prompt_chars: usize,
+    /// Set once the reply's tokens were handed to the task's budget.
+    billed: bool,
+}
+
+enum StreamEvent {
+    Delta(String),
+    Done(Result<Completion, LlmError>),
+}
+
+impl CompletionStream {
+    pub fn start(settings: LlmSettings, messages: Vec<Message>, options: Options) -> Self {
+        let prompt_chars = prompt_chars(&messages);
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || {
+            let mut log = PartialLog::default();
+            let result = chat(&settings, &messages, None, &options, &mut |text| {
+                log.push(text);
+                let _ = sender.send(StreamEvent::Delta(text.to_string()));
+            });
+            log.flush();
+            let _ = sender.send(StreamEvent::Done(result));
+        });
+        Self {
+            events,
+            result: None,
+            prompt_chars,
+            billed: false,
+        }
+    }
+
+    /// The tokens of a finished reply, the first time it is asked for.
+    pub fn take_tokens_used(&mut self) -> Option<u64> {
+        let Some(Ok(completion)) = &self.result else {
+            return None;
+        };
+        if self.billed {
+            return None;
+        }
+        self.billed = true;
+        Some(completion.tokens_used(self.prompt_chars))
+    }
+
+    /// The next piece of text, waiting for it if needed; `None` once the
+    /// reply is complete or has failed.
+    pub fn next(&mut self) -> Option<String> {
+        if self.result.is_some() {
+            return None;
+        }
+        match self.events.recv() {
+            Ok(StreamEvent::Delta(text)) => Some(text),
+            Ok(StreamEvent::Done(result)) => {
+                self.result = Some(result);
+                None
+            }
+            Err(_) => {
+                self.result = Some(Err(LlmError::Stream(
+                    "completion thread exited".to_string(),
+                )));
+                None
+            }
+        }
+    }
+
+    /// Takes in whatever has arrived without waiting for more.
+    pub fn drain_ready(&mut self) {
+        while self.result.is_none() {
+            match self.events.try_recv() {
+                Ok(StreamEvent::Delta(_)) => {}
+                Ok(StreamEvent::Done(result)) => self.result = Some(result),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Waits for the rest of the reply and returns the whole of it.
+    pub fn finish(&mut self) -> Result<Completion, LlmError> {
+        while self.next().is_some() {}
+        self.result
+            .clone()
+            .unwrap_or_else(|| unreachable!("next() only returns None once the result is set"))
+    }
+}
+
+impl std::fmt::Debug for CompletionStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompletionStream")
+            .field("done", &self.result.is_some())
+            .finish()
+    }
+}
+
+/// Logs streamed output a line at a time.
+#[derive(Default)]
+struct PartialLog {
+    line: String,
+}
+
+impl PartialLog {
+    fn push(&mut self, text: &str) {
+        for ch in text.chars() {
+            if ch == '\n' {
+                self.flush();
+            } else {
+                self.line.push(ch);
+                if self.line.len() >= LOG_LINE_CHARS {
+                    self.flush();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.line.trim().is_empty() {
+            info!(text = %self.line, "llm output");
+        }
+        self.line.clear();
+    }
+}
+
+fn http_agent() -> Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+fn endpoint(base: &str, path: &str) -> String {
+    format!("{}/{path}", base.trim_end_matches('/'))
+}
+
+/// Posts `body` and returns the streamed reply, turning an error status
+/// into [`LlmError::Status`].
+fn open_stream(
+    request: ureq::Request,
+    body: &impl Serialize,
+) -> Result<Box<dyn BufRead + Send>, LlmError> {
+    let payload = serde_json::to_value(body).map_err(|err| LlmError::Encode(err.to_string()))?;
+    let response = request
+        .set("Content-Type", "application/json")
+        .send_json(payload)
+        .map_err(|err| match err {
+            ureq::Error::Status(status, response) => LlmError::Status {
+                status,
+                message: error_message(&response.into_string().unwrap_or_default()),
+            },
+            ureq::Error::Transport(err) => LlmError::Transport(err.to_string()),
+        })?;
+    Ok(Box::new(BufReader::new(response.into_reader())))
+}
+
+/// Posts `body` and parses the whole reply, for the endpoints that do not
+/// stream.
+fn post_json(request: ureq::Request, body: &impl Serialize) -> Result<Value, LlmError> {
+    let reader = open_stream(request, body)?;
+    serde_json::from_reader(reader).map_err(|err| LlmError::Decode(err.to_string()))
+}
+
+/// Each JSON value in a stream: server-sent `data:` lines, or bare lines
+/// for newline-delimited JSON. Stops at the OpenAI `[DONE]` marker.
+fn for_each_event(
+    reader: Box<dyn BufRead + Send>,
+    mut handle: impl FnMut(Value) -> Result<(), LlmError>,
+) -> Result<(), LlmError> {
+    for line in reader.lines() {
+        let line = line.map_err(|err| LlmError::Transport(err.to_string()))?;
+        let line = line.trim();
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim_start(),
+            None if line.starts_with('{') => line,
+            None => continue,
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let event: Value =
+            serde_json::from_str(data).map_err(|err| LlmError::Decode(err.to_string()))?;
+        if let Some(message) = event["error"]["message"]
+            .as_str()
+            .or(event["error"].as_str())
+        {
+            return Err(LlmError::Stream(message.to_string()));
+        }
+        handle(event)?;
+    }
+    Ok(())
+}
+
+/// The `error.message` of an OpenAI- or Anthropic-style error body, or the
+/// `error` string of an Ollama one, else the body itself, cut to a readable
+/// length.
+fn error_message(body: &str) -> String {
+    const MAX_CHARS: usize = 500;
+    let message = serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|value| {
+            value["error"]["message"]
+                .as_str()
+                .or(value["error"].as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.trim().to_string());
+    match message.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &message[..end]),
+        None => message,
+    }
+}
+
+/// Replies stored under `llm.cache_dir`, one file per request, named by a
+/// SHA-256 of everything that shapes the reply: provider, endpoint, model,
+/// messages, tools and options (never the API key). Expired entries are
+/// replaced on the next miss.
+mod cache {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use std::{fs, io};
+
+    use camino::{Utf8Path, Utf8PathBuf};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use tracing::{debug, warn};
+
+    use super::{Completion, Message, Options, Tool};
+    use crate::config::LlmSettings;
+
+    #[derive(Serialize, Deserialize)]
+    struct Stored {
+        /// Seconds since the epoch.
+        created: u64,
+        completion: Completion,
+    }
+
+    pub struct Entry {
+        path: Utf8PathBuf,
+        ttl: Duration,
+    }
+
+    impl Entry {
+        /// `None` when there is no cache dir to use.
+        pub fn new(
+            settings: &LlmSettings,
+            messages: &[Message],
+            tools: Option<&[Tool]>,
+            options: &Options,
+        ) -> Option<Self> {
+            let dir = settings.cache_dir.clone().or_else(default_dir)?;
+            let request = json!({
+                "provider": format!("{:?}", settings.provider),
+                "api_base": settings.api_base,
+                "model": settings.model,
+                "deployment": settings.deployment,
+                "api_version": settings.api_version,
+                "messages": messages,
+                "tools": tools,
+                "options": options,
+            });
+            let digest = Sha256::digest(request.to_string().as_bytes());
+            let name: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+            Some(Self {
+                path: dir.join(format!("{name}.json")),
+                ttl: settings.cache_ttl,
+            })
+        }
+
+        pub fn load(&self) -> Option<Completion> {
+            let raw = fs::read(self.path.as_std_path()).ok()?;
+            let stored: Stored = serde_json::from_slice(&raw).ok()?;
+            if now().saturating_sub(stored.created) >= self.ttl.as_secs() {
+                return None;
+            }
+            debug!(entry = %self.path, "llm reply served from cache");
+            Some(Completion {
+                cached: true,
+                ..stored.completion
+            })
+        }
+
+        /// Best effort: a reply that cannot be cached is still returned.
+        pub fn store(&self, completion: &Completion) {
+            let stored = Stored {
+                created: now(),
+                completion: completion.clone(),
+            };
+            if let Err(err) = write(&self.path, &stored) {
+                warn!(entry = %self.path, error = %err, "failed to cache llm reply");
+            }
+        }
+    }
+
+    /// Replies can quote files the agent read, so on unix only the host
+    /// user can read them.
+    fn write(path: &Utf8Path, stored: &Stored) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec(stored).map_err(io::Error::from)?;
+        let tmp = path.with_extension("json.tmp");
+        let mut open = fs::OpenOptions::new();
+        open.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+        io::Write::write_all(&mut open.open(&tmp)?, &data)?;
+        fs::rename(&tmp, path)
+    }
+
+    fn default_dir() -> Option<Utf8PathBuf> {
+        let base = dirs::cache_dir()?.join("wasi-warden").join("llm");
+        Utf8PathBuf::from_path_buf(base).ok()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+}
+
+/// Sleeps before sending a request that failed with `err` again, when the
+/// failure is transient and `llm.max_retries` is not used up. False when
+/// `err` should be returned instead.
+fn wait_to_retry(settings: &LlmSettings, attempt: &mut u32, err: &LlmError) -> bool {
+    if !err.is_transient() || *attempt >= settings.max_retries {
+        return false;
+    }
+    let delay = retry_delay(settings.retry_backoff, *attempt);
+    *attempt += 1;
+    warn!(
+        error = %err,
+        attempt = *attempt,
+        delay_ms = delay.as_millis() as u64,
+        "retrying llm request"
+    );
+    thread::sleep(delay);
+    true
+}
+
+/// `base` doubled per earlier retry, scaled by a random factor between one
+/// half and one so that several hosts rate-limited together spread out.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let backoff = base.saturating_mul(1 << attempt.min(16));
+    let jitter = RandomState::new().hash_one(attempt) % 1000;
+    backoff / 2 + backoff.mul_f64(jitter as f64 / 2000.0)
+}
+
+/// Replies read from the `llm.script` fixture, a JSON object:
+///
+/// ```json
+/// {
+///   "replies": [{ "content": "...", "tool_calls": [{ "name": "...", "arguments": {} }] }],
+///   "by_prompt": { "<sha256 of the messages>": { "content": "..." } }
+/// }
+/// ```
+///
+/// A request whose messages hash to a `by_prompt` key gets that reply;
+/// any other takes the next of `replies`, in order. A reply may instead be
+/// `{ "error": { "status": 429, "message": "..." } }` to stand in for a
+/// provider failure.
+mod script {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::Mutex;
+
+    use camino::Utf8PathBuf;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use tracing::debug;
+
+    use super::{Completion, FunctionCall, LlmError, Message, ToolCall};
+    use crate::config::LlmSettings;
+
+    /// How many of each fixture's `replies` this process has used.
+    static USED: Mutex<BTreeMap<Utf8PathBuf, usize>> = Mutex::new(BTreeMap::new());
+
+    #[derive(Deserialize)]
+    struct Fixture {
+        #[serde(default)]
+        replies: Vec<Reply>,
+        #[serde(default)]
+        by_prompt: BTreeMap<String, Reply>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    struct Reply {
+        content: Option<String>,
+        #[serde(default)]
+        tool_calls: Vec<ScriptedCall>,
+        finish_reason: Option<String>,
+        error: Option<ScriptedError>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    struct ScriptedCall {
+        name: String,
+        /// An object, or the arguments already encoded as a string.
+        #[serde(default)]
+        arguments: Value,
+    }
+
+    #[derive(Clone, Deserialize)]
+    struct ScriptedError {
+        status: u16,
+        message: String,
+    }
+
+    pub fn chat(
+        settings: &LlmSettings,
+        messages: &[Message],
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<Completion, LlmError> {
+        let path = settings
+            .script
+            .as_ref()
+            .ok_or_else(|| LlmError::Script("llm.script is not set".to_string()))?;
+        let raw = fs::read(path).map_err(|err| LlmError::Script(format!("{path}: {err}")))?;
+        let fixture: Fixture = serde_json::from_slice(&raw)
+            .map_err(|err| LlmError::Script(format!("{path}: {err}")))?;
+        let prompt = prompt_hash(messages)?;
+        debug!(script = %path, prompt, "scripted llm request");
+        let reply = match fixture.by_prompt.get(&prompt) {
+            Some(reply) => reply.clone(),
+            None => {
+                let mut used = USED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let next = used.entry(path.clone()).or_default();
+                let reply = fixture.replies.get(*next).cloned().ok_or_else(|| {
+                    LlmError::Script(format!(
+                        "no reply left in {path} for prompt {prompt} ({} used)",
+                        *next
+                    ))
+                })?;
+                *next += 1;
+                reply
+            }
+        };
+        if let Some(error) = reply.error {
+            return Err(LlmError::Status {
+                status: error.status,
+                message: error.message,
+            });
+        }
+        if let Some(content) = &reply.content {
+            on_delta(content);
+        }
+        let tool_calls: Vec<ToolCall> = reply
+            .tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| {
+                let arguments = match call.arguments {
+                    Value::String(arguments) => arguments,
+                    Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                };
+                on_delta(&arguments);
+                ToolCall {
+                    kind: Some("function".to_string()),
+                    id: Some(format!("call_{index}")),
+                    function: FunctionCall {
+                        name: call.name,
+                        arguments,
+                    },
+                }
+            })
+            .collect();
+        let finish_reason = reply.finish_reason.or_else(|| {
+            Some(
+                if tool_calls.is_empty() {
+                    "stop"
+                } else {
+                    "tool_calls"
+                }
+                .to_string(),
+            )
+        });
+        Ok(Completion {
+            content: reply.content,
+            tool_calls,
+            finish_reason,
+            usage: None,
+            cached: false,
+        })
+    }
+
+    /// The `by_prompt` key for `messages`: SHA-256 of their JSON, in hex.
+    fn prompt_hash(messages: &[Message]) -> Result<String, LlmError> {
+        let json =
+            serde_json::to_string(messages).map_err(|err| LlmError::Encode(err.to_string()))?;
+        let digest = Sha256::digest(json.as_bytes());
+        Ok(digest.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+}
+
+fn to_u32(value: &Value) -> Option<u32> {
+    value.as_u64().map(|n| u32::try_from(n).unwrap_or(u32::MAX))
+}
+
+fn to_vector(value: &Value) -> Result<Vec<f32>, LlmError> {
+    let items = value
+        .as_array()
+        .ok_or_else(|| LlmError::Decode("embedding is not an array".to_string()))?;
+    items
+        .iter()
+        .map(|item| {
+            item.as_f64()
+                .map(|x| x as f32)
+                .ok_or_else(|| LlmError::Decode("embedding holds a non-number".to_string()))
+        })
+        .collect()
+}
+
+mod openai {
+    use serde::Serialize;
+    use serde_json::{Value, json};
+
+    use super::{
+        Completion, Embeddings, FunctionCall, LlmError, LlmProvider, Message, Options, Role, Tool,
+        ToolCall, Usage, endpoint, for_each_event, http_agent, open_stream, post_json, to_u32,
+        to_vector,
+    };
+    use crate::config::LlmSettings;
+
+    /// A request to `path` (`chat/completions` or `embeddings`). Azure
+    /// addresses the model by deployment, takes the API version as a
+    /// query parameter and the key in its own header.
+    fn post(settings: &LlmSettings, path: &str, deployment: &str) -> ureq::Request {
+        if settings.provider == LlmProvider::Azure {
+            let path = format!("openai/deployments/{deployment}/{path}");
+            return http_agent()
+                .post(&endpoint(&settings.api_base, &path))
+                .query("api-version", &settings.api_version)
+                .set("api-key", &settings.api_key);
+        }
+        http_agent()
+            .post(&endpoint(&settings.api_base, path))
+            .set("Authorization", &format!("Bearer {}", settings.api_key))
+    }
+
+    #[derive(Serialize)]
+    struct EmbedRequest<'a> {
+        model: &'a str,
+        input: &'a [String],
+    }
+
+    pub fn embed(settings: &LlmSettings, texts: &[String]) -> Result<Embeddings, LlmError> {
+        let body = EmbedRequest {
+            model: settings.embedding_model(),
+            input: texts,
+        };
+        let request = post(settings, "embeddings", settings.embedding_model());
+        let reply = post_json(request, &body)?;
+        let mut data: Vec<&Value> = reply["data"].as_array().into_iter().flatten().collect();
+        data.sort_by_key(|item| item["index"].as_u64());
+        let vectors = data
+            .iter()
+            .map(|item| to_vector(&item["embedding"]))
+            .collect::<Result<_, _>>()?;
+        let usage = to_u32(&reply["usage"]["prompt_tokens"]).map(|prompt| Usage {
+            prompt_tokens: prompt,
+            completion_tokens: 0,
+            total_tokens: to_u32(&reply["usage"]["total_tokens"]).unwrap_or(prompt),
+        });
+        Ok(Embeddings { vectors, usage })
+    }
+
+    #[derive(Serialize)]
+    struct ChatRequest<'a> {
+        model: &'a str,
+        messages: Vec<ChatMessage<'a>>,
+        stream: bool,
+        stream_options: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tools: Option<Vec<ChatTool<'a>>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_choice: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_tokens: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        top_p: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stop: Option<&'a [String]>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        presence_penalty: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frequency_penalty: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response_format: Option<Value>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatMessage<'a> {
+        role: &'static str,
+        content: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<&'a str>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatTool<'a> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        function: ChatToolFunction<'a>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatToolFunction<'a> {
+        name: &'a str,
+        description: &'a str,
+        parameters: &'a Value,
+    }
+
+    pub fn chat(
+        settings: &LlmSettings,
+        messages: &[Message],
+        tools: Option<&[Tool]>,
+        options: &Options,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<Completion, LlmError> {
+        let body = ChatRequest {
+            model: &settings.model,
+            messages: messages
+                .iter()
+                .map(|message| ChatMessage {
+                    role: match message.role {
+                        Role::System => "system",
+                        Role::User => "user",
+                        Role::Assistant => "assistant",
+                        Role::Tool => "tool",
+                    },
+                    content: &message.content,
+                    name: message.name.as_deref(),
+                })
+                .collect(),
+            stream: true,
+            stream_options: json!({ "include_usage": true }),
+            tools: tools.map(|tools| {
+                tools
+                    .iter()
+                    .map(|tool| ChatTool {
+                        kind: "function",
+                        function: ChatToolFunction {
+                            name: &tool.name,
+                            description: &tool.description,
+                            parameters: &tool.parameters,
+                        },
+                    })
+                    .collect()
+            }),
+            tool_choice: tools.map(|_| "required"),
+            max_tokens: options.max_tokens,
+            temperature: options.temperature,
+            top_p: options.top_p,
+            stop: (!options.stop.is_empty()).then_some(options.stop.as_slice()),
+            presence_penalty: options.presence_penalty,
+            frequency_penalty: options.frequency_penalty,
+            response_format: match &options.json_schema {
+                Some(schema) => Some(json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema, "strict": true },
+                })),
+                None => options.json.then(|| json!({ "type": "json_object" })),
+            },
+        };
+        let request = post(settings, "chat/completions", settings.deployment());
+        let reader = open_stream(request, &body)?;
+
+        let mut completion = Completion {
+            content: None,
+            tool_calls: Vec::new(),
+            finish_reason: None,
+            usage: None,
+            cached: false,
+        };
+        let mut saw_choice = false;
+        for_each_event(reader, |chunk| {
+            if let Some(usage) = chunk["usage"].as_object() {
+                completion.usage = Some(Usage {
+                    prompt_tokens: to_u32(&usage["prompt_tokens"]).unwrap_or_default(),
+                    completion_tokens: to_u32(&usage["completion_tokens"]).unwrap_or_default(),
+                    total_tokens: to_u32(&usage["total_tokens"]).unwrap_or_default(),
+                });
+            }
+            let Some(choice) = chunk["choices"].get(0) else {
+                return Ok(());
+            };
+            saw_choice = true;
+            let delta = &choice["delta"];
+            if let Some(text) = delta["content"].as_str() {
+                completion
+                    .content
+                    .get_or_insert_with(String::new)
+                    .push_str(text);
+                on_delta(text);
+            }
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                let index = call["index"].as_u64().unwrap_or(0) as usize;
+                while completion.tool_calls.len() <= index {
+                    completion.tool_calls.push(ToolCall {
+                        kind: Some("function".to_string()),
+                        id: None,
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    });
+                }
+                let entry = &mut completion.tool_calls[index];
+                if let Some(id) = call["id"].as_str() {
+                    entry.id = Some(id.to_string());
+                }
+                if let Some(name) = call["function"]["name"].as_str() {
+                    entry.function.name.push_str(name);
+                }
+                if let Some(arguments) = call["function"]["arguments"].as_str() {
+                    entry.function.arguments.push_str(arguments);
+                    on_delta(arguments);
+                }
+            }
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                completion.finish_reason = Some(reason.to_string());
+            }
+            Ok(())
+        })?;
+        if !saw_choice {
+            return Err(LlmError::Decode("llm response missing choices".to_string()));
+        }
+        Ok(completion)
+    }
+}
+
+/// Anthropic's Messages API differs from OpenAI's in three ways that matter
+/// here: the system prompt is a separate field, `max_tokens` is required,
+/// and turns must alternate between `user` and `assistant`. Tool messages
+/// carry no call id in `osagent:llm`, so they are sent as user text.
+/// Presence and frequency penalties have no equivalent and are dropped;
+/// JSON mode has none either and is refused.
+mod anthropic {
+    use serde::Serialize;
+    use serde_json::{Value, json};
+
+    use super::{
+        Completion, FunctionCall, LlmError, Message, Options, Role, Tool, ToolCall, Usage,
+        endpoint, for_each_event, http_agent, open_stream, to_u32,
+    };
+    use crate::config::LlmSettings;
+
+    const API_VERSION: &str = "2023-06-01";
+
+    /// Used when the guest does not set `max_tokens`.
+    const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+    #[derive(Serialize)]
+    struct MessagesRequest<'a> {
+        model: &'a str,
+        max_tokens: u32,
+        stream: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system: Option<String>,
+        messages: Vec<Turn>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tools: Option<Vec<AnthropicTool<'a>>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_choice: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        top_p: Option<f32>,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        stop_sequences: &'a [String],
+    }
+
+    #[derive(Serialize)]
+    struct Turn {
+        role: &'static str,
+        content: String,
+    }
+
+    #[derive(Serialize)]
+    struct AnthropicTool<'a> {
+        name: &'a str,
+        description: &'a str,
+        input_schema: &'a Value,
+    }
+
+    /// A content block being streamed in.
+    enum Block {
+        Text,
+        ToolUse(usize),
+        Other,
+    }
+
+    pub fn chat(
+        settings: &LlmSettings,
+        messages: &[Message],
+        tools: Option<&[Tool]>,
+        options: &Options,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<Completion, LlmError> {
+        if options.json || options.json_schema.is_some() {
+            return Err(LlmError::Unsupported(
+                "the anthropic llm provider has no JSON mode".to_string(),
+            ));
+        }
+        let system: Vec<&str> = messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
+            .collect();
+        let body = MessagesRequest {
+            model: &settings.model,
+            max_tokens: options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            stream: true,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages: turns(messages),
+            tools: tools.map(|tools| {
+                tools
+                    .iter()
+                    .map(|tool| AnthropicTool {
+                        name: &tool.name,
+                        description: &tool.description,
+                        input_schema: &tool.parameters,
+                    })
+                    .collect()
+            }),
+            tool_choice: tools.map(|_| json!({ "type": "any" })),
+            temperature: options.temperature,
+            top_p: options.top_p,
+            stop_sequences: &options.stop,
+        };
+        let request = http_agent()
+            .post(&endpoint(&settings.api_base, "messages"))
+            .set("x-api-key", &settings.api_key)
+            .set("anthropic-version", API_VERSION);
+        let reader = open_stream(request, &body)?;
+
+        let mut content: Option<String> = None;
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut stop_reason = None;
+        let (mut input_tokens, mut output_tokens) = (0, 0);
+        for_each_event(reader, |event| {
+            match event["type"].as_str().unwrap_or_default() {
+                "message_start" => {
+                    let usage = &event["message"]["usage"];
+                    input_tokens = to_u32(&usage["input_tokens"]).unwrap_or(0);
+                    output_tokens = to_u32(&usage["output_tokens"]).unwrap_or(0);
+                }
+                "content_block_start" => {
+                    let block = &event["content_block"];
+                    let kind = match block["type"].as_str() {
+                        Some("text") => Block::Text,
+                        Some("tool_use") => {
+                            tool_calls.push(ToolCall {
+                                kind: Some("function".to_string()),
+                                id: block["id"].as_str().map(str::to_string),
+                                function: FunctionCall {
+                                    name: block["name"].as_str().unwrap_or_default().to_string(),
+                                    arguments: String::new(),
+                                },
+                            });
+                            Block::ToolUse(tool_calls.len() - 1)
+                        }
+                        _ => Block::Other,
+                    };
+                    let index = event["index"].as_u64().unwrap_or(0) as usize;
+                    while blocks.len() <= index {
+                        blocks.push(Block::Other);
+                    }
+                    blocks[index] = kind;
+                }
+                "content_block_delta" => {
+                    let index = event["index"].as_u64().unwrap_or(0) as usize;
+                    let delta = &event["delta"];
+                    match (blocks.get(index), delta["type"].as_str()) {
+                        (Some(Block::Text), Some("text_delta")) => {
+                            let text = delta["text"].as_str().unwrap_or_default();
+                            content.get_or_insert_with(String::new).push_str(text);
+                            on_delta(text);
+                        }
+                        (Some(Block::ToolUse(call)), Some("input_json_delta")) => {
+                            let json = delta["partial_json"].as_str().unwrap_or_default();
+                            tool_calls[*call].function.arguments.push_str(json);
+                            on_delta(json);
+                        }
+                        _ => {}
+                    }
+                }
+                "message_delta" => {
+                    if let Some(reason) = event["delta"]["stop_reason"].as_str() {
+                        stop_reason = Some(finish_reason(reason));
+                    }
+                    if let Some(tokens) = to_u32(&event["usage"]["output_tokens"]) {
+                        output_tokens = tokens;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        // A tool called without arguments streams no input at all.
+        for call in &mut tool_calls {
+            if call.function.arguments.is_empty() {
+                call.function.arguments = "{}".to_string();
+            }
+        }
+        Ok(Completion {
+            content,
+            tool_calls,
+            finish_reason: stop_reason,
+            usage: Some(Usage {
+                prompt_tokens: input_tokens,
+                completion_tokens: output_tokens,
+                total_tokens: input_tokens.saturating_add(output_tokens),
+            }),
+            cached: false,
+        })
+    }
+
+    /// The non-system messages as alternating turns, merging neighbours
+    /// that map to the same role.
+    fn turns(messages: &[Message]) -> Vec<Turn> {
+        let mut turns: Vec<Turn> = Vec::new();
+        for message in messages {
+            let (role, content) = match message.role {
+                Role::System => continue,
+                Role::User => ("user", message.content.clone()),
+                Role::Assistant => ("assistant", message.content.clone()),
+                Role::Tool => {
+                    let label = match &message.name {
+                        Some(name) => format!("Result of tool `{name}`"),
+                        None => "Tool result".to_string(),
+                    };
+                    ("user", format!("{label}:\n{}", message.content))
+                }
+            };
+            match turns.last_mut() {
+                Some(last) if last.role == role => {
+                    last.content.push_str("\n\n");
+                    last.content.push_str(&content);
+                }
+                _ => turns.push(Turn { role, content }),
+            }
+        }
+        turns
+    }
+
+    fn finish_reason(stop_reason: &str) -> String {
+        match stop_reason {
+            "end_turn" | "stop_sequence" => "stop",
+            "max_tokens" => "length",
+            "tool_use" => "tool_calls",
+            other => other,
+        }
+        .to_string()
+    }
+}
+
+/// Ollama's native chat API, which also loads the model on first use.
+/// Tool calls come back whole, with arguments as an object and without
+/// ids, so both are filled in to match the OpenAI shape.
+mod ollama {
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use super::{
+        Completion, Embeddings, FunctionCall, LlmError, Message, Options, Role, Tool, ToolCall,
+        Usage, endpoint, for_each_event, http_agent, open_stream, post_json, to_u32, to_vector,
+    };
+    use crate::config::LlmSettings;
+
+    #[derive(Serialize)]
+    struct EmbedRequest<'a> {
+        model: &'a str,
+        input: &'a [String],
+    }
+
+    pub fn embed(settings: &LlmSettings, texts: &[String]) -> Result<Embeddings, LlmError> {
+        let body = EmbedRequest {
+            model: settings.embedding_model(),
+            input: texts,
+        };
+        let mut request = http_agent().post(&endpoint(&settings.api_base, "api/embed"));
+        if !settings.api_key.is_empty() {
+            request = request.set("Authorization", &format!("Bearer {}", settings.api_key));
+        }
+        let reply = post_json(request, &body)?;
+        let vectors = reply["embeddings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(to_vector)
+            .collect::<Result<_, _>>()?;
+        let usage = to_u32(&reply["prompt_eval_count"]).map(|prompt| Usage {
+            prompt_tokens: prompt,
+            completion_tokens: 0,
+            total_tokens: prompt,
+        });
+        Ok(Embeddings { vectors, usage })
+    }
+
+    #[derive(Serialize)]
+    struct ChatRequest<'a> {
+        model: &'a str,
+        messages: Vec<ChatMessage<'a>>,
+        stream: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tools: Option<Vec<ChatTool<'a>>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        format: Option<Value>,
+        options: ModelOptions<'a>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatMessage<'a> {
+        role: &'static str,
+        content: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_name: Option<&'a str>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatTool<'a> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        function: ChatToolFunction<'a>,
+    }
+
+    #[derive(Serialize)]
+    struct ChatToolFunction<'a> {
+        name: &'a str,
+        description: &'a str,
+        parameters: &'a Value,
+    }
+
+    #[derive(Serialize)]
+    struct ModelOptions<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        num_predict: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        temperature: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        top_p: Option<f32>,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        stop: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        presence_penalty: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frequency_penalty: Option<f32>,
+    }
+
+    pub fn chat(
+        settings: &LlmSettings,
+        messages: &[Message],
+        tools: Option<&[Tool]>,
+        options: &Options,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<Completion, LlmError> {
+        let body = ChatRequest {
+            model: &settings.model,
+            messages: messages
+                .iter()
+                .map(|message| ChatMessage {
+                    role: match message.role {
+                        Role::System => "system",
+                        Role::User => "user",
+                        Role::Assistant => "assistant",
+                        Role::Tool => "tool",
+                    },
+                    content: &message.content,
+                    tool_name: match message.role {
+                        Role::Tool => message.name.as_deref(),
+                        _ => None,
+                    },
+                })
+                .collect(),
+            stream: true,
+            tools: tools.map(|tools| {
+                tools
+                    .iter()
+                    .map(|tool| ChatTool {
+                        kind: "function",
+                        function: ChatToolFunction {
+                            name: &tool.name,
+                            description: &tool.description,
+                            parameters: &tool.parameters,
+                        },
+                    })
+                    .collect()
+            }),
+            format: match &options.json_schema {
+                Some(schema) => Some(schema.clone()),
+                None => options.json.then(|| Value::from("json")),
+            },
+            options: ModelOptions {
+                num_predict: options.max_tokens,
+                temperature: options.temperature,
+                top_p: options.top_p,
+                stop: &options.stop,
+                presence_penalty: options.presence_penalty,
+                frequency_penalty: options.frequency_penalty,
+            },
+        };
+        let mut request = http_agent().post(&endpoint(&settings.api_base, "api/chat"));
+        if !settings.api_key.is_empty() {
+            request = request.set("Authorization", &format!("Bearer {}", settings.api_key));
+        }
+        let reader = open_stream(request, &body)?;
+
+        let mut content = String::new();
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut done_reason = None;
+        let mut usage = None;
+        for_each_event(reader, |chunk| {
+            let message = &chunk["message"];
+            if let Some(text) = message["content"].as_str() {
+                content.push_str(text);
+                on_delta(text);
+            }
+            for call in message["tool_calls"].as_array().into_iter().flatten() {
+                let arguments = match &call["function"]["arguments"] {
+                    Value::String(arguments) => arguments.clone(),
+                    Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                };
+                on_delta(&arguments);
+                tool_calls.push(ToolCall {
+                    kind: Some("function".to_string()),
+                    id: Some(format!("call_{}", tool_calls.len())),
+                    function: FunctionCall {
+                        name: call["function"]["name"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                        arguments,
+                    },
+                });
+            }
+            if chunk["done"].as_bool() == Some(true) {
+                done_reason = chunk["done_reason"].as_str().map(str::to_string);
+                let prompt = to_u32(&chunk["prompt_eval_count"]);
+                let completion = to_u32(&chunk["eval_count"]);
+                if prompt.is_some() || completion.is_some() {
+                    let (prompt, completion) = (prompt.unwrap_or(0), completion.unwrap_or(0));
+                    usage = Some(Usage {
+                        prompt_tokens: prompt,
+                        completion_tokens: completion,
+                        total_tokens: prompt.saturating_add(completion),
+                    });
+                }
+            }
+            Ok(())
+        })?;
+        let finish_reason = if tool_calls.is_empty() {
+            done_reason
+        } else {
+            Some("tool_calls".to_string())
+        };
+        Ok(Completion {
+            content: Some(content),
+            tool_calls,
+            finish_reason,
+            usage,
+            cached: false,
+        })
+    }
+}
@@ -1,12 +1,22 @@
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, fmt};
 
-/// Initialize tracing using RUST_LOG or a sensible default.
+use crate::audit;
+
+/// Initialize tracing using RUST_LOG or a sensible default. Logs go to
+/// stderr, leaving stdout to `hostd run`'s result.
 pub fn init() {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new("info,wasi_warden=debug,hostd=debug"));
 
-    let _ = fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
+    let _ = tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_target(false)
+                .with_filter(env_filter),
+        )
+        .with(audit::CallLayer.with_filter(filter_fn(audit::wants)))
         .try_init();
 }
@@ -0,0 +1,418 @@
+//! Operator-recorded input macros. `hostd record-input` captures the
+//! keyboard and mouse into a named file under `input.macro_dir`, and
+//! `osagent:input/play-macro` replays it through the `[input]` policy, so an
+//! agent can run a vetted interaction instead of composing raw input. Keys
+//! are stored as X11 keycodes: recording and playback need X11, and a macro
+//! only types the same characters under the keyboard map it was recorded in.
+
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::cli::RecordInputArgs;
+use crate::config::InputSettings;
+use crate::input::{InputError, MouseButton};
+
+/// Longest a macro may run, from its first step to its last.
+pub const MAX_LENGTH: Duration = Duration::from_secs(10 * 60);
+
+/// Most steps one macro may hold.
+const MAX_STEPS: usize = 100_000;
+
+/// Pointer motions closer together than this are stored as one move.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const MOVE_COALESCE_MS: u64 = 15;
+
+/// A recorded input sequence, stored as `<macro_dir>/<name>.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Macro {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// Milliseconds after the start of playback.
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    Key {
+        keycode: u16,
+        down: bool,
+    },
+    Button {
+        button: MouseButton,
+        down: bool,
+    },
+    /// An absolute pointer position in desktop coordinates.
+    Move {
+        x: i32,
+        y: i32,
+    },
+    /// Wheel notches; positive values go down and right.
+    Scroll {
+        horizontal: i32,
+        vertical: i32,
+    },
+}
+
+/// Loads a macro by name, refusing files that break the format limits.
+pub fn load(policy: &InputSettings, name: &str) -> Result<Macro, InputError> {
+    let path = path(policy, name)?;
+    let raw = fs::read(path.as_std_path()).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => InputError::NoSuchMacro(name.to_string()),
+        _ => InputError::InvalidMacro(format!("cannot read {path}: {err}")),
+    })?;
+    let recording: Macro = serde_json::from_slice(&raw)
+        .map_err(|err| InputError::InvalidMacro(format!("{path}: {err}")))?;
+    if recording.steps.len() > MAX_STEPS {
+        return Err(InputError::InvalidMacro(format!(
+            "{path} has more than {MAX_STEPS} steps"
+        )));
+    }
+    if recording
+        .steps
+        .windows(2)
+        .any(|pair| pair[1].at_ms < pair[0].at_ms)
+    {
+        return Err(InputError::InvalidMacro(format!(
+            "{path} has steps out of order"
+        )));
+    }
+    if recording
+        .steps
+        .last()
+        .is_some_and(|step| Duration::from_millis(step.at_ms) > MAX_LENGTH)
+    {
+        return Err(InputError::InvalidMacro(format!(
+            "{path} runs longer than {MAX_LENGTH:?}"
+        )));
+    }
+    Ok(recording)
+}
+
+/// Records a macro for `hostd record-input` until the stop key is pressed.
+pub fn record(args: &RecordInputArgs) -> Result<()> {
+    let policy = InputSettings::from_config_file(&args.config)?;
+    let path = path(&policy, &args.name)?;
+    if path.exists() && !args.force {
+        bail!("macro {path} already exists; pass --force to replace it");
+    }
+    let stop_keysym = stop_keysym(&args.stop_key)?;
+    eprintln!(
+        "Recording input macro `{}`; press {} to stop.",
+        args.name, args.stop_key
+    );
+    let steps = record_steps(stop_keysym)?;
+    if steps.is_empty() {
+        bail!("nothing was recorded");
+    }
+    let recording = Macro { steps };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent.as_std_path())
+            .with_context(|| format!("failed to create macro dir {parent}"))?;
+    }
+    let bytes = serde_json::to_vec_pretty(&recording)?;
+    fs::write(path.as_std_path(), bytes)
+        .with_context(|| format!("failed to write macro {path}"))?;
+    info!(macro_name = %args.name, steps = recording.steps.len(), %path, "recorded input macro");
+    eprintln!("Saved {} steps to {path}.", recording.steps.len());
+    Ok(())
+}
+
+/// Default location for macros: the per-user data dir, outside any
+/// workspace an agent can write to.
+pub fn default_dir() -> Option<Utf8PathBuf> {
+    let base = dirs::data_dir()?.join("wasi-warden").join("input-macros");
+    Utf8PathBuf::from_path_buf(base).ok()
+}
+
+/// The file for macro `name`; names are limited to letters, digits, `-`
+/// and `_` so they cannot leave the macro dir.
+fn path(policy: &InputSettings, name: &str) -> Result<Utf8PathBuf, InputError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(InputError::InvalidMacro(format!(
+            "macro name `{name}` may only use letters, digits, `-` and `_`"
+        )));
+    }
+    let dir = policy.macro_dir().ok_or_else(|| {
+        InputError::InvalidMacro("no input.macro_dir is set and there is no data dir".to_string())
+    })?;
+    Ok(Utf8Path::join(&dir, format!("{name}.json")))
+}
+
+/// The X11 keysym for a `--stop-key` name.
+fn stop_keysym(name: &str) -> Result<u32> {
+    let lower = name.to_ascii_lowercase();
+    let keysym = match lower.as_str() {
+        "escape" | "esc" => 0xff1b,
+        "pause" => 0xff13,
+        "scrolllock" => 0xff14,
+        _ => match lower.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+            Some(n @ 1..=12) => 0xffbe + n - 1,
+            _ => bail!(
+                "unsupported stop key `{name}` (expected F1-F12, Escape, Pause or ScrollLock)"
+            ),
+        },
+    };
+    Ok(keysym)
+}
+
+#[cfg(target_os = "linux")]
+fn record_steps(stop_keysym: u32) -> Result<Vec<Step>> {
+    x11::record(stop_keysym)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn record_steps(_stop_keysym: u32) -> Result<Vec<Step>> {
+    bail!("recording input macros is only supported on X11")
+}
+
+/// Appends `event`, merging it into the previous step when both are quick
+/// successive pointer moves.
+#[cfg(target_os = "linux")]
+fn push_step(steps: &mut Vec<Step>, at_ms: u64, event: Event) {
+    match steps.last_mut() {
+        Some(last)
+            if matches!(
+                (last.event, event),
+                (Event::Move { .. }, Event::Move { .. })
+            ) && at_ms - last.at_ms < MOVE_COALESCE_MS =>
+        {
+            last.event = event;
+        }
+        _ => steps.push(Step { at_ms, event }),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result, bail};
+    use x11rb::connection::{Connection, RequestConnection};
+    use x11rb::protocol::record::{self, ConnectionExt as _};
+    use x11rb::protocol::xproto::{self, ConnectionExt as _};
+    use x11rb::x11_utils::TryParse;
+
+    use super::{Event, MAX_LENGTH, Step, push_step};
+    use crate::input::MouseButton;
+
+    /// `EnableContext` reply categories from the RECORD protocol.
+    const FROM_SERVER: u8 = 0;
+    const END_OF_DATA: u8 = 5;
+
+    /// Core device events are always this long.
+    const EVENT_LEN: usize = 32;
+
+    /// Records every client's keyboard and mouse events until a key bound
+    /// to `stop_keysym` is pressed, or the first event past [`MAX_LENGTH`].
+    pub fn record(stop_keysym: u32) -> Result<Vec<Step>> {
+        // RECORD wants one connection to control the context and another
+        // that only reads the recorded data.
+        let (control, _) = x11rb::connect(None).context("cannot reach the X server")?;
+        let (data, _) = x11rb::connect(None).context("cannot reach the X server")?;
+        if control
+            .extension_information(record::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            bail!("the X server does not support the RECORD extension");
+        }
+        let stop_keys = keycodes_for(&control, stop_keysym)?;
+        if stop_keys.is_empty() {
+            bail!("no key on this keyboard produces the stop key");
+        }
+        let context = control.generate_id()?;
+        let empty = record::Range8 { first: 0, last: 0 };
+        let empty_ext = record::ExtRange {
+            major: empty,
+            minor: record::Range16 { first: 0, last: 0 },
+        };
+        let range = record::Range {
+            core_requests: empty,
+            core_replies: empty,
+            ext_requests: empty_ext,
+            ext_replies: empty_ext,
+            delivered_events: empty,
+            device_events: record::Range8 {
+                first: xproto::KEY_PRESS_EVENT,
+                last: xproto::MOTION_NOTIFY_EVENT,
+            },
+            errors: empty,
+            client_started: false,
+            client_died: false,
+        };
+        control
+            .record_create_context(context, 0, &[record::CS::ALL_CLIENTS.into()], &[range])?
+            .check()?;
+
+        let mut recorder = Recorder {
+            stop_keys,
+            steps: Vec::new(),
+            start: None,
+            held_keys: HashSet::new(),
+            held_buttons: HashSet::new(),
+        };
+        let result = (|| -> Result<()> {
+            for reply in data.record_enable_context(context)? {
+                let reply = reply?;
+                if reply.category == END_OF_DATA {
+                    break;
+                }
+                if reply.category != FROM_SERVER || reply.client_swapped {
+                    continue;
+                }
+                for event in reply.data.chunks_exact(EVENT_LEN) {
+                    if recorder.handle(event)? {
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        })();
+        let _ = control.record_disable_context(context);
+        let _ = control.record_free_context(context);
+        let _ = control.flush();
+        result?;
+        Ok(recorder.steps)
+    }
+
+    struct Recorder {
+        stop_keys: HashSet<u8>,
+        steps: Vec<Step>,
+        /// Server time of the first recorded event.
+        start: Option<u32>,
+        /// Keys and buttons pressed since recording began; releases of
+        /// anything else, such as the Enter that started hostd, are dropped.
+        held_keys: HashSet<u8>,
+        held_buttons: HashSet<u8>,
+    }
+
+    impl Recorder {
+        /// Records one event; `true` once recording should stop.
+        fn handle(&mut self, event: &[u8]) -> Result<bool> {
+            let (kind, time, detail, position) = match event[0] & 0x7f {
+                xproto::KEY_PRESS_EVENT | xproto::KEY_RELEASE_EVENT => {
+                    let (event, _) = xproto::KeyPressEvent::try_parse(event)?;
+                    (event.response_type & 0x7f, event.time, event.detail, None)
+                }
+                xproto::BUTTON_PRESS_EVENT | xproto::BUTTON_RELEASE_EVENT => {
+                    let (event, _) = xproto::ButtonPressEvent::try_parse(event)?;
+                    (event.response_type & 0x7f, event.time, event.detail, None)
+                }
+                xproto::MOTION_NOTIFY_EVENT => {
+                    let (event, _) = xproto::MotionNotifyEvent::try_parse(event)?;
+                    let position = (i32::from(event.root_x), i32::from(event.root_y));
+                    (xproto::MOTION_NOTIFY_EVENT, event.time, 0, Some(position))
+                }
+                _ => return Ok(false),
+            };
+            if kind == xproto::KEY_PRESS_EVENT && self.stop_keys.contains(&detail) {
+                return Ok(true);
+            }
+            let start = *self.start.get_or_insert(time);
+            let at_ms = u64::from(time.wrapping_sub(start));
+            if Duration::from_millis(at_ms) > MAX_LENGTH {
+                eprintln!("Stopped at the {MAX_LENGTH:?} macro length limit.");
+                return Ok(true);
+            }
+            let event = match kind {
+                xproto::KEY_PRESS_EVENT => {
+                    self.held_keys.insert(detail);
+                    Event::Key {
+                        keycode: u16::from(detail),
+                        down: true,
+                    }
+                }
+                xproto::KEY_RELEASE_EVENT => {
+                    if !self.held_keys.remove(&detail) {
+                        return Ok(false);
+                    }
+                    Event::Key {
+                        keycode: u16::from(detail),
+                        down: false,
+                    }
+                }
+                xproto::BUTTON_PRESS_EVENT | xproto::BUTTON_RELEASE_EVENT => {
+                    let down = kind == xproto::BUTTON_PRESS_EVENT;
+                    let Some(event) = button_event(detail, down) else {
+                        return Ok(false);
+                    };
+                    if let Event::Button { .. } = event {
+                        if down {
+                            self.held_buttons.insert(detail);
+                        } else if !self.held_buttons.remove(&detail) {
+                            return Ok(false);
+                        }
+                    }
+                    event
+                }
+                _ => {
+                    let (x, y) = position.unwrap_or_default();
+                    Event::Move { x, y }
+                }
+            };
+            push_step(&mut self.steps, at_ms, event);
+            Ok(false)
+        }
+    }
+
+    /// Buttons 1-3 are the mouse buttons and 4-7 the wheel, which X11
+    /// reports as a press and release per notch; the release is dropped.
+    fn button_event(detail: u8, down: bool) -> Option<Event> {
+        let (horizontal, vertical) = match detail {
+            1..=3 => {
+                return Some(Event::Button {
+                    button: to_button(detail),
+                    down,
+                });
+            }
+            4 => (0, -1),
+            5 => (0, 1),
+            6 => (-1, 0),
+            7 => (1, 0),
+            _ => return None,
+        };
+        down.then_some(Event::Scroll {
+            horizontal,
+            vertical,
+        })
+    }
+
+    fn to_button(detail: u8) -> MouseButton {
+        match detail {
+            2 => MouseButton::Middle,
+            3 => MouseButton::Right,
+            _ => MouseButton::Left,
+        }
+    }
+
+    /// Keycodes with `keysym` anywhere in their mapping.
+    fn keycodes_for(conn: &impl Connection, keysym: u32) -> Result<HashSet<u8>> {
+        let setup = conn.setup();
+        let (min, max) = (setup.min_keycode, setup.max_keycode);
+        let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+        let per_keycode = usize::from(mapping.keysyms_per_keycode).max(1);
+        Ok(mapping
+            .keysyms
+            .chunks(per_keycode)
+            .zip(min..=max)
+            .filter(|(keysyms, _)| keysyms.contains(&keysym))
+            .map(|(_, keycode)| keycode)
+            .collect())
+    }
+}
@@ -1,34 +1,117 @@
 mod actions;
+mod archive;
+mod audit;
 mod resources;
 mod bindings {
     wasmtime::component::bindgen!({
         path: "../wit",
         world: "control",
+        async: true,
+        // Spans around every import, which the audit log records calls from.
+        tracing: true,
         with: {
             "osagent:fs/fs/dir-handle": crate::resources::DirHandleResource,
             "osagent:fs/fs/file-handle": crate::resources::FileHandleResource,
             "osagent:proc/proc/process": crate::resources::ProcessResource,
+            "osagent:shell/shell/session": crate::shell::ShellSession,
+            "osagent:browser/browser/session": crate::browser::BrowserSession,
+            "osagent:browser/browser/element-handle": crate::browser::BrowserElement,
+            "osagent:llm/llm/completion-stream": crate::llm::CompletionStream,
         },
     });
 }
+mod browser;
+mod budget;
 mod capabilities;
+mod cdp;
+mod cgroup;
+mod changes;
 mod cli;
 mod config;
+mod deadline;
+mod driver;
+mod encoding;
+mod executor;
+mod grant;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod har;
+mod input;
+mod llm;
 mod logging;
+mod macros;
+mod notify;
+mod policy;
+mod process;
+mod queue;
+mod ratelimit;
 mod runtime;
+mod sandbox;
+mod screen;
+mod serve;
+mod session;
+mod shell;
+mod skill;
+mod snapshot;
 mod state;
+mod stdio;
+mod taint;
+mod transcript;
+mod workspace;
 
 use anyhow::Result;
 use clap::Parser;
+use tracing::warn;
 
 use crate::cli::{Cli, Commands};
 
+/// Exit status after an interrupt, as a shell reports SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     logging::init();
     let cli = Cli::parse();
-    match cli.command {
-        Commands::Step(args) => runtime::run_step(args).await?,
+    // Runs on a worker thread: the step itself blocks the main one.
+    tokio::spawn(kill_children_on_signal());
+    let result = match cli.command {
+        Commands::Step(args) => runtime::run_step(args).await,
+        Commands::Run(args) => runtime::run_task(args).await,
+        Commands::Serve(args) => serve::run(args).await,
+        Commands::Stdio(args) => stdio::run(args).await,
+        Commands::RecordInput(args) => macros::record(&args),
+    };
+    driver::shutdown();
+    result
+}
+
+/// Children run in their own process groups, so a terminal Ctrl+C never
+/// reaches them; kill every tracked group before exiting instead.
+async fn kill_children_on_signal() {
+    if let Err(err) = shutdown_signal().await {
+        warn!(error = %err, "failed to listen for shutdown signals");
+        return;
     }
-    Ok(())
+    let killed = process::kill_tracked_groups();
+    let removed = executor::remove_tracked_containers();
+    warn!(killed, removed, "interrupted; killed child processes");
+    std::process::exit(INTERRUPTED_EXIT_CODE);
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+        _ = hangup.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
 }
@@ -0,0 +1,389 @@
+//! Chat notifications through Slack or Teams incoming webhooks, when
+//! `[notify]` names one: requests waiting on approval and a summary once the
+//! task ends. Both services accept the same `{"text": ...}` body.
+//!
+//! Under `hostd serve --slack-signing-secret`, Slack approval requests also
+//! carry approve and deny buttons. Slack posts a click to the app's
+//! interactivity URL, `POST /slack/actions`, signed with the app's signing
+//! secret; `slack_action` checks the signature and reads the answer. Teams
+//! posts stay text only: its buttons need a Bot Framework app.
+//!
+//! Delivery is best effort; a failed post is logged and the task goes on.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use url::Url;
+
+use crate::config::NotifySettings;
+use crate::grant::{Answer, GrantDecision, Pending};
+use crate::serve::token_matches;
+
+/// Keeps a slow chat service from stalling the task.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Commands and changed files listed in a summary before it says how many
+/// more there were.
+const MAX_LISTED: usize = 20;
+/// How far a Slack request's timestamp may be from the clock, so a captured
+/// request cannot be replayed later.
+const SLACK_MAX_SKEW_SECS: u64 = 5 * 60;
+
+thread_local! {
+    /// Commands the task running on this thread ran; a task stays on one
+    /// thread from start to end.
+    static COMMANDS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Forgets the commands an earlier task on this thread ran.
+pub fn start_task() {
+    COMMANDS.with_borrow_mut(Vec::clear);
+}
+
+/// Notes a command the task ran, for the summary.
+pub fn record_command(command_line: String) {
+    COMMANDS.with_borrow_mut(|commands| commands.push(command_line));
+}
+
+/// With `approval_id`, the Slack post carries buttons that answer it.
+pub fn approval_pending(
+    settings: &NotifySettings,
+    pending: &Pending<'_>,
+    approval_id: Option<u64>,
+) {
+    let target = match pending.scope {
+        Some(scope) => format!("{} ({scope})", pending.capability),
+        None => pending.capability.to_string(),
+    };
+    let text = format!(
+        "hostd (pid {}) is waiting for approval of {} `{target}`:\n>{}",
+        std::process::id(),
+        pending.kind,
+        pending.detail
+    );
+    let Some(id) = approval_id else {
+        return post(settings, &text);
+    };
+    let agent = agent();
+    if let Some(url) = &settings.teams_webhook {
+        send(&agent, url, json!({ "text": text }));
+    }
+    if let Some(url) = &settings.slack_webhook {
+        let button = |action: &str, label: &str| {
+            json!({
+                "type": "button",
+                "action_id": action,
+                "value": id.to_string(),
+                "text": { "type": "plain_text", "text": label },
+            })
+        };
+        let body = json!({
+            "text": text,
+            "blocks": [
+                { "type": "section", "text": { "type": "mrkdwn", "text": text } },
+                {
+                    "type": "actions",
+                    "elements": [
+                        button("approve_step", "Approve for this step"),
+                        button("approve_task", "Approve for the task"),
+                        button("deny", "Deny"),
+                    ],
+                },
+            ],
+        });
+        send(&agent, url, body);
+    }
+}
+
+/// A button click from a Slack approval request.
+pub struct SlackAction {
+    pub approval_id: u64,
+    pub answer: Answer,
+    /// Where Slack takes a replacement for the message clicked.
+    pub response_url: Option<Url>,
+}
+
+/// Checks a `POST /slack/actions` request's signature against the app's
+/// signing secret and reads the click it carries. `Err` says what was wrong.
+pub fn slack_action(
+    secret: &str,
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    body: &[u8],
+    now_secs: u64,
+) -> Result<SlackAction, String> {
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err("missing X-Slack-Request-Timestamp or X-Slack-Signature".to_string());
+    };
+    let sent: u64 = timestamp
+        .parse()
+        .map_err(|_| "invalid X-Slack-Request-Timestamp".to_string())?;
+    if sent.abs_diff(now_secs) > SLACK_MAX_SKEW_SECS {
+        return Err("X-Slack-Request-Timestamp is too far from now".to_string());
+    }
+    if !token_matches(signature, &slack_signature(secret, timestamp, body)) {
+        return Err("X-Slack-Signature does not match".to_string());
+    }
+    let payload = url::form_urlencoded::parse(body)
+        .find(|(key, _)| key == "payload")
+        .map(|(_, value)| value)
+        .ok_or_else(|| "no payload in the request".to_string())?;
+    let payload: Value =
+        serde_json::from_str(&payload).map_err(|err| format!("invalid payload: {err}"))?;
+    let action = &payload["actions"][0];
+    let approval_id = action["value"]
+        .as_str()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| "the action carries no approval id".to_string())?;
+    let decision = match action["action_id"].as_str() {
+        Some("approve_step") => GrantDecision::Step,
+        Some("approve_task") => GrantDecision::Session,
+        Some("deny") => GrantDecision::Deny,
+        _ => return Err("unknown action".to_string()),
+    };
+    let user = &payload["user"];
+    let user = ["username", "name", "id"]
+        .into_iter()
+        .find_map(|key| user[key].as_str())
+        .unwrap_or("unknown user");
+    // Only Slack's own hosts get the message back.
+    let response_url = payload["response_url"]
+        .as_str()
+        .and_then(|url| Url::parse(url).ok())
+        .filter(|url| {
+            url.scheme() == "https"
+                && url
+                    .host_str()
+                    .is_some_and(|host| host == "slack.com" || host.ends_with(".slack.com"))
+        });
+    Ok(SlackAction {
+        approval_id,
+        answer: Answer {
+            decision,
+            reason: Some(format!("answered in Slack by {user}")),
+        },
+        response_url,
+    })
+}
+
+/// `v0=` and the hex HMAC-SHA256 of `v0:{timestamp}:{body}` under the
+/// signing secret, as Slack signs requests.
+fn slack_signature(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mac = hmac_sha256(
+        secret.as_bytes(),
+        &[b"v0:".as_slice(), timestamp.as_bytes(), b":", body],
+    );
+    let hex: String = mac.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("v0={hex}")
+}
+
+fn hmac_sha256(key: &[u8], message: &[&[u8]]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    for part in message {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Replaces the clicked Slack message with `text`, so nobody else answers
+/// it.
+pub fn slack_answered(response_url: &Url, text: &str) {
+    send(
+        &agent(),
+        response_url,
+        json!({ "replace_original": true, "text": text }),
+    );
+}
+
+pub struct TaskSummary<'a> {
+    pub task: &'a str,
+    pub error: Option<String>,
+    pub steps: u64,
+    pub llm_tokens: u64,
+    /// As `ChangeSet::to_json` lists them.
+    pub changed_files: Value,
+}
+
+pub fn task_finished(settings: &NotifySettings, summary: &TaskSummary<'_>) {
+    let outcome = match &summary.error {
+        Some(err) => format!("failed: {err}"),
+        None => "completed".to_string(),
+    };
+    let mut text = format!(
+        "hostd task {outcome}\n*Task:* {}\n*Steps:* {}, *LLM tokens:* {}",
+        summary.task, summary.steps, summary.llm_tokens
+    );
+    let files: Vec<String> = summary
+        .changed_files
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| {
+            let change = entry["change"].as_str().unwrap_or_default();
+            let path = entry["path"].as_str().unwrap_or_default();
+            format!("{change} {path}")
+        })
+        .collect();
+    push_list(&mut text, "Changed files", &files);
+    let commands = COMMANDS.take();
+    push_list(&mut text, "Commands run", &commands);
+    post(settings, &text);
+}
+
+fn push_list(text: &mut String, title: &str, items: &[String]) {
+    if items.is_empty() {
+        return;
+    }
+    text.push_str(&format!("\n*{title}:*"));
+    for item in items.iter().take(MAX_LISTED) {
+        text.push_str(&format!("\n• `{item}`"));
+    }
+    if items.len() > MAX_LISTED {
+        text.push_str(&format!("\n…and {} more", items.len() - MAX_LISTED));
+    }
+}
+
+fn post(settings: &NotifySettings, text: &str) {
+    let agent = agent();
+    for url in [&settings.slack_webhook, &settings.teams_webhook]
+        .into_iter()
+        .flatten()
+    {
+        send(&agent, url, json!({ "text": text }));
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(POST_TIMEOUT).build()
+}
+
+fn send(agent: &ureq::Agent, url: &Url, body: Value) {
+    // Webhook URLs carry their secret, so errors are logged without them.
+    let error = match agent.post(url.as_str()).send_json(body) {
+        Ok(_) => return,
+        Err(ureq::Error::Status(status, _)) => format!("status {status}"),
+        Err(ureq::Error::Transport(err)) => err.kind().to_string(),
+    };
+    warn!(host = url.host_str(), error, "failed to post notification");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const NOW: u64 = 1_531_420_618;
+
+    fn click(action: &str, response_url: &str) -> Vec<u8> {
+        let payload = json!({
+            "type": "block_actions",
+            "user": { "id": "U2CERLKJA", "username": "roadrunner" },
+            "response_url": response_url,
+            "actions": [{ "action_id": action, "value": "7" }],
+        });
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("payload", &payload.to_string())
+            .finish()
+            .into_bytes()
+    }
+
+    fn signed(body: &[u8]) -> String {
+        slack_signature(SECRET, &NOW.to_string(), body)
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(b"Jefe", &[b"what do ya want ".as_slice(), b"for nothing?"]);
+        let hex: String = mac.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn reads_signed_click() {
+        let body = click("approve_task", "https://hooks.slack.com/actions/T1/1/x");
+        let action = slack_action(
+            SECRET,
+            Some(&NOW.to_string()),
+            Some(&signed(&body)),
+            &body,
+            NOW + 10,
+        )
+        .unwrap();
+        assert_eq!(action.approval_id, 7);
+        assert_eq!(action.answer.decision, GrantDecision::Session);
+        assert_eq!(
+            action.answer.reason.as_deref(),
+            Some("answered in Slack by roadrunner")
+        );
+        assert!(action.response_url.is_some());
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let body = click("approve_step", "https://hooks.slack.com/actions/T1/1/x");
+        let signature = slack_signature("another-secret", &NOW.to_string(), &body);
+        assert!(
+            slack_action(SECRET, Some(&NOW.to_string()), Some(&signature), &body, NOW).is_err()
+        );
+        let tampered = click("approve_task", "https://hooks.slack.com/actions/T1/1/x");
+        assert!(
+            slack_action(
+                SECRET,
+                Some(&NOW.to_string()),
+                Some(&signed(&body)),
+                &tampered,
+                NOW
+            )
+            .is_err()
+        );
+        assert!(slack_action(SECRET, Some(&NOW.to_string()), None, &body, NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        let body = click("deny", "https://hooks.slack.com/actions/T1/1/x");
+        let later = NOW + SLACK_MAX_SKEW_SECS + 1;
+        assert!(
+            slack_action(
+                SECRET,
+                Some(&NOW.to_string()),
+                Some(&signed(&body)),
+                &body,
+                later
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn ignores_response_url_outside_slack() {
+        let body = click("deny", "https://hooks.slack.com.evil.example/x");
+        let action = slack_action(
+            SECRET,
+            Some(&NOW.to_string()),
+            Some(&signed(&body)),
+            &body,
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(action.answer.decision, GrantDecision::Deny);
+        assert!(action.response_url.is_none());
+    }
+}
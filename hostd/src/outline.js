@@ -0,0 +1,125 @@
+// Compact outline of the visible page for planners: landmarks, headings and
+// interactive elements, one per line. Interactive elements are tagged with a
+// `data-warden-id` that survives until the page navigates, so planners can
+// target them with the CSS selector `[data-warden-id="N"]`.
+(() => {
+  const MAX_NODES = 400;
+  const MAX_NAME = 80;
+  const INTERACTIVE = new Set([
+    'button', 'checkbox', 'combobox', 'link', 'listbox', 'menuitem', 'option',
+    'radio', 'searchbox', 'slider', 'spinbutton', 'switch', 'tab', 'textbox',
+  ]);
+  const LANDMARKS = new Set([
+    'banner', 'complementary', 'contentinfo', 'dialog', 'form', 'main',
+    'navigation', 'region', 'search',
+  ]);
+  const INPUT_ROLES = {
+    button: 'button', submit: 'button', reset: 'button', image: 'button',
+    checkbox: 'checkbox', radio: 'radio', range: 'slider', number: 'spinbutton',
+    search: 'searchbox',
+  };
+  const TAG_ROLES = {
+    button: 'button', select: 'combobox', textarea: 'textbox', option: 'option',
+    summary: 'button', nav: 'navigation', main: 'main', header: 'banner',
+    footer: 'contentinfo', aside: 'complementary', form: 'form', dialog: 'dialog',
+    h1: 'heading', h2: 'heading', h3: 'heading', h4: 'heading', h5: 'heading',
+    h6: 'heading',
+  };
+
+  const roleOf = (el) => {
+    const explicit = el.getAttribute('role');
+    if (explicit) return explicit.split(/\s+/)[0];
+    const tag = el.tagName.toLowerCase();
+    if (tag === 'a') return el.hasAttribute('href') ? 'link' : null;
+    if (tag === 'input') {
+      const type = (el.getAttribute('type') || 'text').toLowerCase();
+      if (type === 'hidden') return null;
+      return INPUT_ROLES[type] || 'textbox';
+    }
+    if (TAG_ROLES[tag]) return TAG_ROLES[tag];
+    if (el.isContentEditable && el.parentElement && !el.parentElement.isContentEditable) {
+      return 'textbox';
+    }
+    if (el.hasAttribute('onclick') || (el.tabIndex >= 0 && el.hasAttribute('tabindex'))) {
+      return 'button';
+    }
+    return null;
+  };
+
+  const clip = (text) => {
+    const flat = (text || '').replace(/\s+/g, ' ').trim();
+    return flat.length > MAX_NAME ? flat.slice(0, MAX_NAME - 1) + '…' : flat;
+  };
+
+  const nameOf = (el) => {
+    const labelledBy = el.getAttribute('aria-labelledby');
+    if (labelledBy) {
+      const text = labelledBy.split(/\s+/)
+        .map((id) => document.getElementById(id))
+        .filter(Boolean)
+        .map((node) => node.innerText || node.textContent)
+        .join(' ');
+      if (clip(text)) return clip(text);
+    }
+    for (const attr of ['aria-label', 'alt', 'title']) {
+      if (clip(el.getAttribute(attr))) return clip(el.getAttribute(attr));
+    }
+    if (el.labels && el.labels.length) {
+      return clip(Array.from(el.labels, (label) => label.innerText).join(' '));
+    }
+    if (clip(el.getAttribute('placeholder'))) return clip(el.getAttribute('placeholder'));
+    const tag = el.tagName.toLowerCase();
+    if (tag === 'input' || tag === 'select' || tag === 'textarea') return '';
+    return clip(el.innerText || el.textContent);
+  };
+
+  const visible = (el) => {
+    const style = getComputedStyle(el);
+    if (style.display === 'contents') return true;
+    return el.getClientRects().length > 0 && style.visibility !== 'hidden';
+  };
+
+  const lines = [];
+  let skipped = 0;
+  let nextId = Number(document.documentElement.dataset.wardenNextId || 0);
+  const walk = (el, depth) => {
+    if (el.getAttribute('aria-hidden') === 'true' || !visible(el)) return;
+    const role = roleOf(el);
+    let childDepth = depth;
+    if (role && (INTERACTIVE.has(role) || LANDMARKS.has(role) || role === 'heading')) {
+      if (lines.length >= MAX_NODES) {
+        skipped += 1;
+      } else {
+        let line = '  '.repeat(depth) + '- ';
+        if (INTERACTIVE.has(role)) {
+          if (!el.dataset.wardenId) el.dataset.wardenId = String(++nextId);
+          line += `[${el.dataset.wardenId}] `;
+        }
+        line += role;
+        if (role === 'heading') {
+          const level = /^H[1-6]$/.test(el.tagName) ? el.tagName[1] : el.getAttribute('aria-level');
+          if (level) line += ` ${level}`;
+        }
+        const name = nameOf(el);
+        if (name) line += ` ${JSON.stringify(name)}`;
+        if (role === 'link') line += ` -> ${el.getAttribute('href')}`;
+        const secret = el.type === 'password';
+        if (typeof el.value === 'string' && el.value && role !== 'button' && !secret) {
+          line += ` value=${JSON.stringify(clip(el.value))}`;
+        }
+        if (el.checked) line += ' checked';
+        if (el.disabled) line += ' disabled';
+        lines.push(line.trimEnd());
+      }
+      if (LANDMARKS.has(role)) childDepth += 1;
+      // Interactive elements and headings are summarised by their name.
+      if (!LANDMARKS.has(role)) return;
+    }
+    for (const child of el.children) walk(child, childDepth);
+    if (el.shadowRoot) for (const child of el.shadowRoot.children) walk(child, childDepth);
+  };
+  if (document.body) walk(document.body, 0);
+  document.documentElement.dataset.wardenNextId = String(nextId);
+  if (skipped) lines.push(`- … ${skipped} more elements not shown`);
+  return lines.join('\n');
+})()
@@ -0,0 +1,524 @@
+//! The rules every capability handler and planner action consults before
+//! acting: which commands and arguments may run, which workspace paths may
+//! be read or written, which URLs the browser may open, and what a task may
+//! spend. Built from `[policy]` plus the older `allow_proc`, `proc.args`
+//! and `browser.urls` keys.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use url::Url;
+
+use crate::budget::BudgetKind;
+use crate::taint::TaintMode;
+
+/// Planner steps a task gets when `policy.budgets.steps` is unset.
+const DEFAULT_MAX_STEPS: u64 = 8;
+
+/// Fuel each `planner.step` call gets when `policy.planner_fuel` is unset,
+/// roughly that many Wasm instructions.
+const DEFAULT_PLANNER_FUEL: u64 = 10_000_000_000;
+
+/// How long a request waits on `approval_webhook` when
+/// `approval_timeout_ms` is unset.
+const DEFAULT_APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Programs the guest may run; `"*"` allows any program and is only
+    /// accepted with a container backend.
+    pub commands: Vec<String>,
+    /// Per-command argv rules, keyed by program name as in `commands`.
+    pub arg_rules: BTreeMap<String, ArgRule>,
+    pub fs: PathRule,
+    /// Operations that need explicit approval even where allowed.
+    pub confirm: ConfirmRule,
+    /// Checked against every URL the browser is asked to open.
+    pub urls: UrlRule,
+    /// What happens to commands and write paths that quote web content.
+    pub taint: TaintMode,
+    /// `[policy.rate_limits]`: calls per minute, keyed by `interface` or
+    /// `interface.function`.
+    pub rate_limits: BTreeMap<String, u32>,
+    /// `[policy.budgets]`: per-task limits; kinds left out are unlimited.
+    pub budgets: BTreeMap<BudgetKind, u64>,
+    /// Ask the operator on the terminal about `request-capability` calls;
+    /// off, every request not already allowed is denied.
+    pub grant_prompts: bool,
+    /// Where capability requests and `approval_required` actions are sent
+    /// for review instead of the terminal.
+    pub approval_webhook: Option<Url>,
+    /// How long to wait for the webhook before denying.
+    pub approval_timeout: Duration,
+    /// Planner actions held for approval before they run, by interface or
+    /// full action name.
+    pub approval_required: Vec<String>,
+    /// Where each task's audit log is written as JSONL; off when unset.
+    pub audit_dir: Option<Utf8PathBuf>,
+    /// Re-read the config between planner steps when it changes on disk.
+    pub hot_reload: bool,
+    /// Wasmtime fuel for each `planner.step` call, and for instantiating the
+    /// component.
+    pub planner_fuel: u64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            commands: Vec::new(),
+            arg_rules: BTreeMap::new(),
+            fs: PathRule::default(),
+            confirm: ConfirmRule::default(),
+            urls: UrlRule::default(),
+            taint: TaintMode::default(),
+            rate_limits: BTreeMap::new(),
+            budgets: BTreeMap::from([(BudgetKind::Steps, DEFAULT_MAX_STEPS)]),
+            grant_prompts: true,
+            approval_webhook: None,
+            approval_timeout: DEFAULT_APPROVAL_TIMEOUT,
+            approval_required: Vec::new(),
+            audit_dir: None,
+            hot_reload: true,
+            planner_fuel: DEFAULT_PLANNER_FUEL,
+        }
+    }
+}
+
+impl Policy {
+    pub fn is_proc_allowed(&self, program: &str) -> bool {
+        self.command_denial(program).is_none()
+    }
+
+    pub fn command_denial(&self, program: &str) -> Option<Denial> {
+        let base = program_base_name(program);
+        let listed = self
+            .commands
+            .iter()
+            .any(|entry| entry == "*" || entry == program || entry == base);
+        (!listed).then(|| Denial::unmatched("policy.proc.commands"))
+    }
+
+    /// Applies the argument rule for `program`, if any, to its space-joined
+    /// argv. Deny patterns win; a non-empty allow list must match.
+    pub fn args_denial(&self, program: &str, args: &[String]) -> Option<Denial> {
+        let rules = &self.arg_rules;
+        let (name, rule) = rules
+            .get_key_value(program)
+            .or_else(|| rules.get_key_value(program_base_name(program)))?;
+        let argv = args.join(" ");
+        if let Some(pattern) = rule.deny.iter().find(|pattern| pattern.is_match(&argv)) {
+            return Some(Denial::matched(
+                format!("policy.proc.args.{name}.deny"),
+                pattern,
+            ));
+        }
+        if !rule.allow.is_empty() && !rule.allow.iter().any(|pattern| pattern.is_match(&argv)) {
+            return Some(Denial::unmatched(format!("policy.proc.args.{name}.allow")));
+        }
+        None
+    }
+
+    pub fn needs_approval(&self, action: &str) -> bool {
+        self.approval_required.iter().any(|entry| {
+            let covers_interface = !entry.contains('.')
+                && action
+                    .strip_prefix(entry.as_str())
+                    .is_some_and(|rest| rest.starts_with('.'));
+            action == entry || covers_interface
+        })
+    }
+
+    /// Why running `command_line` needs confirmation, if it does.
+    pub fn command_confirmation(&self, command_line: &str) -> Option<String> {
+        let pattern = self
+            .confirm
+            .commands
+            .iter()
+            .find(|pattern| pattern.is_match(command_line))?;
+        Some(format!("matches policy.confirm.commands rule `{pattern}`"))
+    }
+
+    /// Why writing `path` needs confirmation, if it does. `path` must
+    /// already be inside `root`.
+    pub fn write_confirmation(&self, root: &Utf8Path, path: &Utf8Path) -> Option<String> {
+        let globs = &self.confirm.writes_outside;
+        let relative = path.strip_prefix(root).unwrap_or(path).as_str();
+        if globs.is_empty() || globs.iter().any(|glob| glob.matches(relative)) {
+            return None;
+        }
+        Some("outside every policy.confirm.writes_outside glob".to_string())
+    }
+
+    /// The rule that keeps `path` from being accessed, or `None` when it
+    /// may be. `path` must already be inside `root`.
+    pub fn path_denial(&self, root: &Utf8Path, path: &Utf8Path, access: Access) -> Option<Denial> {
+        let relative = path.strip_prefix(root).unwrap_or(path).as_str();
+        self.fs.denial(relative, access)
+    }
+}
+
+/// Which rule refused something, so a denial can say exactly why.
+#[derive(Debug, Clone)]
+pub struct Denial {
+    /// Config key of the rule, e.g. `policy.fs.deny`.
+    pub rule: String,
+    /// The pattern that matched; `None` when nothing in an allow list did.
+    pub pattern: Option<String>,
+}
+
+impl Denial {
+    pub fn matched(rule: impl Into<String>, pattern: &impl fmt::Display) -> Self {
+        Self {
+            rule: rule.into(),
+            pattern: Some(pattern.to_string()),
+        }
+    }
+
+    pub fn unmatched(rule: impl Into<String>) -> Self {
+        Self {
+            rule: rule.into(),
+            pattern: None,
+        }
+    }
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Some(pattern) => write!(f, "denied by {} rule `{pattern}`", self.rule),
+            None => write!(f, "not matched by any {} rule", self.rule),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Creating, changing, renaming or removing.
+    Write,
+}
+
+/// Regexes matched against a command's arguments joined with single spaces.
+#[derive(Debug, Clone, Default)]
+pub struct ArgRule {
+    pub allow: Vec<Regex>,
+    pub deny: Vec<Regex>,
+}
+
+/// Regexes matched against a URL before the browser navigates to it.
+#[derive(Debug, Clone, Default)]
+pub struct UrlRule {
+    pub allow: Vec<Regex>,
+    pub deny: Vec<Regex>,
+}
+
+impl UrlRule {
+    /// The rule that keeps `url` from being opened, or `None` when it may
+    /// be.
+    pub fn denial(&self, url: &str) -> Option<Denial> {
+        if let Some(pattern) = self.deny.iter().find(|pattern| pattern.is_match(url)) {
+            return Some(Denial::matched("policy.urls.deny", pattern));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|pattern| pattern.is_match(url)) {
+            return Some(Denial::unmatched("policy.urls.allow"));
+        }
+        None
+    }
+}
+
+/// `[policy.confirm]`: a classifier for operations dangerous enough to ask
+/// about every time, e.g. `rm -rf` or `git push`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfirmRule {
+    /// Regexes over a command line: the program and its arguments joined
+    /// with single spaces, or a shell command as given.
+    pub commands: Vec<Regex>,
+    /// Removing a directory together with its contents.
+    pub recursive_remove: bool,
+    /// When non-empty, writes to paths outside all of these globs.
+    pub writes_outside: Vec<Glob>,
+}
+
+/// `[policy.fs]` globs over workspace-relative paths. `*` stays within one
+/// path segment, `**` spans any number of them.
+#[derive(Debug, Clone, Default)]
+pub struct PathRule {
+    /// When non-empty, paths outside all of these are off limits.
+    pub allow: Vec<Glob>,
+    /// Off limits even when allowed.
+    pub deny: Vec<Glob>,
+    /// May be read but not written.
+    pub read_only: Vec<Glob>,
+}
+
+impl PathRule {
+    fn denial(&self, relative: &str, access: Access) -> Option<Denial> {
+        if let Some(glob) = self.deny.iter().find(|glob| glob.matches(relative)) {
+            return Some(Denial::matched("policy.fs.deny", glob));
+        }
+        // The workspace root itself stays reachable, or an allow list
+        // would make every path unreachable by handle.
+        if !self.allow.is_empty()
+            && !relative.is_empty()
+            && !self.allow.iter().any(|glob| glob.matches(relative))
+        {
+            return Some(Denial::unmatched("policy.fs.allow"));
+        }
+        let read_only = self
+            .read_only
+            .iter()
+            .find(|glob| access == Access::Write && glob.matches(relative));
+        read_only.map(|glob| Denial::matched("policy.fs.read_only", glob))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim().trim_start_matches("./");
+        let mut regex = String::from("^");
+        let mut rest = pattern;
+        while !rest.is_empty() {
+            if let Some(tail) = rest.strip_prefix("**/") {
+                regex.push_str("(?:.*/)?");
+                rest = tail;
+            } else if rest == "/**" {
+                regex.push_str("(?:/.*)?");
+                rest = "";
+            } else if let Some(tail) = rest.strip_prefix("**") {
+                regex.push_str(".*");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('*') {
+                regex.push_str("[^/]*");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('?') {
+                regex.push_str("[^/]");
+                rest = tail;
+            } else {
+                let c = rest.chars().next().unwrap_or_default();
+                regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        regex.push('$');
+        let regex = Regex::new(&regex).with_context(|| format!("invalid glob `{pattern}`"))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn matches(&self, relative: &str) -> bool {
+        self.regex.is_match(relative)
+    }
+}
+
+impl std::fmt::Display for Glob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+pub fn program_base_name(program: &str) -> &str {
+    Path::new(program)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<Regex> {
+        patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn git_policy(allow: &[&str], deny: &[&str]) -> Policy {
+        Policy {
+            commands: vec!["git".to_string()],
+            arg_rules: BTreeMap::from([(
+                "git".to_string(),
+                ArgRule {
+                    allow: patterns(allow),
+                    deny: patterns(deny),
+                },
+            )]),
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn denies_args_matching_deny_pattern() {
+        let policy = git_policy(&[], &[r"^push\b"]);
+        let denial = policy
+            .args_denial("git", &args(&["push", "origin", "main"]))
+            .expect("push is denied");
+        assert_eq!(denial.rule, "policy.proc.args.git.deny");
+        assert_eq!(denial.pattern.as_deref(), Some(r"^push\b"));
+        assert!(policy.args_denial("git", &args(&["status"])).is_none());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let policy = git_policy(&[r"^push\b"], &[r"--force"]);
+        let denial = policy
+            .args_denial("git", &args(&["push", "--force"]))
+            .expect("force push is denied");
+        assert_eq!(denial.rule, "policy.proc.args.git.deny");
+        assert!(policy.args_denial("git", &args(&["push"])).is_none());
+    }
+
+    #[test]
+    fn denies_args_outside_allow_list() {
+        let policy = git_policy(&[r"^(status|diff)\b"], &[]);
+        let denial = policy
+            .args_denial("git", &args(&["commit", "-m", "x"]))
+            .expect("commit is not allowed");
+        assert_eq!(denial.rule, "policy.proc.args.git.allow");
+        assert!(denial.pattern.is_none());
+        assert!(
+            policy
+                .args_denial("git", &args(&["diff", "HEAD"]))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn matches_arg_rule_by_base_name() {
+        let policy = git_policy(&[], &[r"^push\b"]);
+        assert!(
+            policy
+                .args_denial("/usr/bin/git", &args(&["push"]))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn allows_any_args_without_rule() {
+        let policy = git_policy(&[], &[r"^push\b"]);
+        assert!(policy.args_denial("ls", &args(&["push"])).is_none());
+    }
+
+    #[test]
+    fn matches_args_joined_with_spaces() {
+        let policy = git_policy(&[], &[r"reset --hard"]);
+        assert!(
+            policy
+                .args_denial("git", &args(&["reset", "--hard", "HEAD~1"]))
+                .is_some()
+        );
+    }
+
+    fn globs(patterns: &[&str]) -> Vec<Glob> {
+        patterns
+            .iter()
+            .map(|pattern| Glob::new(pattern).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn single_star_stays_in_one_segment() {
+        let glob = Glob::new("src/*.rs").unwrap();
+        assert!(glob.matches("src/main.rs"));
+        assert!(!glob.matches("src/bin/tool.rs"));
+        assert!(!glob.matches("other/src/main.rs"));
+    }
+
+    #[test]
+    fn double_star_spans_segments() {
+        let glob = Glob::new("**/*.pem").unwrap();
+        assert!(glob.matches("key.pem"));
+        assert!(glob.matches("certs/prod/key.pem"));
+        assert!(!glob.matches("key.pem.bak"));
+
+        let tree = Glob::new("secrets/**").unwrap();
+        assert!(tree.matches("secrets"));
+        assert!(tree.matches("secrets/a/b.txt"));
+        assert!(!tree.matches("secrets-old/a.txt"));
+    }
+
+    #[test]
+    fn question_mark_and_literals() {
+        let glob = Glob::new("./logs/day?.log").unwrap();
+        assert_eq!(glob.to_string(), "logs/day?.log");
+        assert!(glob.matches("logs/day1.log"));
+        assert!(!glob.matches("logs/day/.log"));
+        assert!(!glob.matches("logs/dayx.logs"));
+        assert!(Glob::new("a+b(c).txt").unwrap().matches("a+b(c).txt"));
+    }
+
+    #[test]
+    fn fs_deny_wins_over_allow() {
+        let policy = Policy {
+            fs: PathRule {
+                allow: globs(&["src/**"]),
+                deny: globs(&["src/**/*.key"]),
+                read_only: Vec::new(),
+            },
+            ..Policy::default()
+        };
+        let root = Utf8Path::new("/ws");
+        let denial = policy
+            .path_denial(root, Utf8Path::new("/ws/src/tls/server.key"), Access::Read)
+            .expect("key is denied");
+        assert_eq!(denial.rule, "policy.fs.deny");
+        assert!(
+            policy
+                .path_denial(root, Utf8Path::new("/ws/src/lib.rs"), Access::Write)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn fs_allow_list_keeps_root_reachable() {
+        let policy = Policy {
+            fs: PathRule {
+                allow: globs(&["docs/**"]),
+                ..PathRule::default()
+            },
+            ..Policy::default()
+        };
+        let root = Utf8Path::new("/ws");
+        assert!(policy.path_denial(root, root, Access::Read).is_none());
+        let denial = policy
+            .path_denial(root, Utf8Path::new("/ws/.env"), Access::Read)
+            .expect(".env is outside the allow list");
+        assert_eq!(denial.rule, "policy.fs.allow");
+    }
+
+    #[test]
+    fn fs_read_only_blocks_writes() {
+        let policy = Policy {
+            fs: PathRule {
+                read_only: globs(&["Cargo.lock"]),
+                ..PathRule::default()
+            },
+            ..Policy::default()
+        };
+        let root = Utf8Path::new("/ws");
+        let lock = Utf8Path::new("/ws/Cargo.lock");
+        assert!(policy.path_denial(root, lock, Access::Read).is_none());
+        let denial = policy
+            .path_denial(root, lock, Access::Write)
+            .expect("lock file is read-only");
+        assert_eq!(denial.rule, "policy.fs.read_only");
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::cgroup::Cgroup;
+use crate::executor::Container;
+
+/// Process groups of every live child, so a signal handler that cannot unwind
+/// through their owners can still kill them before the host exits.
+static TRACKED_GROUPS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+fn tracked_groups() -> MutexGuard<'static, BTreeSet<u32>> {
+    TRACKED_GROUPS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Records a child started with [`isolate_group`] for [`kill_tracked_groups`].
+pub fn track_group(pid: u32) {
+    tracked_groups().insert(pid);
+}
+
+/// Forgets a child once it has been reaped, before its pid can be reused.
+pub fn untrack_group(pid: u32) {
+    tracked_groups().remove(&pid);
+}
+
+/// Kills every tracked process group and returns how many there were.
+pub fn kill_tracked_groups() -> usize {
+    let groups = std::mem::take(&mut *tracked_groups());
+    for pid in &groups {
+        let _ = kill_group(*pid);
+    }
+    groups.len()
+}
+
+/// Places the child in its own process group so a timeout or signal reaches
+/// everything it forks, not just the direct child.
+pub fn isolate_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// Scheduling priority requested for a spawned command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Yields the CPU to interactive work on a shared machine.
+    Low,
+    #[default]
+    Normal,
+}
+
+/// Niceness given to low-priority children on unix.
+#[cfg(unix)]
+const LOW_PRIORITY_NICE: libc::c_int = 10;
+
+/// Lowers the child's priority before it execs: nice on unix,
+/// `BELOW_NORMAL_PRIORITY_CLASS` on Windows. `Normal` keeps the host's.
+pub fn apply_priority(cmd: &mut Command, priority: Priority) {
+    if priority == Priority::Normal {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setpriority is async-signal-safe and nothing is allocated
+        // between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICE) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+        cmd.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = cmd;
+}
+
+/// Switches a stdin pipe or pty master to non-blocking so a child that stops
+/// reading yields short writes instead of stalling the host.
+#[cfg(unix)]
+pub fn set_nonblocking<F: std::os::fd::AsRawFd>(file: &F) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl on a descriptor owned by `file` for its lifetime.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_nonblocking<F>(_file: &F) -> io::Result<()> {
+    Ok(())
+}
+
+/// Pseudo-terminal for a spawned child. The child gets the slave side as its
+/// stdio and controlling terminal; the host keeps the master.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Pty {
+    pub master: File,
+    slave: File,
+}
+
+#[cfg(unix)]
+impl Pty {
+    pub fn open(rows: u16, cols: u16) -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let mut master = -1;
+        let mut slave = -1;
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: openpty writes two descriptors into the provided slots; the
+        // name and termios pointers may be null.
+        let rc = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just returned by openpty and are owned
+        // by nobody else.
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        for fd in [&master, &slave] {
+            set_cloexec(fd)?;
+        }
+        Ok(Self { master, slave })
+    }
+
+    /// Wires the slave side into `cmd` and makes it the child's controlling
+    /// terminal. The child leads a new session, which also gives it its own
+    /// process group, so do not combine with [`isolate_group`].
+    pub fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        cmd.stdin(self.slave.try_clone()?);
+        cmd.stdout(self.slave.try_clone()?);
+        cmd.stderr(self.slave.try_clone()?);
+        // SAFETY: setsid and ioctl are async-signal-safe and nothing is
+        // allocated between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Closes the host's copy of the slave once the child holds its own, so
+    /// reads on the master see EOF when the child exits.
+    pub fn into_master(self) -> File {
+        self.master
+    }
+}
+
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub struct Pty;
+
+#[cfg(not(unix))]
+impl Pty {
+    pub fn open(_rows: u16, _cols: u16) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pseudo-terminals require a unix host",
+        ))
+    }
+
+    pub fn attach(&self, _cmd: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn into_master(self) -> File {
+        unreachable!("pty cannot be opened on this platform")
+    }
+}
+
+#[cfg(unix)]
+fn set_cloexec(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl on a descriptor owned by `file` for its lifetime.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Renders `program` and `args` as a shell-style line for logs and dry runs,
+/// single-quoting arguments that would otherwise be ambiguous.
+pub fn display_command(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .map(|word| {
+            let plain = !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            if plain {
+                word.to_string()
+            } else {
+                format!("'{}'", word.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Term,
+    Kill,
+    Interrupt,
+    Hangup,
+}
+
+#[cfg(unix)]
+impl Signal {
+    fn raw(self) -> libc::c_int {
+        match self {
+            Self::Term => libc::SIGTERM,
+            Self::Kill => libc::SIGKILL,
+            Self::Interrupt => libc::SIGINT,
+            Self::Hangup => libc::SIGHUP,
+        }
+    }
+
+    fn from_raw(raw: libc::c_int) -> Option<Self> {
+        match raw {
+            libc::SIGTERM => Some(Self::Term),
+            libc::SIGKILL => Some(Self::Kill),
+            libc::SIGINT => Some(Self::Interrupt),
+            libc::SIGHUP => Some(Self::Hangup),
+            _ => None,
+        }
+    }
+}
+
+/// Delivers `signal` to the child's process group. Outside unix only `Kill`
+/// is available, and it terminates the direct child alone.
+pub fn send_signal(child: &mut Child, signal: Signal) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        signal_group(child.id(), signal)
+    }
+    #[cfg(not(unix))]
+    match signal {
+        Signal::Kill => child.kill(),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only kill is supported on this platform",
+        )),
+    }
+}
+
+/// Signal that terminated the child, if it did not exit normally.
+pub fn exit_signal(status: &ExitStatus) -> Option<Signal> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal().and_then(Signal::from_raw)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(unix)]
+fn signal_group(pid: u32, signal: Signal) -> io::Result<()> {
+    // SAFETY: killpg has no memory-safety preconditions; the group id is the
+    // pid of a child spawned with `isolate_group`.
+    if unsafe { libc::killpg(pid as libc::pid_t, signal.raw()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+pub fn kill_group(pid: u32) -> io::Result<()> {
+    signal_group(pid, Signal::Kill)
+}
+
+#[cfg(not(unix))]
+pub fn kill_group(_pid: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "process groups are only supported on unix",
+    ))
+}
+
+/// Kills a child's process group once its deadline passes. The watchdog keeps
+/// running if the handle is dropped, so closing a process never lifts its
+/// timeout; call [`Watchdog::disarm`] once the child has been reaped.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    state: Arc<WatchState>,
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    done: Mutex<bool>,
+    wake: Condvar,
+    fired: AtomicBool,
+}
+
+impl Watchdog {
+    pub fn arm(child: &Child, timeout: Duration) -> Self {
+        let watchdog = Self {
+            state: Arc::default(),
+        };
+        let state = Arc::clone(&watchdog.state);
+        let pid = child.id();
+        thread::spawn(move || {
+            let done = state.done.lock().unwrap_or_else(|p| p.into_inner());
+            let (done, _) = state
+                .wake
+                .wait_timeout_while(done, timeout, |done| !*done)
+                .unwrap_or_else(|p| p.into_inner());
+            // Kill while holding the lock so a concurrent disarm (the child
+            // was just reaped and its pid may be reused) cannot interleave.
+            if !*done {
+                state.fired.store(true, Ordering::SeqCst);
+                if let Err(err) = kill_group(pid) {
+                    warn!(pid, error = %err, "failed to kill timed out process group");
+                }
+            }
+        });
+        watchdog
+    }
+
+    pub fn disarm(&self) {
+        *self.state.done.lock().unwrap_or_else(|p| p.into_inner()) = true;
+        self.state.wake.notify_all();
+    }
+
+    /// Whether the deadline passed and the process group was killed.
+    pub fn fired(&self) -> bool {
+        self.state.fired.load(Ordering::SeqCst)
+    }
+
+    /// Runs `reap` (a `try_wait`) with the deadline held off, and disarms
+    /// if it reaped the child. The watchdog kills under the same lock, so it
+    /// never signals a pid the kernel may already have reused.
+    pub fn reap<T>(&self, reap: impl FnOnce() -> io::Result<Option<T>>) -> io::Result<Option<T>> {
+        let mut done = self.state.done.lock().unwrap_or_else(|p| p.into_inner());
+        let reaped = reap()?;
+        if reaped.is_some() {
+            *done = true;
+            self.state.wake.notify_all();
+        }
+        Ok(reaped)
+    }
+}
+
+/// Child shared between its guest handle and the [`ProcessRegistry`], so the
+/// registry still sees it exit after the handle is closed.
+pub type SharedChild = Arc<Mutex<Child>>;
+
+pub fn lock_child(child: &SharedChild) -> MutexGuard<'_, Child> {
+    child.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Reaps the child if it has exited, without blocking, disarming its
+/// watchdog in the same step (see [`Watchdog::reap`]).
+pub fn try_reap(
+    child: &SharedChild,
+    watchdog: Option<&Watchdog>,
+) -> io::Result<Option<ExitStatus>> {
+    let try_wait = || lock_child(child).try_wait();
+    match watchdog {
+        Some(watchdog) => watchdog.reap(try_wait),
+        None => try_wait(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub command: String,
+    pub started_ms: u64,
+    /// False once the guest closed its handle while the child kept running.
+    pub handle_open: bool,
+}
+
+#[derive(Debug)]
+struct RegistryEntry {
+    info: ProcessInfo,
+    child: SharedChild,
+    watchdog: Option<Watchdog>,
+    /// When its run time was last charged; `None` for shell sessions, whose
+    /// commands are charged as they run instead.
+    metered_since: Option<Instant>,
+    /// Held until the child exits so its cgroup is not removed while in use.
+    _cgroup: Option<Arc<Cgroup>>,
+    /// Likewise keeps a container-backend child's container from being removed.
+    _container: Option<Arc<Container>>,
+}
+
+/// Every child spawned for the guest that has not been reaped yet, keyed by pid.
+#[derive(Debug, Default)]
+pub struct ProcessRegistry {
+    entries: BTreeMap<u32, RegistryEntry>,
+    /// Uncharged run time of metered children that have since exited.
+    exited_run_time: Duration,
+}
+
+impl ProcessRegistry {
+    pub fn register(
+        &mut self,
+        command: &str,
+        child: SharedChild,
+        watchdog: Option<Watchdog>,
+        cgroup: Option<Arc<Cgroup>>,
+        container: Option<Arc<Container>>,
+    ) {
+        self.insert(command, child, watchdog, true, cgroup, container);
+    }
+
+    /// Tracks a shell session's child without metering its run time.
+    pub fn register_session(
+        &mut self,
+        command: &str,
+        child: SharedChild,
+        cgroup: Option<Arc<Cgroup>>,
+        container: Option<Arc<Container>>,
+    ) {
+        self.insert(command, child, None, false, cgroup, container);
+    }
+
+    fn insert(
+        &mut self,
+        command: &str,
+        child: SharedChild,
+        watchdog: Option<Watchdog>,
+        metered: bool,
+        cgroup: Option<Arc<Cgroup>>,
+        container: Option<Arc<Container>>,
+    ) {
+        let pid = lock_child(&child).id();
+        track_group(pid);
+        let started_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let info = ProcessInfo {
+            pid,
+            command: command.to_string(),
+            started_ms,
+            handle_open: true,
+        };
+        self.entries.insert(
+            pid,
+            RegistryEntry {
+                info,
+                child,
+                watchdog,
+                metered_since: metered.then(Instant::now),
+                _cgroup: cgroup,
+                _container: container,
+            },
+        );
+    }
+
+    /// Marks the guest handle for `pid` as closed; the child stays tracked
+    /// until it exits.
+    pub fn release(&mut self, pid: u32) {
+        if let Some(entry) = self.entries.get_mut(&pid) {
+            entry.info.handle_open = false;
+        }
+        self.prune();
+    }
+
+    /// Children still running, after reaping any that exited.
+    pub fn live(&mut self) -> Vec<ProcessInfo> {
+        self.prune();
+        self.entries
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    pub fn live_count(&mut self) -> usize {
+        self.prune();
+        self.entries.len()
+    }
+
+    /// Time metered children ran since the previous call, including those
+    /// that exited in between.
+    pub fn take_run_time(&mut self) -> Duration {
+        self.prune();
+        let now = Instant::now();
+        let mut total = std::mem::take(&mut self.exited_run_time);
+        for since in self
+            .entries
+            .values_mut()
+            .filter_map(|entry| entry.metered_since.as_mut())
+        {
+            total += now.duration_since(*since);
+            *since = now;
+        }
+        total
+    }
+
+    /// Kills and reaps every child still running, e.g. when a step aborts.
+    /// Returns what was killed.
+    pub fn kill_all(&mut self) -> Vec<ProcessInfo> {
+        self.prune();
+        let entries = std::mem::take(&mut self.entries);
+        entries
+            .into_values()
+            .map(|entry| {
+                // Disarmed before the child is reaped, so it cannot signal a
+                // reused pid.
+                if let Some(watchdog) = entry.watchdog.as_ref() {
+                    watchdog.disarm();
+                }
+                let mut child = lock_child(&entry.child);
+                let _ = send_signal(&mut child, Signal::Kill);
+                let _ = child.wait();
+                if let Some(since) = entry.metered_since {
+                    self.exited_run_time += since.elapsed();
+                }
+                untrack_group(entry.info.pid);
+                entry.info
+            })
+            .collect()
+    }
+
+    fn prune(&mut self) {
+        self.entries.retain(|pid, entry| {
+            let exited = !matches!(try_reap(&entry.child, entry.watchdog.as_ref()), Ok(None));
+            if exited {
+                if let Some(since) = entry.metered_since {
+                    self.exited_run_time += since.elapsed();
+                }
+                untrack_group(*pid);
+            }
+            !exited
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    pub data: Vec<u8>,
+    pub truncated: bool,
+}
+
+/// Reads `reader` to EOF on a background thread, keeping the first `limit`
+/// bytes and discarding the rest so the child never blocks on a full pipe.
+pub fn capture_bounded<R: Read + Send + 'static>(
+    mut reader: R,
+    limit: u64,
+) -> JoinHandle<CapturedOutput> {
+    thread::spawn(move || {
+        let mut output = CapturedOutput::default();
+        let mut chunk = [0u8; 8192];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    let room = limit.saturating_sub(output.data.len() as u64);
+                    let keep = read.min(usize::try_from(room).unwrap_or(usize::MAX));
+                    output.data.extend_from_slice(&chunk[..keep]);
+                    output.truncated |= keep < read;
+                }
+            }
+        }
+        output
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_holds_off_while_reaping() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let watchdog = Watchdog::arm(&child, Duration::from_millis(10));
+        let status = watchdog
+            .reap(|| {
+                // The deadline passes while the child is being reaped.
+                thread::sleep(Duration::from_millis(100));
+                child.kill()?;
+                child.wait().map(Some)
+            })
+            .unwrap();
+        assert!(status.is_some());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!watchdog.fired());
+    }
+}
@@ -0,0 +1,386 @@
+//! The task queue behind `hostd serve`'s HTTP API and the gRPC service.
+//!
+//! Tasks start in submission order, up to `--max-concurrent-tasks` at a
+//! time, each on its own thread with its own store, host state, workspace
+//! and budgets. They share only the Wasmtime engine and the components
+//! compiled with it. A task's logs carry its id.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::runtime::Handle;
+use tracing::{Instrument, info, info_span, warn};
+
+use crate::cli::{StepArgs, TaskHostArgs, parse_duration};
+use crate::runtime::{self, Loader, Watch};
+
+/// Finished tasks kept for status requests; older ones are forgotten.
+const MAX_FINISHED_TASKS: usize = 1000;
+
+thread_local! {
+    /// The id of the task this thread runs.
+    static CURRENT_TASK: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The queued task running on this thread, for requests it makes along the
+/// way such as approvals.
+#[cfg(feature = "grpc")]
+pub fn current_task() -> Option<u64> {
+    CURRENT_TASK.get()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn finished(self) -> bool {
+        matches!(self, Status::Succeeded | Status::Failed | Status::Cancelled)
+    }
+}
+
+/// The body of `POST /tasks`, the params of `hostd stdio`'s `submit_task`,
+/// and gRPC's `SubmitTask`. The workspace and the commands the task may run
+/// come from the config file only, never from a caller.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaskRequest {
+    pub task: String,
+    #[serde(default = "empty_observation")]
+    pub observation: Value,
+    #[serde(default)]
+    pub max_steps: Option<u64>,
+    /// As `--max-wall-clock` takes it, e.g. `"15m"`.
+    #[serde(default)]
+    pub max_wall_clock: Option<String>,
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub no_llm_cache: bool,
+    /// A directory inside the configured `workspace_root` to use as the
+    /// task's workspace, so tasks running side by side keep apart.
+    #[serde(default)]
+    pub workspace: Option<String>,
+}
+
+fn empty_observation() -> Value {
+    json!({})
+}
+
+impl TaskRequest {
+    pub fn into_step_args(self, host: &TaskHostArgs) -> Result<StepArgs, String> {
+        if self.max_steps == Some(0) {
+            return Err("max_steps must be at least 1".to_string());
+        }
+        let max_wall_clock = self
+            .max_wall_clock
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .map_err(|err| format!("invalid max_wall_clock: {err}"))?;
+        Ok(StepArgs {
+            component: host.component.clone(),
+            config: host.config.clone(),
+            workspace: None,
+            task: Some(self.task),
+            session: None,
+            resume: None,
+            observation: self.observation.to_string(),
+            step: 0,
+            allow_proc: Vec::new(),
+            rollback_on_failure: self.rollback_on_failure,
+            dry_run: self.dry_run,
+            allow_browser_attach: host.allow_browser_attach,
+            no_llm_cache: self.no_llm_cache,
+            max_steps: self.max_steps,
+            max_wall_clock,
+            max_actions: None,
+            max_failed_steps: None,
+            watch: false,
+            workspace_subdir: self.workspace.filter(|dir| !dir.trim().is_empty()),
+        })
+    }
+}
+
+pub enum CancelError {
+    NotFound,
+    Finished,
+}
+
+struct Task {
+    description: String,
+    status: Status,
+    /// Taken by the worker when the task starts.
+    args: Option<StepArgs>,
+    cancelled: Arc<AtomicBool>,
+    /// One per step, as `Watch::on_step` receives them, plus whatever a
+    /// front end adds with `push_event`.
+    events: Vec<Value>,
+    result: Option<Value>,
+    error: Option<String>,
+}
+
+impl Task {
+    fn to_json(&self, id: u64) -> Value {
+        json!({
+            "id": id,
+            "task": self.description,
+            "status": self.status.as_str(),
+            "steps": self.events.iter().filter(|event| event.get("step").is_some()).count(),
+            "result": self.result,
+            "error": self.error,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Tasks {
+    next_id: u64,
+    by_id: BTreeMap<u64, Task>,
+    queue: VecDeque<u64>,
+    running: usize,
+}
+
+impl Tasks {
+    fn forget_oldest_finished(&mut self) {
+        let finished: Vec<u64> = self
+            .by_id
+            .iter()
+            .filter(|(_, task)| task.status.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_TASKS);
+        for id in &finished[..excess] {
+            self.by_id.remove(id);
+        }
+    }
+}
+
+pub struct TaskQueue {
+    host: TaskHostArgs,
+    max_concurrent: usize,
+    loader: Loader,
+    tasks: Mutex<Tasks>,
+    /// Signalled whenever a task is queued, changes status or adds an event.
+    changed: Condvar,
+}
+
+impl TaskQueue {
+    /// Compiles the component, so a bad path fails at startup rather than
+    /// in the first task, and starts dispatching tasks on the current
+    /// runtime.
+    pub fn start(host: TaskHostArgs, max_concurrent: usize) -> Result<Arc<Self>> {
+        let loader = Loader::new()?;
+        loader.component(&host.component)?;
+        let queue = Arc::new(Self {
+            host,
+            max_concurrent,
+            loader,
+            tasks: Mutex::new(Tasks::default()),
+            changed: Condvar::new(),
+        });
+        let handle = Handle::current();
+        let dispatcher = Arc::clone(&queue);
+        thread::spawn(move || dispatcher.dispatch(&handle));
+        Ok(queue)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tasks> {
+        self.tasks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wait<'a>(&self, tasks: MutexGuard<'a, Tasks>) -> MutexGuard<'a, Tasks> {
+        self.changed
+            .wait(tasks)
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update(&self, id: u64, change: impl FnOnce(&mut Task)) {
+        if let Some(task) = self.lock().by_id.get_mut(&id) {
+            change(task);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Starts each queued task on a thread of its own once fewer than
+    /// `max_concurrent` run.
+    fn dispatch(self: Arc<Self>, handle: &Handle) {
+        loop {
+            let (id, args, cancelled) = self.next_task();
+            let queue = Arc::clone(&self);
+            let handle = handle.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("task-{id}"))
+                .spawn(move || queue.run(&handle, id, &args, &cancelled));
+            if let Err(err) = spawned {
+                warn!(id, error = %err, "failed to start a thread for the task");
+                self.finish(id, Err(anyhow!("failed to start the task: {err}")), false);
+            }
+        }
+    }
+
+    fn run(&self, handle: &Handle, id: u64, args: &StepArgs, cancelled: &AtomicBool) {
+        CURRENT_TASK.set(Some(id));
+        let span = info_span!("task", id);
+        span.in_scope(|| info!(task = args.task.as_deref(), "queued task started"));
+        let on_step = |event: Value| self.push_event(id, event);
+        let watch = Watch {
+            cancelled: Some(cancelled),
+            on_step: Some(&on_step),
+            ..Watch::default()
+        };
+        let result =
+            handle.block_on(runtime::execute(args, &self.loader, watch).instrument(span.clone()));
+        let status = self.finish(id, result, cancelled.load(Ordering::Relaxed));
+        span.in_scope(|| info!(status = status.as_str(), "queued task finished"));
+        CURRENT_TASK.set(None);
+    }
+
+    fn finish(&self, id: u64, result: Result<runtime::Finished>, cancelled: bool) -> Status {
+        let status = match &result {
+            Ok(_) => Status::Succeeded,
+            Err(_) if cancelled => Status::Cancelled,
+            Err(_) => Status::Failed,
+        };
+        self.update(id, |task| {
+            task.status = status;
+            match result {
+                Ok(finished) => task.result = Some(finished.to_json()),
+                Err(err) => task.error = Some(format!("{err:#}")),
+            }
+        });
+        let mut tasks = self.lock();
+        tasks.running -= 1;
+        tasks.forget_oldest_finished();
+        drop(tasks);
+        // Wakes the dispatcher, waiting for a free slot.
+        self.changed.notify_all();
+        status
+    }
+
+    /// Waits for the next queued task and a free slot, and marks the task
+    /// running.
+    fn next_task(&self) -> (u64, StepArgs, Arc<AtomicBool>) {
+        let mut tasks = self.lock();
+        loop {
+            while tasks.running < self.max_concurrent {
+                let Some(id) = tasks.queue.pop_front() else {
+                    break;
+                };
+                // Tasks cancelled while queued stay in the queue until here.
+                let Some(task) = tasks.by_id.get_mut(&id) else {
+                    continue;
+                };
+                let Some(args) = task.args.take() else {
+                    continue;
+                };
+                task.status = Status::Running;
+                let cancelled = Arc::clone(&task.cancelled);
+                tasks.running += 1;
+                drop(tasks);
+                self.changed.notify_all();
+                return (id, args, cancelled);
+            }
+            tasks = self.wait(tasks);
+        }
+    }
+
+    /// Queues a task and returns its status.
+    pub fn submit(&self, request: TaskRequest) -> Result<Value, String> {
+        let args = request.into_step_args(&self.host)?;
+        let task = Task {
+            description: args.task.clone().unwrap_or_default(),
+            status: Status::Queued,
+            args: Some(args),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            events: Vec::new(),
+            result: None,
+            error: None,
+        };
+        let mut tasks = self.lock();
+        tasks.next_id += 1;
+        let id = tasks.next_id;
+        let status = task.to_json(id);
+        tasks.by_id.insert(id, task);
+        tasks.queue.push_back(id);
+        drop(tasks);
+        self.changed.notify_all();
+        info!(id, "task queued");
+        Ok(status)
+    }
+
+    pub fn list(&self) -> Vec<Value> {
+        self.lock()
+            .by_id
+            .iter()
+            .map(|(id, task)| task.to_json(*id))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Value> {
+        self.lock().by_id.get(&id).map(|task| task.to_json(id))
+    }
+
+    /// Cancels a queued task, or stops a running one before its next step.
+    pub fn cancel(&self, id: u64) -> Result<Value, CancelError> {
+        let mut tasks = self.lock();
+        let task = tasks.by_id.get_mut(&id).ok_or(CancelError::NotFound)?;
+        match task.status {
+            Status::Queued => {
+                task.status = Status::Cancelled;
+                task.args = None;
+                task.error = Some("task cancelled before it started".to_string());
+            }
+            Status::Running => task.cancelled.store(true, Ordering::Relaxed),
+            _ => return Err(CancelError::Finished),
+        }
+        let status = task.to_json(id);
+        drop(tasks);
+        self.changed.notify_all();
+        Ok(status)
+    }
+
+    pub fn push_event(&self, id: u64, event: Value) {
+        self.update(id, |task| task.events.push(event));
+    }
+
+    /// Waits until the task has events past the first `seen`, or has
+    /// finished. Returns those events and, once it finished, its final
+    /// status; `None` when there is no such task (any more).
+    pub fn wait_events(&self, id: u64, seen: usize) -> Option<(Vec<Value>, Option<Value>)> {
+        let mut tasks = self.lock();
+        loop {
+            let task = tasks.by_id.get(&id)?;
+            if task.events.len() > seen || task.status.finished() {
+                let last = task.status.finished().then(|| task.to_json(id));
+                return Some((task.events[seen..].to_vec(), last));
+            }
+            tasks = self.wait(tasks);
+        }
+    }
+}
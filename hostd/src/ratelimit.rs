@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window every `[policy.rate_limits]` entry counts calls over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct RateLimited {
+    /// The `[policy.rate_limits]` key that refused the call.
+    pub rule: String,
+    pub per_minute: u32,
+    /// How long until the oldest counted call leaves the window.
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` allows {} calls per minute; retry in {} ms",
+            self.rule,
+            self.per_minute,
+            self.retry_after.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Calls-per-minute limits keyed by `interface` or `interface.function`,
+/// shared by the guest's capability calls and the planner's actions so both
+/// count against the same windows.
+#[derive(Debug)]
+pub struct RateLimits {
+    limits: Mutex<BTreeMap<String, u32>>,
+    recent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimits {
+    pub fn new(limits: BTreeMap<String, u32>) -> Self {
+        Self {
+            limits: Mutex::new(limits),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the limits after a policy reload; calls already made stay
+    /// in their windows.
+    pub fn set_limits(&self, limits: BTreeMap<String, u32>) {
+        *self
+            .limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = limits;
+    }
+
+    /// Counts one call to `interface.function` against every rule covering
+    /// it, or refuses it, counting nothing, when any of them is used up.
+    pub fn admit(&self, interface: &str, function: &str) -> Result<(), RateLimited> {
+        let limits = self
+            .limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if limits.is_empty() {
+            return Ok(());
+        }
+        let call = format!("{interface}.{function}");
+        let rules: Vec<(&String, u32)> = [interface, call.as_str()]
+            .into_iter()
+            .filter_map(|key| limits.get_key_value(key))
+            .map(|(rule, per_minute)| (rule, *per_minute))
+            .collect();
+        if rules.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut recent = self
+            .recent
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (rule, per_minute) in &rules {
+            let window = recent.entry((*rule).clone()).or_default();
+            while window
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+            {
+                window.pop_front();
+            }
+            if window.len() >= *per_minute as usize {
+                let oldest = window.front().copied().unwrap_or(now);
+                return Err(RateLimited {
+                    rule: (*rule).clone(),
+                    per_minute: *per_minute,
+                    retry_after: WINDOW.saturating_sub(now.duration_since(oldest)),
+                });
+            }
+        }
+        for (rule, _) in rules {
+            recent.entry(rule.clone()).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
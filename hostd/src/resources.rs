@@ -1,7 +1,19 @@
+use std::collections::VecDeque;
 use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::{ChildStdin, ExitStatus};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
 use camino::Utf8PathBuf;
 
+use crate::cgroup::Cgroup;
+use crate::executor::Container;
+use crate::process::{self, SharedChild, Watchdog};
+
+const READER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(Debug)]
 pub struct DirHandleResource {
     pub path: Utf8PathBuf,
@@ -12,16 +24,153 @@ pub struct DirHandleResource {
 pub struct FileHandleResource {
     pub path: Utf8PathBuf,
     pub file: File,
+    pub append: bool,
 }
 
 #[derive(Debug)]
 pub struct ProcessResource {
     #[allow(dead_code)]
     pub command: String,
-    pub stdout: Vec<u8>,
-    pub stderr: Vec<u8>,
-    pub stdout_pos: usize,
-    pub stderr_pos: usize,
-    pub exit_code: Option<i32>,
-    pub timed_out: bool,
+    pub child: SharedChild,
+    /// Open while stdin accepts writes; dropped to signal EOF.
+    pub stdin: Option<StdinWriter>,
+    pub stdout: OutputBuffer,
+    pub stderr: OutputBuffer,
+    pub status: Option<ExitStatus>,
+    pub watchdog: Option<Watchdog>,
+    /// Shared with the registry so the cgroup outlives a closed handle.
+    pub cgroup: Option<Arc<Cgroup>>,
+    /// Held only to keep the container alive; the last holder removes it.
+    #[allow(dead_code)]
+    pub container: Option<Arc<Container>>,
+}
+
+impl ProcessResource {
+    pub fn pid(&self) -> u32 {
+        process::lock_child(&self.child).id()
+    }
+
+    /// Reaps the child if it has exited, without blocking.
+    pub fn poll_status(&mut self) -> io::Result<Option<ExitStatus>> {
+        if self.status.is_none() {
+            let exited = process::try_reap(&self.child, self.watchdog.as_ref())?;
+            if let Some(status) = exited {
+                self.record_exit(status);
+            }
+        }
+        Ok(self.status)
+    }
+
+    pub fn record_exit(&mut self, status: ExitStatus) {
+        self.status = Some(status);
+        process::untrack_group(self.pid());
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.disarm();
+        }
+    }
+}
+
+/// Host end of a child's stdin: a plain pipe, or the pty master when the
+/// child runs on a pseudo-terminal.
+#[derive(Debug)]
+pub enum StdinWriter {
+    Pipe(ChildStdin),
+    Pty(File),
+}
+
+impl Write for StdinWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Pipe(pipe) => pipe.write(buf),
+            Self::Pty(master) => master.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Pipe(pipe) => pipe.flush(),
+            Self::Pty(master) => master.flush(),
+        }
+    }
+}
+
+/// Output captured from a child pipe by a background reader thread so guest
+/// reads never block on the child.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    inner: Arc<Mutex<OutputState>>,
+}
+
+#[derive(Debug, Default)]
+struct OutputState {
+    data: VecDeque<u8>,
+    closed: bool,
+    /// Bytes accepted over the lifetime of the pipe, checked against the cap.
+    captured: u64,
+    truncated: bool,
+}
+
+#[derive(Debug)]
+pub struct OutputChunk {
+    pub data: Vec<u8>,
+    pub eof: bool,
+    /// Output past the capture cap was discarded.
+    pub truncated: bool,
+}
+
+impl OutputBuffer {
+    /// Buffer for a stream that was never piped; reads report EOF immediately.
+    pub fn closed() -> Self {
+        let buffer = Self::default();
+        buffer.state().closed = true;
+        buffer
+    }
+
+    /// Captures at most `limit` bytes from `reader`. The pipe keeps being
+    /// drained past the cap so the child never blocks on a full pipe. A
+    /// non-blocking reader (the pty master) is polled until it closes.
+    pub fn spawn_reader<R: Read + Send + 'static>(mut reader: R, limit: u64) -> Self {
+        let buffer = Self::default();
+        let sink = buffer.clone();
+        thread::spawn(move || {
+            let mut chunk = [0u8; 8192];
+            loop {
+                match reader.read(&mut chunk) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(READER_POLL_INTERVAL);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        let mut state = sink.state();
+                        let room = limit.saturating_sub(state.captured);
+                        let keep = read.min(usize::try_from(room).unwrap_or(usize::MAX));
+                        state.data.extend(&chunk[..keep]);
+                        state.captured += keep as u64;
+                        state.truncated |= keep < read;
+                    }
+                }
+            }
+            sink.state().closed = true;
+        });
+        buffer
+    }
+
+    /// Drains up to `max` buffered bytes; `eof` once the pipe closed and drained.
+    pub fn read(&self, max: usize) -> OutputChunk {
+        let mut state = self.state();
+        let take = state.data.len().min(max);
+        let data: Vec<u8> = state.data.drain(..take).collect();
+        OutputChunk {
+            data,
+            eof: state.closed && state.data.is_empty(),
+            truncated: state.truncated,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, OutputState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
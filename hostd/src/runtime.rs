@@ -1,55 +1,541 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
 use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
 use tracing::{debug, info, warn};
 use wasmtime::{
-    Config, Engine, Store,
+    Config, Engine, Store, Trap,
     component::{Component, Linker},
 };
-use wasmtime_wasi::add_to_linker_sync;
+use wasmtime_wasi::add_to_linker_async;
 
 use tokio::runtime::Handle;
 
 use crate::actions::{ActionExecutor, ActionReport};
+use crate::audit;
 use crate::bindings;
-use crate::bindings::exports::osagent::agent::planner::{AgentError, Observation, StepResponse};
+use crate::bindings::exports::osagent::agent::planner::{
+    AgentError, CompletePlan, ContinuePlan, Guest as Planner, Observation, StepResponse,
+};
+use crate::budget::{BudgetKind, Budgets};
+use crate::capabilities;
+use crate::changes::ChangeSet;
 use crate::cli::StepArgs;
 use crate::config::HostConfig;
+use crate::grant::Grants;
+use crate::notify;
+use crate::ratelimit::RateLimits;
+use crate::session::{self, Checkpoint, PendingStep, Session};
+use crate::skill::Skills;
+use crate::snapshot::{self, Snapshot};
 use crate::state::HostState;
+use crate::taint::Untrusted;
+use crate::transcript::Transcript;
 
-const MAX_HOST_STEPS: u32 = 8;
-
+/// Runs the task, or with a session a single planner step of it.
 pub async fn run_step(args: StepArgs) -> Result<()> {
-    let config = HostConfig::from_step_args(&args)?;
-    let engine = build_engine()?;
-    let component = load_component(&engine, &args.component)?;
-
-    let observation_json = validate_json(&args.observation)?;
-    let mut current_step = args.step;
-    let mut observation = Observation {
-        step: current_step,
-        summary: format!("host bootstrap step {}", current_step),
-        data: observation_json,
+    let watch = Watch {
+        step_limit: args.session_id().is_some().then_some(1),
+        ..Watch::default()
+    };
+    execute(&args, &Loader::new()?, watch).await.map(|_| ())
+}
+
+/// Runs the task to completion and prints how it ended on stdout; a task
+/// that fails or runs out of budget returns the error instead. Runs without
+/// a session get one, so `--resume` can pick them up after a crash.
+pub async fn run_task(mut args: StepArgs) -> Result<()> {
+    if args.session_id().is_none() {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let id = format!("run-{millis}");
+        info!(
+            session = id,
+            "checkpointing the run; continue it with --resume {id}"
+        );
+        args.session = Some(id);
+    }
+    let finished = execute(&args, &Loader::new()?, Watch::default()).await?;
+    println!("{}", serde_json::to_string_pretty(&finished.to_json())?);
+    Ok(())
+}
+
+/// How a task ended, or where it stopped when `Watch::step_limit` cut it
+/// short.
+pub struct Finished {
+    /// `None` when the task stopped before the planner completed it.
+    plan: Option<CompletePlan>,
+    steps: u64,
+    llm_tokens: u64,
+    changed_files: Value,
+}
+
+impl Finished {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "reason": self.plan.as_ref().map(|plan| &plan.reason),
+            "outcome": self.plan.as_ref().map(outcome_json),
+            "steps": self.steps,
+            "llm_tokens": self.llm_tokens,
+            "changed_files": self.changed_files,
+        })
+    }
+}
+
+/// The Wasmtime engine and the components compiled with it. `hostd serve`
+/// keeps one for every task it runs, so each component is compiled once
+/// (and again only when the file changes). Compiled components are also
+/// kept on disk, so later invocations skip compiling too.
+pub struct Loader {
+    engine: Engine,
+    /// `<cache dir>/wasi-warden/components`; none when the platform has no
+    /// per-user cache dir.
+    cache_dir: Option<Utf8PathBuf>,
+    /// By path.
+    components: Mutex<HashMap<PathBuf, Compiled>>,
+}
+
+/// A component as compiled from its file.
+struct Compiled {
+    /// The file's modified time when it was read.
+    modified: Option<SystemTime>,
+    /// The file's hex SHA-256 digest.
+    digest: String,
+    component: Component,
+}
+
+/// How often the engine's epoch advances, and so how closely
+/// `planner.step_timeout_ms` is kept.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+impl Loader {
+    pub fn new() -> Result<Self> {
+        let engine = build_engine()?;
+        let ticked = engine.weak();
+        thread::spawn(move || {
+            while let Some(engine) = ticked.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                thread::sleep(EPOCH_TICK);
+            }
+        });
+        let cache_dir = dirs::cache_dir()
+            .map(|base| base.join("wasi-warden").join("components"))
+            .and_then(|dir| Utf8PathBuf::from_path_buf(dir).ok());
+        Ok(Self {
+            engine,
+            cache_dir,
+            components: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn component(&self, path: &Path) -> Result<Component> {
+        self.load(path).map(|(_, component)| component)
+    }
+
+    /// Like `component`, but refuses a file whose SHA-256 digest is not in
+    /// `allowed_component_sha256` when the config sets it. The digest is of
+    /// the bytes that were compiled, so it covers exactly what runs.
+    pub fn allowed_component(&self, path: &Path, config: &HostConfig) -> Result<Component> {
+        let (digest, component) = self.load(path)?;
+        let refused = config
+            .allowed_component_sha256
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&digest));
+        if refused {
+            warn!(
+                target: "audit",
+                component = %path.display(),
+                sha256 = digest,
+                "refused a component that is not in allowed_component_sha256"
+            );
+            audit::record(
+                "host",
+                "component_refused",
+                None,
+                json!({ "component": path.display().to_string(), "sha256": digest }),
+            );
+            bail!(
+                "component {} (sha256 {digest}) is not in allowed_component_sha256",
+                path.display()
+            );
+        }
+        Ok(component)
+    }
+
+    fn load(&self, path: &Path) -> Result<(String, Component)> {
+        let modified = modified_time(path);
+        let mut components = self
+            .components
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let cached = components
+            .get(path)
+            .filter(|compiled| modified.is_some() && compiled.modified == modified);
+        if let Some(compiled) = cached {
+            return Ok((compiled.digest.clone(), compiled.component.clone()));
+        }
+        let (digest, component) = self
+            .compile(path)
+            .with_context(|| format!("failed to load component {}", path.display()))?;
+        components.insert(
+            path.to_path_buf(),
+            Compiled {
+                modified,
+                digest: digest.clone(),
+                component: component.clone(),
+            },
+        );
+        Ok((digest, component))
+    }
+
+    /// Loads the compiled artifact for the file's contents from the cache
+    /// dir, or compiles the file and stores the artifact there. Artifacts
+    /// are named by a hash of the component and of the engine's settings
+    /// and version, so a changed file or a new hostd compiles afresh.
+    /// Returns the file's hex SHA-256 digest with it.
+    fn compile(&self, path: &Path) -> Result<(String, Component)> {
+        let wasm = fs::read(path)?;
+        let digest: String = Sha256::digest(&wasm)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let Some(dir) = &self.cache_dir else {
+            return Ok((digest, Component::new(&self.engine, &wasm)?));
+        };
+        let cached = dir.join(format!("{}.cwasm", self.cache_key(&digest)));
+        if cached.exists() {
+            // SAFETY: artifacts in the cache dir are written by
+            // `precompile_component` below, for an engine whose settings
+            // match the key, and only the host user can write them.
+            match unsafe { Component::deserialize_file(&self.engine, &cached) } {
+                Ok(component) => {
+                    debug!(artifact = %cached, "loaded compiled component from cache");
+                    return Ok((digest, component));
+                }
+                Err(err) => {
+                    warn!(artifact = %cached, error = %err, "ignoring unreadable compiled component");
+                }
+            }
+        }
+        let compiled = self.engine.precompile_component(&wasm)?;
+        match write_private(&cached, &compiled) {
+            Ok(()) => info!(artifact = %cached, "cached compiled component"),
+            Err(err) => {
+                warn!(artifact = %cached, error = %err, "failed to cache compiled component")
+            }
+        }
+        // SAFETY: produced just above by this engine.
+        let component = unsafe { Component::deserialize(&self.engine, &compiled) }?;
+        Ok((digest, component))
+    }
+
+    fn cache_key(&self, digest: &str) -> String {
+        let mut engine = DefaultHasher::new();
+        self.engine
+            .precompile_compatibility_hash()
+            .hash(&mut engine);
+        format!("{digest}-{:016x}", engine.finish())
+    }
+}
+
+/// Written to a temporary file first, so a reader never sees half an
+/// artifact; on unix only the host user can read or replace it.
+fn write_private(path: &Utf8Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        let mut create = fs::DirBuilder::new();
+        create.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut create, 0o700);
+        create.create(parent)?;
+    }
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    let mut open = fs::OpenOptions::new();
+    open.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+    io::Write::write_all(&mut open.open(&tmp)?, data)?;
+    fs::rename(&tmp, path)
+}
+
+/// The guest hands back JSON; anything else is kept as a string.
+fn outcome_json(plan: &CompletePlan) -> Value {
+    serde_json::from_str(&plan.outcome).unwrap_or_else(|_| Value::String(plan.outcome.clone()))
+}
+
+/// Lets a caller follow a task step by step and stop it between steps.
+#[derive(Default, Clone, Copy)]
+pub struct Watch<'a> {
+    /// Planner steps this invocation takes before it stops, leaving the
+    /// task unfinished.
+    pub step_limit: Option<u32>,
+    /// Checked before each planner step; once set, the task fails as
+    /// cancelled.
+    pub cancelled: Option<&'a AtomicBool>,
+    /// Called after each step's actions ran, with the step, the planner's
+    /// thought, the action reports and the files they changed.
+    pub on_step: Option<&'a (dyn Fn(Value) + Sync)>,
+}
+
+pub async fn execute(args: &StepArgs, loader: &Loader, watch: Watch<'_>) -> Result<Finished> {
+    let config = HostConfig::from_step_args(args)?;
+    let engine = &loader.engine;
+    let component = loader.allowed_component(&args.component, &config)?;
+    notify::start_task();
+
+    let mut checkpoint = match args.session_id() {
+        Some(id) => {
+            let path = session::path(&config.session_dir, id)?;
+            Some(Checkpoint::new(open_session(args, &path)?, path))
+        }
+        None => None,
+    };
+    let task = match &checkpoint {
+        Some(checkpoint) => checkpoint.session.task.clone(),
+        None => args.task.clone().context("--task is required")?,
+    };
+    let observation = match checkpoint.as_ref().map(|checkpoint| &checkpoint.session) {
+        Some(session) => Observation {
+            step: session.step,
+            summary: session.summary.clone(),
+            data: session.observation.to_string(),
+        },
+        None => Observation {
+            step: args.step,
+            summary: format!("host bootstrap step {}", args.step),
+            data: validate_json(&args.observation)?,
+        },
     };
 
-    let mut linker: Linker<HostState> = Linker::new(&engine);
-    add_to_linker_sync(&mut linker).context("failed to add WASI to linker")?;
+    let mut linker: Linker<HostState> = Linker::new(engine);
+    add_to_linker_async(&mut linker).context("failed to add WASI to linker")?;
     bindings::Control::add_to_linker(&mut linker, |state: &mut HostState| state)?;
 
     let tokio_handle = Handle::current();
-    let mut executor = ActionExecutor::new(config.clone(), tokio_handle.clone());
+    let limits = config.policy.budgets.clone();
+    let budgets = Arc::new(match &checkpoint {
+        Some(checkpoint) => Budgets::resume(limits, checkpoint.session.used_budgets()),
+        None => Budgets::new(limits),
+    });
+    let grants = Arc::new(Grants::default());
+    let rate_limits = Arc::new(RateLimits::new(config.policy.rate_limits.clone()));
+    let untrusted = Arc::new(Untrusted::default());
+    let mut executor = ActionExecutor::new(
+        config.clone(),
+        tokio_handle.clone(),
+        Arc::clone(&budgets),
+        Arc::clone(&grants),
+        Arc::clone(&rate_limits),
+        Arc::clone(&untrusted),
+    );
+    if args.rollback_on_failure || config.fs.snapshot {
+        executor.set_snapshot(capture_snapshot(&config)?);
+    }
 
-    let mut store = Store::new(&engine, HostState::new(config.clone()));
-    let control = bindings::Control::instantiate(&mut store, &component, &linker)
+    if let Some(dir) = &config.policy.audit_dir {
+        audit::open(dir).with_context(|| format!("failed to create audit log in {dir}"))?;
+        audit::record("host", "task_started", None, json!({ "task": task }));
+    }
+    info!(
+        capabilities = ?capabilities::enabled_capabilities(&config, &grants),
+        "enabled capabilities"
+    );
+    let mut state = HostState::new(
+        config.clone(),
+        tokio_handle,
+        budgets,
+        grants,
+        rate_limits,
+        untrusted,
+    );
+    state.llm_transcript = open_llm_transcript(&config)?;
+    let mut store = Store::new(engine, state);
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(config.policy.planner_fuel)?;
+    store.set_epoch_deadline(epoch_ticks(config.planner.step_timeout));
+    let mut control = bindings::Control::instantiate_async(&mut store, &component, &linker)
+        .await
         .context("failed to instantiate component")?;
-    let planner = control.osagent_agent_planner();
+    let skills = Skills::instantiate(&mut store, &linker, loader).await?;
+    let watcher = args
+        .watch
+        .then(|| ComponentWatcher::new(&args.component, loader, &linker));
+
+    let reloader = hot_reload_allowed(&config, &args.config).then(|| PolicyReloader::new(args));
+    let mut run_changes = ChangeSet::default();
+    let result = drive_planner(
+        &mut store,
+        &mut control,
+        watcher,
+        &skills,
+        &mut executor,
+        reloader,
+        &mut run_changes,
+        checkpoint.as_mut(),
+        &task,
+        observation,
+        watch,
+    )
+    .await;
+    kill_leaked_processes(store.data_mut());
+    audit::record(
+        "host",
+        "task_finished",
+        None,
+        json!({
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|err| format!("{err:#}")),
+        }),
+    );
+    audit::close();
+    if let Some(settings) = &config.notify {
+        let budgets = &store.data().budgets;
+        let summary = notify::TaskSummary {
+            task: &task,
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+            steps: budgets.used(BudgetKind::Steps),
+            llm_tokens: budgets.used(BudgetKind::LlmTokens),
+            changed_files: run_changes.to_json(&config.workspace_root),
+        };
+        notify::task_finished(settings, &summary);
+    }
+    if result.is_err() && args.rollback_on_failure {
+        match executor.rollback() {
+            Ok(Some(summary)) => warn!(
+                restored = summary.restored,
+                removed = summary.removed,
+                "task failed; workspace rolled back to snapshot"
+            ),
+            Ok(None) => {}
+            Err(err) => warn!(error = %err, "task failed and workspace rollback failed"),
+        }
+    }
+    let budgets = &store.data().budgets;
+    if let Some(checkpoint) = &mut checkpoint {
+        if let Ok(Some(plan)) = &result {
+            checkpoint.session.complete =
+                Some(json!({ "reason": plan.reason, "outcome": outcome_json(plan) }));
+        }
+        checkpoint.save(budgets)?;
+        info!(
+            path = %checkpoint.path(),
+            next_step = checkpoint.session.step,
+            "saved session"
+        );
+    }
+    result.map(|plan| Finished {
+        plan,
+        steps: budgets.used(BudgetKind::Steps),
+        llm_tokens: budgets.used(BudgetKind::LlmTokens),
+        changed_files: run_changes.to_json(&config.workspace_root),
+    })
+}
+
+/// Loads the saved session, or starts one for `--task`. A saved one keeps
+/// its own task, step and observation; `--resume` needs one.
+fn open_session(args: &StepArgs, path: &Utf8Path) -> Result<Session> {
+    let Some(mut saved) = Session::load(path)? else {
+        if args.resume.is_some() {
+            bail!("there is no session {path} to resume");
+        }
+        let task = args
+            .task
+            .clone()
+            .context("--task is required to start a session")?;
+        let observation = serde_json::from_str(&validate_json(&args.observation)?)?;
+        return Ok(Session::new(task, args.step, observation));
+    };
+    if saved.complete.is_some() {
+        bail!("session {path} already completed its task");
+    }
+    if args.task.as_ref().is_some_and(|task| *task != saved.task) {
+        bail!(
+            "session {path} is for the task {:?}; leave out --task to continue it",
+            saved.task
+        );
+    }
+    if saved.settle_interrupted_step() {
+        warn!(
+            %path,
+            "the last run stopped during a step; its unfinished actions are reported to the planner, not run again"
+        );
+    }
+    info!(%path, step = saved.step, "continuing session");
+    Ok(saved)
+}
+
+/// Returns `None` when `watch.step_limit` stopped the task first.
+#[allow(clippy::too_many_arguments)]
+async fn drive_planner(
+    store: &mut Store<HostState>,
+    control: &mut bindings::Control,
+    mut watcher: Option<ComponentWatcher<'_>>,
+    skills: &Skills,
+    executor: &mut ActionExecutor,
+    mut reloader: Option<PolicyReloader<'_>>,
+    run_changes: &mut ChangeSet,
+    mut checkpoint: Option<&mut Checkpoint>,
+    task: &str,
+    mut observation: Observation,
+    watch: Watch<'_>,
+) -> Result<Option<CompletePlan>> {
+    let workspace_root = store.data().config.workspace_root.clone();
+    let mut current_step = observation.step;
+    let budgets = Arc::clone(&store.data().budgets);
+    let mut steps_taken = 0;
+    let limits = store.data().config.planner.clone();
+    let (mut actions_run, mut failed_streak) = checkpoint.as_deref().map_or((0, 0), |checkpoint| {
+        (
+            checkpoint.session.actions_run(),
+            checkpoint.session.failed_streak(),
+        )
+    });
 
-    for iteration in 0..MAX_HOST_STEPS {
-        let planner_result = planner
-            .call_step(&mut store, &args.task, &observation)
-            .context("planner.step failed")?;
-        let response = planner_result.map_err(agent_failure)?;
+    loop {
+        if watch.step_limit.is_some_and(|limit| steps_taken >= limit) {
+            return Ok(None);
+        }
+        steps_taken += 1;
+        if watch
+            .cancelled
+            .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+        {
+            bail!("task cancelled before step {current_step}");
+        }
+        audit::set_step(current_step);
+        if let Some(reloader) = reloader.as_mut() {
+            reloader.poll(store, executor);
+        }
+        if let Some(watcher) = watcher.as_mut() {
+            watcher.poll(store, control).await;
+        }
+        // Each step is claimed before the planner runs, so the guest's own
+        // `claim-budget` calls for steps come out of the same allowance.
+        if let Err(err) = budgets
+            .claim(BudgetKind::Steps, 1)
+            .and_then(|_| budgets.check(BudgetKind::WallclockMs))
+        {
+            bail!(
+                "planner did not complete: {} (last summary: {}; changed files: {})",
+                err,
+                observation.summary,
+                run_changes.to_json(&workspace_root)
+            );
+        }
+        let planner = control.osagent_agent_planner();
+        let response = call_planner(store, planner, task, &observation, current_step).await?;
+        let mut step_changes = store.data_mut().changes.take();
 
         match response {
             StepResponse::Continue(plan) => {
@@ -59,45 +545,427 @@ pub async fn run_step(args: StepArgs) -> Result<()> {
                     actions = plan.actions.len(),
                     "planner requested capability executions"
                 );
-                let reports = executor.execute(&plan.actions);
+                let requested = plan.actions.len() as u64;
+                if limits
+                    .max_actions
+                    .is_some_and(|max| actions_run + requested > max)
+                {
+                    bail!(
+                        "planner did not complete: step {current_step} asks for {requested} action(s), past the max_actions limit of {} with {actions_run} run (planner.max_actions or --max-actions; last summary: {}; changed files: {})",
+                        limits.max_actions.unwrap_or_default(),
+                        observation.summary,
+                        run_changes.to_json(&workspace_root)
+                    );
+                }
+                let reports = run_actions(
+                    store,
+                    skills,
+                    executor,
+                    checkpoint.as_deref_mut(),
+                    &budgets,
+                    current_step,
+                    &plan,
+                )
+                .await?;
+                store.data().grants.end_step();
                 log_action_reports(&reports);
+                step_changes.merge(&store.data_mut().changes.take());
+                step_changes.merge(&executor.take_changes());
+                run_changes.merge(&step_changes);
+                if let Some(on_step) = watch.on_step {
+                    on_step(json!({
+                        "step": current_step,
+                        "thought": plan.thought,
+                        "actions": reports,
+                        "changed_files": step_changes.to_json(&workspace_root),
+                    }));
+                }
+                let finished_step = current_step;
                 current_step = current_step.saturating_add(1);
                 observation = Observation {
                     step: current_step,
                     summary: summarize_reports(&reports),
-                    data: build_action_observation(&reports)?,
+                    data: build_action_observation(
+                        &reports,
+                        step_changes.to_json(&workspace_root),
+                    )?,
                 };
+                if let Some(checkpoint) = checkpoint.as_deref_mut() {
+                    let session = &mut checkpoint.session;
+                    session.pending = None;
+                    session.reports.push(json!({
+                        "step": finished_step,
+                        "thought": plan.thought,
+                        "actions": reports,
+                    }));
+                    session.step = current_step;
+                    session.summary.clone_from(&observation.summary);
+                    session.observation = serde_json::from_str(&observation.data)?;
+                    checkpoint.save(&budgets)?;
+                }
+                actions_run += requested;
+                if reports.is_empty() || reports.iter().any(|report| report.success) {
+                    failed_streak = 0;
+                } else {
+                    failed_streak += 1;
+                }
+                if failed_streak >= limits.max_failed_steps {
+                    bail!(
+                        "planner did not complete: every action failed in {failed_streak} steps in a row, the max_failed_steps limit (planner.max_failed_steps or --max-failed-steps; last summary: {}; changed files: {})",
+                        observation.summary,
+                        run_changes.to_json(&workspace_root)
+                    );
+                }
             }
             StepResponse::Complete(done) => {
+                run_changes.merge(&step_changes);
                 info!(
                     reason = done.reason,
                     outcome = done.outcome,
-                    total_steps = iteration + 1,
+                    total_steps = budgets.used(BudgetKind::Steps),
+                    llm_tokens = budgets.used(BudgetKind::LlmTokens),
+                    changed_files = %run_changes.to_json(&workspace_root),
                     "planner completed task"
                 );
-                return Ok(());
+                return Ok(Some(done));
             }
         }
     }
+}
 
-    bail!(
-        "planner did not complete within {} steps (last summary: {})",
-        MAX_HOST_STEPS,
-        observation.summary
-    )
+/// Calls `planner.step` under the fuel and time limits, read per step so a
+/// hot reload can change them. A failure the guest marks retryable is tried
+/// again up to `planner.max_retries` times, waiting longer each time.
+async fn call_planner(
+    store: &mut Store<HostState>,
+    planner: &Planner,
+    task: &str,
+    observation: &Observation,
+    step: u32,
+) -> Result<StepResponse> {
+    let mut attempt = 0;
+    loop {
+        let fuel = store.data().config.policy.planner_fuel;
+        store.set_fuel(fuel)?;
+        let timeout = store.data().config.planner.step_timeout;
+        store.set_epoch_deadline(epoch_ticks(timeout));
+        let err = match planner.call_step(&mut *store, task, observation).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(err)) => err,
+            // Retryable as far as the task goes, but a trapped instance
+            // cannot be entered again, so not here.
+            Err(err) if err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) => {
+                return Err(agent_failure(AgentError {
+                    retryable: true,
+                    message: format!(
+                        "planner.step ran out of fuel ({fuel} units, policy.planner_fuel) at step {step}"
+                    ),
+                }));
+            }
+            Err(err) if err.downcast_ref::<Trap>() == Some(&Trap::Interrupt) => {
+                bail!(
+                    "planner.step timed out after {timeout:?} (planner.step_timeout_ms) at step {step}"
+                );
+            }
+            Err(err) => return Err(err).context("planner.step failed"),
+        };
+        let planner_settings = &store.data().config.planner;
+        if !err.retryable || attempt >= planner_settings.max_retries {
+            return Err(agent_failure(err));
+        }
+        let delay = planner_settings.retry_delay(attempt);
+        attempt += 1;
+        warn!(
+            step,
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            error = err.message,
+            "planner.step failed with a retryable error; retrying"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Runs the actions one at a time. With a session, it is saved before each
+/// one, so a crash leaves a record of which ones finished. Each report is cut
+/// to the action's `[planner]` output limit, so one large result cannot crowd
+/// the rest of the next observation out of the planner's context.
+async fn run_actions(
+    store: &mut Store<HostState>,
+    skills: &Skills,
+    executor: &mut ActionExecutor,
+    mut checkpoint: Option<&mut Checkpoint>,
+    budgets: &Budgets,
+    step: u32,
+    plan: &ContinuePlan,
+) -> Result<Vec<ActionReport>> {
+    if let Some(checkpoint) = checkpoint.as_deref_mut() {
+        checkpoint.session.pending = Some(PendingStep {
+            step,
+            thought: plan.thought.clone(),
+            actions: plan
+                .actions
+                .iter()
+                .map(|action| json!({ "capability": action.capability, "input": action.input }))
+                .collect(),
+            reports: Vec::new(),
+        });
+    }
+    let mut reports = Vec::with_capacity(plan.actions.len());
+    for action in &plan.actions {
+        if let Some(checkpoint) = checkpoint.as_deref_mut() {
+            checkpoint.save(budgets)?;
+        }
+        // Host actions run synchronously; the worker is handed back to the
+        // runtime meanwhile so other tasks and timers keep going.
+        let mut report = if Skills::handles(&action.capability) {
+            let result = match tokio::task::block_in_place(|| executor.admit(action)) {
+                Ok(()) => {
+                    skills
+                        .invoke(store, &action.capability, &action.input)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            ActionReport::recorded(action.capability.clone(), result)
+        } else {
+            tokio::task::block_in_place(|| executor.execute_action(action))
+        };
+        let limit = store.data().config.planner.output_limit(&action.capability);
+        report.truncate(limit);
+        if let Some(bytes) = report.output_bytes {
+            debug!(
+                capability = report.capability,
+                bytes, limit, "truncated action output for the observation"
+            );
+        }
+        if let Some(pending) = checkpoint
+            .as_deref_mut()
+            .and_then(|checkpoint| checkpoint.session.pending.as_mut())
+        {
+            pending.reports.push(serde_json::to_value(&report)?);
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+/// Watches the config file for `policy.hot_reload`, checked between planner
+/// steps so no capability call sees a mix of old and new rules.
+struct PolicyReloader<'a> {
+    args: &'a StepArgs,
+    modified: Option<SystemTime>,
+}
+
+impl<'a> PolicyReloader<'a> {
+    fn new(args: &'a StepArgs) -> Self {
+        Self {
+            args,
+            modified: modified_time(&args.config),
+        }
+    }
+
+    /// Swaps in `[policy]` and `enabled_capabilities` once the file changed.
+    /// A config that no longer loads leaves the current policy in place.
+    fn poll(&mut self, store: &mut Store<HostState>, executor: &mut ActionExecutor) {
+        let modified = modified_time(&self.args.config);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        let path = self.args.config.display();
+        let reloaded = HostConfig::from_step_args(self.args).and_then(|fresh| {
+            store.data_mut().config.reload_policy(&fresh)?;
+            executor.reload_policy(&fresh)?;
+            Ok(fresh)
+        });
+        let fresh = match reloaded {
+            Ok(fresh) => fresh,
+            Err(err) => {
+                warn!(
+                    target: "audit",
+                    config = %path,
+                    error = format!("{err:#}"),
+                    "config changed but was not reloaded; keeping the current policy"
+                );
+                audit::record(
+                    "host",
+                    "policy_reload_failed",
+                    None,
+                    json!({ "config": path.to_string(), "error": format!("{err:#}") }),
+                );
+                return;
+            }
+        };
+        let state = store.data();
+        state.budgets.set_limits(fresh.policy.budgets.clone());
+        state
+            .rate_limits
+            .set_limits(fresh.policy.rate_limits.clone());
+        let capabilities = capabilities::enabled_capabilities(&state.config, &state.grants);
+        info!(target: "audit", config = %path, ?capabilities, "reloaded policy");
+        audit::record(
+            "host",
+            "policy_reloaded",
+            None,
+            json!({ "config": path.to_string(), "enabled_capabilities": capabilities }),
+        );
+    }
+}
+
+/// Watches the agent component for `--watch`, checked between planner steps
+/// like the config. The planner keeps no state between steps, so the next
+/// one simply calls a fresh instance of the rebuilt component.
+struct ComponentWatcher<'a> {
+    path: &'a Path,
+    loader: &'a Loader,
+    linker: &'a Linker<HostState>,
+    modified: Option<SystemTime>,
+}
+
+impl<'a> ComponentWatcher<'a> {
+    fn new(path: &'a Path, loader: &'a Loader, linker: &'a Linker<HostState>) -> Self {
+        info!(component = %path.display(), "watching the component for changes");
+        Self {
+            path,
+            loader,
+            linker,
+            modified: modified_time(path),
+        }
+    }
+
+    /// Swaps in a new instance once the file changed. A build that does not
+    /// compile or instantiate, say one still being written, leaves the
+    /// current instance in place until the file changes again.
+    async fn poll(&mut self, store: &mut Store<HostState>, control: &mut bindings::Control) {
+        let modified = modified_time(self.path);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        let component = self.path.display();
+        match self.instantiate(store).await {
+            Ok(fresh) => {
+                *control = fresh;
+                info!(%component, "component changed; the next step runs the new build");
+                audit::record(
+                    "host",
+                    "component_reloaded",
+                    None,
+                    json!({ "component": component.to_string() }),
+                );
+            }
+            Err(err) => warn!(
+                %component,
+                error = format!("{err:#}"),
+                "component changed but was not reloaded; keeping the current build"
+            ),
+        }
+    }
+
+    /// The replaced instance stays in the store until the task ends, so
+    /// every reload counts against `planner.max_instances`.
+    async fn instantiate(&self, store: &mut Store<HostState>) -> Result<bindings::Control> {
+        let component = self
+            .loader
+            .allowed_component(self.path, &store.data().config)?;
+        let fuel = store.data().config.policy.planner_fuel;
+        store.set_fuel(fuel)?;
+        let timeout = store.data().config.planner.step_timeout;
+        store.set_epoch_deadline(epoch_ticks(timeout));
+        bindings::Control::instantiate_async(store, &component, self.linker)
+            .await
+            .context("failed to instantiate component")
+    }
+}
+
+/// A config the agent can write to must not be able to widen its own
+/// policy, so hot reload stays off for one inside the workspace.
+fn hot_reload_allowed(config: &HostConfig, path: &Path) -> bool {
+    if !config.policy.hot_reload {
+        return false;
+    }
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if path.starts_with(&config.workspace_root) {
+        warn!(
+            target: "audit",
+            config = %path.display(),
+            "config is inside the workspace; policy hot reload is off"
+        );
+        audit::record(
+            "host",
+            "policy_hot_reload_off",
+            None,
+            json!({ "config": path.display().to_string() }),
+        );
+        return false;
+    }
+    true
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Kills the children the guest left running when the task ended or aborted,
+/// so none outlive the host.
+fn kill_leaked_processes(state: &mut HostState) {
+    for process in state.processes.kill_all() {
+        warn!(
+            pid = process.pid,
+            command = %process.command,
+            started_ms = process.started_ms,
+            handle_open = process.handle_open,
+            "killed process still running after task ended"
+        );
+    }
+}
+
+fn open_llm_transcript(config: &HostConfig) -> Result<Option<Transcript>> {
+    let Some(settings) = &config.llm else {
+        return Ok(None);
+    };
+    let Some(dir) = &settings.transcript_dir else {
+        return Ok(None);
+    };
+    let transcript = Transcript::create(settings, dir)
+        .with_context(|| format!("failed to create llm transcript in {dir}"))?;
+    Ok(Some(transcript))
+}
+
+fn capture_snapshot(config: &HostConfig) -> Result<Snapshot> {
+    let store = config
+        .fs
+        .snapshot_dir
+        .clone()
+        .or_else(snapshot::default_store)
+        .context("no fs.snapshot_dir configured and no user cache dir available")?;
+    if store.starts_with(&config.workspace_root) {
+        bail!("snapshot dir {store} must live outside the workspace");
+    }
+    Snapshot::capture(&config.workspace_root, &store)
 }
 
 fn build_engine() -> Result<Engine> {
     let mut config = Config::default();
     config.wasm_backtrace(true);
     config.wasm_component_model(true);
-    config.async_support(false);
+    // Host calls are async, so a capability waiting on the network or a
+    // process yields the runtime instead of blocking it.
+    config.async_support(true);
+    // Bounds how long a planner step may compute; see `policy.planner_fuel`.
+    config.consume_fuel(true);
+    // And how long it may take; see `planner.step_timeout_ms`.
+    config.epoch_interruption(true);
     Engine::new(&config).context("failed to build Wasmtime engine")
 }
 
-fn load_component(engine: &Engine, path: &PathBuf) -> Result<Component> {
-    Component::from_file(engine, path)
-        .with_context(|| format!("failed to load component {}", path.display()))
+/// Epoch ticks in `timeout`, rounded up.
+pub fn epoch_ticks(timeout: Duration) -> u64 {
+    let ticks = timeout.as_nanos().div_ceil(EPOCH_TICK.as_nanos());
+    u64::try_from(ticks).unwrap_or(u64::MAX)
 }
 
 fn validate_json(input: &str) -> Result<String> {
@@ -140,7 +1008,7 @@ fn summarize_reports(reports: &[ActionReport]) -> String {
     )
 }
 
-fn build_action_observation(reports: &[ActionReport]) -> Result<String> {
-    let payload = json!({ "actions": reports });
+fn build_action_observation(reports: &[ActionReport], changed_files: Value) -> Result<String> {
+    let payload = json!({ "actions": reports, "changed_files": changed_files });
     serde_json::to_string(&payload).context("failed to serialize action observation")
 }
@@ -0,0 +1,438 @@
+use std::process::Command;
+#[cfg(target_os = "linux")]
+use std::sync::{Arc, Once, OnceLock};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use crate::config::ProcSettings;
+
+/// How spawned commands are confined beyond the command allowlist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxMode {
+    #[default]
+    None,
+    /// Runs the command under bubblewrap with only the workspace writable.
+    Bwrap,
+}
+
+/// Syscalls a profile may name in `deny`. Anything else is a config error, so
+/// a typo can never silently leave a syscall open.
+#[cfg(target_os = "linux")]
+const KNOWN_SYSCALLS: &[(&str, libc::c_long)] = &[
+    ("ptrace", libc::SYS_ptrace),
+    ("mount", libc::SYS_mount),
+    ("umount2", libc::SYS_umount2),
+    ("pivot_root", libc::SYS_pivot_root),
+    ("chroot", libc::SYS_chroot),
+    ("unshare", libc::SYS_unshare),
+    ("setns", libc::SYS_setns),
+    ("kexec_load", libc::SYS_kexec_load),
+    ("init_module", libc::SYS_init_module),
+    ("finit_module", libc::SYS_finit_module),
+    ("delete_module", libc::SYS_delete_module),
+    ("reboot", libc::SYS_reboot),
+    ("swapon", libc::SYS_swapon),
+    ("swapoff", libc::SYS_swapoff),
+    ("bpf", libc::SYS_bpf),
+    ("perf_event_open", libc::SYS_perf_event_open),
+    ("process_vm_readv", libc::SYS_process_vm_readv),
+    ("process_vm_writev", libc::SYS_process_vm_writev),
+    ("keyctl", libc::SYS_keyctl),
+    ("add_key", libc::SYS_add_key),
+    ("request_key", libc::SYS_request_key),
+    ("userfaultfd", libc::SYS_userfaultfd),
+    ("acct", libc::SYS_acct),
+    ("quotactl", libc::SYS_quotactl),
+    ("open_by_handle_at", libc::SYS_open_by_handle_at),
+    ("personality", libc::SYS_personality),
+];
+
+/// Landlock confinement for direct spawns, from `proc.landlock*`.
+#[derive(Debug, Clone, Default)]
+pub struct LandlockSettings {
+    pub enabled: bool,
+    /// Extra paths children may read and execute.
+    pub read_only: Vec<Utf8PathBuf>,
+    /// Extra paths children may write, beyond the workspace.
+    pub read_write: Vec<Utf8PathBuf>,
+}
+
+/// Resource limits from `[proc.rlimits.<command>]`; `"*"` supplies defaults
+/// that per-command tables override field by field. Soft and hard limits are
+/// both set, so the child cannot raise them again.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rlimits {
+    /// Maximum open file descriptors.
+    pub nofile: Option<u64>,
+    /// Largest file the child may create, in bytes.
+    pub fsize: Option<u64>,
+    /// Processes for the whole user, not just this tree (kernel semantics).
+    pub nproc: Option<u64>,
+    /// Core dump size in bytes; `0` disables core dumps.
+    pub core: Option<u64>,
+}
+
+impl Rlimits {
+    pub fn or(self, fallback: Rlimits) -> Rlimits {
+        Rlimits {
+            nofile: self.nofile.or(fallback.nofile),
+            fsize: self.fsize.or(fallback.fsize),
+            nproc: self.nproc.or(fallback.nproc),
+            core: self.core.or(fallback.core),
+        }
+    }
+
+    /// The same limits as `--ulimit` flags for a container runtime.
+    pub fn ulimit_args(self) -> Vec<String> {
+        [
+            ("nofile", self.nofile),
+            ("fsize", self.fsize),
+            ("nproc", self.nproc),
+            ("core", self.core),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|value| format!("{name}={value}:{value}")))
+        .collect()
+    }
+
+    #[cfg(unix)]
+    fn attach(self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        let limits = [
+            (libc::RLIMIT_NOFILE, self.nofile),
+            (libc::RLIMIT_FSIZE, self.fsize),
+            (libc::RLIMIT_NPROC, self.nproc),
+            (libc::RLIMIT_CORE, self.core),
+        ];
+        // SAFETY: setrlimit(2) on a stack value; nothing is allocated between
+        // fork and exec.
+        unsafe {
+            cmd.pre_exec(move || {
+                for (resource, value) in limits {
+                    let Some(value) = value else { continue };
+                    let limit = libc::rlimit {
+                        rlim_cur: value as libc::rlim_t,
+                        rlim_max: value as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn attach(self, _cmd: &mut Command) {}
+}
+
+/// Named seccomp profile from `[proc.seccomp_profiles.<name>]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SeccompProfile {
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Refuse `AF_PACKET` and `SOCK_RAW` sockets.
+    #[serde(default)]
+    pub deny_raw_sockets: bool,
+}
+
+impl SeccompProfile {
+    /// Profile available as `default` unless the config defines its own.
+    pub fn builtin_default() -> Self {
+        let deny = [
+            "ptrace",
+            "mount",
+            "umount2",
+            "pivot_root",
+            "chroot",
+            "kexec_load",
+            "init_module",
+            "finit_module",
+            "delete_module",
+            "reboot",
+            "swapon",
+            "swapoff",
+            "bpf",
+            "process_vm_readv",
+            "process_vm_writev",
+            "open_by_handle_at",
+        ];
+        Self {
+            deny: deny.into_iter().map(String::from).collect(),
+            deny_raw_sockets: true,
+        }
+    }
+}
+
+/// Seccomp profile compiled once at config load; denied syscalls fail with
+/// `EPERM` in the child.
+#[derive(Debug, Clone)]
+pub struct SyscallFilter {
+    #[cfg(target_os = "linux")]
+    program: Arc<seccompiler::BpfProgram>,
+}
+
+impl SyscallFilter {
+    #[cfg(target_os = "linux")]
+    pub fn compile(profile: &SeccompProfile) -> Result<Self> {
+        use std::collections::BTreeMap;
+
+        use anyhow::{Context, anyhow};
+        use seccompiler::{
+            SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+            SeccompRule,
+        };
+
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> = BTreeMap::new();
+        for syscall in &profile.deny {
+            let (_, number) = KNOWN_SYSCALLS
+                .iter()
+                .find(|(known, _)| known == syscall)
+                .ok_or_else(|| anyhow!("unknown syscall `{syscall}` in seccomp profile"))?;
+            // An empty rule list matches the syscall unconditionally.
+            rules.insert(*number, Vec::new());
+        }
+        if profile.deny_raw_sockets {
+            let packet = SeccompRule::new(vec![SeccompCondition::new(
+                0,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::Eq,
+                libc::AF_PACKET as u64,
+            )?])?;
+            // Mask off SOCK_NONBLOCK/SOCK_CLOEXEC, which share the type argument.
+            let raw = SeccompRule::new(vec![SeccompCondition::new(
+                1,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::MaskedEq(0xf),
+                libc::SOCK_RAW as u64,
+            )?])?;
+            rules.insert(libc::SYS_socket, vec![packet, raw]);
+        }
+        let arch = std::env::consts::ARCH
+            .try_into()
+            .with_context(|| format!("seccomp is not supported on {}", std::env::consts::ARCH))?;
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )?;
+        let program: seccompiler::BpfProgram = filter.try_into()?;
+        Ok(Self {
+            program: Arc::new(program),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn compile(_profile: &SeccompProfile) -> Result<Self> {
+        anyhow::bail!("seccomp profiles are only supported on Linux")
+    }
+
+    /// Installs the filter in the child between fork and exec.
+    fn attach(&self, cmd: &mut Command) {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let program = Arc::clone(&self.program);
+            // SAFETY: the closure only issues prctl/seccomp syscalls and does
+            // not allocate, so it is sound to run between fork and exec.
+            unsafe {
+                cmd.pre_exec(move || {
+                    seccompiler::apply_filter(&program).map_err(|_| std::io::Error::last_os_error())
+                });
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = cmd;
+    }
+}
+
+/// System directories exposed read-only to sandboxed children (bubblewrap
+/// binds, Landlock rules); missing ones are skipped. Home directories are
+/// deliberately absent.
+const SYSTEM_READ_ONLY: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+
+/// Device nodes most tools expect to read and write under Landlock.
+#[cfg(target_os = "linux")]
+const LANDLOCK_DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+];
+
+/// Builds the command for `program`, wrapped according to `proc.sandbox`.
+/// `cwd` must already be validated to lie inside `workspace`.
+pub fn command(
+    settings: &ProcSettings,
+    workspace: &Utf8Path,
+    cwd: &Utf8Path,
+    program: &str,
+    args: &[String],
+) -> Result<Command> {
+    let mut cmd = match settings.sandbox {
+        SandboxMode::None => Command::new(program),
+        SandboxMode::Bwrap => {
+            let mut cmd = Command::new(&settings.bwrap_path);
+            cmd.args(bwrap_args(settings, workspace, cwd));
+            cmd.arg("--").arg(program);
+            cmd
+        }
+    };
+    cmd.args(args);
+    cmd.current_dir(cwd.as_std_path());
+    if let Some(limits) = settings.rlimits_for(program) {
+        limits.attach(&mut cmd);
+    }
+    if settings.landlock.enabled {
+        attach_landlock(&mut cmd, &settings.landlock, workspace)?;
+    }
+    if let Some(filter) = settings.seccomp.as_ref() {
+        filter.attach(&mut cmd);
+    }
+    Ok(cmd)
+}
+
+/// Confines the child to the workspace (read-write), the system directories
+/// (read/execute), and any configured extras. Refuses to spawn on kernels
+/// without Landlock rather than run the child unconfined, and warns once when
+/// the kernel only enforces part of the ruleset.
+#[cfg(target_os = "linux")]
+fn attach_landlock(
+    cmd: &mut Command,
+    settings: &LandlockSettings,
+    workspace: &Utf8Path,
+) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    use anyhow::{Context, bail};
+    use landlock::{
+        ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+        path_beneath_rules,
+    };
+    use tracing::warn;
+
+    let abi = ABI::V3;
+    match landlock_kernel_abi() {
+        0 => bail!(
+            "proc.landlock is on but this kernel does not support Landlock; \
+             refusing to run the command unconfined"
+        ),
+        version if version < LANDLOCK_FULL_ABI => {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| {
+                warn!(
+                    version,
+                    "kernel supports Landlock ABI v{version} only; access rights added \
+                     in later versions are not enforced on spawned commands"
+                );
+            });
+        }
+        _ => {}
+    }
+    let read_only = SYSTEM_READ_ONLY
+        .iter()
+        .map(|dir| dir.to_string())
+        .chain(settings.read_only.iter().map(|dir| dir.to_string()));
+    let read_write = std::iter::once(workspace.to_string())
+        .chain(settings.read_write.iter().map(|dir| dir.to_string()));
+    // Built in the parent so the child only has to call restrict_self, which
+    // does not allocate between fork and exec.
+    let ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(read_only, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(
+            LANDLOCK_DEVICES,
+            AccessFs::from_read(abi) | AccessFs::WriteFile,
+        ))?
+        .add_rules(path_beneath_rules(read_write, AccessFs::from_all(abi)))
+        .context("failed to build landlock ruleset")?;
+    let mut ruleset = Some(ruleset);
+    // SAFETY: restrict_self only issues prctl/landlock syscalls on the
+    // success path, so it is sound to run between fork and exec.
+    unsafe {
+        cmd.pre_exec(
+            move || match ruleset.take().map(|ruleset| ruleset.restrict_self()) {
+                // The kernel check above makes this unlikely; never exec
+                // unconfined if it happens anyway.
+                Some(Ok(status)) if matches!(status.ruleset, RulesetStatus::NotEnforced) => {
+                    Err(std::io::Error::from_raw_os_error(libc::EPERM))
+                }
+                Some(Ok(_)) | None => Ok(()),
+                Some(Err(_)) => Err(std::io::Error::last_os_error()),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Landlock ABI the ruleset is built for; older kernels enforce a subset.
+#[cfg(target_os = "linux")]
+const LANDLOCK_FULL_ABI: libc::c_long = 3;
+
+/// The Landlock ABI version the running kernel supports, or 0 without
+/// Landlock.
+#[cfg(target_os = "linux")]
+fn landlock_kernel_abi() -> libc::c_long {
+    static VERSION: OnceLock<libc::c_long> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+        // SAFETY: with a null attribute and size 0, this flag only asks the
+        // kernel for its ABI version; nothing is created.
+        let version = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<libc::c_void>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        version.max(0)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_landlock(
+    _cmd: &mut Command,
+    _settings: &LandlockSettings,
+    _workspace: &Utf8Path,
+) -> Result<()> {
+    anyhow::bail!("landlock is only supported on Linux")
+}
+
+fn bwrap_args(settings: &ProcSettings, workspace: &Utf8Path, cwd: &Utf8Path) -> Vec<String> {
+    let mut args: Vec<String> = ["--die-with-parent", "--new-session", "--unshare-all"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if settings.sandbox_network {
+        args.push("--share-net".into());
+    }
+    for dir in SYSTEM_READ_ONLY {
+        args.extend(["--ro-bind-try".into(), dir.to_string(), dir.to_string()]);
+    }
+    args.extend([
+        "--proc".into(),
+        "/proc".into(),
+        "--dev".into(),
+        "/dev".into(),
+    ]);
+    args.extend(["--tmpfs".into(), "/tmp".into()]);
+    args.extend([
+        "--bind".into(),
+        workspace.to_string(),
+        workspace.to_string(),
+        "--chdir".into(),
+        cwd.to_string(),
+    ]);
+    args
+}
@@ -0,0 +1,429 @@
+//! Desktop screenshots for the `osagent:screen` capability, so a guest driving
+//! `osagent:input` can see what it is clicking on. Captures go through X11
+//! (on a Wayland session that means XWayland windows only); other platforms
+//! report the capability as unsupported.
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Area in desktop coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let (x, y) = (i64::from(x), i64::from(y));
+        x >= i64::from(self.x)
+            && y >= i64::from(self.y)
+            && x < i64::from(self.x) + i64::from(self.width)
+            && y < i64::from(self.y) + i64::from(self.height)
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        (
+            (i64::from(self.x) + i64::from(self.width) / 2) as i32,
+            (i64::from(self.y) + i64::from(self.height) / 2) as i32,
+        )
+    }
+
+    /// The overlap of two areas, if they have one.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = (i64::from(self.x) + i64::from(self.width))
+            .min(i64::from(other.x) + i64::from(other.width));
+        let bottom = (i64::from(self.y) + i64::from(self.height))
+            .min(i64::from(other.y) + i64::from(other.height));
+        if right <= i64::from(left) || bottom <= i64::from(top) {
+            return None;
+        }
+        Some(Rect {
+            x: left,
+            y: top,
+            width: (right - i64::from(left)) as u32,
+            height: (bottom - i64::from(top)) as u32,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    pub name: String,
+    pub geometry: Rect,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct WindowInfo {
+    pub id: u64,
+    pub title: String,
+    pub geometry: Rect,
+}
+
+/// A PNG of `geometry`, with the monitor layout it was taken in.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub png: Vec<u8>,
+    pub geometry: Rect,
+    pub monitors: Vec<Monitor>,
+}
+
+#[derive(Debug, Error)]
+pub enum ScreenError {
+    #[error("cannot reach the desktop: {0}")]
+    Connect(String),
+    #[error("no monitor named `{0}`")]
+    NoSuchMonitor(String),
+    #[error("no window with id {0}")]
+    NoSuchWindow(u64),
+    #[error("window {0} is not on the screen")]
+    Offscreen(u64),
+    #[error("screen capture is only supported on X11")]
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    Unsupported,
+    #[error("{0}")]
+    Capture(String),
+}
+
+/// Connected monitors, primary first.
+#[cfg(target_os = "linux")]
+pub fn list_monitors() -> Result<Vec<Monitor>, ScreenError> {
+    x11::Display::connect()?.monitors()
+}
+
+/// Top-level application windows, front-most last.
+#[cfg(target_os = "linux")]
+pub fn list_windows() -> Result<Vec<WindowInfo>, ScreenError> {
+    x11::Display::connect()?.windows()
+}
+
+/// Captures the named monitor, or the whole desktop.
+#[cfg(target_os = "linux")]
+pub fn capture_screen(monitor: Option<&str>) -> Result<Capture, ScreenError> {
+    let display = x11::Display::connect()?;
+    let monitors = display.monitors()?;
+    let geometry = match monitor {
+        Some(name) => {
+            monitors
+                .iter()
+                .find(|monitor| monitor.name == name)
+                .ok_or_else(|| ScreenError::NoSuchMonitor(name.to_string()))?
+                .geometry
+        }
+        None => display.bounds(),
+    };
+    let png = display.capture(geometry)?;
+    Ok(Capture {
+        png,
+        geometry,
+        monitors,
+    })
+}
+
+/// Captures the on-screen part of a window from [`list_windows`], including
+/// anything overlapping it.
+#[cfg(target_os = "linux")]
+pub fn capture_window(id: u64) -> Result<Capture, ScreenError> {
+    let display = x11::Display::connect()?;
+    let window = display
+        .windows()?
+        .into_iter()
+        .find(|window| window.id == id)
+        .ok_or(ScreenError::NoSuchWindow(id))?;
+    let geometry = window
+        .geometry
+        .intersect(&display.bounds())
+        .ok_or(ScreenError::Offscreen(id))?;
+    let png = display.capture(geometry)?;
+    Ok(Capture {
+        png,
+        geometry,
+        monitors: display.monitors()?,
+    })
+}
+
+/// Where the window with keyboard focus is, or `None` when keystrokes go to
+/// the window under the pointer (or nowhere).
+#[cfg(target_os = "linux")]
+pub fn focused_window() -> Result<Option<Rect>, ScreenError> {
+    x11::Display::connect()?.focused_window()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn focused_window() -> Result<Option<Rect>, ScreenError> {
+    Err(ScreenError::Unsupported)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list_monitors() -> Result<Vec<Monitor>, ScreenError> {
+    Err(ScreenError::Unsupported)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list_windows() -> Result<Vec<WindowInfo>, ScreenError> {
+    Err(ScreenError::Unsupported)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capture_screen(_monitor: Option<&str>) -> Result<Capture, ScreenError> {
+    Err(ScreenError::Unsupported)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capture_window(_id: u64) -> Result<Capture, ScreenError> {
+    Err(ScreenError::Unsupported)
+}
+
+/// Encodes 8-bit RGB rows as a PNG.
+#[cfg(target_os = "linux")]
+fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Result<Vec<u8>, ScreenError> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|err| ScreenError::Capture(err.to_string()))?;
+    writer
+        .write_image_data(rgb)
+        .and_then(|()| writer.finish())
+        .map_err(|err| ScreenError::Capture(err.to_string()))?;
+    Ok(png)
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use x11rb::connection::Connection;
+    use x11rb::image::{Image, PixelLayout};
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, InputFocus, Screen, Window};
+    use x11rb::rust_connection::RustConnection;
+
+    use super::{Monitor, Rect, ScreenError, WindowInfo, encode_png};
+
+    /// Longest window title read, in bytes.
+    const MAX_TITLE: u32 = 1024;
+
+    fn error(err: impl std::fmt::Display) -> ScreenError {
+        ScreenError::Capture(err.to_string())
+    }
+
+    pub struct Display {
+        conn: RustConnection,
+        screen: usize,
+    }
+
+    impl Display {
+        pub fn connect() -> Result<Self, ScreenError> {
+            let (conn, screen) =
+                x11rb::connect(None).map_err(|err| ScreenError::Connect(err.to_string()))?;
+            Ok(Self { conn, screen })
+        }
+
+        fn screen(&self) -> &Screen {
+            &self.conn.setup().roots[self.screen]
+        }
+
+        fn root(&self) -> Window {
+            self.screen().root
+        }
+
+        /// The whole desktop, spanning every monitor.
+        pub fn bounds(&self) -> Rect {
+            let screen = self.screen();
+            Rect {
+                x: 0,
+                y: 0,
+                width: u32::from(screen.width_in_pixels),
+                height: u32::from(screen.height_in_pixels),
+            }
+        }
+
+        /// RandR's monitors, or the whole desktop as one monitor when the
+        /// server lacks RandR 1.5.
+        pub fn monitors(&self) -> Result<Vec<Monitor>, ScreenError> {
+            let reply = self
+                .conn
+                .randr_get_monitors(self.root(), true)
+                .ok()
+                .and_then(|cookie| cookie.reply().ok());
+            let Some(reply) = reply.filter(|reply| !reply.monitors.is_empty()) else {
+                return Ok(vec![Monitor {
+                    name: "screen".to_string(),
+                    geometry: self.bounds(),
+                    primary: true,
+                }]);
+            };
+            let mut monitors = Vec::with_capacity(reply.monitors.len());
+            for info in reply.monitors {
+                let name = self
+                    .conn
+                    .get_atom_name(info.name)
+                    .map_err(error)?
+                    .reply()
+                    .map_err(error)?
+                    .name;
+                monitors.push(Monitor {
+                    name: String::from_utf8_lossy(&name).into_owned(),
+                    geometry: Rect {
+                        x: i32::from(info.x),
+                        y: i32::from(info.y),
+                        width: u32::from(info.width),
+                        height: u32::from(info.height),
+                    },
+                    primary: info.primary,
+                });
+            }
+            monitors.sort_by_key(|monitor| !monitor.primary);
+            Ok(monitors)
+        }
+
+        /// The window manager's client list in stacking order. Windows that
+        /// disappear while being described are skipped.
+        pub fn windows(&self) -> Result<Vec<WindowInfo>, ScreenError> {
+            let mut ids = self.window_list("_NET_CLIENT_LIST_STACKING")?;
+            if ids.is_empty() {
+                ids = self.window_list("_NET_CLIENT_LIST")?;
+            }
+            Ok(ids
+                .into_iter()
+                .filter_map(|id| self.describe(id).ok())
+                .collect())
+        }
+
+        fn atom(&self, name: &str) -> Result<u32, ScreenError> {
+            Ok(self
+                .conn
+                .intern_atom(false, name.as_bytes())
+                .map_err(error)?
+                .reply()
+                .map_err(error)?
+                .atom)
+        }
+
+        fn window_list(&self, property: &str) -> Result<Vec<Window>, ScreenError> {
+            let reply = self
+                .conn
+                .get_property(
+                    false,
+                    self.root(),
+                    self.atom(property)?,
+                    AtomEnum::WINDOW,
+                    0,
+                    u32::MAX,
+                )
+                .map_err(error)?
+                .reply()
+                .map_err(error)?;
+            Ok(reply.value32().map(|ids| ids.collect()).unwrap_or_default())
+        }
+
+        fn describe(&self, id: Window) -> Result<WindowInfo, ScreenError> {
+            Ok(WindowInfo {
+                id: u64::from(id),
+                title: self.title(id)?,
+                geometry: self.geometry(id)?,
+            })
+        }
+
+        /// A window's area on the desktop, whatever its parent.
+        fn geometry(&self, id: Window) -> Result<Rect, ScreenError> {
+            let geometry = self
+                .conn
+                .get_geometry(id)
+                .map_err(error)?
+                .reply()
+                .map_err(error)?;
+            let origin = self
+                .conn
+                .translate_coordinates(id, self.root(), 0, 0)
+                .map_err(error)?
+                .reply()
+                .map_err(error)?;
+            Ok(Rect {
+                x: i32::from(origin.dst_x),
+                y: i32::from(origin.dst_y),
+                width: u32::from(geometry.width),
+                height: u32::from(geometry.height),
+            })
+        }
+
+        pub fn focused_window(&self) -> Result<Option<Rect>, ScreenError> {
+            let focus = self
+                .conn
+                .get_input_focus()
+                .map_err(error)?
+                .reply()
+                .map_err(error)?
+                .focus;
+            // With PointerRoot or the root window focused, keys go to the
+            // window under the pointer instead.
+            if focus == x11rb::NONE
+                || focus == u32::from(InputFocus::POINTER_ROOT)
+                || focus == self.root()
+            {
+                return Ok(None);
+            }
+            self.geometry(focus).map(Some)
+        }
+
+        /// `_NET_WM_NAME`, falling back to the legacy `WM_NAME`.
+        fn title(&self, id: Window) -> Result<String, ScreenError> {
+            let utf8 = self.atom("UTF8_STRING")?;
+            for (property, kind) in [
+                (self.atom("_NET_WM_NAME")?, utf8),
+                (AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()),
+            ] {
+                let reply = self
+                    .conn
+                    .get_property(false, id, property, kind, 0, MAX_TITLE / 4)
+                    .map_err(error)?
+                    .reply()
+                    .map_err(error)?;
+                if !reply.value.is_empty() {
+                    return Ok(String::from_utf8_lossy(&reply.value).into_owned());
+                }
+            }
+            Ok(String::new())
+        }
+
+        /// A PNG of `area`, read from the root window.
+        pub fn capture(&self, area: Rect) -> Result<Vec<u8>, ScreenError> {
+            let area = area
+                .intersect(&self.bounds())
+                .ok_or_else(|| ScreenError::Capture("nothing to capture".to_string()))?;
+            // The intersection lies within the root window, whose size is u16.
+            let (image, visual) = Image::get(
+                &self.conn,
+                self.root(),
+                area.x as i16,
+                area.y as i16,
+                area.width as u16,
+                area.height as u16,
+            )
+            .map_err(error)?;
+            let visual = self
+                .screen()
+                .allowed_depths
+                .iter()
+                .flat_map(|depth| &depth.visuals)
+                .find(|candidate| candidate.visual_id == visual)
+                .ok_or_else(|| ScreenError::Capture(format!("unknown visual {visual}")))?;
+            let layout = PixelLayout::from_visual_type(*visual).map_err(error)?;
+            let mut rgb = Vec::with_capacity(area.width as usize * area.height as usize * 3);
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    let (red, green, blue) = layout.decode(image.get_pixel(x, y));
+                    rgb.extend([(red >> 8) as u8, (green >> 8) as u8, (blue >> 8) as u8]);
+                }
+            }
+            encode_png(area.width, area.height, &rgb)
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! `hostd serve`: a small HTTP/1.1 API other services use to run tasks.
+//!
+//! - `POST /tasks` queues a task and answers with its id.
+//! - `GET /tasks` lists tasks; `GET /tasks/{id}` reports one, with its result
+//!   or error once it ends.
+//! - `GET /tasks/{id}/events` streams the task's steps as JSON lines, ending
+//!   with its final status.
+//! - `POST /tasks/{id}/cancel` stops a task before its next step.
+//! - `POST /slack/actions` takes approve and deny clicks from Slack, with
+//!   `--slack-signing-secret`.
+//!
+//! Tasks go through the `TaskQueue` the gRPC API, when enabled, shares.
+//! Every response closes its connection.
+//!
+//! Every request must carry `--token` as a bearer token. Requests with an
+//! `Origin` header, and bodies that are not `application/json`, are refused
+//! so a web page the operator visits cannot post tasks cross-site.
+//! `/slack/actions` is signed with the Slack app's secret instead of the
+//! token.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
+
+use crate::cli::ServeArgs;
+use crate::grant::{self, Approvals, GrantDecision, Remote};
+use crate::notify;
+use crate::queue::{CancelError, TaskQueue, TaskRequest};
+
+/// Largest request (line and headers, then body) the API reads.
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Keeps a client that stops sending or reading from holding its thread.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What every connection needs.
+struct Api {
+    queue: Arc<TaskQueue>,
+    token: String,
+    slack_signing_secret: Option<String>,
+    approvals: Arc<Approvals>,
+}
+
+pub async fn run(args: ServeArgs) -> Result<()> {
+    if args.token.trim().is_empty() {
+        bail!("--token (or HOSTD_API_TOKEN) must not be empty");
+    }
+    let slack_signing_secret = args
+        .slack_signing_secret
+        .map(|secret| secret.trim().to_string())
+        .filter(|secret| !secret.is_empty());
+    let listener = TcpListener::bind(args.listen)
+        .with_context(|| format!("failed to listen on {}", args.listen))?;
+    let max_concurrent = usize::try_from(args.max_concurrent_tasks).unwrap_or(usize::MAX);
+    let queue = TaskQueue::start(args.host, max_concurrent)?;
+    let approvals = Arc::new(Approvals::default());
+    #[cfg(feature = "grpc")]
+    let announce = args
+        .grpc_listen
+        .map(|_| crate::grpc::announcer(Arc::clone(&queue)));
+    #[cfg(not(feature = "grpc"))]
+    let announce = None;
+    if announce.is_some() || slack_signing_secret.is_some() {
+        grant::set_remote(Remote {
+            approvals: Arc::clone(&approvals),
+            announce,
+            slack_buttons: slack_signing_secret.is_some(),
+        });
+    }
+    info!(address = %args.listen, "serving the task API");
+    let api = Arc::new(Api {
+        queue: Arc::clone(&queue),
+        token: args.token.trim().to_string(),
+        slack_signing_secret,
+        approvals: Arc::clone(&approvals),
+    });
+    #[cfg(feature = "grpc")]
+    let token: Arc<str> = api.token.as_str().into();
+    let http = tokio::task::spawn_blocking(move || accept(&listener, &api));
+    #[cfg(feature = "grpc")]
+    if let Some(address) = args.grpc_listen {
+        return tokio::select! {
+            result = crate::grpc::serve(address, queue, token, approvals) => result,
+            result = http => result.map_err(Into::into),
+        };
+    }
+    http.await?;
+    Ok(())
+}
+
+fn accept(listener: &TcpListener, api: &Arc<Api>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(error = %err, "failed to accept connection");
+                continue;
+            }
+        };
+        let api = Arc::clone(api);
+        thread::spawn(move || {
+            if let Err(err) = handle(&api, stream) {
+                debug!(error = %err, "API connection failed");
+            }
+        });
+    }
+}
+
+fn handle(api: &Api, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(reply) => return reply.send(&mut stream),
+    };
+    let path = request.target.split('?').next().unwrap_or_default();
+    if path == "/slack/actions" && !request.origin {
+        return slack_action(api, &request, &mut stream);
+    }
+    if let Err(reply) = check_request(&request, &api.token) {
+        return reply.send(&mut stream);
+    }
+    let queue = &*api.queue;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let reply = match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["tasks"]) => submit(queue, &request.body),
+        ("GET", ["tasks"]) => Reply::json(200, json!({ "tasks": queue.list() })),
+        ("GET", ["tasks", id]) => match parse_id(id).and_then(|id| queue.get(id)) {
+            Some(task) => Reply::json(200, task),
+            None => Reply::not_found(id),
+        },
+        ("GET", ["tasks", id, "events"]) => return stream_events(queue, id, &mut stream),
+        ("POST", ["tasks", id, "cancel"]) => cancel(queue, id),
+        (_, ["tasks"] | ["tasks", _] | ["tasks", _, "events" | "cancel"]) => {
+            Reply::error(405, format!("{} is not allowed here", request.method))
+        }
+        _ => Reply::error(404, format!("no endpoint at {path}")),
+    };
+    reply.send(&mut stream)
+}
+
+/// Answers the approval request a Slack button names, then replaces the
+/// message clicked so it cannot be answered twice.
+fn slack_action(api: &Api, request: &Request, stream: &mut TcpStream) -> io::Result<()> {
+    let Some(secret) = &api.slack_signing_secret else {
+        return Reply::error(404, "Slack actions need --slack-signing-secret".to_string())
+            .send(stream);
+    };
+    if request.method != "POST" {
+        return Reply::error(405, format!("{} is not allowed here", request.method)).send(stream);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let action = match notify::slack_action(
+        secret,
+        request.slack_timestamp.as_deref(),
+        request.slack_signature.as_deref(),
+        &request.body,
+        now,
+    ) {
+        Ok(action) => action,
+        Err(err) => {
+            warn!(error = err, "refused Slack action");
+            return Reply::error(401, err).send(stream);
+        }
+    };
+    let id = action.approval_id;
+    let outcome = match action.answer.decision {
+        GrantDecision::Deny => "denied",
+        GrantDecision::Step => "approved for this step",
+        GrantDecision::Session => "approved for the task",
+    };
+    let text = format!(
+        "Approval request {id} {outcome} ({}).",
+        action.answer.reason.as_deref().unwrap_or_default()
+    );
+    let text = if api.approvals.answer(id, action.answer) {
+        text
+    } else {
+        format!("Approval request {id} is no longer waiting; it timed out or was answered.")
+    };
+    // Slack wants the acknowledgement within 3 s, so it goes first.
+    Reply::json(200, json!({})).send(stream)?;
+    if let Some(url) = action.response_url {
+        notify::slack_answered(&url, &text);
+    }
+    Ok(())
+}
+
+/// Refuses browser-originated and unauthenticated requests before routing.
+fn check_request(request: &Request, token: &str) -> Result<(), Reply> {
+    if request.origin {
+        return Err(Reply::error(
+            403,
+            "cross-origin requests are not accepted".to_string(),
+        ));
+    }
+    let presented = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    if !presented.is_some_and(|presented| token_matches(presented, token)) {
+        return Err(Reply::error(
+            401,
+            "send the API token as `Authorization: Bearer <token>`".to_string(),
+        ));
+    }
+    if !request.body.is_empty() && !request.json {
+        return Err(Reply::error(
+            415,
+            "request bodies must be application/json".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Compares without stopping at the first differing byte, so response
+/// timing does not reveal how much of a guess was right.
+pub(crate) fn token_matches(presented: &str, token: &str) -> bool {
+    let (presented, token) = (presented.as_bytes(), token.as_bytes());
+    presented.len() == token.len()
+        && presented
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn submit(queue: &TaskQueue, body: &[u8]) -> Reply {
+    let request: TaskRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(err) => return Reply::error(400, format!("invalid task request: {err}")),
+    };
+    match queue.submit(request) {
+        Ok(task) => Reply::json(202, task),
+        Err(err) => Reply::error(400, err),
+    }
+}
+
+fn cancel(queue: &TaskQueue, raw_id: &str) -> Reply {
+    let Some(id) = parse_id(raw_id) else {
+        return Reply::not_found(raw_id);
+    };
+    match queue.cancel(id) {
+        Ok(task) => Reply::json(202, task),
+        Err(CancelError::NotFound) => Reply::not_found(raw_id),
+        Err(CancelError::Finished) => Reply::error(409, format!("task {id} has already finished")),
+    }
+}
+
+/// Writes each step as a JSON line as the task takes it (earlier ones
+/// first), then the task's final status.
+fn stream_events(queue: &TaskQueue, raw_id: &str, stream: &mut TcpStream) -> io::Result<()> {
+    let Some(id) = parse_id(raw_id).filter(|id| queue.get(*id).is_some()) else {
+        return Reply::not_found(raw_id).send(stream);
+    };
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+    )?;
+    let mut seen = 0;
+    while let Some((events, last)) = queue.wait_events(id, seen) {
+        seen += events.len();
+        for line in events.iter().chain(&last) {
+            writeln!(stream, "{line}")?;
+        }
+        stream.flush()?;
+        if last.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn parse_id(id: &str) -> Option<u64> {
+    id.parse().ok()
+}
+
+struct Request {
+    method: String,
+    target: String,
+    authorization: Option<String>,
+    /// Browsers send `Origin` on cross-site requests; API clients do not.
+    origin: bool,
+    /// `Content-Type` is `application/json`.
+    json: bool,
+    slack_timestamp: Option<String>,
+    slack_signature: Option<String>,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, Reply> {
+    let mut reader = BufReader::new(stream);
+    let mut head = (&mut reader).take(MAX_HEADER_BYTES);
+    let mut line = String::new();
+    read_line(&mut head, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Reply::error(400, "malformed request line".to_string()));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut content_length = 0;
+    let (mut authorization, mut origin, mut json) = (None, false, false);
+    let (mut slack_timestamp, mut slack_signature) = (None, None);
+    loop {
+        line.clear();
+        read_line(&mut head, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(Reply::error(400, "malformed header".to_string()));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| Reply::error(400, "invalid Content-Length".to_string()))?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = true;
+        } else if name.eq_ignore_ascii_case("content-type") {
+            let media_type = value.split(';').next().unwrap_or_default().trim();
+            json = media_type.eq_ignore_ascii_case("application/json");
+        } else if name.eq_ignore_ascii_case("x-slack-request-timestamp") {
+            slack_timestamp = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("x-slack-signature") {
+            slack_signature = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(Reply::error(
+                411,
+                "send the body with a Content-Length".to_string(),
+            ));
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(Reply::error(
+            413,
+            format!("request bodies are limited to {MAX_BODY_BYTES} bytes"),
+        ));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|err| Reply::error(400, format!("failed to read request body: {err}")))?;
+    Ok(Request {
+        method,
+        target,
+        authorization,
+        origin,
+        json,
+        slack_timestamp,
+        slack_signature,
+        body,
+    })
+}
+
+fn read_line(head: &mut impl BufRead, line: &mut String) -> Result<(), Reply> {
+    match head.read_line(line) {
+        Ok(_) if line.ends_with('\n') => Ok(()),
+        Ok(_) => Err(Reply::error(
+            431,
+            format!("request line and headers are limited to {MAX_HEADER_BYTES} bytes"),
+        )),
+        Err(err) => Err(Reply::error(400, format!("failed to read request: {err}"))),
+    }
+}
+
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+impl Reply {
+    fn json(status: u16, body: Value) -> Self {
+        Self { status, body }
+    }
+
+    fn error(status: u16, message: String) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn not_found(id: &str) -> Self {
+        Self::error(404, format!("no task {id}"))
+    }
+
+    fn send(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            411 => "Length Required",
+            413 => "Content Too Large",
+            415 => "Unsupported Media Type",
+            431 => "Request Header Fields Too Large",
+            _ => "",
+        };
+        let body = format!("{}\n", self.body);
+        write!(
+            stream,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.status,
+            body.len()
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "s3cret-token";
+
+    fn submission() -> Request {
+        Request {
+            method: "POST".to_string(),
+            target: "/tasks".to_string(),
+            authorization: Some(format!("Bearer {TOKEN}")),
+            origin: false,
+            json: true,
+            slack_timestamp: None,
+            slack_signature: None,
+            body: br#"{"task": "List files"}"#.to_vec(),
+        }
+    }
+
+    fn status(request: &Request) -> Option<u16> {
+        check_request(request, TOKEN)
+            .err()
+            .map(|reply| reply.status)
+    }
+
+    #[test]
+    fn accepts_authorized_json_request() {
+        assert_eq!(status(&submission()), None);
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_token() {
+        let mut request = submission();
+        request.authorization = None;
+        assert_eq!(status(&request), Some(401));
+        request.authorization = Some("Bearer s3cret-tokem".to_string());
+        assert_eq!(status(&request), Some(401));
+        request.authorization = Some(TOKEN.to_string());
+        assert_eq!(status(&request), Some(401));
+    }
+
+    #[test]
+    fn rejects_cross_origin_request() {
+        let mut request = submission();
+        request.origin = true;
+        assert_eq!(status(&request), Some(403));
+    }
+
+    #[test]
+    fn rejects_non_json_body() {
+        let mut request = submission();
+        request.json = false;
+        assert_eq!(status(&request), Some(415));
+    }
+
+    #[test]
+    fn allows_empty_body_without_content_type() {
+        let request = Request {
+            target: "/tasks/1/cancel".to_string(),
+            json: false,
+            body: Vec::new(),
+            ..submission()
+        };
+        assert_eq!(status(&request), None);
+    }
+}
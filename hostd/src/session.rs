@@ -0,0 +1,201 @@
+//! Sessions let one task span several hostd invocations. `--session <id>`
+//! saves the task, the step the planner takes next with its observation,
+//! every step's action reports and what the budgets used to
+//! `<session_dir>/<id>.json`; the next invocation with the same id picks up
+//! from there. Budgets carry over, so restarting never resets them.
+//!
+//! The file is also a checkpoint: it is saved after every step and before
+//! each action, so a run that crashed or was killed resumes after the last
+//! action that finished instead of running any of them again.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::budget::{BudgetKind, Budgets};
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub task: String,
+    /// The step the planner takes next.
+    pub step: u32,
+    /// The observation for that step.
+    pub summary: String,
+    pub observation: Value,
+    /// `{"step", "thought", "actions"}` for every step taken so far.
+    pub reports: Vec<Value>,
+    /// What each budget used, by its `policy.budgets` name.
+    pub budgets: BTreeMap<String, u64>,
+    /// The planner's `{"reason", "outcome"}` once it completed the task; a
+    /// completed session cannot continue.
+    #[serde(default)]
+    pub complete: Option<Value>,
+    /// The step whose actions were running when the file was last saved.
+    #[serde(default)]
+    pub pending: Option<PendingStep>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingStep {
+    pub step: u32,
+    pub thought: String,
+    /// Every action the planner asked for, `{"capability", "input"}`.
+    pub actions: Vec<Value>,
+    /// Reports of the actions that finished, in order.
+    pub reports: Vec<Value>,
+}
+
+impl Session {
+    pub fn new(task: String, step: u32, observation: Value) -> Self {
+        Self {
+            task,
+            step,
+            summary: format!("host bootstrap step {step}"),
+            observation,
+            reports: Vec::new(),
+            budgets: BTreeMap::new(),
+            complete: None,
+            pending: None,
+        }
+    }
+
+    /// Turns a step that was cut off into the next observation, so none of
+    /// its actions run again. The action that was running is reported as
+    /// interrupted, since it may have partly taken effect; later ones as not
+    /// run. Returns whether there was such a step.
+    pub fn settle_interrupted_step(&mut self) -> bool {
+        let Some(pending) = self.pending.take() else {
+            return false;
+        };
+        let finished = pending.reports.len();
+        let mut reports = pending.reports;
+        for (index, action) in pending.actions.iter().enumerate().skip(finished) {
+            let error = if index == finished {
+                "interrupted: hostd stopped while this action ran, so it may have partly taken effect"
+            } else {
+                "not run: hostd stopped before this action started"
+            };
+            reports.push(json!({
+                "capability": action["capability"],
+                "success": false,
+                "output": null,
+                "error": error,
+            }));
+        }
+        self.summary = format!(
+            "step {} was interrupted after {finished} of {} action(s); check their effects before repeating them",
+            pending.step,
+            pending.actions.len()
+        );
+        self.observation = json!({ "actions": reports, "changed_files": [], "interrupted": true });
+        self.reports.push(json!({
+            "step": pending.step,
+            "thought": pending.thought,
+            "actions": reports,
+            "interrupted": true,
+        }));
+        self.step = pending.step.saturating_add(1);
+        true
+    }
+
+    /// Actions run across every step so far.
+    pub fn actions_run(&self) -> u64 {
+        self.reports
+            .iter()
+            .map(|step| step["actions"].as_array().map_or(0, Vec::len) as u64)
+            .sum()
+    }
+
+    /// The latest steps, counted back from the last, in which every action
+    /// failed.
+    pub fn failed_streak(&self) -> u32 {
+        let failed = |step: &Value| {
+            step["actions"].as_array().is_some_and(|actions| {
+                !actions.is_empty() && actions.iter().all(|action| action["success"] != true)
+            })
+        };
+        self.reports
+            .iter()
+            .rev()
+            .take_while(|step| failed(step))
+            .count() as u32
+    }
+
+    /// The saved counters, for `Budgets::resume`.
+    pub fn used_budgets(&self) -> BTreeMap<BudgetKind, u64> {
+        BudgetKind::ALL
+            .into_iter()
+            .filter_map(|kind| Some((kind, *self.budgets.get(kind.name())?)))
+            .collect()
+    }
+
+    pub fn save_budgets(&mut self, budgets: &Budgets) {
+        self.budgets = BudgetKind::ALL
+            .into_iter()
+            .map(|kind| (kind.name().to_string(), budgets.used(kind)))
+            .collect();
+    }
+
+    pub fn load(path: &Utf8Path) -> Result<Option<Self>> {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("failed to read session {path}")),
+        };
+        let session = serde_json::from_str(&raw)
+            .with_context(|| format!("failed to parse session {path}"))?;
+        Ok(Some(session))
+    }
+
+    /// Written to a temporary file first, so an interrupted save leaves the
+    /// previous state in place.
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("failed to create {dir}"))?;
+        }
+        let partial = path.with_extension("json.partial");
+        let json = serde_json::to_vec_pretty(self).context("failed to serialize session")?;
+        fs::write(&partial, json).with_context(|| format!("failed to write {partial}"))?;
+        fs::rename(&partial, path).with_context(|| format!("failed to save session {path}"))
+    }
+}
+
+/// A session being run, with the file it is checkpointed to.
+pub struct Checkpoint {
+    pub session: Session,
+    path: Utf8PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(session: Session, path: Utf8PathBuf) -> Self {
+        Self { session, path }
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// Saves the session with what the budgets have used by now.
+    pub fn save(&mut self, budgets: &Budgets) -> Result<()> {
+        self.session.save_budgets(budgets);
+        self.session.save(&self.path)
+    }
+}
+
+/// The file for session `id`; ids are limited to letters, digits, `-` and
+/// `_` so they cannot leave the session dir.
+pub fn path(dir: &Utf8Path, id: &str) -> Result<Utf8PathBuf> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("session id `{id}` may only use letters, digits, `-` and `_`");
+    }
+    Ok(dir.join(format!("{id}.json")))
+}
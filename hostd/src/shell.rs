@@ -0,0 +1,324 @@
+use std::io::{self, Read, Write};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::cgroup::Cgroup;
+use crate::executor::Container;
+use crate::process::{self, SharedChild, Signal};
+
+/// Room kept after the marker for the exit status and line ending.
+const MARKER_TRAILER: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum ShellError {
+    #[error("shell session has exited")]
+    Exited,
+    #[error("command did not finish within {0:?}; the session was terminated")]
+    Timeout(Duration),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Command language spoken by the session's shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellKind {
+    Posix,
+    Pwsh,
+}
+
+impl ShellKind {
+    pub fn from_program(name: &str) -> Option<Self> {
+        match name.strip_suffix(".exe").unwrap_or(name) {
+            "bash" | "sh" | "zsh" | "dash" => Some(Self::Posix),
+            "pwsh" | "powershell" => Some(Self::Pwsh),
+            _ => None,
+        }
+    }
+
+    /// Arguments that make the shell read commands from stdin without a prompt.
+    pub fn args(self) -> Vec<String> {
+        let args: &[&str] = match self {
+            Self::Posix => &["-s"],
+            Self::Pwsh => &["-NoLogo", "-NoProfile", "-NonInteractive", "-Command", "-"],
+        };
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Wraps `command` so it runs in the shell's own scope, then prints
+    /// `marker` and the exit status on stdout and `marker` on stderr.
+    fn script(self, command: &str, marker: &str) -> String {
+        match self {
+            Self::Posix => format!(
+                "{{\n{command}\n}} </dev/null\n\
+                 __warden_status=$?\n\
+                 printf '\\n%s %d\\n' '{marker}' \"$__warden_status\"\n\
+                 printf '\\n%s\\n' '{marker}' >&2\n"
+            ),
+            // pwsh reading `-Command -` runs a multi-line block only once a
+            // blank line follows it.
+            Self::Pwsh => format!(
+                "$global:LASTEXITCODE = 0\n\
+                 . {{\n{command}\n}}\n\n\
+                 $__wardenOk = $?\n\
+                 $__wardenStatus = if ($global:LASTEXITCODE) {{ $global:LASTEXITCODE }} elseif ($__wardenOk) {{ 0 }} else {{ 1 }}\n\
+                 [Console]::Out.Write(\"`n{marker} $__wardenStatus`n\")\n\
+                 [Console]::Error.Write(\"`n{marker}`n\")\n"
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExecOutput {
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stdout_truncated: bool,
+    pub stderr: Vec<u8>,
+    pub stderr_truncated: bool,
+}
+
+#[derive(Debug)]
+enum ShellOutput {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Closed,
+}
+
+/// A long-lived shell whose cwd and environment carry over between `exec`
+/// calls. Commands run one at a time; each is followed by a marker line the
+/// host waits for, so output never bleeds between calls.
+#[derive(Debug)]
+pub struct ShellSession {
+    kind: ShellKind,
+    child: SharedChild,
+    stdin: ChildStdin,
+    output: Receiver<ShellOutput>,
+    output_limit: u64,
+    execs: u64,
+    exited: bool,
+    /// Removed after the shell is killed, when the session drops.
+    cgroup: Option<Arc<Cgroup>>,
+    container: Option<Arc<Container>>,
+}
+
+impl ShellSession {
+    /// Starts the shell from `cmd`, which the caller has already sandboxed and
+    /// given its env and cgroup. The shell leads its own process group.
+    pub fn spawn(
+        mut cmd: Command,
+        kind: ShellKind,
+        output_limit: u64,
+        cgroup: Option<Arc<Cgroup>>,
+        container: Option<Arc<Container>>,
+    ) -> io::Result<Self> {
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        process::isolate_group(&mut cmd);
+        let mut child = cmd.spawn()?;
+        process::track_group(child.id());
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let (sender, output) = mpsc::channel();
+        if let Some(pipe) = child.stdout.take() {
+            forward(pipe, sender.clone(), ShellOutput::Stdout);
+        }
+        if let Some(pipe) = child.stderr.take() {
+            forward(pipe, sender, ShellOutput::Stderr);
+        }
+        Ok(Self {
+            kind,
+            child: Arc::new(Mutex::new(child)),
+            stdin,
+            output,
+            output_limit,
+            execs: 0,
+            exited: false,
+            cgroup,
+            container,
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        process::lock_child(&self.child).id()
+    }
+
+    pub fn child(&self) -> &SharedChild {
+        &self.child
+    }
+
+    pub fn cgroup(&self) -> Option<&Arc<Cgroup>> {
+        self.cgroup.as_ref()
+    }
+
+    pub fn container(&self) -> Option<&Arc<Container>> {
+        self.container.as_ref()
+    }
+
+    /// Runs `command` and waits for it to finish. Commands read from
+    /// `/dev/null`, not the session's stdin. Exceeding `timeout` kills the
+    /// shell, as does anything that makes it exit.
+    pub fn exec(&mut self, command: &str, timeout: Duration) -> Result<ExecOutput, ShellError> {
+        if self.exited {
+            return Err(ShellError::Exited);
+        }
+        self.execs += 1;
+        let marker = format!("__warden_done_{}_{}__", self.pid(), self.execs);
+        let script = self.kind.script(command, &marker);
+        if let Err(err) = self
+            .stdin
+            .write_all(script.as_bytes())
+            .and_then(|()| self.stdin.flush())
+        {
+            self.terminate();
+            return Err(if err.kind() == io::ErrorKind::BrokenPipe {
+                ShellError::Exited
+            } else {
+                err.into()
+            });
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut stdout = MarkedCapture::new(&marker, self.output_limit);
+        let mut stderr = MarkedCapture::new(&marker, self.output_limit);
+        while !(stdout.finished() && stderr.finished()) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(remaining) {
+                Ok(ShellOutput::Stdout(chunk)) => stdout.push(&chunk),
+                Ok(ShellOutput::Stderr(chunk)) => stderr.push(&chunk),
+                Ok(ShellOutput::Closed) | Err(RecvTimeoutError::Disconnected) => {
+                    self.terminate();
+                    return Err(ShellError::Exited);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.terminate();
+                    return Err(ShellError::Timeout(timeout));
+                }
+            }
+        }
+        let exit_code = stdout.trailer().trim().parse().unwrap_or(-1);
+        let (stdout, stdout_truncated) = stdout.finish();
+        let (stderr, stderr_truncated) = stderr.finish();
+        Ok(ExecOutput {
+            exit_code,
+            stdout,
+            stdout_truncated,
+            stderr,
+            stderr_truncated,
+        })
+    }
+
+    /// Kills the shell's process group and reaps it.
+    fn terminate(&mut self) {
+        self.exited = true;
+        let mut child = process::lock_child(&self.child);
+        let _ = process::send_signal(&mut child, Signal::Kill);
+        let _ = child.wait();
+        process::untrack_group(child.id());
+    }
+}
+
+impl Drop for ShellSession {
+    fn drop(&mut self) {
+        if !self.exited {
+            self.terminate();
+        }
+    }
+}
+
+fn forward<R: Read + Send + 'static>(
+    mut reader: R,
+    sink: Sender<ShellOutput>,
+    wrap: fn(Vec<u8>) -> ShellOutput,
+) {
+    thread::spawn(move || {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if sink.send(wrap(chunk[..read].to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = sink.send(ShellOutput::Closed);
+    });
+}
+
+/// Output of one `exec` on one stream: the first `limit` bytes, plus a short
+/// tail scanned for the marker line that ends the command.
+struct MarkedCapture {
+    needle: Vec<u8>,
+    head: Vec<u8>,
+    limit: usize,
+    tail: Vec<u8>,
+    seen: usize,
+    /// Stream offset of the marker and the text after it, once complete.
+    end: Option<(usize, String)>,
+}
+
+impl MarkedCapture {
+    fn new(marker: &str, limit: u64) -> Self {
+        Self {
+            needle: format!("\n{marker}").into_bytes(),
+            head: Vec::new(),
+            limit: usize::try_from(limit).unwrap_or(usize::MAX),
+            tail: Vec::new(),
+            seen: 0,
+            end: None,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        if self.end.is_some() {
+            return;
+        }
+        let room = self.limit.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        self.seen += chunk.len();
+        self.tail.extend_from_slice(chunk);
+
+        if let Some(pos) = find(&self.tail, &self.needle) {
+            let rest = &self.tail[pos + self.needle.len()..];
+            if let Some(newline) = rest.iter().position(|&byte| byte == b'\n') {
+                let offset = self.seen - self.tail.len() + pos;
+                let trailer = String::from_utf8_lossy(&rest[..newline]).into_owned();
+                self.end = Some((offset, trailer));
+                return;
+            }
+        }
+        let keep = self.needle.len() + MARKER_TRAILER;
+        if self.tail.len() > keep * 2 {
+            self.tail.drain(..self.tail.len() - keep);
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.end.is_some()
+    }
+
+    fn trailer(&self) -> &str {
+        self.end
+            .as_ref()
+            .map_or("", |(_, trailer)| trailer.as_str())
+    }
+
+    /// Output before the marker and whether it was cut at the limit.
+    fn finish(mut self) -> (Vec<u8>, bool) {
+        let offset = self.end.as_ref().map_or(self.seen, |(offset, _)| *offset);
+        self.head.truncate(offset);
+        (self.head, offset > self.limit)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,135 @@
+//! Skill components: specialized capabilities, such as a spreadsheet parser,
+//! shipped as their own components instead of inside agent-core. Each one
+//! `[[skills]]` lists is instantiated into the planner's store with the same
+//! linker, so its fs, llm and policy calls go through the planner's policy,
+//! budgets and audit log. The planner runs a skill's functions with
+//! `skill.<name>.<function>` actions; `skill.<name>.describe` answers what
+//! the skill said it offers.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+use tracing::info;
+use wasmtime::component::Linker;
+use wasmtime::{Store, Trap};
+
+use crate::runtime::{Loader, epoch_ticks};
+use crate::state::HostState;
+
+mod bindings {
+    // Skills import a subset of the planner's interfaces, so those reuse
+    // its bindings and host implementations.
+    wasmtime::component::bindgen!({
+        path: "../wit",
+        world: "osagent:skill/provider",
+        async: true,
+        with: {
+            "osagent:common/types": crate::bindings::osagent::common::types,
+            "osagent:fs/fs": crate::bindings::osagent::fs::fs,
+            "osagent:llm/llm": crate::bindings::osagent::llm::llm,
+            "osagent:policy/policy": crate::bindings::osagent::policy::policy,
+        },
+    });
+}
+
+/// Planner actions with this prefix run a skill.
+const ACTION_PREFIX: &str = "skill.";
+
+struct Skill {
+    provider: bindings::Provider,
+    description: String,
+}
+
+/// The task's skill instances, by name.
+pub struct Skills {
+    loaded: BTreeMap<String, Skill>,
+}
+
+impl Skills {
+    /// Instantiates every `[[skills]]` component into `store` and asks each
+    /// what it offers.
+    pub async fn instantiate(
+        store: &mut Store<HostState>,
+        linker: &Linker<HostState>,
+        loader: &Loader,
+    ) -> Result<Self> {
+        let settings = store.data().config.skills.clone();
+        let mut loaded = BTreeMap::new();
+        for skill in settings {
+            let component =
+                loader.allowed_component(skill.component.as_std_path(), &store.data().config)?;
+            let provider = bindings::Provider::instantiate_async(&mut *store, &component, linker)
+                .await
+                .with_context(|| format!("failed to instantiate skill `{}`", skill.name))?;
+            let description = provider
+                .osagent_skill_skill()
+                .call_describe(&mut *store)
+                .await
+                .with_context(|| format!("skill `{}` failed to describe itself", skill.name))?;
+            info!(
+                skill = skill.name,
+                component = %skill.component,
+                description,
+                "loaded skill"
+            );
+            loaded.insert(
+                skill.name,
+                Skill {
+                    provider,
+                    description,
+                },
+            );
+        }
+        Ok(Self { loaded })
+    }
+
+    /// Whether the action names a skill function rather than a host
+    /// capability.
+    pub fn handles(capability: &str) -> bool {
+        capability.starts_with(ACTION_PREFIX)
+    }
+
+    /// Runs a `skill.<name>.<function>` action under `policy.planner_fuel`
+    /// and `planner.action_timeout_ms`. The output is the function's JSON,
+    /// or its text when that is not JSON.
+    pub async fn invoke(
+        &self,
+        store: &mut Store<HostState>,
+        capability: &str,
+        input: &str,
+    ) -> Result<Value> {
+        let (name, function) = capability
+            .strip_prefix(ACTION_PREFIX)
+            .and_then(|target| target.split_once('.'))
+            .with_context(|| format!("`{capability}` is not `skill.<name>.<function>`"))?;
+        let skill = self
+            .loaded
+            .get(name)
+            .with_context(|| format!("no skill `{name}` is loaded (see [[skills]])"))?;
+        if function == "describe" {
+            return Ok(Value::String(skill.description.clone()));
+        }
+        let fuel = store.data().config.policy.planner_fuel;
+        store.set_fuel(fuel)?;
+        let timeout = store.data().config.planner.action_timeout;
+        store.set_epoch_deadline(epoch_ticks(timeout));
+        let result = skill
+            .provider
+            .osagent_skill_skill()
+            .call_invoke(&mut *store, function, &input.to_string())
+            .await;
+        let output = match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => bail!("{:?}: {}", err.code, err.message),
+            Err(err) if err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) => {
+                bail!("`{capability}` ran out of fuel ({fuel} units, policy.planner_fuel)")
+            }
+            Err(err) if err.downcast_ref::<Trap>() == Some(&Trap::Interrupt) => {
+                bail!("`{capability}` timed out after {timeout:?} (planner.action_timeout_ms)")
+            }
+            Err(err) => return Err(err).with_context(|| format!("skill `{name}` failed")),
+        };
+        Ok(serde_json::from_str(&output).unwrap_or(Value::String(output)))
+    }
+}
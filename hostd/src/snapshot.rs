@@ -0,0 +1,310 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::{debug, info};
+
+use crate::changes::{ChangeKind, ChangeSet};
+
+/// Tells apart snapshots that tasks running side by side take in the same
+/// millisecond.
+static NEXT_SNAPSHOT: AtomicU64 = AtomicU64::new(0);
+
+/// Point-in-time copy of the workspace kept outside of it so the agent cannot
+/// tamper with it. Files are cloned with reflinks where the filesystem supports
+/// copy-on-write and copied otherwise; hardlinks are never used because
+/// in-place writes through `fs.file.write` would mutate the snapshot too.
+#[derive(Debug)]
+pub struct Snapshot {
+    workspace: Utf8PathBuf,
+    dir: Utf8PathBuf,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RestoreSummary {
+    pub restored: u64,
+    pub removed: u64,
+}
+
+impl Snapshot {
+    pub fn capture(workspace: &Utf8Path, store: &Utf8Path) -> Result<Self> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let dir = store.join(format!(
+            "{}-{now_ms}-{}",
+            std::process::id(),
+            NEXT_SNAPSHOT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(dir.as_std_path())
+            .with_context(|| format!("failed to create snapshot dir {dir}"))?;
+        let snapshot = Self {
+            workspace: workspace.to_path_buf(),
+            dir,
+        };
+        let files = copy_tree(workspace, &snapshot.dir, &snapshot.dir)
+            .with_context(|| format!("failed to snapshot workspace {workspace}"))?;
+        info!(workspace = %workspace, snapshot = %snapshot.dir, files, "captured workspace snapshot");
+        Ok(snapshot)
+    }
+
+    /// Rewrites the workspace to match the snapshot, recording every path
+    /// touched in `changes`.
+    pub fn restore(&self, changes: &mut ChangeSet) -> Result<RestoreSummary> {
+        let mut summary = RestoreSummary::default();
+        self.prune(&self.workspace, changes, &mut summary)?;
+        self.restore_dir(&self.dir, &self.workspace, changes, &mut summary)?;
+        info!(
+            workspace = %self.workspace,
+            restored = summary.restored,
+            removed = summary.removed,
+            "restored workspace snapshot"
+        );
+        Ok(summary)
+    }
+
+    /// Removes workspace entries that did not exist when the snapshot was taken.
+    fn prune(
+        &self,
+        dir: &Utf8Path,
+        changes: &mut ChangeSet,
+        summary: &mut RestoreSummary,
+    ) -> Result<()> {
+        for entry in read_dir_utf8(dir)? {
+            let rel = entry.strip_prefix(&self.workspace).unwrap_or(&entry);
+            let saved = self.dir.join(rel);
+            if entry.starts_with(&self.dir) {
+                continue;
+            }
+            let meta = fs::symlink_metadata(entry.as_std_path())?;
+            let saved_meta = fs::symlink_metadata(saved.as_std_path()).ok();
+            let same_kind = saved_meta.as_ref().is_some_and(|saved| {
+                saved.is_dir() == meta.is_dir() && saved.is_symlink() == meta.is_symlink()
+            });
+            // A link pointed elsewhere is removed here and recreated from
+            // the snapshot by `restore_dir`.
+            let unchanged = same_kind && (!meta.is_symlink() || same_link(&saved, &entry));
+            if !unchanged {
+                if meta.is_dir() {
+                    fs::remove_dir_all(entry.as_std_path())?;
+                } else {
+                    fs::remove_file(entry.as_std_path())?;
+                }
+                changes.record(&entry, ChangeKind::Deleted);
+                summary.removed += 1;
+            } else if meta.is_dir() {
+                self.prune(&entry, changes, summary)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_dir(
+        &self,
+        from: &Utf8Path,
+        to: &Utf8Path,
+        changes: &mut ChangeSet,
+        summary: &mut RestoreSummary,
+    ) -> Result<()> {
+        if !to.exists() {
+            fs::create_dir_all(to.as_std_path())?;
+            changes.record(to, ChangeKind::Created);
+        }
+        for entry in read_dir_utf8(from)? {
+            let target = to.join(entry.file_name().unwrap_or_default());
+            let meta = fs::symlink_metadata(entry.as_std_path())?;
+            if meta.is_dir() {
+                self.restore_dir(&entry, &target, changes, summary)?;
+            } else if meta.is_symlink() {
+                if !same_link(&entry, &target) {
+                    let existing = fs::symlink_metadata(target.as_std_path()).ok();
+                    match &existing {
+                        Some(existing) if existing.is_dir() => {
+                            fs::remove_dir_all(target.as_std_path())?;
+                        }
+                        Some(_) => fs::remove_file(target.as_std_path())?,
+                        None => {}
+                    }
+                    copy_symlink(&entry, &target)?;
+                    changes.record_write(&target, existing.is_some());
+                    summary.restored += 1;
+                }
+            } else if !same_contents(&entry, &target)? {
+                let existed = target.exists();
+                if existed {
+                    fs::remove_file(target.as_std_path())?;
+                }
+                reflink_copy::reflink_or_copy(entry.as_std_path(), target.as_std_path())
+                    .with_context(|| format!("failed to restore {target}"))?;
+                changes.record_write(&target, existed);
+                summary.restored += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(self.dir.as_std_path()) {
+            debug!(snapshot = %self.dir, error = %err, "failed to remove snapshot");
+        }
+    }
+}
+
+/// Default location for snapshots: the per-user cache dir, never the workspace.
+pub fn default_store() -> Option<Utf8PathBuf> {
+    let base = dirs::cache_dir()?.join("wasi-warden").join("snapshots");
+    Utf8PathBuf::from_path_buf(base).ok()
+}
+
+fn copy_tree(from: &Utf8Path, to: &Utf8Path, skip: &Utf8Path) -> Result<u64> {
+    let mut files = 0;
+    for entry in read_dir_utf8(from)? {
+        if entry.starts_with(skip) {
+            continue;
+        }
+        let target = to.join(entry.file_name().unwrap_or_default());
+        let meta = fs::symlink_metadata(entry.as_std_path())?;
+        if meta.is_dir() {
+            fs::create_dir_all(target.as_std_path())?;
+            files += copy_tree(&entry, &target, skip)?;
+        } else if meta.is_symlink() {
+            copy_symlink(&entry, &target)?;
+            files += 1;
+        } else if meta.is_file() {
+            reflink_copy::reflink_or_copy(entry.as_std_path(), target.as_std_path())
+                .with_context(|| format!("failed to copy {entry}"))?;
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+fn read_dir_utf8(dir: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir.as_std_path()).with_context(|| format!("failed to read {dir}"))? {
+        let path = entry?.path();
+        let path = Utf8PathBuf::from_path_buf(path)
+            .map_err(|p| anyhow!("path {} is not valid UTF-8", p.display()))?;
+        entries.push(path);
+    }
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    let link = fs::read_link(from.as_std_path())?;
+    std::os::unix::fs::symlink(link, to.as_std_path())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(_from: &Utf8Path, _to: &Utf8Path) -> Result<()> {
+    Ok(())
+}
+
+/// Whether both are symlinks with the same target.
+fn same_link(a: &Utf8Path, b: &Utf8Path) -> bool {
+    match (
+        fs::read_link(a.as_std_path()),
+        fs::read_link(b.as_std_path()),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn same_contents(a: &Utf8Path, b: &Utf8Path) -> Result<bool> {
+    let (Ok(meta_a), Ok(meta_b)) = (fs::metadata(a), fs::metadata(b)) else {
+        return Ok(false);
+    };
+    if !meta_b.is_file() || meta_a.len() != meta_b.len() {
+        return Ok(false);
+    }
+    let mut reader_a = BufReader::new(File::open(a)?);
+    let mut reader_b = BufReader::new(File::open(b)?);
+    let mut buf_a = [0u8; 8192];
+    let mut buf_b = [0u8; 8192];
+    loop {
+        let read = reader_a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        reader_b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use super::*;
+
+    fn utf8_dir(dir: &tempfile::TempDir) -> Utf8PathBuf {
+        Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).expect("temp dir is UTF-8")
+    }
+
+    #[test]
+    fn restores_retargeted_symlink() {
+        let workspace = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let workspace = utf8_dir(&workspace);
+        fs::write(workspace.join("notes.txt"), "notes").unwrap();
+        symlink("notes.txt", workspace.join("current")).unwrap();
+        let snapshot = Snapshot::capture(&workspace, &utf8_dir(&store)).unwrap();
+
+        fs::remove_file(workspace.join("current")).unwrap();
+        symlink("/etc/passwd", workspace.join("current")).unwrap();
+        let mut changes = ChangeSet::default();
+        let summary = snapshot.restore(&mut changes).unwrap();
+
+        assert_eq!(
+            fs::read_link(workspace.join("current")).unwrap(),
+            Path::new("notes.txt")
+        );
+        assert_eq!(summary.restored, 1);
+    }
+
+    #[test]
+    fn recreates_removed_symlink_and_drops_new_entries() {
+        let workspace = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let workspace = utf8_dir(&workspace);
+        symlink("missing-target", workspace.join("dangling")).unwrap();
+        let snapshot = Snapshot::capture(&workspace, &utf8_dir(&store)).unwrap();
+
+        fs::remove_file(workspace.join("dangling")).unwrap();
+        fs::write(workspace.join("added.txt"), "new").unwrap();
+        let mut changes = ChangeSet::default();
+        let summary = snapshot.restore(&mut changes).unwrap();
+
+        assert_eq!(
+            fs::read_link(workspace.join("dangling")).unwrap(),
+            Path::new("missing-target")
+        );
+        assert!(!workspace.join("added.txt").exists());
+        assert_eq!((summary.restored, summary.removed), (1, 1));
+    }
+
+    #[test]
+    fn keeps_unchanged_symlink() {
+        let workspace = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let workspace = utf8_dir(&workspace);
+        symlink("elsewhere", workspace.join("link")).unwrap();
+        let snapshot = Snapshot::capture(&workspace, &utf8_dir(&store)).unwrap();
+
+        let summary = snapshot.restore(&mut ChangeSet::default()).unwrap();
+
+        assert_eq!((summary.restored, summary.removed), (0, 0));
+    }
+}
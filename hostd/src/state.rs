@@ -1,22 +1,85 @@
+use std::sync::Arc;
+
+use tokio::runtime::Handle;
 use wasmtime::component::ResourceTable;
+use wasmtime::{StoreLimits, StoreLimitsBuilder};
 use wasmtime_wasi::{IoView, WasiCtx, WasiCtxBuilder, WasiView};
 
+use crate::budget::Budgets;
+use crate::changes::ChangeSet;
 use crate::config::HostConfig;
+use crate::grant::Grants;
+use crate::input::Desktop;
+use crate::process::ProcessRegistry;
+use crate::ratelimit::RateLimits;
+use crate::taint::Untrusted;
+use crate::transcript::Transcript;
 
 #[allow(dead_code)]
 pub struct HostState {
     pub config: HostConfig,
     pub resources: ResourceTable,
     pub wasi_ctx: WasiCtx,
+    /// Live fs dir/file handles, checked against `fs.max_open_handles`.
+    pub open_fs_handles: usize,
+    /// Paths changed through the fs/archive capabilities since the last drain.
+    pub changes: ChangeSet,
+    /// Children spawned through the proc capability that are still running.
+    pub processes: ProcessRegistry,
+    /// Open shell sessions, checked against `shell.max_sessions`.
+    pub open_shell_sessions: usize,
+    /// Desktop connection for the input capability, opened on first use.
+    pub desktop: Option<Desktop>,
+    /// The task's `[policy.budgets]` counters, shared with the planner's
+    /// action executor.
+    pub budgets: Arc<Budgets>,
+    /// Capabilities the operator approved through `request-capability`.
+    pub grants: Arc<Grants>,
+    /// `[policy.rate_limits]` windows, shared with the action executor.
+    pub rate_limits: Arc<RateLimits>,
+    /// Web content read through the browser, shared with the action
+    /// executor.
+    pub untrusted: Arc<Untrusted>,
+    /// Set when `llm.transcript_dir` is configured.
+    pub llm_transcript: Option<Transcript>,
+    /// Runtime the async browser client is driven on.
+    pub tokio: Handle,
+    /// The `[planner]` memory, table and instance limits, which the store's
+    /// resource limiter enforces.
+    pub limits: StoreLimits,
 }
 
 impl HostState {
-    pub fn new(config: HostConfig) -> Self {
+    pub fn new(
+        config: HostConfig,
+        tokio: Handle,
+        budgets: Arc<Budgets>,
+        grants: Arc<Grants>,
+        rate_limits: Arc<RateLimits>,
+        untrusted: Arc<Untrusted>,
+    ) -> Self {
         let wasi_ctx = WasiCtxBuilder::new().build();
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.planner.max_memory_bytes)
+            .table_elements(config.planner.max_table_elements)
+            .instances(config.planner.max_instances)
+            .build();
         Self {
             config,
             resources: ResourceTable::new(),
             wasi_ctx,
+            open_fs_handles: 0,
+            changes: ChangeSet::default(),
+            processes: ProcessRegistry::default(),
+            open_shell_sessions: 0,
+            desktop: None,
+            budgets,
+            grants,
+            rate_limits,
+            untrusted,
+            llm_transcript: None,
+            tokio,
+            limits,
         }
     }
 }
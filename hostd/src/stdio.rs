@@ -0,0 +1,286 @@
+//! `hostd stdio`: JSON-RPC 2.0 over stdin and stdout, one message per line,
+//! so an editor or desktop app can run hostd as a subprocess the way it runs
+//! a language server. Logs stay on stderr.
+//!
+//! - `submit_task` takes what `POST /tasks` does under `hostd serve` and
+//!   answers `{"task_id"}`; `cancel_task` takes `{"task_id"}`.
+//! - hostd notifies `step_event` after each step and `task_finished` when a
+//!   task ends.
+//! - hostd asks `approve` instead of prompting on the terminal, unless
+//!   `policy.approval_webhook` is set. The client answers as the webhook
+//!   would.
+//!
+//! Tasks run one at a time in submission order. Closing stdin cancels them,
+//! and hostd exits once the running one stops.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::cli::{StdioArgs, StepArgs, TaskHostArgs};
+use crate::grant::{self, Answer, Pending};
+use crate::queue::TaskRequest;
+use crate::runtime::{self, Loader, Watch};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Requests that are well formed but name no queued or running task.
+const UNKNOWN_TASK: i64 = -32000;
+
+type RpcResult = Result<Value, (i64, String)>;
+
+pub async fn run(args: StdioArgs) -> Result<()> {
+    let loader = Loader::new()?;
+    // Compiled now, so a bad path fails before the client submits anything.
+    loader.component(&args.host.component)?;
+    let rpc = Arc::new(Rpc::default());
+    let approver = Arc::clone(&rpc);
+    grant::set_approver(Box::new(move |pending: &Pending<'_>, timeout: Duration| {
+        approver.approve(pending, timeout)
+    }));
+    info!("speaking JSON-RPC on stdio");
+
+    let (queue, queued) = mpsc::channel();
+    let handle = Handle::current();
+    let worker_rpc = Arc::clone(&rpc);
+    let worker = thread::spawn(move || worker_rpc.run_queue(&queued, &loader, &handle));
+    tokio::task::spawn_blocking(move || {
+        let result = rpc.read_requests(&args.host, queue);
+        rpc.cancel_all();
+        if worker.join().is_err() {
+            warn!("task worker panicked");
+        }
+        result
+    })
+    .await?
+}
+
+struct Queued {
+    id: u64,
+    args: StepArgs,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Deserialize)]
+struct TaskRef {
+    task_id: u64,
+}
+
+#[derive(Default)]
+struct Rpc {
+    /// Held while a message is written, so lines from the reader and the
+    /// worker never interleave.
+    stdout: Mutex<()>,
+    next_task: AtomicU64,
+    /// The task running now, for `approve`; 0 when none is.
+    running: AtomicU64,
+    /// Cancellation flags of queued and running tasks.
+    tasks: Mutex<HashMap<u64, Arc<AtomicBool>>>,
+    next_request: AtomicU64,
+    /// hostd's requests still waiting on the client's response.
+    replies: Mutex<HashMap<u64, Sender<Value>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Rpc {
+    fn write(&self, message: &Value) {
+        let _guard = lock(&self.stdout);
+        let mut stdout = io::stdout().lock();
+        let written = writeln!(stdout, "{message}").and_then(|()| stdout.flush());
+        if let Err(err) = written {
+            warn!(error = %err, "failed to write to stdout");
+        }
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        self.write(&json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn respond(&self, id: Value, result: RpcResult) {
+        let message = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        self.write(&message);
+    }
+
+    fn read_requests(&self, host: &TaskHostArgs, queue: Sender<Queued>) -> Result<()> {
+        for line in io::stdin().lock().lines() {
+            let line = line.context("failed to read stdin")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(message) => self.dispatch(message, host, &queue),
+                Err(err) => self.respond(Value::Null, Err((PARSE_ERROR, err.to_string()))),
+            }
+        }
+        info!("stdin closed; cancelling tasks");
+        Ok(())
+    }
+
+    fn dispatch(&self, message: Value, host: &TaskHostArgs, queue: &Sender<Queued>) {
+        let id = message.get("id").cloned();
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            // The client's response to one of hostd's requests.
+            match id.as_ref().and_then(Value::as_u64) {
+                Some(id) => self.deliver(id, message),
+                None => self.respond(
+                    Value::Null,
+                    Err((INVALID_REQUEST, "message has no method".to_string())),
+                ),
+            }
+            return;
+        };
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let result = match method {
+            "submit_task" => self.submit(params, host, queue),
+            "cancel_task" => self.cancel(params),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {method}"))),
+        };
+        // Notifications from the client get no response.
+        if let Some(id) = id {
+            self.respond(id, result);
+        }
+    }
+
+    fn deliver(&self, id: u64, response: Value) {
+        match lock(&self.replies).remove(&id) {
+            Some(reply) => {
+                let _ = reply.send(response);
+            }
+            None => warn!(id, "response to no outstanding request"),
+        }
+    }
+
+    fn submit(&self, params: Value, host: &TaskHostArgs, queue: &Sender<Queued>) -> RpcResult {
+        let request: TaskRequest = serde_json::from_value(params)
+            .map_err(|err| (INVALID_PARAMS, format!("invalid task request: {err}")))?;
+        let args = request
+            .into_step_args(host)
+            .map_err(|err| (INVALID_PARAMS, err))?;
+        let id = self.next_task.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        lock(&self.tasks).insert(id, Arc::clone(&cancelled));
+        queue
+            .send(Queued {
+                id,
+                args,
+                cancelled,
+            })
+            .map_err(|_| (INTERNAL_ERROR, "the task worker has stopped".to_string()))?;
+        info!(id, "stdio task queued");
+        Ok(json!({ "task_id": id }))
+    }
+
+    fn cancel(&self, params: Value) -> RpcResult {
+        let TaskRef { task_id } = serde_json::from_value(params)
+            .map_err(|err| (INVALID_PARAMS, format!("invalid cancel request: {err}")))?;
+        let tasks = lock(&self.tasks);
+        let cancelled = tasks
+            .get(&task_id)
+            .ok_or_else(|| (UNKNOWN_TASK, format!("no queued or running task {task_id}")))?;
+        cancelled.store(true, Ordering::Relaxed);
+        Ok(json!({ "task_id": task_id }))
+    }
+
+    fn cancel_all(&self) {
+        for cancelled in lock(&self.tasks).values() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        // Nobody is left to answer; waiting approvals deny.
+        lock(&self.replies).clear();
+    }
+
+    fn run_queue(&self, queued: &Receiver<Queued>, loader: &Loader, handle: &Handle) {
+        for task in queued {
+            let id = task.id;
+            let result = if task.cancelled.load(Ordering::Relaxed) {
+                Err(anyhow!("task cancelled before it started"))
+            } else {
+                info!(id, task = task.args.task.as_deref(), "stdio task started");
+                self.running.store(id, Ordering::Relaxed);
+                let on_step = |mut event: Value| {
+                    event["task_id"] = json!(id);
+                    self.notify("step_event", event);
+                };
+                let watch = Watch {
+                    cancelled: Some(&task.cancelled),
+                    on_step: Some(&on_step),
+                    ..Watch::default()
+                };
+                let result = handle.block_on(runtime::execute(&task.args, loader, watch));
+                self.running.store(0, Ordering::Relaxed);
+                result
+            };
+            let status = match &result {
+                Ok(_) => "succeeded",
+                Err(_) if task.cancelled.load(Ordering::Relaxed) => "cancelled",
+                Err(_) => "failed",
+            };
+            info!(id, status, "stdio task finished");
+            lock(&self.tasks).remove(&id);
+            self.notify(
+                "task_finished",
+                json!({
+                    "task_id": id,
+                    "status": status,
+                    "result": result.as_ref().ok().map(runtime::Finished::to_json),
+                    "error": result.as_ref().err().map(|err| format!("{err:#}")),
+                }),
+            );
+        }
+    }
+
+    /// Sends `approve` to the client and waits up to `timeout` for its
+    /// answer.
+    fn approve(&self, pending: &Pending<'_>, timeout: Duration) -> Result<Answer, String> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed) + 1;
+        let (reply, response) = mpsc::channel();
+        lock(&self.replies).insert(id, reply);
+        self.write(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "approve",
+            "params": {
+                "task_id": self.running.load(Ordering::Relaxed),
+                "kind": pending.kind,
+                "capability": pending.capability,
+                "scope": pending.scope,
+                "detail": pending.detail,
+            },
+        }));
+        let response = response.recv_timeout(timeout);
+        lock(&self.replies).remove(&id);
+        let response = response.map_err(|err| match err {
+            RecvTimeoutError::Timeout => format!("the client did not answer within {timeout:?}"),
+            RecvTimeoutError::Disconnected => "the client closed stdin".to_string(),
+        })?;
+        if let Some(error) = response.get("error") {
+            return Err(format!("the client refused the approval request: {error}"));
+        }
+        grant::answer_from_json(response.get("result").cloned().unwrap_or(Value::Null))
+    }
+}
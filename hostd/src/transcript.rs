@@ -0,0 +1,137 @@
+//! Per-task record of what the planner sent the model and what came back,
+//! written when `llm.transcript_dir` is set, so planner behavior can be
+//! debugged from the host instead of from prints inside the component.
+//! Secrets are masked before anything reaches the disk.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::config::LlmSettings;
+use crate::llm::{Completion, LlmError, Message, Options, Tool};
+
+const REDACTED: &str = "[redacted]";
+
+static NEXT_TRANSCRIPT: AtomicU64 = AtomicU64::new(0);
+
+/// Masked whatever `llm.redact` says: provider API keys, cloud and forge
+/// tokens, bearer credentials and PEM private keys.
+const BUILTIN_PATTERNS: &[&str] = &[
+    r"sk-[A-Za-z0-9_-]{20,}",
+    r"AKIA[0-9A-Z]{16}",
+    r"gh[pousr]_[A-Za-z0-9]{36,}",
+    r"xox[abpr]-[A-Za-z0-9-]{10,}",
+    r"(?i)bearer\s+[A-Za-z0-9._~+/=-]{16,}",
+    r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----",
+];
+
+pub struct Transcript {
+    path: Utf8PathBuf,
+    file: File,
+    /// Every configured API key, masked wherever it appears.
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Transcript {
+    /// Starts a new file under `dir` for this task. On unix only the host
+    /// user can read it.
+    pub fn create(settings: &LlmSettings, dir: &Utf8Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "{}-{}-{}.jsonl",
+            now_ms(),
+            std::process::id(),
+            NEXT_TRANSCRIPT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut open = fs::OpenOptions::new();
+        open.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+        let file = open.open(&path)?;
+        let mut secrets: Vec<String> = std::iter::once(settings)
+            .chain(settings.profiles.values())
+            .map(|settings| settings.api_key.clone())
+            .filter(|key| !key.is_empty())
+            .collect();
+        secrets.sort();
+        secrets.dedup();
+        let patterns = BUILTIN_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).expect("built-in redaction pattern is valid"))
+            .chain(settings.redact.iter().cloned())
+            .collect();
+        info!(path = %path, "writing llm transcript");
+        Ok(Self {
+            path,
+            file,
+            secrets,
+            patterns,
+        })
+    }
+
+    /// Appends one request and its outcome as a line of JSON. A failed
+    /// write is logged rather than returned; the task goes on without it.
+    pub fn record(
+        &mut self,
+        op: &str,
+        settings: &LlmSettings,
+        messages: &[Message],
+        tools: Option<&[Tool]>,
+        options: &Options,
+        result: &Result<Completion, LlmError>,
+    ) {
+        let mut entry = json!({
+            "at_ms": now_ms(),
+            "op": op,
+            "provider": settings.provider,
+            "model": settings.model,
+            "messages": messages,
+            "tools": tools,
+            "options": options,
+        });
+        match result {
+            Ok(completion) => {
+                entry["response"] = json!(completion);
+                entry["cached"] = json!(completion.cached);
+            }
+            Err(err) => entry["error"] = json!(err.to_string()),
+        }
+        self.redact(&mut entry);
+        if let Err(err) = writeln!(self.file, "{entry}") {
+            warn!(path = %self.path, error = %err, "failed to write llm transcript");
+        }
+    }
+
+    fn redact(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                for secret in &self.secrets {
+                    if text.contains(secret.as_str()) {
+                        *text = text.replace(secret.as_str(), REDACTED);
+                    }
+                }
+                for pattern in &self.patterns {
+                    if pattern.is_match(text) {
+                        *text = pattern.replace_all(text, REDACTED).into_owned();
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact(item)),
+            Value::Object(fields) => fields.values_mut().for_each(|field| self.redact(field)),
+            _ => {}
+        }
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis())
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PathError {
+    #[error("absolute paths are not allowed")]
+    Absolute,
+    #[error("parent segments are not allowed")]
+    ParentSegment,
+    #[error("path is not valid UTF-8")]
+    NotUtf8,
+    #[error("path `{0}` escapes workspace root")]
+    Escapes(Utf8PathBuf),
+    #[error("path `{0}` goes through a dangling symlink")]
+    DanglingSymlink(Utf8PathBuf),
+    #[error("failed to resolve `{path}`: {source}")]
+    Resolve {
+        path: Utf8PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Lexically joins a guest-supplied relative path onto `parent`, rejecting
+/// absolute paths and `..` so the result can only descend.
+pub fn join_relative(parent: &Utf8Path, relative: &str) -> Result<Utf8PathBuf, PathError> {
+    let rel_path = Path::new(relative);
+    if rel_path.is_absolute() {
+        return Err(PathError::Absolute);
+    }
+    let mut result = parent.as_std_path().to_path_buf();
+    for component in rel_path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return Err(PathError::Absolute),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(PathError::ParentSegment),
+            Component::Normal(seg) => result.push(seg),
+        }
+    }
+    Utf8PathBuf::from_path_buf(result).map_err(|_| PathError::NotUtf8)
+}
+
+/// Verifies `candidate` stays under `root` after resolving symlinks. The
+/// longest existing prefix is canonicalized (the rest cannot contain links
+/// yet) and compared component-wise, so `/ws-evil` never matches `/ws` and a
+/// symlink inside the workspace cannot point the agent elsewhere.
+pub fn ensure_contained(root: &Utf8Path, candidate: &Utf8Path) -> Result<(), PathError> {
+    let root = fs::canonicalize(root.as_std_path()).map_err(|source| PathError::Resolve {
+        path: root.to_path_buf(),
+        source,
+    })?;
+    let resolved = resolve_existing_prefix(candidate)?;
+    if resolved.starts_with(&root) {
+        Ok(())
+    } else {
+        Err(PathError::Escapes(candidate.to_path_buf()))
+    }
+}
+
+fn resolve_existing_prefix(candidate: &Utf8Path) -> Result<PathBuf, PathError> {
+    let resolve_error = |source| PathError::Resolve {
+        path: candidate.to_path_buf(),
+        source,
+    };
+    let mut pending = Vec::new();
+    let mut current = candidate.as_std_path();
+    loop {
+        match fs::canonicalize(current) {
+            Ok(mut resolved) => {
+                for segment in pending.iter().rev() {
+                    resolved.push(segment);
+                }
+                return Ok(resolved);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // The entry exists but cannot be resolved: a link to nowhere,
+                // which a later create would follow to an unchecked target.
+                if fs::symlink_metadata(current).is_ok() {
+                    return Err(PathError::DanglingSymlink(candidate.to_path_buf()));
+                }
+                let (Some(parent), Some(name)) = (current.parent(), current.file_name()) else {
+                    return Err(resolve_error(err));
+                };
+                pending.push(name.to_os_string());
+                current = parent;
+            }
+            Err(err) => return Err(resolve_error(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn utf8_dir(dir: &tempfile::TempDir) -> Utf8PathBuf {
+        Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).expect("temp dir is UTF-8")
+    }
+
+    #[test]
+    fn rejects_parent_segments() {
+        let root = Utf8Path::new("/workspace");
+        assert!(matches!(
+            join_relative(root, "../etc/passwd"),
+            Err(PathError::ParentSegment)
+        ));
+        assert!(matches!(
+            join_relative(root, "notes/../../etc"),
+            Err(PathError::ParentSegment)
+        ));
+        assert!(matches!(
+            join_relative(root, "/etc/passwd"),
+            Err(PathError::Absolute)
+        ));
+        assert_eq!(
+            join_relative(root, "./notes/today.md").unwrap(),
+            Utf8PathBuf::from("/workspace/notes/today.md")
+        );
+    }
+
+    #[test]
+    fn rejects_parent_traversal_in_candidate() {
+        let root = tempfile::tempdir().unwrap();
+        let root = utf8_dir(&root);
+        fs::create_dir(root.join("notes")).unwrap();
+
+        let candidate = root.join("notes/../../outside.txt");
+        assert!(matches!(
+            ensure_contained(&root, &candidate),
+            Err(PathError::Escapes(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_sibling_with_root_prefix() {
+        let base = tempfile::tempdir().unwrap();
+        let base = utf8_dir(&base);
+        let root = base.join("workspace");
+        let sibling = base.join("workspace-evil");
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&sibling).unwrap();
+
+        assert!(matches!(
+            ensure_contained(&root, &sibling.join("loot.txt")),
+            Err(PathError::Escapes(_))
+        ));
+        assert!(ensure_contained(&root, &root.join("notes.txt")).is_ok());
+    }
+
+    #[test]
+    fn rejects_symlinked_parent_outside_root() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root = utf8_dir(&root);
+        symlink(outside.path(), root.join("escape")).unwrap();
+
+        let candidate = join_relative(&root, "escape/new/file.txt").unwrap();
+        assert!(matches!(
+            ensure_contained(&root, &candidate),
+            Err(PathError::Escapes(_))
+        ));
+    }
+
+    #[test]
+    fn allows_symlink_within_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = utf8_dir(&root);
+        fs::create_dir(root.join("real")).unwrap();
+        symlink(root.join("real"), root.join("alias")).unwrap();
+
+        let candidate = join_relative(&root, "alias/file.txt").unwrap();
+        assert!(ensure_contained(&root, &candidate).is_ok());
+    }
+
+    #[test]
+    fn rejects_dangling_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root = utf8_dir(&root);
+        symlink(outside.path().join("missing"), root.join("dangling")).unwrap();
+
+        let direct = join_relative(&root, "dangling").unwrap();
+        assert!(matches!(
+            ensure_contained(&root, &direct),
+            Err(PathError::DanglingSymlink(_))
+        ));
+        let below = join_relative(&root, "dangling/child.txt").unwrap();
+        assert!(matches!(
+            ensure_contained(&root, &below),
+            Err(PathError::DanglingSymlink(_))
+        ));
+    }
+}